@@ -0,0 +1,11 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // use the vendored protoc, so that building doesn't require one installed
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    tonic_build::configure().build_client(false).compile(
+        &["proto/redlimit.proto", "proto/envoy_rls.proto"],
+        &["proto"],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,322 @@
+//! redlimit-bench drives "POST /limiting" of a running redlimit service with
+//! the JSON contract of the redlimit client, or the limiting functions in Redis
+//! directly with `--redis`, then reports the throughput, the latency
+//! percentiles and the error rate.
+//!
+//! ```sh
+//! redlimit-bench --url http://127.0.0.1:8080 -c 64 -d 30 --keys 10000
+//! redlimit-bench --redis redis://127.0.0.1:6379 -c 64 -n 1000000 --limit 100,10000
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use redlimit_client::{CheckRequest, Client, Options};
+use redlimit_core::{
+    conf::{self, Algorithm},
+    redis::{self, RedisPool},
+    redlimit::{self, LimitArgs, NS},
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// The base URL of the redlimit service.
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    url: String,
+
+    /// Call the limiting functions in Redis at this URL directly instead of
+    /// the service, e.g. "redis://127.0.0.1:6379/0".
+    #[arg(long, value_name = "URL")]
+    redis: Option<String>,
+
+    /// The number of concurrent callers.
+    #[arg(short, long, default_value = "32")]
+    concurrency: u64,
+
+    /// The total number of calls, 0 to run for --duration.
+    #[arg(short = 'n', long, default_value = "0")]
+    requests: u64,
+
+    /// The duration to run in seconds if --requests is 0.
+    #[arg(short, long, default_value = "10")]
+    duration: u64,
+
+    /// The number of distinct ids called in turn, "bench0" to "bench<keys - 1>".
+    #[arg(long, default_value = "1000")]
+    keys: u64,
+
+    /// The namespace, the main namespace of the service if empty, "RL" with --redis.
+    #[arg(long, default_value = "")]
+    namespace: String,
+
+    #[arg(long, default_value = "core")]
+    scope: String,
+
+    #[arg(long, default_value = "GET /v1/file/list")]
+    path: String,
+
+    /// The limit of the calls with --redis: max count, period with millisecond,
+    /// and optional max burst and burst period, as a rule's "limit".
+    #[arg(long, value_delimiter = ',', default_value = "100,10000")]
+    limit: Vec<u64>,
+
+    /// The algorithm of the calls with --redis: fixed-window, sliding, gcra or token-bucket.
+    #[arg(long, default_value = "fixed-window", value_parser = parse_algorithm)]
+    algorithm: Algorithm,
+
+    /// The secret to sign the requests, see security.hmac.
+    #[arg(long, env = "REDLIMIT_SIGNING_SECRET", default_value = "")]
+    signing_secret: String,
+
+    /// The timeout of a call in milliseconds.
+    #[arg(long, default_value = "200")]
+    timeout: u64,
+}
+
+fn parse_algorithm(s: &str) -> Result<Algorithm, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string())).map_err(|err| err.to_string())
+}
+
+// Target is what the callers call, returns true if the call is limited.
+enum Target {
+    Http(Client),
+    Redis {
+        pool: RedisPool,
+        retry: conf::Retry,
+        ns: NS,
+        args: LimitArgs,
+        algorithm: Algorithm,
+    },
+}
+
+impl Target {
+    async fn new(args: &Args) -> Result<Self> {
+        if let Some(url) = &args.redis {
+            let cfg = conf::Redis {
+                url: url.clone(),
+                max_connections: args.concurrency.clamp(1, u16::MAX as u64) as u16,
+                command_timeout: args.timeout,
+                ..Default::default()
+            };
+            let pool = redis::new(cfg).await?;
+            redlimit::init_redlimit_fn(&pool).await?;
+            let ns = if args.namespace.is_empty() {
+                "RL"
+            } else {
+                args.namespace.as_str()
+            };
+            return Ok(Target::Redis {
+                pool,
+                // no retry to measure the calls as they are
+                retry: conf::Retry {
+                    attempts: 0,
+                    ..Default::default()
+                },
+                ns: NS::new(ns.to_string()),
+                args: LimitArgs::new(1, &args.limit),
+                algorithm: args.algorithm,
+            });
+        }
+
+        let cli = Client::new(
+            &args.url,
+            Options {
+                signing_secret: args.signing_secret.clone(),
+                timeout: Duration::from_millis(args.timeout),
+                retries: 0,
+                pool_max_idle: args.concurrency as usize,
+                cache_capacity: 0,
+                ..Default::default()
+            },
+        )?;
+        Ok(Target::Http(cli))
+    }
+
+    async fn call(&self, req: &CheckRequest) -> Result<bool> {
+        match self {
+            Target::Http(cli) => Ok(cli.check(req).await?.retry > 0),
+            Target::Redis {
+                pool,
+                retry,
+                ns,
+                args,
+                algorithm,
+            } => {
+                let key = ns.limiting_key(&req.scope, &req.id);
+                let rt = redlimit::limiting(pool, retry, &key, *args, *algorithm).await?;
+                Ok(rt.1 > 0)
+            }
+        }
+    }
+}
+
+// Report collects the outcomes of the calls.
+#[derive(Default, Debug)]
+struct Report {
+    latencies: Vec<Duration>,
+    allowed: u64,
+    limited: u64,
+    errors: HashMap<String, u64>,
+}
+
+impl Report {
+    fn add(&mut self, elapsed: Duration, rt: Result<bool>) {
+        self.latencies.push(elapsed);
+        match rt {
+            Ok(true) => self.limited += 1,
+            Ok(false) => self.allowed += 1,
+            Err(err) => *self.errors.entry(err.to_string()).or_default() += 1,
+        }
+    }
+
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        self.allowed += other.allowed;
+        self.limited += other.limited;
+        for (err, count) in other.errors {
+            *self.errors.entry(err).or_default() += count;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.latencies.len() as u64
+    }
+
+    fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    // percentile returns the nearest-rank percentile of the latencies, they
+    // should be sorted.
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    fn print(&mut self, elapsed: Duration) {
+        self.latencies.sort();
+        let total = self.total();
+        let errors = self.error_count();
+        println!(
+            "calls:    {} in {:.2}s, {:.1} calls/s",
+            total,
+            elapsed.as_secs_f64(),
+            total as f64 / elapsed.as_secs_f64().max(0.001)
+        );
+        println!(
+            "outcome:  {} allowed, {} limited, {} errors ({:.2}%)",
+            self.allowed,
+            self.limited,
+            errors,
+            errors as f64 * 100.0 / (total.max(1) as f64)
+        );
+        println!(
+            "latency:  p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        );
+
+        let mut errors: Vec<(&String, &u64)> = self.errors.iter().collect();
+        errors.sort_by(|a, b| b.1.cmp(a.1));
+        for (err, count) in errors.into_iter().take(5) {
+            println!("error:    {} x {}", count, err);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let target = Arc::new(Target::new(&args).await?);
+    let counter = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + Duration::from_secs(args.duration);
+    let keys = args.keys.max(1);
+    let requests = args.requests;
+
+    let start = Instant::now();
+    let mut handles = Vec::with_capacity(args.concurrency as usize);
+    for _ in 0..args.concurrency.max(1) {
+        let (target, counter) = (target.clone(), counter.clone());
+        let mut req = CheckRequest {
+            namespace: args.namespace.clone(),
+            scope: args.scope.clone(),
+            path: args.path.clone(),
+            ..Default::default()
+        };
+        handles.push(tokio::spawn(async move {
+            let mut report = Report::default();
+            loop {
+                let n = counter.fetch_add(1, Ordering::Relaxed);
+                if (requests > 0 && n >= requests) || (requests == 0 && Instant::now() >= deadline)
+                {
+                    return report;
+                }
+                req.id = format!("bench{}", n % keys);
+                let ts = Instant::now();
+                let rt = target.call(&req).await;
+                report.add(ts.elapsed(), rt);
+            }
+        }));
+    }
+
+    let mut report = Report::default();
+    for handle in handles {
+        report.merge(handle.await?);
+    }
+    report.print(start.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_works() {
+        let mut report = Report::default();
+        for ms in 1..=100 {
+            report.add(Duration::from_millis(ms), Ok(ms > 90));
+        }
+        let mut other = Report::default();
+        other.add(
+            Duration::from_millis(500),
+            Err(anyhow::Error::msg("timeout")),
+        );
+        report.merge(other);
+        report.latencies.sort();
+
+        assert_eq!(101, report.total());
+        assert_eq!(90, report.allowed);
+        assert_eq!(10, report.limited);
+        assert_eq!(1, report.error_count());
+        assert_eq!(Duration::from_millis(51), report.percentile(50.0));
+        assert_eq!(Duration::from_millis(100), report.percentile(99.0));
+        assert_eq!(Duration::from_millis(500), report.percentile(100.0));
+        assert_eq!(Duration::ZERO, Report::default().percentile(99.0));
+    }
+
+    #[test]
+    fn args_works() {
+        let args = Args::parse_from(["redlimit-bench", "--limit", "10,1000,2,100"]);
+        assert_eq!(vec![10, 1000, 2, 100], args.limit);
+        assert_eq!(Algorithm::FixedWindow, args.algorithm);
+
+        let args = Args::parse_from(["redlimit-bench", "--algorithm", "token-bucket"]);
+        assert_eq!(Algorithm::TokenBucket, args.algorithm);
+        assert!(Args::try_parse_from(["redlimit-bench", "--algorithm", "x"]).is_err());
+    }
+}
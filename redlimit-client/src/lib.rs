@@ -0,0 +1,611 @@
+//! The Rust client of the redlimit service, with the connection pooling,
+//! timeouts and retries done once, and the limited decisions cached locally
+//! until they expire.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use redlimit_client::{CheckRequest, Client, Options};
+//!
+//! let cli = Client::new("http://127.0.0.1:8080", Options::default())?;
+//! let res = cli
+//!     .check(&CheckRequest {
+//!         scope: "core".to_string(),
+//!         path: "GET /v1/file/list".to_string(),
+//!         id: "user123".to_string(),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! if res.retry > 0 {
+//!     // respond 429
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Error, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{header::HeaderMap, Method, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+// Options of the client, the defaults suit a redlimit service in the same
+// cluster.
+#[derive(Debug, Clone)]
+pub struct Options {
+    // api key of the admin endpoints, sent as "authorization: Bearer <key>"
+    pub api_key: String,
+    // secret to sign the /limiting requests, see `security.hmac`
+    pub signing_secret: String,
+    // timeout of a request, including the connecting
+    pub timeout: Duration,
+    // retries on connection errors, timeouts and 5xx responses
+    pub retries: u32,
+    // delay before the first retry, doubled for each retry
+    pub retry_backoff: Duration,
+    // idle connections kept per host
+    pub pool_max_idle: usize,
+    // max number of limited decisions cached, 0 to disable the cache
+    pub cache_capacity: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            api_key: String::new(),
+            signing_secret: String::new(),
+            timeout: Duration::from_millis(200),
+            retries: 2,
+            retry_backoff: Duration::from_millis(20),
+            pool_max_idle: 32,
+            cache_capacity: 10000,
+        }
+    }
+}
+
+// CheckRequest is the body of "POST /limiting".
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckRequest {
+    // the main namespace if empty
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub namespace: String,
+    pub scope: String,
+    pub path: String,
+    pub id: String,
+    // limit by it if the id is empty, with the "fallback" empty_id policy
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub fallback_id: String,
+}
+
+impl CheckRequest {
+    fn cache_key(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            self.namespace, self.scope, self.path, self.id, self.fallback_id
+        )
+    }
+}
+
+// LimitResponse is the result of "POST /limiting", the request should be
+// limited if retry > 0. A throttled request is out of the allow_percent of the
+// rule, retrying it doesn't help.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitResponse {
+    pub limit: u64,     // x-ratelimit-limit
+    pub remaining: u64, // x-ratelimit-remaining
+    pub reset: u64,     // x-ratelimit-reset
+    pub retry: u64,     // retry-after delay-milliseconds
+    #[serde(default)]
+    pub throttled: bool,
+}
+
+// ApiError is the error responded by the service, it can be downcast from the
+// errors of the client.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ApiError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redlimit error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// RedlistEntry is a redlist entry to add, the ttl is the expire duration with
+// millisecond, the reason and the source tag are kept in the redlist metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RedlistEntry {
+    pub ttl: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub source: String,
+}
+
+// SyncStatus is the outcome of the last sync job of a namespace.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncStatus {
+    pub last_run: u64,     // unix ms
+    pub last_success: u64, // unix ms
+    pub elapsed: u64,      // ms
+    pub cursor: u64,
+    pub redrules: usize,
+    pub redlist: usize,
+    pub error: String, // of the last run, empty on success
+    pub failures: u64, // consecutive failed runs
+}
+
+// AppInfo is the result of "GET /version".
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppInfo {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Deserialize)]
+struct ResponseBody<T> {
+    result: Option<T>,
+    error: Option<ApiError>,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    url: Url,
+    opts: Options,
+    // cache key -> (expire at with millisecond, limited response)
+    cache: Mutex<HashMap<String, (u64, LimitResponse)>>,
+}
+
+impl Client {
+    // new creates a client of the service at the base url, such as
+    // "http://redlimit:8080".
+    pub fn new(url: &str, opts: Options) -> Result<Self> {
+        let mut url = Url::parse(url)?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        let http = reqwest::Client::builder()
+            .timeout(opts.timeout)
+            .connect_timeout(opts.timeout)
+            .pool_max_idle_per_host(opts.pool_max_idle)
+            .tcp_nodelay(true)
+            .build()?;
+        Ok(Client {
+            http,
+            url,
+            opts,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // check returns the limiting decision of the request, a limited decision
+    // is served from the local cache until its retry delay passed.
+    pub async fn check(&self, req: &CheckRequest) -> Result<LimitResponse> {
+        let now = unix_ms();
+        let key = req.cache_key();
+        if let Some(res) = self.cached(&key, now) {
+            return Ok(res);
+        }
+
+        let body = serde_json::to_vec(req)?;
+        let res: LimitResponse = self.request(Method::POST, &["limiting"], &[], body).await?;
+        if res.retry > 0 {
+            self.cache(key, unix_ms() + res.retry, res.clone());
+        }
+        Ok(res)
+    }
+
+    // redlist_add adds the ids to the redlist of the namespace (the main
+    // namespace if empty), the values are the expire durations with
+    // millisecond.
+    pub async fn redlist_add(&self, namespace: &str, ids: &HashMap<String, u64>) -> Result<()> {
+        let body = serde_json::to_vec(ids)?;
+        let _: Value = self
+            .request(
+                Method::POST,
+                &["redlist"],
+                &[("namespace", namespace)],
+                body,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // redlist_add_entries adds the ids with the reason and the source tag to the
+    // redlist of the namespace.
+    pub async fn redlist_add_entries(
+        &self,
+        namespace: &str,
+        entries: &HashMap<String, RedlistEntry>,
+    ) -> Result<()> {
+        let body = serde_json::to_vec(entries)?;
+        let _: Value = self
+            .request(
+                Method::POST,
+                &["redlist"],
+                &[("namespace", namespace)],
+                body,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // redlist_remove erases the id with "DELETE /admin/ids/{id}": the redlist
+    // entry and the limiting keys of all scopes.
+    pub async fn redlist_remove(&self, namespace: &str, id: &str) -> Result<()> {
+        let _: Value = self
+            .request(
+                Method::DELETE,
+                &["admin", "ids", id],
+                &[("namespace", namespace)],
+                Vec::new(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // redlist returns the unexpired ids of the redlist of the namespace with
+    // their expire time with millisecond.
+    pub async fn redlist(&self, namespace: &str) -> Result<HashMap<String, u64>> {
+        self.request(
+            Method::GET,
+            &["redlist"],
+            &[("namespace", namespace)],
+            Vec::new(),
+        )
+        .await
+    }
+
+    // redrules_add adds the dynamic rules of the scope, the values are
+    // (quantity, expire duration with millisecond) of the paths.
+    pub async fn redrules_add(
+        &self,
+        namespace: &str,
+        scope: &str,
+        rules: &HashMap<String, (u64, u64)>,
+    ) -> Result<()> {
+        let body = serde_json::to_vec(&serde_json::json!({"scope": scope, "rules": rules}))?;
+        let _: Value = self
+            .request(
+                Method::POST,
+                &["redrules"],
+                &[("namespace", namespace)],
+                body,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // redrules returns the unexpired dynamic rules of the namespace, the values
+    // are (quantity, expire time with millisecond).
+    pub async fn redrules(&self, namespace: &str) -> Result<HashMap<String, (u64, u64)>> {
+        self.request(
+            Method::GET,
+            &["redrules"],
+            &[("namespace", namespace)],
+            Vec::new(),
+        )
+        .await
+    }
+
+    // sync triggers the sync job of the service to run now, it returns before
+    // the sync is done.
+    pub async fn sync(&self) -> Result<()> {
+        let _: Value = self
+            .request(Method::POST, &["admin", "sync"], &[], Vec::new())
+            .await?;
+        Ok(())
+    }
+
+    // sync_status returns the last sync job status by namespace.
+    pub async fn sync_status(&self) -> Result<HashMap<String, SyncStatus>> {
+        self.request(Method::GET, &["admin", "sync", "status"], &[], Vec::new())
+            .await
+    }
+
+    // export returns the snapshot JSON of the redlists, the redrules and the
+    // metadata of all namespaces, it can be posted to "/admin/import".
+    pub async fn export(&self) -> Result<Vec<u8>> {
+        self.request_raw(Method::GET, &["admin", "export"], &[], Vec::new())
+            .await
+    }
+
+    // version returns the name and the version of the service.
+    pub async fn version(&self) -> Result<AppInfo> {
+        self.request(Method::GET, &["version"], &[], Vec::new())
+            .await
+    }
+
+    fn cached(&self, key: &str, now: u64) -> Option<LimitResponse> {
+        let cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some((expire_at, res)) if *expire_at > now => {
+                let mut res = res.clone();
+                res.remaining = 0;
+                res.retry = expire_at - now;
+                Some(res)
+            }
+            _ => None,
+        }
+    }
+
+    fn cache(&self, key: String, expire_at: u64, res: LimitResponse) {
+        if self.opts.cache_capacity == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.opts.cache_capacity {
+            let now = unix_ms();
+            cache.retain(|_, (expire_at, _)| *expire_at > now);
+            if cache.len() >= self.opts.cache_capacity {
+                return;
+            }
+        }
+        cache.insert(key, (expire_at, res));
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &[&str],
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<T> {
+        let data = self.request_raw(method, path, query, body).await?;
+        match serde_json::from_slice::<ResponseBody<T>>(&data) {
+            Ok(ResponseBody {
+                result: Some(result),
+                ..
+            }) => Ok(result),
+            Ok(ResponseBody {
+                error: Some(err), ..
+            }) => Err(Error::new(err)),
+            _ => Err(Error::new(ApiError {
+                code: StatusCode::OK.as_u16(),
+                message: String::from_utf8_lossy(&data).to_string(),
+            })),
+        }
+    }
+
+    // request_raw returns the body of a 200 response, with retries.
+    async fn request_raw(
+        &self,
+        method: Method,
+        path: &[&str],
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| Error::msg("invalid base url"))?
+            .pop_if_empty()
+            .extend(path);
+        for (k, v) in query.iter().filter(|(_, v)| !v.is_empty()) {
+            url.query_pairs_mut().append_pair(k, v);
+        }
+
+        let mut attempt = 0;
+        loop {
+            let rt = self.send(&method, &url, &body).await;
+            let retryable = match &rt {
+                Ok(_) => false,
+                Err(err) => match err.downcast_ref::<ApiError>() {
+                    Some(err) => err.code >= 500,
+                    None => err
+                        .downcast_ref::<reqwest::Error>()
+                        .map_or(false, |err| err.is_connect() || err.is_timeout()),
+                },
+            };
+            if !retryable || attempt >= self.opts.retries {
+                return rt;
+            }
+            tokio::time::sleep(self.opts.retry_backoff * 2u32.pow(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send(&self, method: &Method, url: &Url, body: &[u8]) -> Result<Vec<u8>> {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse()?);
+        if !self.opts.api_key.is_empty() {
+            headers.insert(
+                "authorization",
+                format!("Bearer {}", self.opts.api_key).parse()?,
+            );
+        }
+        if !self.opts.signing_secret.is_empty() {
+            let ts = unix_ms() / 1000;
+            let request = format!("{}\n{}", method.as_str(), url.path());
+            let signature = sign(&self.opts.signing_secret, ts, &request, body)?;
+            headers.insert("x-signature-timestamp", ts.to_string().parse()?);
+            headers.insert("x-signature", signature.parse()?);
+        }
+
+        let res = self
+            .http
+            .request(method.clone(), url.clone())
+            .headers(headers)
+            .body(body.to_vec())
+            .send()
+            .await?;
+        let status = res.status();
+        let data = res.bytes().await?;
+        if status == StatusCode::OK {
+            return Ok(data.to_vec());
+        }
+        match serde_json::from_slice::<ResponseBody<Value>>(&data) {
+            Ok(ResponseBody {
+                error: Some(err), ..
+            }) => Err(Error::new(err)),
+            _ => Err(Error::new(ApiError {
+                code: status.as_u16(),
+                message: String::from_utf8_lossy(&data).to_string(),
+            })),
+        }
+    }
+}
+
+// sign returns the hex HMAC-SHA256 signature of the request as
+// "<timestamp>\n<method>\n<path>\n<hex sha256 of body>".
+fn sign(secret: &str, ts: u64, request: &str, body: &[u8]) -> Result<String> {
+    let message = format!("{}\n{}\n{}", ts, request, hex::encode(Sha256::digest(body)));
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(message.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // serve responds the responses in turn, one per connection.
+    async fn serve(responses: Vec<(u16, &'static str)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let res = format!(
+                    "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://{}", addr), count)
+    }
+
+    #[tokio::test]
+    async fn check_works() -> Result<()> {
+        let (url, count) = serve(vec![
+            (503, r#"{"error":{"code":503,"message":"unavailable"}}"#),
+            (
+                200,
+                r#"{"result":{"limit":10,"remaining":0,"reset":1,"retry":5000}}"#,
+            ),
+        ])
+        .await;
+        let cli = Client::new(&url, Options::default())?;
+        let req = CheckRequest {
+            scope: "core".to_string(),
+            path: "GET /v1/file/list".to_string(),
+            id: "user1".to_string(),
+            ..Default::default()
+        };
+
+        let res = cli.check(&req).await?;
+        assert_eq!(10, res.limit);
+        assert_eq!(5000, res.retry);
+        assert_eq!(2, count.load(Ordering::SeqCst), "retried once on 503");
+
+        let res = cli.check(&req).await?;
+        assert!(res.retry > 4000 && res.retry <= 5000);
+        assert_eq!(2, count.load(Ordering::SeqCst), "served from the cache");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_error_works() -> Result<()> {
+        let (url, count) = serve(vec![(
+            401,
+            r#"{"error":{"code":401,"message":"invalid api key"}}"#,
+        )])
+        .await;
+        let cli = Client::new(&url, Options::default())?;
+        let err = cli
+            .redlist_add("", &HashMap::from([("user1".to_string(), 10000)]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            &ApiError {
+                code: 401,
+                message: "invalid api key".to_string()
+            },
+            err.downcast_ref::<ApiError>().unwrap()
+        );
+        assert_eq!(1, count.load(Ordering::SeqCst), "no retry on 4xx");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_works() -> Result<()> {
+        let (url, count) = serve(vec![
+            (200, r#"{"result":{"user1":1700000000000}}"#),
+            (200, r#"{"result":{"RL":{"last_run":1,"failures":2}}}"#),
+            (200, r#"{"version":1,"namespaces":{}}"#),
+            (
+                404,
+                r#"{"error":{"code":404,"message":"namespace x not found"}}"#,
+            ),
+        ])
+        .await;
+        let cli = Client::new(&url, Options::default())?;
+        assert_eq!(
+            HashMap::from([("user1".to_string(), 1700000000000)]),
+            cli.redlist("").await?
+        );
+        let status = cli.sync_status().await?;
+        assert_eq!(1, status["RL"].last_run);
+        assert_eq!(2, status["RL"].failures);
+        assert_eq!(
+            br#"{"version":1,"namespaces":{}}"#.to_vec(),
+            cli.export().await?
+        );
+        let err = cli.redlist_remove("x", "user/1").await.unwrap_err();
+        assert_eq!(404, err.downcast_ref::<ApiError>().unwrap().code);
+        assert_eq!(4, count.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn sign_works() {
+        let s1 = sign("secret1", 1700000000, "POST\n/limiting", b"{}").unwrap();
+        assert_eq!(64, s1.len());
+        assert_eq!(
+            s1,
+            sign("secret1", 1700000000, "POST\n/limiting", b"{}").unwrap()
+        );
+        assert_ne!(
+            s1,
+            sign("secret2", 1700000000, "POST\n/limiting", b"{}").unwrap()
+        );
+        assert_ne!(
+            s1,
+            sign("secret1", 1700000001, "POST\n/limiting", b"{}").unwrap()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpResponse,
+};
+
+use super::guard::Guard;
+
+type Extract = Rc<dyn Fn(&ServiceRequest) -> Option<String>>;
+
+// RateLimit is the actix-web middleware that enforces the limits with the
+// Guard, the limited requests are responded with 429. The scope, path and id
+// of a request are extracted by the closures, the request is not limited if
+// any of them is None. The path defaults to "<method> <path>".
+//
+//     App::new().wrap(
+//         RateLimit::new(guard.clone(), |_| Some("core".to_string()), |req| {
+//             req.headers().get("x-user-id")?.to_str().ok().map(String::from)
+//         }),
+//     )
+pub struct RateLimit {
+    guard: Arc<Guard>,
+    namespace: Rc<String>,
+    scope: Extract,
+    path: Extract,
+    id: Extract,
+}
+
+impl RateLimit {
+    pub fn new(
+        guard: Arc<Guard>,
+        scope: impl Fn(&ServiceRequest) -> Option<String> + 'static,
+        id: impl Fn(&ServiceRequest) -> Option<String> + 'static,
+    ) -> Self {
+        RateLimit {
+            guard,
+            namespace: Rc::new(String::new()),
+            scope: Rc::new(scope),
+            path: Rc::new(|req: &ServiceRequest| Some(format!("{} {}", req.method(), req.path()))),
+            id: Rc::new(id),
+        }
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Rc::new(namespace.to_string());
+        self
+    }
+
+    pub fn path(mut self, path: impl Fn(&ServiceRequest) -> Option<String> + 'static) -> Self {
+        self.path = Rc::new(path);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            guard: self.guard.clone(),
+            namespace: self.namespace.clone(),
+            scope: self.scope.clone(),
+            path: self.path.clone(),
+            id: self.id.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    guard: Arc<Guard>,
+    namespace: Rc<String>,
+    scope: Extract,
+    path: Extract,
+    id: Extract,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let keys = (self.scope)(&req)
+            .zip((self.path)(&req))
+            .zip((self.id)(&req));
+        let service = self.service.clone();
+        let guard = self.guard.clone();
+        let namespace = self.namespace.clone();
+        Box::pin(async move {
+            let d = match keys {
+                Some(((scope, path), id)) => {
+                    match guard.check(&namespace, &scope, &path, &id).await {
+                        Ok(d) => Some(d),
+                        Err(err) => {
+                            log::warn!("rate limit error: {}", err);
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            match d {
+                Some(d) if d.is_limited() => {
+                    let mut res = HttpResponse::TooManyRequests();
+                    for (name, value) in d.headers() {
+                        res.insert_header((name, value));
+                    }
+                    let res = res.json(serde_json::json!({
+                        "error": {"code": 429, "message": "too many requests"}
+                    }));
+                    Ok(req.into_response(res).map_into_right_body())
+                }
+                Some(d) => {
+                    let mut res = service.call(req).await?;
+                    for (name, value) in d.headers() {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static(name), HeaderValue::from(value));
+                    }
+                    Ok(res.map_into_left_body())
+                }
+                None => Ok(service.call(req).await?.map_into_left_body()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{conf, context::unix_ms};
+    use actix_web::{test, web, App};
+
+    #[actix_web::test]
+    async fn rate_limit_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let guard = Arc::new(Guard::new(&cfg).await?);
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    guard,
+                    |_| Some("core".to_string()),
+                    |req| {
+                        req.headers()
+                            .get("x-user-id")?
+                            .to_str()
+                            .ok()
+                            .map(String::from)
+                    },
+                ))
+                .route("/v1/file/list", web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        let id = format!("actix_user{}", unix_ms());
+        // "GET /v1/file/list" consumes 5 of the burst 50
+        for _ in 0..10 {
+            let req = test::TestRequest::get()
+                .uri("/v1/file/list")
+                .insert_header(("x-user-id", id.as_str()))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(200, res.status().as_u16());
+            assert_eq!("100", res.headers().get("x-ratelimit-limit").unwrap());
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/v1/file/list")
+            .insert_header(("x-user-id", id.as_str()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(429, res.status().as_u16());
+        assert!(res.headers().get("retry-after").is_some());
+
+        // no id, not limited
+        let req = test::TestRequest::get().uri("/v1/file/list").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(200, res.status().as_u16());
+        Ok(())
+    }
+}
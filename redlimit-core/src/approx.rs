@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use tokio::{
+    task::{JoinHandle, JoinSet},
+    time::{sleep, Duration},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    conf::{self, Algorithm, Composite},
+    context::unix_ms,
+    guard,
+    redis::Shards,
+    redlimit::{LimitResult, Limits},
+};
+
+// ApproxLimiter decides the requests of the approximate rules against the
+// in-memory counters of the instance, the flush job adds the counts to Redis in
+// batches and refreshes the counters with the global counts. The instances may
+// overshoot the limit by the requests counted in a flush interval.
+pub struct ApproxLimiter {
+    cfg: conf::Approximate,
+    counters: Mutex<HashMap<String, Counter>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Counter {
+    max_count: u64,
+    period: u64,
+    count: u64,     // the global count from Redis
+    flushing: u64,  // being added to Redis
+    pending: u64,   // counted since the last flush
+    expire_at: u64, // the end of the window
+}
+
+impl Counter {
+    fn total(&self) -> u64 {
+        self.count + self.flushing + self.pending
+    }
+}
+
+impl ApproxLimiter {
+    pub fn new(cfg: conf::Approximate) -> Self {
+        ApproxLimiter {
+            cfg,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // erase removes the counters of the matched keys.
+    pub fn erase(&self, matches: impl Fn(&str) -> bool) {
+        self.counters.lock().unwrap().retain(|k, _| !matches(k));
+    }
+
+    // limiting decides in memory, None if the limits are not approximate (or
+    // the counters are full) and should be limited by Redis.
+    pub fn limiting(
+        &self,
+        now: u64,
+        limiting_key: &str,
+        limits: &Limits,
+    ) -> Option<(u64, LimitResult)> {
+        let args = limits.args;
+        if !limits.approximate
+            || limits.algorithm != Algorithm::FixedWindow
+            || args.3 > 0
+            || limits.extra.iter().any(|a| a.is_valid())
+        {
+            return None;
+        }
+        if !args.is_valid() {
+            return Some((args.1, LimitResult(0, 0)));
+        }
+
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.contains_key(limiting_key) && counters.len() >= self.cfg.max_keys {
+            counters.retain(|_, c| !is_idle(c, now));
+            if counters.len() >= self.cfg.max_keys {
+                return None;
+            }
+        }
+        let c = counters.entry(limiting_key.to_string()).or_insert(Counter {
+            max_count: args.1,
+            period: args.2,
+            count: 0,
+            flushing: 0,
+            pending: 0,
+            expire_at: now + args.2,
+        });
+        c.max_count = args.1;
+        c.period = args.2;
+        if c.expire_at <= now {
+            // the pending counts of the expired window are dropped
+            c.count = 0;
+            c.pending = 0;
+            c.expire_at = now + args.2;
+        }
+
+        let total = c.total();
+        if total + args.0 > args.1 {
+            return Some((args.1, LimitResult(total, c.expire_at - now)));
+        }
+        c.pending += args.0;
+        Some((args.1, LimitResult(total + args.0, 0)))
+    }
+
+    // flush adds the pending counts to Redis, one limiting call per key.
+    pub async fn flush(&self, shards: &Arc<Shards>, retry: &conf::Retry) {
+        let mut tasks = JoinSet::new();
+        for (key, limits) in self.take_pending(unix_ms()) {
+            let (shards, retry) = (shards.clone(), retry.clone());
+            tasks.spawn(async move {
+                let rt = guard::limiting(&shards, &retry, &key, &limits).await;
+                (key, rt.map(|(_, rt)| rt))
+            });
+        }
+        while let Some(rt) = tasks.join_next().await {
+            if let Ok((key, rt)) = rt {
+                if let Err(err) = &rt {
+                    log::warn!(target: "approx", "flush {} error: {}", key, err);
+                }
+                self.flushed(unix_ms(), &key, rt);
+            }
+        }
+    }
+
+    // take_pending moves the pending counts to flushing and returns their
+    // limits, the idle counters are removed.
+    fn take_pending(&self, now: u64) -> Vec<(String, Limits)> {
+        let mut counters = self.counters.lock().unwrap();
+        counters.retain(|_, c| !is_idle(c, now));
+        counters
+            .iter_mut()
+            .filter(|(_, c)| c.pending > 0 && c.flushing == 0)
+            .map(|(key, c)| {
+                c.flushing = std::mem::take(&mut c.pending);
+                // the overshot counts are not accepted by Redis in a batch
+                let quantity = c.flushing.min(c.max_count);
+                let limits = Limits::new(quantity, &[c.max_count, c.period], &[], Composite::And);
+                (key.clone(), limits)
+            })
+            .collect()
+    }
+
+    // flushed refreshes the counter with the result of Redis, the counts are
+    // retried in the next flush on errors.
+    fn flushed(&self, now: u64, limiting_key: &str, rt: Result<LimitResult>) {
+        let mut counters = self.counters.lock().unwrap();
+        let c = match counters.get_mut(limiting_key) {
+            Some(c) => c,
+            None => return,
+        };
+        let flushing = std::mem::take(&mut c.flushing);
+        match rt {
+            Ok(LimitResult(count, 0)) => {
+                if count <= flushing {
+                    // the window was started by the batch
+                    c.expire_at = now + c.period;
+                }
+                c.count = count;
+            }
+            Ok(LimitResult(_, wait)) => {
+                // the window is full, the batch is dropped by Redis
+                c.count = c.max_count;
+                c.expire_at = now + wait;
+            }
+            Err(_) => c.pending += flushing,
+        }
+    }
+}
+
+fn is_idle(c: &Counter, now: u64) -> bool {
+    c.pending == 0 && c.flushing == 0 && c.expire_at <= now
+}
+
+// init_approx_flush flushes the approximate counters every flush interval, and
+// once more on shutdown.
+pub fn init_approx_flush(
+    approx: Arc<ApproxLimiter>,
+    shards: Arc<Shards>,
+    retry: conf::Retry,
+) -> (JoinHandle<()>, CancellationToken) {
+    let cancel_flush = CancellationToken::new();
+    let stop_signal = cancel_flush.clone();
+    let interval = Duration::from_millis(approx.cfg.flush_interval.max(1));
+    (
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = stop_signal.cancelled() => {
+                        approx.flush(&shards, &retry).await;
+                        log::info!("gracefully shutting down approximate flush job");
+                        return;
+                    }
+                    _ = sleep(interval) => {}
+                };
+                approx.flush(&shards, &retry).await;
+            }
+        }),
+        cancel_flush,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redlimit::LimitArgs;
+
+    fn limits(quantity: u64, max_count: u64, period: u64) -> Limits {
+        Limits::new(quantity, &[max_count, period], &[], Composite::And).with_approximate(true)
+    }
+
+    #[test]
+    fn limiting_works() {
+        let approx = ApproxLimiter::new(conf::Approximate {
+            flush_interval: 10,
+            max_keys: 2,
+        });
+        let now = 1000;
+
+        assert_eq!(
+            None,
+            approx.limiting(
+                now,
+                "RL:core:user1",
+                &limits(1, 3, 1000).with_approximate(false)
+            )
+        );
+        assert_eq!(
+            None,
+            approx.limiting(
+                now,
+                "RL:core:user1",
+                &Limits::new(1, &[3, 1000, 2], &[], Composite::And).with_approximate(true)
+            ),
+            "burst is not approximate"
+        );
+
+        let l = limits(1, 3, 1000);
+        assert_eq!(
+            Some((3, LimitResult(1, 0))),
+            approx.limiting(now, "RL:core:user1", &l)
+        );
+        assert_eq!(
+            Some((3, LimitResult(2, 0))),
+            approx.limiting(now, "RL:core:user1", &l)
+        );
+        assert_eq!(
+            Some((3, LimitResult(3, 0))),
+            approx.limiting(now + 10, "RL:core:user1", &l)
+        );
+        assert_eq!(
+            Some((3, LimitResult(3, 990))),
+            approx.limiting(now + 10, "RL:core:user1", &l)
+        );
+        assert_eq!(
+            Some((3, LimitResult(1, 0))),
+            approx.limiting(now + 1000, "RL:core:user1", &l),
+            "next window"
+        );
+
+        assert!(approx.limiting(now, "RL:core:user2", &l).is_some());
+        assert_eq!(None, approx.limiting(now, "RL:core:user3", &l), "full");
+
+        approx.erase(|k| k == "RL:core:user2");
+        assert!(approx.limiting(now, "RL:core:user3", &l).is_some());
+    }
+
+    #[test]
+    fn flush_works() {
+        let approx = ApproxLimiter::new(conf::Approximate::default());
+        let l = limits(2, 10, 1000);
+        let now = 1000;
+        for _ in 0..3 {
+            approx.limiting(now, "RL:core:user1", &l);
+        }
+        approx.limiting(now, "RL:core:user2", &l);
+
+        let mut pending = approx.take_pending(now + 5);
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(2, pending.len());
+        assert_eq!(LimitArgs(6, 10, 1000, 0, 0), pending[0].1.args);
+        assert_eq!(LimitArgs(2, 10, 1000, 0, 0), pending[1].1.args);
+        assert!(approx.take_pending(now + 5).is_empty(), "no pending");
+
+        // counted by the other instances
+        approx.flushed(now + 10, "RL:core:user1", Ok(LimitResult(8, 0)));
+        assert_eq!(
+            Some((10, LimitResult(10, 0))),
+            approx.limiting(now + 10, "RL:core:user1", &l)
+        );
+        assert_eq!(
+            Some((10, LimitResult(10, 990))),
+            approx.limiting(now + 10, "RL:core:user1", &l)
+        );
+
+        // retried after errors
+        approx.flushed(
+            now + 10,
+            "RL:core:user2",
+            Err(anyhow::Error::msg("timeout")),
+        );
+        let pending = approx.take_pending(now + 20);
+        assert_eq!(2, pending.len());
+
+        // limited by Redis
+        approx.flushed(now + 30, "RL:core:user2", Ok(LimitResult(9, 500)));
+        assert_eq!(
+            Some((10, LimitResult(10, 500))),
+            approx.limiting(now + 30, "RL:core:user2", &l)
+        );
+
+        // idle counters are removed
+        approx.flushed(now + 30, "RL:core:user1", Ok(LimitResult(10, 0)));
+        assert!(approx.take_pending(now + 2000).is_empty());
+        assert!(approx.counters.lock().unwrap().is_empty());
+    }
+}
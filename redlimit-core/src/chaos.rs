@@ -0,0 +1,93 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use tokio::time::{sleep, Duration};
+
+use super::{conf, metrics};
+
+// CHAOS is the fault injection config of the process, disabled by default.
+static CHAOS: Lazy<ArcSwap<conf::Chaos>> =
+    Lazy::new(|| ArcSwap::from_pointee(conf::Chaos::default()));
+
+// init enables the fault injection of the config, see [chaos] in the config.
+pub fn init(cfg: &conf::Chaos) {
+    if cfg.enabled {
+        log::warn!(target: "chaos",
+            latency_rate = cfg.latency_rate,
+            latency = cfg.latency,
+            drop_rate = cfg.drop_rate,
+            sync_failure_rate = cfg.sync_failure_rate;
+            "chaos mode enabled, faults will be injected",
+        );
+    }
+    CHAOS.store(Arc::new(cfg.clone()));
+}
+
+// delay sleeps before a Redis command at the latency rate.
+pub async fn delay() {
+    let latency = {
+        let cfg = CHAOS.load();
+        if !cfg.enabled || !hit(cfg.latency_rate, random()) {
+            return;
+        }
+        cfg.latency
+    };
+    metrics::CHAOS_FAULTS.with_label_values(&["latency"]).inc();
+    sleep(Duration::from_millis(latency)).await;
+}
+
+// response drops the response of a Redis command at the drop rate, as if the
+// connection was broken after the command was executed.
+pub fn response<T>(rt: Result<T, rustis::Error>) -> Result<T, rustis::Error> {
+    let cfg = CHAOS.load();
+    if rt.is_err() || !cfg.enabled || !hit(cfg.drop_rate, random()) {
+        return rt;
+    }
+    metrics::CHAOS_FAULTS.with_label_values(&["drop"]).inc();
+    Err(rustis::Error::IO("chaos: response dropped".to_string()))
+}
+
+// sync_failure returns true if the sync job run should fail at the sync
+// failure rate.
+pub fn sync_failure() -> bool {
+    let cfg = CHAOS.load();
+    if !cfg.enabled || !hit(cfg.sync_failure_rate, random()) {
+        return false;
+    }
+    metrics::CHAOS_FAULTS.with_label_values(&["sync"]).inc();
+    true
+}
+
+// hit returns true if the random number in [0, 1) falls in the rate.
+fn hit(rate: f64, random: f64) -> bool {
+    rate > 0.0 && random < rate
+}
+
+fn random() -> f64 {
+    (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_works() {
+        assert!(!hit(0.0, 0.0));
+        assert!(hit(0.5, 0.2));
+        assert!(!hit(0.5, 0.5));
+        assert!(hit(1.0, 0.999));
+
+        for _ in 0..1000 {
+            let r = random();
+            assert!((0.0..1.0).contains(&r), "{}", r);
+        }
+        let hits = (0..10000).filter(|_| hit(0.3, random())).count();
+        assert!(hits > 2500 && hits < 3500, "{}", hits);
+    }
+}
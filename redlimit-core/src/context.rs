@@ -0,0 +1,33 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before Unix epoch")
+        .as_millis() as u64
+}
+
+// Timings accumulates the Redis time of a request.
+#[derive(Default)]
+pub struct Timings {
+    pub pool_wait: Cell<Duration>,
+    pub command: Cell<Duration>,
+}
+
+tokio::task_local! {
+    pub static TIMINGS: Rc<Timings>;
+}
+
+// add_pool_wait records the pool wait time to the current request, if any.
+pub fn add_pool_wait(d: Duration) {
+    let _ = TIMINGS.try_with(|t| t.pool_wait.set(t.pool_wait.get() + d));
+}
+
+// add_command records the command round trip time to the current request, if any.
+pub fn add_command(d: Duration) {
+    let _ = TIMINGS.try_with(|t| t.command.set(t.command.get() + d));
+}
@@ -0,0 +1,387 @@
+use std::{future::Future, sync::Arc};
+
+use anyhow::{Error, Result};
+use tokio::{task::JoinHandle, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    chaos, conf,
+    conf::FailurePolicy,
+    context::unix_ms,
+    local::LocalLimiter,
+    redis::{self, RedisPool, Shards},
+    redlimit::{self, LimitResult, Limits, Namespaces, RedRules},
+};
+
+// limiting calls the limiting function on the shard of the limiting key.
+pub async fn limiting(
+    shards: &Shards,
+    retry: &conf::Retry,
+    limiting_key: &str,
+    limits: &Limits,
+) -> Result<(u64, LimitResult)> {
+    on_shard(shards, limiting_key, |pool| async move {
+        redlimit::limiting_composite(&pool, retry, limiting_key, limits).await
+    })
+    .await
+}
+
+// limiting_with_redlist limits the id as limiting does, the exact id (not the
+// redlist patterns) is checked in Redis by limiting_redlist if the rules
+// enable it. The redlist lives on the main Redis and the function takes a
+// single limit, Conf::validate rejects the redlist check with shards or extra
+// limits, the rules built without it are limited without the check.
+pub async fn limiting_with_redlist(
+    shards: &Shards,
+    retry: &conf::Retry,
+    rules: &RedRules,
+    limiting_key: &str,
+    id: &str,
+    limits: &Limits,
+) -> Result<(u64, LimitResult)> {
+    if !rules.redlist_check()
+        || !shards.is_main(limiting_key)
+        || limits.extra.iter().any(|a| a.is_valid())
+    {
+        return limiting(shards, retry, limiting_key, limits).await;
+    }
+
+    let floor = rules.floor_args();
+    on_shard(shards, limiting_key, |pool| async move {
+        redlimit::limiting_redlist(&pool, retry, &rules.ns, limiting_key, id, limits, floor).await
+    })
+    .await
+}
+
+// on_failure decides by the failure policy of the scope when the limiting
+// fails, returns the max count with the result, and whether the local limiter
+// decided it.
+pub fn on_failure(
+    local: &LocalLimiter,
+    rules: &RedRules,
+    ts: u64,
+    scope: &str,
+    limiting_key: &str,
+    mut limits: Limits,
+) -> (u64, LimitResult, bool) {
+    let limit = limits.args.1;
+    match rules.failure_policy(scope) {
+        FailurePolicy::Open if local.is_enabled() => {
+            let (limit, rt) = local.limiting(ts, limiting_key, &limits);
+            (limit, rt, true)
+        }
+        FailurePolicy::Open => (limit, LimitResult(0, 0), false),
+        FailurePolicy::Closed => (limit, LimitResult(limit, limits.args.2.max(1)), false),
+        FailurePolicy::FloorLimits => {
+            limits.args = rules.floor_args();
+            limits.extra.clear();
+            let (limit, rt) = local.limiting(ts, limiting_key, &limits);
+            (limit, rt, true)
+        }
+    }
+}
+
+// on_shard calls f with the pool of the shard of the limiting key within the
+// limiting timeout, the redlimit function is marked to be reloaded if missing.
+pub async fn on_shard<T, F, Fut>(shards: &Shards, limiting_key: &str, f: F) -> Result<T>
+where
+    F: FnOnce(Arc<RedisPool>) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let pool = shards.pick(limiting_key);
+    if pool.state().connections == 0 {
+        return Err(Error::msg("no redis connection"));
+    }
+    let rt = match timeout(shards.limiting_timeout(), f(pool)).await {
+        Ok(rt) => rt,
+        Err(_) => Err(Error::msg("limiting timeout")),
+    };
+    if let Err(err) = &rt {
+        if redlimit::is_fn_missing(&err.to_string()) {
+            shards.mark_fn_missing();
+        }
+    }
+    rt
+}
+
+// Decision is the limiting decision of a request, it should be rejected with
+// 429 if retry > 0. A throttled decision is out of the allow_percent of the
+// rule, it stays limited on retries, so no retry-after is advertised.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Decision {
+    pub limit: u64,      // x-ratelimit-limit
+    pub remaining: u64,  // x-ratelimit-remaining
+    pub reset: u64,      // x-ratelimit-reset
+    pub retry: u64,      // retry-after delay-milliseconds
+    pub throttled: bool, // x-ratelimit-throttled
+}
+
+impl Decision {
+    pub fn new(ts: u64, limit: u64, rt: &LimitResult) -> Self {
+        Decision {
+            limit,
+            remaining: limit.saturating_sub(rt.0),
+            reset: if rt.1 > 0 { (ts + rt.1) / 1000 } else { 0 },
+            retry: rt.1,
+            throttled: false,
+        }
+    }
+
+    // throttled returns the limited decision of a request out of the
+    // allow_percent of the rule.
+    pub fn throttled(ts: u64, limits: &Limits) -> Self {
+        let limit = limits.args.1;
+        Decision {
+            throttled: true,
+            ..Decision::new(ts, limit, &LimitResult(limit, limits.args.2.max(1)))
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.retry > 0
+    }
+
+    // retry_after returns the retry-after header value with second.
+    pub fn retry_after(&self) -> u64 {
+        (self.retry + 999) / 1000
+    }
+
+    // headers returns the x-ratelimit-* headers of the decision, and the
+    // retry-after header if limited, or x-ratelimit-throttled if throttled.
+    pub fn headers(&self) -> Vec<(&'static str, u64)> {
+        let mut headers = vec![
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+        ];
+        if self.throttled {
+            headers.push(("x-ratelimit-throttled", 1));
+        } else if self.is_limited() {
+            headers.push(("x-ratelimit-reset", self.reset));
+            headers.push(("retry-after", self.retry_after()));
+        }
+        headers
+    }
+}
+
+// Guard enforces the rules in-process against the same Redis data as the
+// redlimit service, it syncs the redlist and redrules in background until
+// dropped. The ids are used as is, "security.id_hash_key" is not applied.
+pub struct Guard {
+    shards: Arc<Shards>,
+    namespaces: Arc<Namespaces>,
+    local: Arc<LocalLimiter>,
+    retry: conf::Retry,
+    sync: (JoinHandle<()>, CancellationToken),
+    subscriber: Option<(JoinHandle<()>, CancellationToken)>,
+}
+
+impl Guard {
+    pub async fn new(cfg: &conf::Conf) -> Result<Self> {
+        let pool = Arc::new(redis::new(cfg.redis.clone()).await?);
+        let replica = if cfg.redis.replica.is_empty() {
+            pool.clone()
+        } else {
+            Arc::new(redis::new_with_addr(&cfg.redis, &cfg.redis.replica).await?)
+        };
+        let shards = Arc::new(Shards::new(&cfg.redis, pool.clone()).await?);
+        for pool in shards.pools() {
+            redlimit::init_redlimit_fn(pool).await?;
+        }
+        if !cfg.redis.replica.is_empty() {
+            redlimit::init_replica_fn(&replica).await?;
+        }
+        chaos::init(&cfg.chaos);
+
+        let namespaces = Arc::new(Namespaces::new(cfg));
+        let sync = redlimit::init_redlimit_sync(
+            pool,
+            replica,
+            shards.clone(),
+            namespaces.clone(),
+            &cfg.job,
+            cfg.central.enabled,
+        );
+        let local = Arc::new(LocalLimiter::new(cfg.fallback.clone()));
+        let subscriber = if cfg.job.subscribe {
+            let config = redis::main_config(&cfg.redis)?;
+            let local = local.clone();
+            Some(redlimit::init_redlimit_subscriber(
+                config,
+                namespaces.clone(),
+                move |ns, id| local.erase(|k| ns.is_id_key(id, k)),
+            ))
+        } else {
+            None
+        };
+        Ok(Guard {
+            shards,
+            namespaces,
+            local,
+            retry: cfg.redis.retry.clone(),
+            sync,
+            subscriber,
+        })
+    }
+
+    // check limits the id on the path of the scope in the namespace (the main
+    // namespace if empty), the same as "POST /limiting" of the service. The
+    // redlimit::LimitError of an invalid scope or of invalid limits resolved from
+    // the rules can be downcast from the error.
+    pub async fn check(
+        &self,
+        namespace: &str,
+        scope: &str,
+        path: &str,
+        id: &str,
+    ) -> Result<Decision> {
+        let rules = self
+            .namespaces
+            .get(namespace)
+            .ok_or_else(|| Error::msg(format!("unknown namespace: {}", namespace)))?;
+        redlimit::validate_scope(scope)?;
+        let ts = unix_ms();
+        if id.is_empty() {
+            return Ok(Decision::new(ts, 0, &LimitResult(0, 0)));
+        }
+        let limits = rules.limits(ts, scope, path, id);
+        limits.validate()?;
+        if rules.is_throttled(scope, path, id) {
+            return Ok(Decision::throttled(ts, &limits));
+        }
+        let limiting_key = rules.ns.limiting_key(scope, id);
+
+        let rt =
+            limiting_with_redlist(&self.shards, &self.retry, rules, &limiting_key, id, &limits);
+        let (limit, rt) = match rt.await {
+            Ok(rt) => rt,
+            Err(err) => {
+                log::warn!("guard limiting error: {}", err);
+                let (limit, rt, _) =
+                    on_failure(&self.local, rules, ts, scope, &limiting_key, limits);
+                (limit, rt)
+            }
+        };
+        Ok(Decision::new(ts, limit, &rt))
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.sync.1.cancel();
+        if let Some((_, cancel_subscriber)) = &self.subscriber {
+            cancel_subscriber.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::testing::TestRedis, *};
+
+    #[test]
+    fn decision_works() {
+        let d = Decision::new(1000, 10, &LimitResult(3, 0));
+        assert_eq!(
+            Decision {
+                limit: 10,
+                remaining: 7,
+                reset: 0,
+                retry: 0,
+                throttled: false,
+            },
+            d
+        );
+        assert!(!d.is_limited());
+        assert_eq!(
+            vec![("x-ratelimit-limit", 10), ("x-ratelimit-remaining", 7)],
+            d.headers()
+        );
+
+        let d = Decision::new(1000, 10, &LimitResult(12, 1500));
+        assert_eq!(0, d.remaining);
+        assert_eq!(2, d.reset);
+        assert!(d.is_limited());
+        assert_eq!(2, d.retry_after());
+        assert_eq!(4, d.headers().len());
+        assert_eq!(("retry-after", 2), d.headers()[3]);
+
+        let limits = Limits::new(1, &[10, 1000], &[], conf::Composite::And);
+        let d = Decision::throttled(1000, &limits);
+        assert!(d.is_limited());
+        assert!(d.throttled);
+        assert_eq!((0, 1000), (d.remaining, d.retry));
+        assert_eq!(
+            vec![
+                ("x-ratelimit-limit", 10),
+                ("x-ratelimit-remaining", 0),
+                ("x-ratelimit-throttled", 1)
+            ],
+            d.headers(),
+            "no retry-after, retrying doesn't help"
+        );
+    }
+
+    #[test]
+    fn on_failure_works() {
+        let mut rules = std::collections::HashMap::new();
+        for (scope, failure_policy) in [
+            ("open", FailurePolicy::Open),
+            ("closed", FailurePolicy::Closed),
+            ("floor", FailurePolicy::FloorLimits),
+        ] {
+            rules.insert(
+                scope.to_string(),
+                conf::Rule {
+                    limit: vec![10, 1000],
+                    failure_policy,
+                    ..conf::Rule::default()
+                },
+            );
+        }
+        rules.insert(
+            "-".to_string(),
+            conf::Rule {
+                limit: vec![1, 1000],
+                ..conf::Rule::default()
+            },
+        );
+        let rules = RedRules::new("TT", &rules);
+        let limits = Limits::new(1, &[10, 1000], &[], conf::Composite::And);
+        let local = LocalLimiter::new(conf::Fallback::default());
+        let disabled = LocalLimiter::new(conf::Fallback {
+            local: false,
+            max_keys: 10,
+        });
+
+        let rt = on_failure(&local, &rules, 1000, "open", "k1", limits.clone());
+        assert_eq!((10, LimitResult(1, 0), true), rt);
+        let rt = on_failure(&disabled, &rules, 1000, "open", "k1", limits.clone());
+        assert_eq!((10, LimitResult(0, 0), false), rt);
+
+        let rt = on_failure(&local, &rules, 1000, "closed", "k2", limits.clone());
+        assert_eq!((10, LimitResult(10, 1000), false), rt);
+
+        let rt = on_failure(&disabled, &rules, 1000, "floor", "k3", limits.clone());
+        assert_eq!((1, LimitResult(1, 0), true), rt);
+        let rt = on_failure(&disabled, &rules, 1000, "floor", "k3", limits);
+        assert_eq!(1, rt.0);
+        assert!(rt.1 .1 > 0, "limited by the floor rule");
+    }
+
+    #[tokio::test]
+    async fn guard_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let guard = Guard::new(&test_redis.cfg).await?;
+
+        assert!(guard
+            .check("unknown", "core", "GET /", "user1")
+            .await
+            .is_err());
+        let d = guard
+            .check("", "core", "POST /v1/file/upload", "guard_user1")
+            .await?;
+        assert!(d.limit > 0);
+        assert!(!d.is_limited());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use rustis::{
+    client::{Client, Config},
+    commands::PubSubCommands,
+    resp,
+};
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use super::{metrics, redlimit::Namespaces};
+
+// the delay before resubscribing after an error
+const SUBSCRIBE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// ExpiredKey is an expired limiting key "<namespace>:<scope>:<id>", the id
+// keeps the ":<index>" suffix of the composite keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredKey {
+    pub ns: String,
+    pub scope: String,
+    pub id: String,
+}
+
+type OnExpired = Arc<dyn Fn(ExpiredKey) + Send + Sync>;
+
+// init_keyspace_subscriber subscribes to the expired events of the main Redis
+// database with a dedicated client, and calls on_expired with the expired
+// limiting keys of the namespaces. Redis must be configured with
+// "notify-keyspace-events Ex", it is checked but not changed.
+pub fn init_keyspace_subscriber(
+    config: Config,
+    namespaces: Arc<Namespaces>,
+    on_expired: impl Fn(ExpiredKey) + Send + Sync + 'static,
+) -> (JoinHandle<()>, CancellationToken) {
+    let cancel_keyspace = CancellationToken::new();
+    let stop_signal = cancel_keyspace.clone();
+    let on_expired: OnExpired = Arc::new(on_expired);
+    (
+        tokio::spawn(async move {
+            loop {
+                let rt = tokio::select! {
+                    _ = stop_signal.cancelled() => return,
+                    rt = keyspace_subscribe(&config, &namespaces, &on_expired) => rt,
+                };
+                if let Err(err) = rt {
+                    log::warn!(target: "keyspace", "keyspace subscriber error: {}", err);
+                }
+                tokio::select! {
+                    _ = stop_signal.cancelled() => return,
+                    _ = sleep(SUBSCRIBE_RETRY_DELAY) => {}
+                };
+            }
+        }),
+        cancel_keyspace,
+    )
+}
+
+async fn keyspace_subscribe(
+    config: &Config,
+    namespaces: &Namespaces,
+    on_expired: &OnExpired,
+) -> anyhow::Result<()> {
+    let client = Client::connect(config.clone()).await?;
+    let cmd = resp::cmd("CONFIG").arg("GET").arg("notify-keyspace-events");
+    match client
+        .send(cmd, None)
+        .await
+        .and_then(|v| v.to::<Vec<String>>())
+    {
+        Ok(v) if !is_expired_notified(v.get(1).map_or("", |s| s.as_str())) => {
+            log::warn!(target: "keyspace",
+                "notify-keyspace-events {:?} misses \"Ex\", no expired events",
+                v.get(1).map_or("", |s| s.as_str())
+            );
+        }
+        Err(err) => log::warn!(target: "keyspace", "CONFIG GET error: {}", err),
+        _ => {}
+    }
+
+    let channel = format!("__keyevent@{}__:expired", config.database);
+    let mut stream = client.subscribe(channel).await?;
+    log::info!(target: "keyspace", "keyspace subscriber start");
+    while let Some(msg) = stream.next().await {
+        let key = String::from_utf8_lossy(&msg?.payload).to_string();
+        if let Some(expired) = parse_key(namespaces, &key) {
+            metrics::EXPIRED
+                .with_label_values(&[&expired.ns, "key"])
+                .inc();
+            on_expired(expired);
+        }
+    }
+    Err(anyhow::Error::msg("subscription closed"))
+}
+
+// is_expired_notified checks the notify-keyspace-events flags for the expired
+// events of the keyevent channels: "E" with "x" or "A" (alias of "g$lshzxet").
+fn is_expired_notified(flags: &str) -> bool {
+    flags.contains('E') && (flags.contains('x') || flags.contains('A'))
+}
+
+// parse_key returns the namespace, scope and id of a limiting key, None if not
+// in the namespaces or an extra key of the composite limits ("<scope>#<n>"),
+// which is counted by its limiting key. The namespace with the longest match
+// wins.
+fn parse_key(namespaces: &Namespaces, key: &str) -> Option<ExpiredKey> {
+    namespaces
+        .iter()
+        .filter_map(|r| {
+            let ns = r.ns.as_str();
+            let (scope, id) = key.strip_prefix(ns)?.strip_prefix(':')?.split_once(':')?;
+            if scope.contains('#') {
+                return None;
+            }
+            Some(ExpiredKey {
+                ns: ns.to_string(),
+                scope: scope.to_string(),
+                id: id.to_string(),
+            })
+        })
+        .max_by_key(|k| k.ns.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf;
+
+    #[test]
+    fn parse_key_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let namespaces = Namespaces::new(&cfg);
+        assert_eq!(
+            Some(ExpiredKey {
+                ns: "RL".to_string(),
+                scope: "core".to_string(),
+                id: "tenant1:user1".to_string(),
+            }),
+            parse_key(&namespaces, "RL:core:tenant1:user1")
+        );
+        assert_eq!(None, parse_key(&namespaces, "RL:core"));
+        assert_eq!(None, parse_key(&namespaces, "RL:core#1:user1"));
+        assert_eq!(None, parse_key(&namespaces, "RL2:core:user1"));
+        assert_eq!(None, parse_key(&namespaces, "other"));
+        Ok(())
+    }
+
+    #[test]
+    fn is_expired_notified_works() {
+        assert!(is_expired_notified("xE"));
+        assert!(is_expired_notified("AKE"));
+        assert!(!is_expired_notified(""));
+        assert!(!is_expired_notified("Kx"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+
+use super::{
+    conf::{self, Algorithm},
+    guard,
+    redis::Shards,
+    redlimit::{self, LimitArgs, LimitResult, Limits},
+};
+
+// LeaseLimiter limits the rules with "lease" by the counts leased from Redis:
+// an instance leases up to "lease" of the remaining count of a window at a
+// time, and decides locally until the leased count is used up or the window
+// ends. The instances never allow more than the limit in total, the leased
+// count not used by an instance is lost for the others until the window ends.
+pub struct LeaseLimiter {
+    cfg: conf::Leasing,
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Lease {
+    remaining: u64, // leased and not used
+    count: u64,     // the count in Redis when leased
+    expire_at: u64, // the end of the window
+    exhausted: bool,
+}
+
+impl LeaseLimiter {
+    pub fn new(cfg: conf::Leasing) -> Self {
+        LeaseLimiter {
+            cfg,
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // erase removes the leases of the matched keys.
+    pub fn erase(&self, matches: impl Fn(&str) -> bool) {
+        self.leases.lock().unwrap().retain(|k, _| !matches(k));
+    }
+
+    // limiting decides with the leased count, it leases more from Redis when
+    // used up. None if the limits are not leased.
+    pub async fn limiting(
+        &self,
+        shards: &Shards,
+        retry: &conf::Retry,
+        now: u64,
+        limiting_key: &str,
+        limits: &Limits,
+    ) -> Option<Result<(u64, LimitResult)>> {
+        let args = limits.args;
+        if limits.lease == 0
+            || limits.algorithm != Algorithm::FixedWindow
+            || args.3 > 0
+            || limits.extra.iter().any(|a| a.is_valid())
+        {
+            return None;
+        }
+        if !args.is_valid() {
+            return Some(Ok((args.1, LimitResult(0, 0))));
+        }
+        if let Some(rt) = self.take(now, limiting_key, &args) {
+            return Some(Ok((args.1, rt)));
+        }
+
+        let size = limits.lease.max(args.0);
+        let rt = guard::on_shard(shards, limiting_key, |pool| async move {
+            redlimit::limiting_lease(&pool, retry, limiting_key, size, args.1, args.2).await
+        })
+        .await;
+        Some(rt.map(|leased| (args.1, self.leased(now, limiting_key, &args, leased))))
+    }
+
+    // take uses the leased count, None if more should be leased.
+    fn take(&self, now: u64, limiting_key: &str, args: &LimitArgs) -> Option<LimitResult> {
+        let mut leases = self.leases.lock().unwrap();
+        let lease = leases
+            .get_mut(limiting_key)
+            .filter(|lease| lease.expire_at > now)?;
+        take_lease(lease, now, args)
+    }
+
+    // leased adds the result of limiting_lease to the lease and uses it.
+    fn leased(
+        &self,
+        now: u64,
+        limiting_key: &str,
+        args: &LimitArgs,
+        (leased, ttl, count): (u64, u64, u64),
+    ) -> LimitResult {
+        let mut leases = self.leases.lock().unwrap();
+        let remaining = match leases.remove(limiting_key) {
+            // leased by the concurrent requests in the same window
+            Some(lease) if lease.expire_at > now => lease.remaining + leased,
+            _ => leased,
+        };
+        let mut lease = Lease {
+            remaining,
+            count,
+            expire_at: now + ttl.max(1),
+            exhausted: count >= args.1,
+        };
+        let rt = take_lease(&mut lease, now, args)
+            .unwrap_or(LimitResult(count - lease.remaining, lease.expire_at - now));
+
+        if leases.len() >= self.cfg.max_keys {
+            leases.retain(|_, lease| lease.expire_at > now);
+        }
+        if leases.len() < self.cfg.max_keys {
+            leases.insert(limiting_key.to_string(), lease);
+        }
+        rt
+    }
+}
+
+fn take_lease(lease: &mut Lease, now: u64, args: &LimitArgs) -> Option<LimitResult> {
+    if lease.remaining >= args.0 {
+        lease.remaining -= args.0;
+        return Some(LimitResult(lease.count - lease.remaining, 0));
+    }
+    if lease.exhausted {
+        return Some(LimitResult(
+            lease.count - lease.remaining,
+            lease.expire_at - now,
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_works() {
+        let leaser = LeaseLimiter::new(conf::Leasing { max_keys: 1 });
+        let args = LimitArgs(2, 10, 1000, 0, 0);
+        let now = 1000;
+        let key = "RL:core:user1";
+
+        assert_eq!(None, leaser.take(now, key, &args), "nothing leased");
+        // leased 4 of [0, 4)
+        assert_eq!(
+            LimitResult(2, 0),
+            leaser.leased(now, key, &args, (4, 1000, 4))
+        );
+        assert_eq!(Some(LimitResult(4, 0)), leaser.take(now, key, &args));
+        assert_eq!(None, leaser.take(now, key, &args), "used up");
+
+        // leased 4 of [6, 10) after the other instances, and 2 more by a
+        // concurrent request
+        assert_eq!(
+            LimitResult(8, 0),
+            leaser.leased(now, key, &args, (4, 900, 10))
+        );
+        assert_eq!(
+            LimitResult(8, 0),
+            leaser.leased(now, key, &args, (2, 900, 10))
+        );
+        assert_eq!(Some(LimitResult(10, 0)), leaser.take(now, key, &args));
+        assert_eq!(
+            Some(LimitResult(10, 900)),
+            leaser.take(now, key, &args),
+            "exhausted"
+        );
+        assert_eq!(None, leaser.take(now + 900, key, &args), "next window");
+
+        // not kept if full
+        assert_eq!(
+            LimitResult(2, 0),
+            leaser.leased(now, "RL:core:user2", &args, (4, 1000, 4))
+        );
+        assert_eq!(None, leaser.take(now, "RL:core:user2", &args));
+
+        leaser.erase(|k| k == key);
+        assert_eq!(None, leaser.take(now, key, &args));
+        assert_eq!(
+            LimitResult(10, 500),
+            leaser.leased(now, "RL:core:user2", &args, (0, 500, 10)),
+            "exhausted by the other instances"
+        );
+    }
+}
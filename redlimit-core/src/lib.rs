@@ -0,0 +1,28 @@
+// redlimit-core is the limiter of redlimit without the HTTP service: the rules,
+// the limiting functions and the Redis calls, so that a service can embed it
+// in-process against the same Redis data. guard::Guard is the entry point, with
+// the actix-web middleware and the tower layer behind the "actix" and "tower"
+// features. testing::TestRedis starts a disposable Redis server for the tests
+// behind the "redis-harness" feature.
+#[cfg(feature = "actix")]
+pub mod actix_middleware;
+pub mod approx;
+pub mod chaos;
+pub mod conf;
+pub mod context;
+pub mod guard;
+pub mod keyspace;
+pub mod lease;
+pub mod local;
+pub mod metrics;
+pub mod redis;
+pub mod redlimit;
+pub mod redlimit_lua;
+pub mod redlist;
+pub mod report;
+pub mod snapshot;
+pub mod stats;
+pub mod telemetry;
+pub mod testing;
+#[cfg(feature = "tower")]
+pub mod tower_middleware;
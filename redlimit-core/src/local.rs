@@ -0,0 +1,271 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use super::{
+    conf,
+    conf::Composite,
+    redlimit::{composite_result, LimitArgs, LimitResult, Limits, NS},
+};
+
+// LocalLimiter is a per-instance in-memory approximation of the limiting
+// function in redlimit.lua, used as a fallback when Redis is unavailable. All
+// algorithms are approximated with fixed-window.
+pub struct LocalLimiter {
+    cfg: conf::Fallback,
+    counters: Mutex<HashMap<String, Counter>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Counter {
+    count: u64,
+    expire_at: u64,
+    burst: u64,
+    burst_at: u64,
+}
+
+impl LocalLimiter {
+    pub fn new(cfg: conf::Fallback) -> Self {
+        LocalLimiter {
+            cfg,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cfg.local
+    }
+
+    // erase removes the counters of the matched keys.
+    pub fn erase(&self, matches: impl Fn(&str) -> bool) {
+        self.counters.lock().unwrap().retain(|k, _| !matches(k));
+    }
+
+    pub fn limiting(&self, now: u64, limiting_key: &str, limits: &Limits) -> (u64, LimitResult) {
+        let limit = limits.args.1;
+        if !limits.args.is_valid() {
+            return (limit, LimitResult(0, 0));
+        }
+
+        let mut all = vec![limits.args];
+        all.extend(limits.extra.iter().filter(|a| a.is_valid()));
+        let keys: Vec<String> = (0..all.len())
+            .map(|i| {
+                if i == 0 {
+                    limiting_key.to_string()
+                } else {
+                    NS::extra_key(limiting_key, i)
+                }
+            })
+            .collect();
+
+        let mut counters = self.counters.lock().unwrap();
+        let evaluated: Vec<(Option<Counter>, LimitResult)> = keys
+            .iter()
+            .zip(all.iter())
+            .map(|(key, args)| evaluate(counters.get(key), now, args))
+            .collect();
+
+        let allowed = evaluated.iter().filter(|(_, rt)| rt.1 == 0).count();
+        let pass = match limits.composite {
+            Composite::And => allowed == all.len(),
+            Composite::Or => allowed > 0,
+        };
+
+        if pass {
+            if counters.len() + keys.len() > self.cfg.max_keys {
+                counters.retain(|_, c| c.expire_at > now);
+            }
+            for (key, (counter, _)) in keys.into_iter().zip(evaluated.iter()) {
+                if let Some(counter) = counter {
+                    if counters.len() < self.cfg.max_keys || counters.contains_key(&key) {
+                        counters.insert(key, *counter);
+                    }
+                }
+            }
+        }
+
+        let rts = evaluated
+            .into_iter()
+            .zip(all.iter())
+            .map(|((_, rt), args)| {
+                if !pass && rt.1 == 0 {
+                    // not committed because other limits were exceeded
+                    LimitResult(rt.0 - args.0, 0)
+                } else {
+                    rt
+                }
+            })
+            .collect();
+        composite_result(&all, rts, limits.composite)
+    }
+}
+
+// evaluate returns the updated counter if allowed, and the result.
+fn evaluate(
+    counter: Option<&Counter>,
+    now: u64,
+    args: &LimitArgs,
+) -> (Option<Counter>, LimitResult) {
+    let LimitArgs(quantity, max_count, period, max_burst, burst_period) = *args;
+    let burst_period = if burst_period > 0 { burst_period } else { 1000 };
+    if quantity > max_count {
+        return (None, LimitResult(quantity, 1));
+    }
+
+    match counter {
+        Some(c) if c.expire_at > now => {
+            let count = c.count + quantity;
+            let mut next = Counter { count, ..*c };
+
+            if max_burst > 0 {
+                next.burst = c.burst + quantity;
+                if c.burst_at + burst_period <= now {
+                    next.burst = quantity;
+                    next.burst_at = now;
+                } else if next.burst > max_burst {
+                    return (None, LimitResult(c.count, c.burst_at + burst_period - now));
+                }
+            }
+
+            if count > max_count {
+                return (None, LimitResult(c.count, c.expire_at - now));
+            }
+            (Some(next), LimitResult(count, 0))
+        }
+        _ => (
+            Some(Counter {
+                count: quantity,
+                expire_at: now + period,
+                burst: if max_burst > 0 { quantity } else { 0 },
+                burst_at: now,
+            }),
+            LimitResult(quantity, 0),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_limiter_works() {
+        let limiter = LocalLimiter::new(conf::Fallback::default());
+        let limits = |quantity: u64| Limits::new(quantity, &[8, 1000, 5, 300], &[], Composite::And);
+
+        let now = 10000;
+        assert_eq!(
+            (8, LimitResult(1, 0)),
+            limiter.limiting(now, "k", &limits(1))
+        );
+        assert_eq!(
+            (8, LimitResult(4, 0)),
+            limiter.limiting(now, "k", &limits(3))
+        );
+        assert_eq!(
+            (8, LimitResult(4, 300)),
+            limiter.limiting(now, "k", &limits(3)),
+            "bursted"
+        );
+        assert_eq!(
+            (8, LimitResult(7, 0)),
+            limiter.limiting(now + 300, "k", &limits(3))
+        );
+        assert_eq!(
+            (8, LimitResult(7, 700)),
+            limiter.limiting(now + 300, "k", &limits(2)),
+            "limited"
+        );
+        assert_eq!(
+            (8, LimitResult(8, 0)),
+            limiter.limiting(now + 300, "k", &limits(1))
+        );
+        assert_eq!(
+            (8, LimitResult(1, 0)),
+            limiter.limiting(now + 1000, "k", &limits(1)),
+            "new period"
+        );
+        assert_eq!(
+            (8, LimitResult(0, 0)),
+            limiter.limiting(now + 1000, "k", &limits(9)),
+            "invalid args"
+        );
+
+        limiter.erase(|k| k == "k");
+        assert_eq!(
+            (8, LimitResult(1, 0)),
+            limiter.limiting(now + 1000, "k", &limits(1)),
+            "erased"
+        );
+    }
+
+    #[test]
+    fn local_limiter_composite_works() {
+        let limiter = LocalLimiter::new(conf::Fallback::default());
+        let limits =
+            |quantity: u64| Limits::new(quantity, &[3, 1000], &[vec![5, 10000]], Composite::And);
+
+        let now = 10000;
+        assert_eq!(
+            (3, LimitResult(1, 0)),
+            limiter.limiting(now, "RL:core:k", &limits(1))
+        );
+        assert_eq!(
+            (3, LimitResult(3, 0)),
+            limiter.limiting(now, "RL:core:k", &limits(2))
+        );
+        assert_eq!(
+            (3, LimitResult(3, 1000)),
+            limiter.limiting(now, "RL:core:k", &limits(1))
+        );
+        assert_eq!(
+            (5, LimitResult(5, 0)),
+            limiter.limiting(now + 1000, "RL:core:k", &limits(2))
+        );
+        assert_eq!(
+            (5, LimitResult(5, 9000)),
+            limiter.limiting(now + 1000, "RL:core:k", &limits(1)),
+            "limited by the second limit"
+        );
+
+        let single = Limits::new(1, &[3, 1000], &[], Composite::And);
+        assert_eq!(
+            (3, LimitResult(1, 0)),
+            limiter.limiting(now + 1000, "RL:core:k:1", &single),
+            "the id \"k:1\" doesn't share the extra counter of \"k\""
+        );
+        limiter.erase(|key| NS::new("RL".to_string()).is_id_key("k:1", key));
+        assert_eq!(
+            (5, LimitResult(5, 9000)),
+            limiter.limiting(now + 1000, "RL:core:k", &limits(1)),
+            "erasing \"k:1\" keeps the counters of \"k\""
+        );
+    }
+
+    #[test]
+    fn local_limiter_max_keys() {
+        let limiter = LocalLimiter::new(conf::Fallback {
+            local: true,
+            max_keys: 2,
+        });
+        let limits = Limits::new(1, &[1, 1000], &[], Composite::And);
+
+        assert_eq!(LimitResult(1, 0), limiter.limiting(0, "k1", &limits).1);
+        assert_eq!(LimitResult(1, 0), limiter.limiting(0, "k2", &limits).1);
+        assert_eq!(LimitResult(1, 0), limiter.limiting(0, "k3", &limits).1);
+        assert_eq!(
+            LimitResult(1, 0),
+            limiter.limiting(0, "k3", &limits).1,
+            "not tracked when full"
+        );
+        assert_eq!(LimitResult(1, 1000), limiter.limiting(0, "k1", &limits).1);
+        assert_eq!(
+            LimitResult(1, 0),
+            limiter.limiting(1000, "k3", &limits).1,
+            "expired counters swept"
+        );
+        assert_eq!(
+            LimitResult(1, 1000),
+            limiter.limiting(1000, "k3", &limits).1
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+
+use super::redis::RedisPool;
+
+pub static REDIS_POOL_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "redlimit_redis_pool_connections",
+        "The number of connections in the Redis pool by state (idle or in_use).",
+        &["pool", "state"]
+    )
+    .unwrap()
+});
+
+pub static REDIS_POOL_WAITERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "redlimit_redis_pool_waiters",
+        "The number of tasks waiting for a connection from the Redis pools."
+    )
+    .unwrap()
+});
+
+pub static REDIS_POOL_WAIT_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "redlimit_redis_pool_wait_seconds",
+        "The time waited for a connection from the Redis pools.",
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 3.0]
+    )
+    .unwrap()
+});
+
+pub static REDIS_COMMAND_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "redlimit_redis_command_seconds",
+        "The round trip time of Redis commands by function (or command) and outcome (ok, error or cancelled), excluding the pool wait.",
+        &["operation", "outcome"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 3.0]
+    )
+    .unwrap()
+});
+
+pub static EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "redlimit_events_total",
+        "The number of violation events by outcome (queued, dropped, published or error).",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+pub static EXPIRED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "redlimit_expired_total",
+        "The number of expired limiting keys (kind \"key\") and redlist entries (kind \"redlist\").",
+        &["ns", "kind"]
+    )
+    .unwrap()
+});
+
+pub static FEED_RUNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "redlimit_feed_runs_total",
+        "The number of blocklist feed runs by feed and outcome (ok or error).",
+        &["feed", "outcome"]
+    )
+    .unwrap()
+});
+
+pub static FEED_ENTRIES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "redlimit_feed_entries",
+        "The number of redlist entries added by the last successful run of a blocklist feed.",
+        &["feed"]
+    )
+    .unwrap()
+});
+
+pub static SYNC_RUNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "redlimit_sync_runs_total",
+        "The number of sync job runs by namespace and outcome (ok or error).",
+        &["ns", "outcome"]
+    )
+    .unwrap()
+});
+
+pub static SYNC_DURATION_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "redlimit_sync_duration_seconds",
+        "The duration of sync job runs by namespace.",
+        &["ns"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 3.0, 10.0]
+    )
+    .unwrap()
+});
+
+pub static SYNC_LAST_SUCCESS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "redlimit_sync_last_success_timestamp_seconds",
+        "The unix time of the last successful sync job run by namespace.",
+        &["ns"]
+    )
+    .unwrap()
+});
+
+pub static SYNC_LOADED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "redlimit_sync_loaded",
+        "The number of redrules or redlist entries loaded in the last successful sync job run.",
+        &["ns", "kind"]
+    )
+    .unwrap()
+});
+
+pub static CHAOS_FAULTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "redlimit_chaos_faults_total",
+        "The number of faults injected by the chaos mode by kind (latency, drop or sync).",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+// observe_pool updates the connection gauges of a pool with its current state.
+pub fn observe_pool(name: &str, pool: &RedisPool) {
+    let state = pool.state();
+    let idle = state.idle_connections as i64;
+    REDIS_POOL_CONNECTIONS
+        .with_label_values(&[name, "idle"])
+        .set(idle);
+    REDIS_POOL_CONNECTIONS
+        .with_label_values(&[name, "in_use"])
+        .set(state.connections as i64 - idle);
+}
+
+// render encodes all registered metrics in the Prometheus text format.
+pub fn render() -> anyhow::Result<String> {
+    Lazy::force(&REDIS_POOL_WAITERS);
+    Lazy::force(&REDIS_POOL_WAIT_SECONDS);
+    Lazy::force(&REDIS_COMMAND_SECONDS);
+
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn render_works() -> anyhow::Result<()> {
+        REDIS_POOL_CONNECTIONS
+            .with_label_values(&["127.0.0.1:6379", "idle"])
+            .set(2);
+        REDIS_POOL_WAIT_SECONDS.observe(0.002);
+        SYNC_RUNS.with_label_values(&["RL", "ok"]).inc();
+        REDIS_COMMAND_SECONDS
+            .with_label_values(&["limiting", "ok"])
+            .observe(0.001);
+
+        let text = render()?;
+        assert!(text
+            .contains("redlimit_redis_pool_connections{pool=\"127.0.0.1:6379\",state=\"idle\"} 2"));
+        assert!(text.contains("# TYPE redlimit_redis_pool_waiters gauge"));
+        assert!(text.contains("redlimit_redis_pool_wait_seconds_count"));
+        assert!(text.contains("redlimit_sync_runs_total{ns=\"RL\",outcome=\"ok\"} 1"));
+        assert!(text.contains(
+            "redlimit_redis_command_seconds_count{operation=\"limiting\",outcome=\"ok\"} 1"
+        ));
+        Ok(())
+    }
+}
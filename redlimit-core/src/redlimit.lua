@@ -0,0 +1,472 @@
+#!lua name=redlimit
+-- version: 3 (bump it on every change, an older resident library is replaced on start)
+
+local function unix_ms()
+  local now = redis.call('TIME')
+  return tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+end
+
+-- keys: <an identifier to rate limit against>
+-- args (should be well formed): <quantity> <max count per period> <period with millisecond> [<max burst> <burst period with millisecond>]
+-- return: [<count in period> or 0, <wait duration with millisecond> or 0]
+local function limiting(keys, args)
+  local quantity = tonumber(args[1]) or 1
+  local max_count = tonumber(args[2]) or 0
+  local period = tonumber(args[3]) or 0
+  local max_burst  = tonumber(args[4]) or 0
+  local burst_period  = tonumber(args[5]) or 1000
+
+  local result = {quantity, 0}
+  if quantity > max_count then
+    result[2] = 1
+    return result
+  end
+
+  local burst = 0
+  local burst_at = 0
+  local limit = redis.call('HMGET', keys[1], 'c', 'b', 't')
+  -- field:c(count in period)
+  -- field:b(burst in burst period)
+  -- field:t(burst start time, millisecond)
+
+  if limit[1] then
+    result[1] = tonumber(limit[1]) + quantity
+
+    if max_burst > 0 then
+      local ts = unix_ms()
+      burst = tonumber(limit[2]) + quantity
+      burst_at = tonumber(limit[3])
+      if burst_at + burst_period <= ts  then
+        burst = quantity
+        burst_at = ts
+      elseif burst > max_burst then
+        result[1] = result[1] - quantity
+        result[2] = burst_at + burst_period - ts
+        return result
+      end
+    end
+
+    if result[1] > max_count then
+      result[1] = result[1] - quantity
+      result[2] = redis.call('PTTL', keys[1])
+
+      if result[2] <= 0 then
+        result[2] = 1
+        redis.call('DEL', keys[1])
+      end
+    elseif max_burst > 0 then
+      redis.call('HSET', keys[1], 'c', result[1], 'b', burst, 't', burst_at)
+    else
+      redis.call('HSET', keys[1], 'c', result[1])
+    end
+
+  else
+    if max_burst > 0 then
+      burst = quantity
+      burst_at = unix_ms()
+    end
+
+    redis.call('HSET', keys[1], 'c', quantity, 'b', burst, 't', burst_at)
+    redis.call('PEXPIRE', keys[1], period)
+  end
+
+  return result
+end
+
+-- keys: <an identifier to rate limit against>
+-- args (should be well formed): <quantity> <max count per sliding period> <period with millisecond> [<max burst> <burst period with millisecond>]
+-- return: [<count in sliding period> or 0, <wait duration with millisecond> or 0]
+-- The count in the sliding period is approximated by weighting the count of the previous window.
+local function limiting_sliding(keys, args)
+  local quantity = tonumber(args[1]) or 1
+  local max_count = tonumber(args[2]) or 0
+  local period = tonumber(args[3]) or 0
+  local max_burst = tonumber(args[4]) or 0
+  local burst_period = tonumber(args[5]) or 1000
+
+  if quantity > max_count then
+    return {quantity, 1}
+  end
+
+  local ts = unix_ms()
+  local limit = redis.call('HMGET', keys[1], 'c', 'p', 's', 'b', 't')
+  -- field:c(count in current window)
+  -- field:p(count in previous window)
+  -- field:s(current window start time, millisecond)
+  -- field:b(burst in burst period)
+  -- field:t(burst start time, millisecond)
+  local count = tonumber(limit[1]) or 0
+  local prev = tonumber(limit[2]) or 0
+  local start = tonumber(limit[3]) or ts
+  local burst = tonumber(limit[4]) or 0
+  local burst_at = tonumber(limit[5]) or 0
+
+  local elapsed = ts - start
+  if elapsed >= period then
+    local windows = math.floor(elapsed / period)
+    if windows == 1 then
+      prev = count
+    else
+      prev = 0
+    end
+    count = 0
+    start = start + windows * period
+    elapsed = ts - start
+  end
+
+  local current = math.floor(prev * (period - elapsed) / period) + count
+  if max_burst > 0 then
+    if burst_at + burst_period <= ts then
+      burst = 0
+      burst_at = ts
+    end
+    if burst + quantity > max_burst then
+      return {current, burst_at + burst_period - ts}
+    end
+  end
+
+  if current + quantity > max_count then
+    local wait = start + period - ts
+    local room = max_count - count - quantity
+    if room >= 0 and prev > 0 then
+      -- wait until the weight of the previous window decays enough
+      wait = math.ceil(period - room * period / prev) - elapsed
+    end
+    if wait < 1 then
+      wait = 1
+    end
+    return {current, wait}
+  end
+
+  redis.call('HSET', keys[1], 'c', count + quantity, 'p', prev, 's', start, 'b', burst + quantity, 't', burst_at)
+  redis.call('PEXPIRE', keys[1], start + 2 * period - ts)
+  return {current + quantity, 0}
+end
+
+-- keys: <an identifier to rate limit against>
+-- args (should be well formed): <quantity> <max count per period> <period with millisecond> [<max burst>]
+-- return: [<count in period> or 0, <wait duration with millisecond> or 0]
+-- Generic cell rate algorithm: requests are spaced by period / max count, up to
+-- <max burst> (default to max count) requests can be sent at once.
+local function limiting_gcra(keys, args)
+  local quantity = tonumber(args[1]) or 1
+  local max_count = tonumber(args[2]) or 0
+  local period = tonumber(args[3]) or 0
+  local max_burst = tonumber(args[4]) or 0
+
+  if quantity > max_count then
+    return {quantity, 1}
+  end
+  if max_burst <= 0 then
+    max_burst = max_count
+  end
+
+  local interval = period / max_count
+  local ts = unix_ms()
+  -- the theoretical arrival time
+  local tat = tonumber(redis.call('GET', keys[1])) or ts
+  if tat < ts then
+    tat = ts
+  end
+
+  local new_tat = tat + quantity * interval
+  local allow_at = new_tat - max_burst * interval
+  if allow_at > ts then
+    return {math.ceil((tat - ts) / interval), math.ceil(allow_at - ts)}
+  end
+
+  redis.call('SET', keys[1], new_tat, 'PX', math.ceil(new_tat - ts))
+  return {math.ceil((new_tat - ts) / interval), 0}
+end
+
+-- keys: <an identifier to rate limit against>
+-- args (should be well formed): <quantity> <max count per period> <period with millisecond> [<max burst>]
+-- return: [<tokens used> or 0, <wait duration with millisecond> or 0]
+-- Token bucket: the bucket holds <max burst> (default to max count) tokens and
+-- is refilled with max count tokens per period.
+local function limiting_token_bucket(keys, args)
+  local quantity = tonumber(args[1]) or 1
+  local max_count = tonumber(args[2]) or 0
+  local period = tonumber(args[3]) or 0
+  local max_burst = tonumber(args[4]) or 0
+
+  if quantity > max_count then
+    return {quantity, 1}
+  end
+  if max_burst <= 0 then
+    max_burst = max_count
+  end
+
+  local rate = max_count / period -- tokens per millisecond
+  local ts = unix_ms()
+  local bucket = redis.call('HMGET', keys[1], 'k', 't')
+  -- field:k(tokens in bucket)
+  -- field:t(last refill time, millisecond)
+  local tokens = tonumber(bucket[1]) or max_burst
+  local last = tonumber(bucket[2]) or ts
+  tokens = math.min(max_burst, tokens + (ts - last) * rate)
+
+  if tokens < quantity then
+    return {math.floor(max_burst - tokens), math.ceil((quantity - tokens) / rate)}
+  end
+
+  tokens = tokens - quantity
+  redis.call('HSET', keys[1], 'k', tokens, 't', ts)
+  redis.call('PEXPIRE', keys[1], math.ceil((max_burst - tokens) / rate) + 1)
+  return {math.ceil(max_burst - tokens), 0}
+end
+
+-- keys: <an identifier to rate limit against>
+-- args (should be well formed): <lease size> <max count per period> <period with millisecond>
+-- return: [<leased count> or 0, <ttl of the period with millisecond>, <count in period>]
+-- Leases up to <lease size> of the remaining count of the fixed window to an
+-- instance, which limits with it locally. The key is the same as 'limiting'.
+local function limiting_lease(keys, args)
+  local size = tonumber(args[1]) or 1
+  local max_count = tonumber(args[2]) or 0
+  local period = tonumber(args[3]) or 0
+
+  local count = tonumber(redis.call('HGET', keys[1], 'c'))
+  local ttl = redis.call('PTTL', keys[1])
+  local fresh = false
+  if not count or ttl <= 0 then
+    count = 0
+    ttl = period
+    fresh = true
+  end
+
+  local leased = math.min(size, max_count - count)
+  if leased <= 0 then
+    return {0, ttl, count}
+  end
+  count = count + leased
+  redis.call('HSET', keys[1], 'c', count, 'b', 0, 't', 0)
+  if fresh then
+    redis.call('PEXPIRE', keys[1], period)
+  end
+  return {leased, ttl, count}
+end
+
+local limiting_fns = {
+  limiting = limiting,
+  limiting_sliding = limiting_sliding,
+  limiting_gcra = limiting_gcra,
+  limiting_token_bucket = limiting_token_bucket,
+}
+
+-- keys: <an identifier to rate limit against> <namespace>
+-- args (should be well formed): <id> <limiting function> <quantity> <max count> <period> <max burst> <burst period> <floor max count> <floor period> <floor max burst> <floor burst period>
+-- return: [<count> or 0, <wait duration with millisecond> or 0, <1 if redlisted or 0>]
+-- The id in the redlist of the namespace is limited by the floor limit with quantity 1, so an id
+-- is limited at once after redlist_add, before the instances sync the redlist.
+local function limiting_redlist(keys, args)
+  local ttl = tonumber(redis.call('ZSCORE', keys[2] .. ':LT', args[1]))
+  if ttl and ttl > unix_ms() then
+    local rt = limiting({keys[1]}, {1, args[8], args[9], args[10], args[11]})
+    return {rt[1], rt[2], 1}
+  end
+
+  local fn = limiting_fns[args[2]]
+  if not fn then
+    return redis.error_reply('ERR unknown limiting function: ' .. args[2])
+  end
+  local rt = fn({keys[1]}, {args[3], args[4], args[5], args[6], args[7]})
+  return {rt[1], rt[2], 0}
+end
+
+-- evaluate a limit without updating it.
+-- return: {count = <count in period>, wait = <wait duration with millisecond>, burst, burst_at, exists}
+local function evaluate(key, quantity, max_count, period, max_burst, burst_period)
+  local res = {count = quantity, wait = 0, burst = 0, burst_at = 0, exists = false}
+  if quantity > max_count then
+    res.wait = 1
+    return res
+  end
+
+  local limit = redis.call('HMGET', key, 'c', 'b', 't')
+  if limit[1] then
+    res.exists = true
+    res.count = tonumber(limit[1]) + quantity
+
+    if max_burst > 0 then
+      local ts = unix_ms()
+      res.burst = tonumber(limit[2]) + quantity
+      res.burst_at = tonumber(limit[3])
+      if res.burst_at + burst_period <= ts then
+        res.burst = quantity
+        res.burst_at = ts
+      elseif res.burst > max_burst then
+        res.count = res.count - quantity
+        res.wait = res.burst_at + burst_period - ts
+        return res
+      end
+    end
+
+    if res.count > max_count then
+      res.count = res.count - quantity
+      res.wait = redis.call('PTTL', key)
+
+      if res.wait <= 0 then
+        res.wait = 1
+        redis.call('DEL', key)
+      end
+    end
+  elseif max_burst > 0 then
+    res.burst = quantity
+    res.burst_at = unix_ms()
+  end
+
+  return res
+end
+
+-- keys: <an identifier to rate limit against> [<an identifier to rate limit against> ...]
+-- args (should be well formed): <quantity> <"and" or "or"> <max count per period> <period with millisecond> <max burst> <burst period with millisecond> [...4 args for every other key]
+-- return: [<count in period> or 0, <wait duration with millisecond> or 0, ...2 values for every other key]
+local function limiting_composite(keys, args)
+  local quantity = tonumber(args[1]) or 1
+  local any = args[2] == 'or'
+
+  local results = {}
+  local allowed = 0
+  for i = 1, #keys do
+    local j = 3 + (i - 1) * 4
+    local max_count = tonumber(args[j]) or 0
+    local period = tonumber(args[j + 1]) or 0
+    local max_burst = tonumber(args[j + 2]) or 0
+    local burst_period = tonumber(args[j + 3]) or 0
+    if burst_period == 0 then
+      burst_period = 1000
+    end
+
+    results[i] = evaluate(keys[i], quantity, max_count, period, max_burst, burst_period)
+    results[i].period = period
+    results[i].max_burst = max_burst
+    if results[i].wait == 0 then
+      allowed = allowed + 1
+    end
+  end
+
+  local pass = allowed == #keys or (any and allowed > 0)
+  local rt = {}
+  for i = 1, #keys do
+    local res = results[i]
+    if res.wait == 0 then
+      if not pass then
+        res.count = res.count - quantity
+      elseif not res.exists then
+        redis.call('HSET', keys[i], 'c', quantity, 'b', res.burst, 't', res.burst_at)
+        redis.call('PEXPIRE', keys[i], res.period)
+      elseif res.max_burst > 0 then
+        redis.call('HSET', keys[i], 'c', res.count, 'b', res.burst, 't', res.burst_at)
+      else
+        redis.call('HSET', keys[i], 'c', res.count)
+      end
+    end
+    table.insert(rt, res.count)
+    table.insert(rt, res.wait)
+  end
+  return rt
+end
+
+-- keys: <redlist key>
+-- args: <member> <expire duration with millisecond> [<member> <expire duration with millisecond> ...]
+-- return: integer or error
+local function redlist_add(keys, args)
+  local cursor_key = keys[1] .. ':LC'
+  local ttl_key = keys[1] .. ':LT'
+  local ts = unix_ms()
+  local members = redis.call('ZRANGE', ttl_key, '-inf', '(' .. ts, 'BYSCORE')
+  if #members > 0 then
+    redis.call('ZREM', ttl_key, unpack(members))
+    redis.call('ZREM', cursor_key, unpack(members))
+    redis.call('HDEL', keys[1] .. ':LM', unpack(members))
+  end
+
+  if #args == 0 then
+    return 0
+  end
+
+  local cursor_members = {}
+  local ttl_members = {}
+  local changes = {}
+  for i = 1, #args, 2 do
+    cursor_members[i] = ts + i
+    cursor_members[i + 1] = args[i]
+    ttl_members[i] = ts + (tonumber(args[i + 1]) or 1000)
+    ttl_members[i + 1] = args[i]
+    changes[args[i]] = ttl_members[i]
+  end
+
+  redis.call('ZADD', ttl_key, unpack(ttl_members))
+  local rt = redis.call('ZADD', cursor_key, unpack(cursor_members))
+  redis.call('PUBLISH', keys[1] .. ':CH', cjson.encode({redlist = changes}))
+  return rt
+end
+
+-- keys: <redlist key>
+-- args: <cursor> [<count>]
+-- return: [<cursor>, <member>, <ttl with millisecond>, <member>, <ttl with millisecond> ...] or error
+local function redlist_scan(keys, args)
+  local cursor_key = keys[1] .. ':LC'
+  local ttl_key = keys[1] .. ':LT'
+  local cursor = tonumber(args[1]) or 0
+  local count = tonumber(args[2]) or 10000
+
+  local res = {}
+  local members = redis.call('ZRANGE', cursor_key, cursor, 'inf', 'BYSCORE', 'LIMIT', 0, count)
+  if #members > 0 then
+    local ttls = redis.call('ZMSCORE', ttl_key, unpack(members))
+    table.insert(res, redis.call('ZSCORE', cursor_key, members[#members]))
+    for i = 1, #members, 1 do
+      table.insert(res, members[i])
+      table.insert(res, ttls[i] or '0')
+    end
+  end
+  return res
+end
+
+-- keys: <redrule key>
+-- args: <scope> <path> <quantity> <expire duration with millisecond>
+-- return: integer or error
+local function redrules_add(keys, args)
+  local ttl_key = keys[1] .. ':RT'
+  local data_key = keys[1] .. ':RD'
+  local ts = unix_ms()
+  local members = redis.call('ZRANGE', ttl_key, '-inf', '(' .. ts, 'BYSCORE')
+  if #members > 0 then
+    redis.call('HDEL', data_key, unpack(members))
+    redis.call('ZREM', ttl_key, unpack(members))
+  end
+
+  if #args == 0 then
+    return 0
+  end
+
+  local id = args[1] .. ':' .. args[2]
+  local quantity = tonumber(args[3]) or 1
+  local ttl = ts + (tonumber(args[4]) or 1000)
+  local rule = {args[1], args[2], quantity,  ttl}
+  redis.call('ZADD', ttl_key, ttl, id)
+  local rt = redis.call('HSET', data_key, id, cjson.encode(rule))
+  redis.call('PUBLISH', keys[1] .. ':CH', cjson.encode({redrules = {rule}}))
+  return rt
+end
+
+-- keys: <redrules key>
+-- return: array or error
+local function redrules_all(keys, args)
+  local data_key = keys[1] .. ':RD'
+  return redis.call('HVALS', data_key)
+end
+
+redis.register_function('limiting', limiting)
+redis.register_function('limiting_sliding', limiting_sliding)
+redis.register_function('limiting_gcra', limiting_gcra)
+redis.register_function('limiting_token_bucket', limiting_token_bucket)
+redis.register_function('limiting_lease', limiting_lease)
+redis.register_function('limiting_redlist', limiting_redlist)
+redis.register_function('limiting_composite', limiting_composite)
+redis.register_function('redlist_add', redlist_add)
+redis.register_function{function_name='redlist_scan', callback=redlist_scan, flags={'no-writes'}}
+redis.register_function('redrules_add', redrules_add)
+redis.register_function{function_name='redrules_all', callback=redrules_all, flags={'no-writes'}}
@@ -0,0 +1,3814 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use arc_swap::{ArcSwap, Guard};
+use once_cell::sync::OnceCell;
+use opentelemetry::{
+    trace::{FutureExt, Span, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use rustis::{
+    client::{Client, Config},
+    commands::{LibraryInfo, PubSubCommands},
+    resp,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, Notify},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    chaos, conf,
+    conf::{Algorithm, Composite, FailurePolicy, Rule},
+    context::unix_ms,
+    metrics, redis,
+    redis::{RedisPool, Shards},
+    redlimit_lua,
+    redlist::{self, PatternList},
+    report, stats, telemetry,
+};
+
+pub struct RedRules {
+    pub ns: NS,
+    static_rules: ArcSwap<StaticRules>,
+    // the snapshot of the dynamic rules read by limits without locking, it is
+    // replaced as a whole by the writers serialized with dyn_writer.
+    dyn_rules: ArcSwap<DynRedRules>,
+    dyn_writer: std::sync::Mutex<()>,
+    sources: std::sync::Mutex<RuleSources>,
+    sync_status: std::sync::Mutex<SyncStatus>,
+    redlist_events: broadcast::Sender<RedlistEvent>,
+    redlist_check: bool,
+}
+
+// RedlistEvent is a change of the redlist observed by this instance: "add"
+// (or a new ttl), "remove" by erasing, and "expire".
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct RedlistEvent {
+    pub event: &'static str,
+    pub id: String,
+    pub ttl: u64,
+}
+
+// SyncStatus is the outcome of the last sync job of a namespace.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct SyncStatus {
+    pub last_run: u64,     // unix ms
+    pub last_success: u64, // unix ms
+    pub elapsed: u64,      // ms
+    pub cursor: u64,
+    pub redrules: usize,     // loaded in the last successful run
+    pub redlist: usize,      // loaded in the last successful run
+    pub error: String,       // of the last run, empty on success
+    pub failures: u64,       // consecutive failed runs
+    pub last_full_sync: u64, // unix ms, the redlist was rescanned from cursor 0
+    pub sweeper: bool,       // held the sweep lease in the last successful run
+}
+
+// The consecutive sync job failures of a namespace to report.
+const SYNC_FAILURES_REPORTED: u64 = 3;
+
+struct SyncLoaded {
+    cursor: u64,
+    redrules: usize,
+    redlist: usize,
+    full: bool,
+    sweeper: bool,
+}
+
+// RuleSources are the rules from config file and the central rules from redis,
+// the later override the former by scope.
+struct RuleSources {
+    file: HashMap<String, Rule>,
+    central: HashMap<String, String>, // scope -> rule in JSON
+    central_rules: HashMap<String, Rule>,
+}
+
+impl RuleSources {
+    fn merged(&self) -> HashMap<String, Rule> {
+        let mut rules = self.file.clone();
+        for (scope, rule) in &self.central_rules {
+            rules.insert(scope.clone(), rule.clone());
+        }
+        rules
+    }
+}
+
+// StaticRules are the rules from config, swapped as a whole on reload.
+struct StaticRules {
+    floor: Vec<u64>,
+    defaut: Rule,
+    rules: HashMap<String, Rule>,
+    patterns: HashMap<String, Vec<(PathPattern, u64)>>, // scope -> [(path pattern, quantity)]
+}
+
+pub struct NS(String);
+
+impl NS {
+    pub fn new(namespace: String) -> Self {
+        NS(namespace)
+    }
+
+    pub fn redlist_key(id: &str) -> &str {
+        id
+    }
+
+    pub fn redrules_key(scope: &str, path: &str) -> String {
+        let mut key = String::with_capacity(scope.len() + path.len() + 1);
+        push_redrules_key(&mut key, scope, path);
+        key
+    }
+
+    pub fn limiting_key(&self, scope: &str, id: &str) -> String {
+        let mut key = String::with_capacity(self.0.len() + scope.len() + id.len() + 2);
+        key.push_str(&self.0);
+        key.push(':');
+        key.push_str(scope);
+        key.push(':');
+        key.push_str(id);
+        key
+    }
+
+    // extra_key is the key of the n-th extra limit of a limiting key, the index
+    // goes into the scope segment ("<ns>:<scope>#<n>:<id>"), so it can't collide
+    // with the limiting key of another id.
+    pub fn extra_key(limiting_key: &str, n: usize) -> String {
+        let end = limiting_key
+            .split_once(':')
+            .and_then(|(ns, rest)| Some(ns.len() + 1 + rest.find(':')?))
+            .unwrap_or(limiting_key.len());
+        format!("{}#{}{}", &limiting_key[..end], n, &limiting_key[end..])
+    }
+
+    // id_keys_pattern matches the limiting keys (and the extra keys) of the id
+    // in all scopes, with false positives filtered by is_id_key.
+    pub fn id_keys_pattern(&self, id: &str) -> String {
+        format!("{}:*:{}", glob_escape(&self.0), glob_escape(id))
+    }
+
+    // is_id_key parses the key as "<ns>:<scope>:<id>" or the extra key
+    // "<ns>:<scope>#<n>:<id>", and compares the id exactly. Scopes have no ':',
+    // the ids may have.
+    pub fn is_id_key(&self, id: &str, key: &str) -> bool {
+        let rest = match key.strip_prefix(&self.0).and_then(|k| k.strip_prefix(':')) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        match rest.split_once(':') {
+            Some((scope, key_id)) if !scope.is_empty() => key_id == id,
+            _ => false,
+        }
+    }
+
+    // admin_limit_key is the fixed window of the admin requests of a caller, it
+    // is out of the "<ns>:" limiting keys so no scope of a limiting request can
+    // reach it.
+    pub fn admin_limit_key(&self, caller: &str) -> String {
+        format!("{}/AL:{}", self.0, caller)
+    }
+
+    pub fn central_key(&self) -> String {
+        format!("{}:SR", self.0)
+    }
+
+    // channel is where redlist_add and redrules_add publish the changes.
+    pub fn channel(&self) -> String {
+        format!("{}:CH", self.0)
+    }
+
+    // the lease of the instance that sweeps the stale redlist and redrules
+    pub fn sweep_lease_key(&self) -> String {
+        format!("{}:SL", self.0)
+    }
+
+    // the hash of the redlist metadata, id -> RedlistMeta in JSON
+    pub fn redlist_meta_key(&self) -> String {
+        format!("{}:LM", self.0)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+fn push_redrules_key(buf: &mut String, scope: &str, path: &str) {
+    buf.push_str(scope);
+    buf.push(':');
+    buf.push_str(path);
+}
+
+thread_local! {
+    // the buffer to look up the redrules of a request without allocating.
+    static REDRULES_KEY: RefCell<String> = RefCell::new(String::with_capacity(128));
+}
+
+// DynRedRules keeps the entries in persistent maps, a modified copy shares the
+// unchanged ones with the snapshot of the readers.
+#[derive(Clone, Default)]
+pub struct DynRedRules {
+    redrules: im::HashMap<String, (u64, u64)>, // ns:scope:path -> (quantity, ttl)
+    redlist: im::HashMap<String, u64>,         // ns:id -> ttl
+    redlist_patterns: PatternList,             // id prefixes and CIDRs in redlist
+    redlist_cursor: u64,
+}
+
+// RedlistSnapshot is an immutable redlist shared with the limiting requests.
+pub struct RedlistSnapshot(Arc<DynRedRules>);
+
+impl RedlistSnapshot {
+    // iter returns the (id, ttl) entries not expired at now.
+    pub fn iter(&self, now: u64) -> impl Iterator<Item = (&str, u64)> + '_ {
+        self.0
+            .redlist
+            .iter()
+            .filter(move |(_, ttl)| **ttl >= now)
+            .map(|(id, ttl)| (id.as_str(), *ttl))
+    }
+}
+
+impl DynRedRules {
+    // redrule returns the (quantity, ttl) of the path in the scope.
+    fn redrule(&self, scope: &str, path: &str) -> Option<(u64, u64)> {
+        if self.redrules.is_empty() {
+            return None;
+        }
+        REDRULES_KEY.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            push_redrules_key(&mut buf, scope, path);
+            self.redrules.get(buf.as_str()).copied()
+        })
+    }
+}
+
+impl StaticRules {
+    fn new(rules: &HashMap<String, Rule>) -> Self {
+        let mut sr = StaticRules {
+            floor: vec![2, 10000, 1, 1000],
+            defaut: Rule {
+                limit: vec![5, 5000, 2, 1000],
+                quantity: 1,
+                ..Rule::default()
+            },
+            rules: HashMap::new(),
+            patterns: HashMap::new(),
+        };
+
+        for (scope, rule) in rules {
+            match scope.as_str() {
+                "*" => sr.defaut = rule.clone(),
+                "-" => sr.floor = rule.limit.clone(),
+                _ => {
+                    sr.rules.insert(scope.clone(), rule.clone());
+                }
+            }
+
+            let mut patterns: Vec<(PathPattern, u64)> = rule
+                .path
+                .iter()
+                .filter_map(|(path, quantity)| PathPattern::parse(path).map(|p| (p, *quantity)))
+                .collect();
+            if !patterns.is_empty() {
+                patterns.sort_by(|a, b| b.0.cmp(&a.0));
+                sr.patterns.insert(scope.clone(), patterns);
+            }
+        }
+        sr
+    }
+
+    fn rule(&self, scope: &str) -> &Rule {
+        self.rules.get(scope).unwrap_or(&self.defaut)
+    }
+
+    // path_quantity returns the quantity of the path in the scope, exact path
+    // first, then the most specific matched path pattern.
+    fn path_quantity(&self, scope: &str, rule: &Rule, path: &str) -> Option<u64> {
+        if let Some(quantity) = rule.path.get(path) {
+            return Some(*quantity);
+        }
+
+        let scope = if self.rules.contains_key(scope) {
+            scope
+        } else {
+            "*"
+        };
+        self.patterns
+            .get(scope)?
+            .iter()
+            .find(|(p, _)| p.matches(path))
+            .map(|(_, quantity)| *quantity)
+    }
+}
+
+impl RedRules {
+    pub fn new(namespace: &str, rules: &HashMap<String, Rule>) -> Self {
+        RedRules {
+            ns: NS::new(namespace.to_string()),
+            static_rules: ArcSwap::from_pointee(StaticRules::new(rules)),
+            dyn_rules: ArcSwap::from_pointee(DynRedRules::default()),
+            dyn_writer: std::sync::Mutex::new(()),
+            sources: std::sync::Mutex::new(RuleSources {
+                file: rules.clone(),
+                central: HashMap::new(),
+                central_rules: HashMap::new(),
+            }),
+            sync_status: std::sync::Mutex::new(SyncStatus::default()),
+            redlist_events: broadcast::channel(1024).0,
+            redlist_check: false,
+        }
+    }
+
+    // with_redlist_check checks the redlist in Redis with every limiting call,
+    // see limiting_redlist.
+    pub fn with_redlist_check(mut self, enabled: bool) -> Self {
+        self.redlist_check = enabled;
+        self
+    }
+
+    pub fn redlist_check(&self) -> bool {
+        self.redlist_check
+    }
+
+    // floor_args returns the args of the floor limit for the redlisted ids.
+    pub fn floor_args(&self) -> LimitArgs {
+        LimitArgs::new(1, &self.static_rules().floor)
+    }
+
+    // subscribe returns the receiver of the redlist events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<RedlistEvent> {
+        self.redlist_events.subscribe()
+    }
+
+    fn send_event(&self, event: &'static str, id: &str, ttl: u64) {
+        // no error but no receivers
+        let _ = self.redlist_events.send(RedlistEvent {
+            event,
+            id: id.to_string(),
+            ttl,
+        });
+    }
+
+    pub fn sync_status(&self) -> SyncStatus {
+        self.sync_status.lock().unwrap().clone()
+    }
+
+    // sync_done records the outcome of a sync job started at `now`.
+    fn sync_done(&self, now: u64, elapsed: Duration, rt: &anyhow::Result<SyncLoaded>) {
+        let ns = self.ns.as_str();
+        let mut status = self.sync_status.lock().unwrap();
+        status.last_run = now;
+        status.elapsed = elapsed.as_millis() as u64;
+        metrics::SYNC_DURATION_SECONDS
+            .with_label_values(&[ns])
+            .observe(elapsed.as_secs_f64());
+        match rt {
+            Ok(loaded) => {
+                status.last_success = now;
+                status.cursor = loaded.cursor;
+                status.redrules = loaded.redrules;
+                status.redlist = loaded.redlist;
+                status.error.clear();
+                status.failures = 0;
+                if loaded.full {
+                    status.last_full_sync = now;
+                }
+                status.sweeper = loaded.sweeper;
+                metrics::SYNC_RUNS.with_label_values(&[ns, "ok"]).inc();
+                metrics::SYNC_LAST_SUCCESS
+                    .with_label_values(&[ns])
+                    .set((now / 1000) as i64);
+                metrics::SYNC_LOADED
+                    .with_label_values(&[ns, "redrules"])
+                    .set(loaded.redrules as i64);
+                metrics::SYNC_LOADED
+                    .with_label_values(&[ns, "redlist"])
+                    .set(loaded.redlist as i64);
+            }
+            Err(err) => {
+                status.error = err.to_string();
+                status.failures += 1;
+                metrics::SYNC_RUNS.with_label_values(&[ns, "error"]).inc();
+                if status.failures == SYNC_FAILURES_REPORTED {
+                    report::error(&format!(
+                        "redlimit sync job of {} failed {} times: {}",
+                        ns, status.failures, err
+                    ));
+                }
+            }
+        }
+    }
+
+    // reload swaps the static rules atomically, the dynamic rules are kept.
+    pub fn reload(&self, rules: &HashMap<String, Rule>) {
+        let mut sources = self.sources.lock().unwrap();
+        sources.file = rules.clone();
+        self.static_rules
+            .store(Arc::new(StaticRules::new(&sources.merged())));
+    }
+
+    // central_update applies the central rules if changed, returns true if
+    // applied. Invalid central rules are rejected as a whole, also the rules
+    // the redlist check doesn't support if it is enabled.
+    pub fn central_update(&self, central: HashMap<String, String>) -> Result<bool> {
+        let mut sources = self.sources.lock().unwrap();
+        if sources.central == central {
+            return Ok(false);
+        }
+
+        let mut rules: HashMap<String, Rule> = HashMap::new();
+        let mut errs: Vec<String> = Vec::new();
+        for (scope, rule) in &central {
+            match serde_json::from_str::<Rule>(rule) {
+                Ok(rule) => {
+                    rules.insert(scope.clone(), rule);
+                }
+                Err(err) => errs.push(format!("central.{:?}: {}", scope, err)),
+            }
+        }
+        conf::validate_rules("central", &rules, &mut errs);
+        if self.redlist_check {
+            conf::validate_redlist_check("central", &rules, &mut errs);
+        }
+        if !errs.is_empty() {
+            return Err(Error::msg(format!(
+                "invalid central rules:\n  {}",
+                errs.join("\n  ")
+            )));
+        }
+
+        sources.central = central;
+        sources.central_rules = rules;
+        self.static_rules
+            .store(Arc::new(StaticRules::new(&sources.merged())));
+        Ok(true)
+    }
+
+    // erase removes the id from the redlist cache of this instance.
+    pub fn erase(&self, id: &str) {
+        self.dyn_modify(|dr| {
+            if let Some(ttl) = dr.redlist.remove(NS::redlist_key(id)) {
+                self.send_event("remove", id, ttl);
+            }
+            dr.redlist_patterns.remove(id);
+        });
+    }
+
+    // dyn_modify publishes a modified copy of the dynamic rules, the readers
+    // keep the previous snapshot until they load it again. The copy is cheap,
+    // the maps of DynRedRules are persistent.
+    fn dyn_modify<F>(&self, f: F)
+    where
+        F: FnOnce(&mut DynRedRules),
+    {
+        let _writer = self.dyn_writer.lock().unwrap();
+        let mut dr = DynRedRules::clone(&self.dyn_rules.load());
+        f(&mut dr);
+        self.dyn_rules.store(Arc::new(dr));
+    }
+
+    // static_rules returns a guard of the current static rules, it doesn't
+    // touch the shared refcount so the readers don't contend on it.
+    fn static_rules(&self) -> Guard<Arc<StaticRules>> {
+        self.static_rules.load()
+    }
+
+    // redlist_snapshot returns the redlist as it is now, to be iterated without
+    // copying it.
+    pub fn redlist_snapshot(&self) -> RedlistSnapshot {
+        RedlistSnapshot(self.dyn_rules.load_full())
+    }
+
+    pub fn redlist(&self, now: u64) -> HashMap<String, u64> {
+        let dr = self.dyn_rules.load();
+        let mut redlist = HashMap::new();
+        for (k, v) in &dr.redlist {
+            if *v >= now {
+                redlist.insert(k.clone(), *v);
+            }
+        }
+        redlist
+    }
+
+    pub fn redrules(&self, now: u64) -> HashMap<String, (u64, u64)> {
+        let dr = self.dyn_rules.load();
+        let mut redrules = HashMap::new();
+        for (k, v) in &dr.redrules {
+            if v.1 >= now {
+                redrules.insert(k.clone(), *v);
+            }
+        }
+        redrules
+    }
+
+    #[cfg(test)]
+    pub fn limit_args(&self, now: u64, scope: &str, path: &str, id: &str) -> LimitArgs {
+        self.limits(now, scope, path, id).args
+    }
+
+    pub fn limits(&self, now: u64, scope: &str, path: &str, id: &str) -> Limits {
+        if id.is_empty() {
+            return Limits::new(0, &[], &[], Composite::And);
+        }
+
+        let sr = self.static_rules();
+        if self.is_redlisted(now, id) {
+            return Limits::new(1, &sr.floor, &[], Composite::And);
+        }
+
+        let dr = self.dyn_rules.load();
+        let rule = sr.rule(scope);
+        let limit = rule
+            .schedules
+            .iter()
+            .find(|s| s.is_active(now))
+            .map_or(&rule.limit, |s| &s.limit);
+        if let Some((quantity, ttl)) = dr.redrule(scope, path) {
+            if ttl >= now {
+                return Limits::new(quantity, limit, &rule.limits, rule.composite)
+                    .with_algorithm(rule.algorithm)
+                    .with_approximate(rule.approximate)
+                    .with_lease(rule.lease);
+            }
+        }
+
+        let quantity = sr.path_quantity(scope, rule, path).unwrap_or(rule.quantity);
+        let quantity = if quantity > 0 { quantity } else { 1 };
+        Limits::new(quantity, limit, &rule.limits, rule.composite)
+            .with_algorithm(rule.algorithm)
+            .with_approximate(rule.approximate)
+            .with_lease(rule.lease)
+    }
+
+    // validate_redrules checks the redrules entries of the scope before they are
+    // added: non-empty paths, the quantity valid for the limits of the scope and
+    // the ttl in the bounds.
+    pub fn validate_redrules(
+        &self,
+        cfg: &conf::Inputs,
+        scope: &str,
+        rules: &HashMap<String, (u64, u64)>,
+    ) -> Result<(), InputError> {
+        if scope.is_empty() {
+            return Err(InputError::new("empty scope"));
+        }
+        if let Err(err) = validate_scope(scope) {
+            return Err(InputError::new(&err.to_string()));
+        }
+        check_entries(cfg, rules.len())?;
+
+        let sr = self.static_rules();
+        let rule = sr.rule(scope);
+        let mut entries = BTreeMap::new();
+        for (path, (quantity, ttl)) in rules {
+            let err = if path.is_empty() {
+                Some("empty path".to_string())
+            } else if let Err(err) =
+                Limits::new(*quantity, &rule.limit, &rule.limits, rule.composite).validate()
+            {
+                Some(err.to_string())
+            } else {
+                check_ttl(cfg, *ttl)
+            };
+            if let Some(err) = err {
+                entries.insert(path.clone(), err);
+            }
+        }
+        InputError::entries("invalid redrules", entries)
+    }
+
+    // is_redlisted returns true if the id or one of its patterns is in the
+    // cached redlist at now.
+    pub fn is_redlisted(&self, now: u64, id: &str) -> bool {
+        let dr = self.dyn_rules.load();
+        if let Some(ttl) = dr.redlist.get(NS::redlist_key(id)) {
+            if *ttl >= now {
+                return true;
+            }
+        }
+        if !dr.redlist_patterns.is_empty() {
+            if let Some((_, ttl)) = dr.redlist_patterns.get(id) {
+                return ttl >= now;
+            }
+        }
+        false
+    }
+
+    // failure_policy returns the failure policy of the scope.
+    pub fn failure_policy(&self, scope: &str) -> FailurePolicy {
+        self.static_rules().rule(scope).failure_policy
+    }
+
+    // is_throttled returns true if the request is out of the allowed percent of
+    // the scope. It is a deterministic hash of scope, path and id, so an id is
+    // either always allowed or always throttled on a path, which is stable for
+    // a gradual rollout.
+    pub fn is_throttled(&self, scope: &str, path: &str, id: &str) -> bool {
+        if id.is_empty() {
+            return false;
+        }
+
+        let rule = self.static_rules();
+        let rule = rule.rule(scope);
+        if rule.allow_percent >= 100 {
+            return false;
+        }
+
+        fnv1a(&[scope, path, id]) % 100 >= rule.allow_percent
+    }
+
+    // apply_change applies a published change without moving the redlist
+    // cursor, the sync job loads the change again. The expired entries are left
+    // to the sync job, so a change costs only its own entries.
+    fn apply_change(&self, now: u64, change: RedlimitChange) {
+        let redrules = change
+            .redrules
+            .into_iter()
+            .map(|v| (NS::redrules_key(&v.0, &v.1), (v.2, v.3)))
+            .collect();
+        self.dyn_modify(|dr| self.dyn_apply(dr, now, 0, change.redlist, redrules, false));
+    }
+
+    // dyn_resync reconciles the dynamic rules with a full load from Redis: the
+    // cached redlist entries and redrules not in Redis any more are removed, and
+    // the cursor is reset. The entries added by Pub/Sub after the load are back
+    // with the next sync from the cursor.
+    pub fn dyn_resync(
+        &self,
+        now: u64,
+        redlist_cursor: u64,
+        redlist: HashMap<String, u64>,
+        redrules: HashMap<String, (u64, u64)>,
+    ) {
+        self.dyn_modify(|dr| {
+            let watched = self.redlist_events.receiver_count() > 0;
+            // the expired ones are left to dyn_apply
+            let missing: Vec<String> = dr
+                .redlist
+                .iter()
+                .filter(|(k, v)| **v > now && !redlist.contains_key(*k))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in missing {
+                if let Some(ttl) = dr.redlist.remove(&k) {
+                    if watched {
+                        self.send_event("remove", &k, ttl);
+                    }
+                }
+                dr.redlist_patterns.remove(&k);
+            }
+            dr.redrules.retain(|k, _| redrules.contains_key(k));
+            dr.redlist_cursor = redlist_cursor;
+            self.dyn_apply(dr, now, redlist_cursor, redlist, redrules, true);
+        });
+    }
+
+    pub fn dyn_update(
+        &self,
+        now: u64,
+        redlist_cursor: u64,
+        redlist: HashMap<String, u64>,
+        redrules: HashMap<String, (u64, u64)>,
+    ) {
+        self.dyn_modify(|dr| self.dyn_apply(dr, now, redlist_cursor, redlist, redrules, true));
+    }
+
+    // dyn_apply adds the entries, and removes the expired ones if sweep.
+    fn dyn_apply(
+        &self,
+        dr: &mut DynRedRules,
+        now: u64,
+        redlist_cursor: u64,
+        redlist: HashMap<String, u64>,
+        redrules: HashMap<String, (u64, u64)>,
+        sweep: bool,
+    ) {
+        if redlist_cursor > dr.redlist_cursor {
+            dr.redlist_cursor = redlist_cursor;
+        }
+
+        let watched = self.redlist_events.receiver_count() > 0;
+        if sweep {
+            dr.redlist.retain(|k, v| {
+                if *v > now {
+                    return true;
+                }
+                if watched {
+                    self.send_event("expire", k, *v);
+                }
+                false
+            });
+            dr.redlist_patterns.retain(now);
+            dr.redrules.retain(|_, v| v.1 > now);
+        }
+        for (k, v) in redlist {
+            if v > now {
+                dr.redlist_patterns.insert(&k, v);
+                if watched && dr.redlist.get(&k) != Some(&v) {
+                    self.send_event("add", &k, v);
+                }
+                dr.redlist.insert(k, v);
+            }
+        }
+
+        for (k, v) in redrules {
+            if v.1 > now {
+                dr.redrules.insert(k, v);
+            }
+        }
+    }
+}
+
+// Namespaces holds the rules of the main namespace and the extra namespaces
+// served by this instance.
+pub struct Namespaces {
+    main: String,
+    rules: HashMap<String, RedRules>,
+    sync_trigger: Notify,
+}
+
+impl Namespaces {
+    pub fn new(cfg: &conf::Conf) -> Self {
+        let mut rules = HashMap::from([(
+            cfg.namespace.clone(),
+            RedRules::new(&cfg.namespace, &cfg.rules).with_redlist_check(cfg.job.redlist_check),
+        )]);
+        for (ns, namespace) in &cfg.namespaces {
+            let redrules =
+                RedRules::new(ns, &namespace.rules).with_redlist_check(cfg.job.redlist_check);
+            rules.insert(ns.clone(), redrules);
+        }
+        Namespaces {
+            main: cfg.namespace.clone(),
+            rules,
+            sync_trigger: Notify::new(),
+        }
+    }
+
+    // trigger_sync wakes up the sync job to run now instead of waiting for the
+    // interval, the triggers before the run are coalesced.
+    pub fn trigger_sync(&self) {
+        self.sync_trigger.notify_one();
+    }
+
+    // get returns the rules of the namespace, the main namespace if empty.
+    pub fn get(&self, ns: &str) -> Option<&RedRules> {
+        if ns.is_empty() {
+            self.rules.get(&self.main)
+        } else {
+            self.rules.get(ns)
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RedRules> {
+        self.rules.values()
+    }
+
+    // reload swaps the static rules of the known namespaces, added or removed
+    // namespaces take effect after restart.
+    pub fn reload(&self, cfg: &conf::Conf) {
+        if cfg.namespace != self.main {
+            log::warn!("namespace changed, it will take effect after restart");
+        }
+        for (ns, redrules) in &self.rules {
+            if *ns == self.main {
+                redrules.reload(&cfg.rules);
+            } else if let Some(namespace) = cfg.namespaces.get(ns) {
+                redrules.reload(&namespace.rules);
+            } else {
+                log::warn!(
+                    "namespace {} removed, it will take effect after restart",
+                    ns
+                );
+            }
+        }
+        for ns in cfg.namespaces.keys() {
+            if !self.rules.contains_key(ns) {
+                log::warn!("namespace {} added, it will take effect after restart", ns);
+            }
+        }
+    }
+}
+
+// PathPattern is a compiled path with wildcard segments split by '/':
+// "*" or ":param" matches exactly one segment, "**" matches the rest segments.
+#[derive(PartialEq, Eq, Debug)]
+pub struct PathPattern(Vec<Segment>);
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Segment {
+    Literal(String),
+    Any,
+    Rest,
+}
+
+impl PathPattern {
+    // parse returns None if the path has no wildcard segment.
+    pub fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<Segment> = path
+            .split('/')
+            .map(|s| match s {
+                "**" => Segment::Rest,
+                "*" => Segment::Any,
+                _ if s.len() > 1 && s.starts_with(':') => Segment::Any,
+                _ => Segment::Literal(s.to_string()),
+            })
+            .collect();
+
+        if segments.iter().all(|s| matches!(s, Segment::Literal(_))) {
+            return None;
+        }
+        Some(PathPattern(segments))
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let mut parts = path.split('/');
+        for segment in &self.0 {
+            match segment {
+                Segment::Rest => return true,
+                Segment::Any => match parts.next() {
+                    Some(p) if !p.is_empty() => {}
+                    _ => return false,
+                },
+                Segment::Literal(s) => match parts.next() {
+                    Some(p) if p == s => {}
+                    _ => return false,
+                },
+            }
+        }
+        parts.next().is_none()
+    }
+
+    fn literals(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|s| matches!(s, Segment::Literal(_)))
+            .count()
+    }
+
+    fn has_rest(&self) -> bool {
+        self.0.iter().any(|s| matches!(s, Segment::Rest))
+    }
+}
+
+// more literal segments are more specific, "**" is less specific.
+impl Ord for PathPattern {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.literals()
+            .cmp(&other.literals())
+            .then_with(|| other.has_rest().cmp(&self.has_rest()))
+            .then_with(|| self.0.len().cmp(&other.0.len()))
+            .then_with(|| other.0.cmp(&self.0))
+    }
+}
+
+impl PartialOrd for PathPattern {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// fnv1a is a stable 64-bit FNV-1a hash over the given parts, separated by ':'.
+pub fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            hash ^= b':' as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        for b in part.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+// (quantity, max count per period, period with millisecond, max burst, burst
+// period with millisecond)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LimitArgs(pub u64, pub u64, pub u64, pub u64, pub u64);
+
+impl LimitArgs {
+    pub fn new(quantity: u64, others: &[u64]) -> Self {
+        let mut args = LimitArgs(quantity, 0, 0, 0, 0);
+        match others.len() {
+            2 => {
+                args.1 = others[0];
+                args.2 = others[1];
+            }
+            3 => {
+                args.1 = others[0];
+                args.2 = others[1];
+                args.3 = others[2];
+            }
+            4 => {
+                args.1 = others[0];
+                args.2 = others[1];
+                args.3 = others[2];
+                args.4 = others[3];
+            }
+            _ => {}
+        }
+        args
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    // validate returns the first reason the args can't be evaluated by the
+    // limiting functions.
+    pub fn validate(&self) -> Result<(), LimitError> {
+        if self.0 == 0 || self.0 > self.1 {
+            return Err(LimitError::Quantity {
+                quantity: self.0,
+                max_count: self.1,
+            });
+        }
+        if self.2 == 0 || self.2 > 60 * 1000 {
+            return Err(LimitError::Period(self.2));
+        }
+        if self.3 > 0 && self.0 > self.3 {
+            return Err(LimitError::Burst {
+                quantity: self.0,
+                max_burst: self.3,
+            });
+        }
+        if self.4 > self.2 {
+            return Err(LimitError::BurstPeriod {
+                burst_period: self.4,
+                period: self.2,
+            });
+        }
+        Ok(())
+    }
+}
+
+// LimitError is why the limiting args resolved from the rules are invalid, it
+// is a bug of the rules rather than of the request. Scope is the exception, the
+// scope of the request is not a valid name, see validate_scope.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LimitError {
+    Scope,
+    Quantity { quantity: u64, max_count: u64 },
+    Period(u64),
+    Burst { quantity: u64, max_burst: u64 },
+    BurstPeriod { burst_period: u64, period: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::Scope => write!(f, "scope should not contain ':' or '#'"),
+            LimitError::Quantity {
+                quantity,
+                max_count,
+            } => write!(
+                f,
+                "quantity {} should be in [1, max count {}]",
+                quantity, max_count
+            ),
+            LimitError::Period(period) => write!(
+                f,
+                "period should be in [1, 60000] milliseconds, got {}",
+                period
+            ),
+            LimitError::Burst {
+                quantity,
+                max_burst,
+            } => write!(f, "quantity {} should <= max burst {}", quantity, max_burst),
+            LimitError::BurstPeriod {
+                burst_period,
+                period,
+            } => write!(
+                f,
+                "burst period {} should <= period {}",
+                burst_period, period
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+// validate_scope checks the scope of a limiting request, see
+// conf::is_valid_name.
+pub fn validate_scope(scope: &str) -> Result<(), LimitError> {
+    if conf::is_valid_name(scope) {
+        Ok(())
+    } else {
+        Err(LimitError::Scope)
+    }
+}
+
+// InputError is why the entries of a redlist or redrules mutation are rejected,
+// with the error of every invalid entry by its id or path.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct InputError {
+    pub message: String,
+    pub entries: BTreeMap<String, String>,
+}
+
+impl InputError {
+    fn new(message: &str) -> Self {
+        InputError {
+            message: message.to_string(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn entries(message: &str, entries: BTreeMap<String, String>) -> Result<(), Self> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        Err(InputError {
+            message: message.to_string(),
+            entries,
+        })
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for (i, (key, err)) in self.entries.iter().enumerate() {
+            write!(f, "{}{:?}: {}", if i == 0 { ": " } else { ", " }, key, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InputError {}
+
+// validate_redlist checks the redlist entries before they are added, see
+// check_redlist_entry.
+pub fn validate_redlist<'a>(
+    cfg: &conf::Inputs,
+    list: impl ExactSizeIterator<Item = (&'a String, u64)>,
+) -> Result<(), InputError> {
+    check_entries(cfg, list.len())?;
+    let mut entries = BTreeMap::new();
+    for (id, ttl) in list {
+        if let Some(err) = check_redlist_entry(cfg, id, ttl) {
+            entries.insert(id.clone(), err);
+        }
+    }
+    InputError::entries("invalid redlist", entries)
+}
+
+// check_redlist_entry returns why a redlist entry is invalid: an empty id, a
+// malformed pattern or the ttl out of the bounds.
+pub fn check_redlist_entry(cfg: &conf::Inputs, id: &str, ttl: u64) -> Option<String> {
+    check_redlist_id(id).or_else(|| check_ttl(cfg, ttl))
+}
+
+pub(crate) fn check_redlist_id(id: &str) -> Option<String> {
+    if id.is_empty() {
+        return Some("empty id".to_string());
+    }
+    redlist::check_pattern(id)
+}
+
+// check_hashed_redlist_id returns why a redlist entry is rejected when the ids
+// are hashed (security.id_hash_key is set): the id prefix and CIDR patterns
+// match the ids in clear text, they would never match a hashed id.
+pub fn check_hashed_redlist_id(id: &str) -> Option<String> {
+    if redlist::is_pattern(id) {
+        return Some("patterns don't match the hashed ids".to_string());
+    }
+    None
+}
+
+// validate_hashed_redlist checks the redlist entries before they are hashed,
+// see check_hashed_redlist_id.
+pub fn validate_hashed_redlist<'a>(
+    ids: impl Iterator<Item = &'a String>,
+) -> Result<(), InputError> {
+    let entries = ids
+        .filter_map(|id| check_hashed_redlist_id(id).map(|err| (id.clone(), err)))
+        .collect();
+    InputError::entries("invalid redlist", entries)
+}
+
+fn check_entries(cfg: &conf::Inputs, len: usize) -> Result<(), InputError> {
+    if len > cfg.max_entries {
+        return Err(InputError::new(&format!(
+            "too many entries: {} > {}",
+            len, cfg.max_entries
+        )));
+    }
+    Ok(())
+}
+
+fn check_ttl(cfg: &conf::Inputs, ttl: u64) -> Option<String> {
+    if ttl < cfg.min_ttl || ttl > cfg.max_ttl {
+        return Some(format!(
+            "ttl {} should be in [{}, {}] milliseconds",
+            ttl, cfg.min_ttl, cfg.max_ttl
+        ));
+    }
+    None
+}
+
+// Limits resolved for a limiting request: the primary args from "limit" and the
+// extra args from "limits" that are evaluated together atomically.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Limits {
+    pub args: LimitArgs,
+    pub extra: Vec<LimitArgs>,
+    pub composite: Composite,
+    pub algorithm: Algorithm,
+    pub approximate: bool,
+    pub lease: u64,
+}
+
+impl Limits {
+    pub fn new(quantity: u64, limit: &[u64], limits: &[Vec<u64>], composite: Composite) -> Self {
+        Limits {
+            args: LimitArgs::new(quantity, limit),
+            extra: limits.iter().map(|l| LimitArgs::new(quantity, l)).collect(),
+            composite,
+            algorithm: Algorithm::default(),
+            approximate: false,
+            lease: 0,
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_approximate(mut self, approximate: bool) -> Self {
+        self.approximate = approximate;
+        self
+    }
+
+    pub fn with_lease(mut self, lease: u64) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    // validate checks the args and the extra args.
+    pub fn validate(&self) -> Result<(), LimitError> {
+        self.args.validate()?;
+        self.extra.iter().try_for_each(|args| args.validate())
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+// LimitResult.0: request count;
+// LimitResult.1: 0: not limited, > 0: limited, milliseconds to wait;
+pub struct LimitResult(pub u64, pub u64);
+
+pub async fn limiting(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    limiting_key: &str,
+    args: LimitArgs,
+    algorithm: Algorithm,
+) -> Result<LimitResult> {
+    args.validate()?;
+
+    let mut cmd = fcall(pool, algorithm.as_str(), &[limiting_key])
+        .arg(args.0)
+        .arg(args.1)
+        .arg(args.2);
+    if args.3 > 0 {
+        cmd = cmd.arg(args.3);
+    }
+    if args.4 > 0 {
+        cmd = cmd.arg(args.4);
+    }
+
+    let data = redis::send_limiting(pool, cmd, retry).await?;
+    if let Ok(rt) = data.to::<(u64, u64)>() {
+        return Ok(LimitResult(rt.0, rt.1));
+    }
+
+    Ok(LimitResult(0, 0))
+}
+
+// limiting_lease leases up to size of the remaining count of the fixed window,
+// returns (leased count, ttl of the window, count in the window).
+pub async fn limiting_lease(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    limiting_key: &str,
+    size: u64,
+    max_count: u64,
+    period: u64,
+) -> Result<(u64, u64, u64)> {
+    let cmd = fcall(pool, "limiting_lease", &[limiting_key])
+        .arg(size)
+        .arg(max_count)
+        .arg(period);
+    let data = redis::send_limiting(pool, cmd, retry).await?;
+    Ok(data.to::<(u64, u64, u64)>()?)
+}
+
+// limiting_redlist limits with the redlist of the namespace checked in Redis:
+// an id in the redlist is limited by the floor args instead of the limits, even
+// if not synced yet. Only the exact id is checked, the prefix and CIDR patterns
+// are enforced by the synced redlist. Returns the max count of the applied
+// limit with its result, the extra limits are not evaluated.
+pub async fn limiting_redlist(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    ns: &NS,
+    limiting_key: &str,
+    id: &str,
+    limits: &Limits,
+    floor: LimitArgs,
+) -> Result<(u64, LimitResult)> {
+    let args = limits.args;
+    args.validate()?;
+    if !floor.is_valid() {
+        let rt = limiting(pool, retry, limiting_key, args, limits.algorithm).await?;
+        return Ok((args.1, rt));
+    }
+
+    // the burst period defaults to 1000 ms as the limiting functions do
+    let burst_period = |args: LimitArgs| if args.4 > 0 { args.4 } else { 1000 };
+    let cmd = fcall(pool, "limiting_redlist", &[limiting_key, ns.as_str()])
+        .arg(id)
+        .arg(limits.algorithm.as_str())
+        .arg(args.0)
+        .arg(args.1)
+        .arg(args.2)
+        .arg(args.3)
+        .arg(burst_period(args))
+        .arg(floor.1)
+        .arg(floor.2)
+        .arg(floor.3)
+        .arg(burst_period(floor));
+    let data = redis::send_limiting(pool, cmd, retry).await?;
+    match data.to::<(u64, u64, u64)>() {
+        Ok((count, wait, 1)) => Ok((floor.1, LimitResult(count, wait))),
+        Ok((count, wait, _)) => Ok((args.1, LimitResult(count, wait))),
+        Err(_) => Ok((args.1, LimitResult(0, 0))),
+    }
+}
+
+// limiting_composite evaluates all limits atomically, returns the max count of
+// the reported limit with its result: the most restrictive one for "and", the
+// most permissive one for "or".
+pub async fn limiting_composite(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    limiting_key: &str,
+    limits: &Limits,
+) -> Result<(u64, LimitResult)> {
+    let limit = limits.args.1;
+    limits.args.validate()?;
+
+    let extra: Vec<LimitArgs> = limits
+        .extra
+        .iter()
+        .filter(|a| a.is_valid())
+        .copied()
+        .collect();
+    if extra.is_empty() {
+        let rt = limiting(pool, retry, limiting_key, limits.args, limits.algorithm).await?;
+        return Ok((limit, rt));
+    }
+
+    let mut all = vec![limits.args];
+    all.extend(extra);
+    let keys: Vec<String> = (1..all.len())
+        .map(|i| NS::extra_key(limiting_key, i))
+        .collect();
+    let mut keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+    keys.insert(0, limiting_key);
+    let mut cmd = fcall(pool, "limiting_composite", &keys)
+        .arg(all[0].0)
+        .arg(limits.composite.as_str());
+    for args in &all {
+        cmd = cmd.arg(args.1).arg(args.2).arg(args.3).arg(args.4);
+    }
+
+    let data = redis::send_limiting(pool, cmd, retry).await?;
+    match data.to::<Vec<u64>>() {
+        Ok(rt) if rt.len() == all.len() * 2 => {
+            let rts: Vec<LimitResult> = rt.chunks(2).map(|c| LimitResult(c[0], c[1])).collect();
+            Ok(composite_result(&all, rts, limits.composite))
+        }
+        _ => Ok((limit, LimitResult(0, 0))),
+    }
+}
+
+pub fn composite_result(
+    all: &[LimitArgs],
+    rts: Vec<LimitResult>,
+    composite: Composite,
+) -> (u64, LimitResult) {
+    let allowed = rts.iter().filter(|rt| rt.1 == 0).count();
+    let pass = match composite {
+        Composite::And => allowed == rts.len(),
+        Composite::Or => allowed > 0,
+    };
+
+    let pick = rts
+        .iter()
+        .enumerate()
+        .filter(|(_, rt)| !pass || rt.1 == 0)
+        .reduce(|a, b| {
+            let better = match (composite, pass) {
+                // the least remaining
+                (Composite::And, true) => {
+                    all[b.0].1.saturating_sub(b.1 .0) < all[a.0].1.saturating_sub(a.1 .0)
+                }
+                // the longest wait
+                (Composite::And, false) => b.1 .1 > a.1 .1,
+                // the most remaining
+                (Composite::Or, true) => {
+                    all[b.0].1.saturating_sub(b.1 .0) > all[a.0].1.saturating_sub(a.1 .0)
+                }
+                // the shortest wait
+                (Composite::Or, false) => b.1 .1 < a.1 .1,
+            };
+            if better {
+                b
+            } else {
+                a
+            }
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+
+    let mut rt = LimitResult(rts[pick].0, rts[pick].1);
+    if !pass && rt.1 == 0 {
+        rt.1 = 1;
+    }
+    (all[pick].1, rt)
+}
+
+pub async fn redrules_add(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    ns: &str,
+    scope: &str,
+    rules: &HashMap<String, (u64, u64)>,
+) -> Result<()> {
+    for (k, v) in rules {
+        let cmd = fcall(pool, "redrules_add", &[ns])
+            .arg(scope)
+            .arg(k)
+            .arg(v.0)
+            .arg(v.1);
+        redis::send(pool, cmd, retry).await?;
+    }
+    Ok(())
+}
+
+pub async fn redlist_add(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    ns: &str,
+    list: &HashMap<String, u64>,
+) -> Result<()> {
+    if !list.is_empty() {
+        let mut cmd = fcall(pool, "redlist_add", &[ns]);
+
+        for (k, v) in list {
+            cmd = cmd.arg(k).arg(*v);
+        }
+
+        redis::send(pool, cmd, retry).await?;
+    }
+    Ok(())
+}
+
+// RedlistMeta tells why an id is in the redlist. It is kept until the entry
+// expires (swept by redlist_add) or is erased.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RedlistMeta {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub source: String,
+}
+
+// redlist_add_with_meta adds the ids to the redlist with their metadata, the
+// metadata of an id is replaced.
+pub async fn redlist_add_with_meta(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    ns: &NS,
+    list: &HashMap<String, u64>,
+    meta: &HashMap<String, RedlistMeta>,
+) -> Result<()> {
+    if !meta.is_empty() {
+        let mut cmd = resp::cmd("HSET").arg(ns.redlist_meta_key());
+        for (id, m) in meta {
+            cmd = cmd.arg(NS::redlist_key(id)).arg(serde_json::to_string(m)?);
+        }
+        redis::send(pool, cmd, retry).await?;
+    }
+    redlist_add(pool, retry, ns.as_str(), list).await
+}
+
+// redlist_meta reads the metadata of the redlist, including the expired
+// entries not swept yet.
+pub async fn redlist_meta(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    ns: &NS,
+) -> Result<HashMap<String, RedlistMeta>> {
+    let cmd = resp::cmd("HGETALL").arg(ns.redlist_meta_key());
+    let data = redis::send(pool, cmd, retry)
+        .await?
+        .to::<HashMap<String, String>>()?;
+    Ok(data
+        .into_iter()
+        .filter_map(|(id, v)| Some((id, serde_json::from_str(&v).ok()?)))
+        .collect())
+}
+
+const ERASE_SCAN_COUNT: u64 = 1000;
+
+// erase_id deletes the limiting keys of the id in all scopes of the namespace
+// on all shards, and the id from the redlist. It publishes the erased id so the
+// subscribed instances scrub their caches, see init_redlimit_subscriber. It
+// returns the number of the deleted keys and redlist entries.
+pub async fn erase_id(shards: &Shards, retry: &conf::Retry, ns: &NS, id: &str) -> Result<u64> {
+    let pattern = ns.id_keys_pattern(id);
+    let mut deleted = 0;
+    for pool in shards.pools() {
+        let mut cursor = "0".to_string();
+        loop {
+            let cmd = resp::cmd("SCAN")
+                .arg(cursor.as_str())
+                .arg("MATCH")
+                .arg(pattern.as_str())
+                .arg("COUNT")
+                .arg(ERASE_SCAN_COUNT);
+            let (next, keys) = redis::send(pool, cmd, retry)
+                .await?
+                .to::<(String, Vec<String>)>()?;
+            let keys: Vec<String> = keys.into_iter().filter(|k| ns.is_id_key(id, k)).collect();
+            if !keys.is_empty() {
+                deleted += redis::send(pool, resp::cmd("UNLINK").arg(keys), retry)
+                    .await?
+                    .to::<u64>()?;
+            }
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+    }
+
+    // the redlist is stored on the main Redis server, see redlist_add
+    let main = &shards.pools()[0];
+    let cmd = resp::cmd("ZREM")
+        .arg(format!("{}:LC", ns.as_str()))
+        .arg(NS::redlist_key(id));
+    deleted += redis::send(main, cmd, retry).await?.to::<u64>()?;
+    let cmd = resp::cmd("ZREM")
+        .arg(format!("{}:LT", ns.as_str()))
+        .arg(NS::redlist_key(id));
+    redis::send(main, cmd, retry).await?;
+    let cmd = resp::cmd("HDEL")
+        .arg(ns.redlist_meta_key())
+        .arg(NS::redlist_key(id));
+    redis::send(main, cmd, retry).await?;
+    let cmd = resp::cmd("PUBLISH")
+        .arg(ns.channel())
+        .arg(serde_json::json!({ "erased": [id] }).to_string());
+    redis::send(main, cmd, retry).await?;
+    Ok(deleted)
+}
+
+fn glob_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// init_redlimit_fn loads the redlimit library to the server of the pool, or the
+// redlimit script if Redis functions are not supported (Redis 6 or forks), then
+// the pool calls the library with EVALSHA instead of FCALL.
+pub async fn init_redlimit_fn(pool: &RedisPool) -> anyhow::Result<()> {
+    init_fn(pool, false).await
+}
+
+// init_replica_fn prepares the pool of a read replica as init_redlimit_fn does,
+// but the library is replicated from the main server, only the script is loaded.
+pub async fn init_replica_fn(pool: &RedisPool) -> anyhow::Result<()> {
+    init_fn(pool, true).await
+}
+
+async fn init_fn(pool: &RedisPool, replica: bool) -> anyhow::Result<()> {
+    let cli = redis::get(pool).await?;
+    if pool.script_sha().is_none() {
+        let info = cli
+            .send(resp::cmd("INFO").arg("server"), None)
+            .await?
+            .to::<String>()?;
+        if redis_major_version(&info).unwrap_or(0) >= 7 {
+            if replica {
+                return Ok(());
+            }
+            match load_redlimit_fn(&cli).await {
+                Ok(_) => return Ok(()),
+                Err(err) if !err.to_string().contains("unknown command") => return Err(err),
+                Err(_) => {}
+            }
+        }
+    }
+
+    let cmd = resp::cmd("SCRIPT").arg("LOAD").arg(redlimit_script());
+    let sha = cli.send(cmd, None).await?.to::<String>()?;
+    if !pool.set_script_sha(&sha) {
+        return Err(Error::msg(format!("unexpected script sha: {}", sha)));
+    }
+    log::info!("redis functions not supported, use EVALSHA {}", sha);
+    Ok(())
+}
+
+// load_redlimit_fn loads the library, or replaces the resident one older than
+// the embedded one. A newer resident one, loaded by the upgraded instances of a
+// rolling upgrade, is kept.
+async fn load_redlimit_fn(cli: &Client) -> anyhow::Result<()> {
+    let version = lua_version(redlimit_lua::REDLIMIT);
+    let cmd = resp::cmd("FUNCTION")
+        .arg("LIST")
+        .arg("LIBRARYNAME")
+        .arg("redlimit")
+        .arg("WITHCODE");
+    let resident = cli
+        .send(cmd, None)
+        .await?
+        .to::<Vec<LibraryInfo>>()?
+        .into_iter()
+        .find(|lib| lib.library_name == "redlimit")
+        .map(|lib| lua_version(lib.library_code.as_deref().unwrap_or("")));
+
+    let mut cmd = resp::cmd("FUNCTION").arg("LOAD");
+    match resident {
+        Some(v) if v >= version => return Ok(()),
+        Some(v) => {
+            log::warn!("replace redlimit library version {} with {}", v, version);
+            cmd = cmd.arg("REPLACE");
+        }
+        None => {}
+    }
+    let data = cli.send(cmd.arg(redlimit_lua::REDLIMIT), None).await?;
+    if data.is_error() {
+        let err = data.to_string();
+        // loaded by another instance at the same time
+        if !err.contains("already exists") {
+            return Err(Error::msg(err));
+        }
+    }
+    Ok(())
+}
+
+// lua_version reads the "-- version: <n>" line of the library, 0 if missing.
+fn lua_version(code: &str) -> u64 {
+    code.lines()
+        .take(3)
+        .find_map(|line| line.strip_prefix("-- version:"))
+        .and_then(|v| v.split_whitespace().next())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+// is_fn_missing checks if an error is caused by the library not being loaded.
+pub fn is_fn_missing(err: &str) -> bool {
+    err.contains("Function not found") || err.contains("NOSCRIPT")
+}
+
+fn redis_major_version(info: &str) -> Option<u64> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("redis_version:"))
+        .and_then(|v| v.trim().split('.').next())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+// redlimit_script converts the redlimit library to a script that dispatches by
+// the first argument, for EVALSHA.
+fn redlimit_script() -> String {
+    let lib = redlimit_lua::REDLIMIT
+        .split_once('\n')
+        .map_or("", |(_, lib)| lib)
+        .replace("redis.register_function", "register_function");
+    format!(
+        r#"local fns = {{}}
+local function register_function(name, callback)
+  if type(name) == 'table' then
+    callback = name.callback
+    name = name.function_name
+  end
+  fns[name] = callback
+end
+{}
+local fn = fns[table.remove(ARGV, 1)]
+if not fn then
+  return redis.error_reply('ERR Function not found')
+end
+return fn(KEYS, ARGV)
+"#,
+        lib
+    )
+}
+
+// fcall builds a FCALL command with keys, or an EVALSHA command if the server
+// of the pool doesn't support Redis functions; the caller appends the args.
+fn fcall(pool: &RedisPool, name: &str, keys: &[&str]) -> resp::Command {
+    call_cmd(pool.script_sha(), "FCALL", name, keys)
+}
+
+// FnClient is a connection with the SHA1 of the redlimit script if its server
+// doesn't support Redis functions, see RedisPool::script_sha.
+#[derive(Clone)]
+pub(crate) struct FnClient {
+    pub cli: Client,
+    sha: Option<String>,
+}
+
+impl FnClient {
+    pub(crate) async fn get(pool: &RedisPool) -> anyhow::Result<Self> {
+        Ok(FnClient {
+            cli: redis::get(pool).await?.clone(),
+            sha: pool.script_sha().map(|sha| sha.to_string()),
+        })
+    }
+
+    pub(crate) fn fcall(&self, name: &str, keys: &[&str]) -> resp::Command {
+        call_cmd(self.sha.as_deref(), "FCALL", name, keys)
+    }
+
+    pub(crate) fn fcall_ro(&self, name: &str, keys: &[&str]) -> resp::Command {
+        call_cmd(self.sha.as_deref(), "FCALL_RO", name, keys)
+    }
+}
+
+fn call_cmd(sha: Option<&str>, command: &'static str, name: &str, keys: &[&str]) -> resp::Command {
+    let mut cmd = match sha {
+        Some(sha) => resp::cmd("EVALSHA").arg(sha),
+        None => resp::cmd(command).arg(name),
+    }
+    .arg(keys.len());
+    for key in keys {
+        cmd = cmd.arg(*key);
+    }
+    if sha.is_some() {
+        cmd = cmd.arg(name);
+    }
+    cmd
+}
+
+pub fn init_redlimit_sync(
+    pool: Arc<RedisPool>,
+    replica: Arc<RedisPool>,
+    shards: Arc<Shards>,
+    namespaces: Arc<Namespaces>,
+    job: &conf::Job,
+    central: bool,
+) -> (JoinHandle<()>, CancellationToken) {
+    let cancel_redrules_sync = CancellationToken::new();
+    (
+        tokio::spawn(spawn_redlimit_sync(
+            pool,
+            replica,
+            shards,
+            namespaces,
+            cancel_redrules_sync.clone(),
+            job.clone(),
+            central,
+        )),
+        cancel_redrules_sync,
+    )
+}
+
+async fn spawn_redlimit_sync(
+    pool: Arc<RedisPool>,
+    replica: Arc<RedisPool>,
+    shards: Arc<Shards>,
+    namespaces: Arc<Namespaces>,
+    stop_signal: CancellationToken,
+    job: conf::Job,
+    central: bool,
+) {
+    let scan = RedlistScan::from(&job);
+    let lease = SweepLease::new(job.sweep_lease);
+    let mut failures: u32 = 0;
+    let mut fn_checked = Instant::now();
+    loop {
+        let delay = sync_delay(&job, failures, RandomState::new().build_hasher().finish());
+        tokio::select! {
+            _ = stop_signal.cancelled() => {
+                log::info!("gracefully shutting down redlimit sync job");
+                break;
+            }
+            _ = sleep(delay) => {}
+            _ = namespaces.sync_trigger.notified() => {
+                log::info!("redlimit sync job triggered");
+            }
+        };
+
+        let mut synced = true;
+        for redrules in namespaces.iter() {
+            let mut span = telemetry::tracer().start("redlimit_sync_job");
+            span.set_attribute(KeyValue::new("ns", redrules.ns.as_str().to_string()));
+            let cx = Context::current_with_span(span);
+            let start = Instant::now();
+            let now = unix_ms();
+            // a full resync recovers the entries missed by a skipped or drifted
+            // cursor, it loads the whole redlist regardless of the limits
+            let full = job.full_sync > 0
+                && now >= redrules.sync_status().last_full_sync + job.full_sync * 1000;
+            let rt = if chaos::sync_failure() {
+                Err(Error::msg("chaos: sync failure injected"))
+            } else {
+                redlimit_sync_job(&pool, &replica, redrules, &scan, &lease, full, central)
+                    .with_context(cx.clone())
+                    .await
+            };
+            redrules.sync_done(now, start.elapsed(), &rt);
+            if let Err(err) = &rt {
+                cx.span().set_status(Status::error(err.to_string()));
+            }
+            cx.span().end();
+            if let Err(err) = rt {
+                synced = false;
+                log::error!("redlimit_sync_job error: {:?}", err);
+
+                if is_fn_missing(&err.to_string()) {
+                    shards.mark_fn_missing();
+                }
+            }
+        }
+
+        if synced {
+            failures = 0;
+            stats::STATS.record_sync(unix_ms());
+        } else {
+            failures = failures.saturating_add(1);
+        }
+
+        // auto load function, and upgrade the outdated one periodically, e.g.
+        // loaded by the old instances of a rolling upgrade
+        let fn_missing = shards.take_fn_missing();
+        if fn_missing || fn_checked.elapsed() >= FN_CHECK_INTERVAL {
+            fn_checked = Instant::now();
+            for pool in shards.pools() {
+                match init_redlimit_fn(pool).await {
+                    Ok(_) => {
+                        if fn_missing {
+                            log::warn!("init_redlimit_fn success");
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("init_redlimit_fn error: {:?}", e);
+                        report::error(&format!("init_redlimit_fn error: {}", e));
+                    }
+                }
+            }
+            if !Arc::ptr_eq(&pool, &replica) {
+                if let Err(e) = init_replica_fn(&replica).await {
+                    log::error!("init_replica_fn error: {:?}", e);
+                    report::error(&format!("init_replica_fn error: {}", e));
+                }
+            }
+        }
+    }
+}
+
+// the interval to check the version of the resident library
+const FN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// sync_delay is the interval doubled by the consecutive failed runs up to the
+// max backoff, plus a random jitter so that the instances don't sync in
+// lockstep.
+fn sync_delay(job: &conf::Job, failures: u32, random: u64) -> Duration {
+    let interval = job.interval * 1000;
+    let mut delay = interval.saturating_mul(1 << failures.min(16));
+    if failures > 0 {
+        delay = delay.min((job.max_backoff * 1000).max(interval));
+    }
+    if job.jitter > 0 {
+        delay += random % (job.jitter + 1);
+    }
+    Duration::from_millis(delay)
+}
+
+// the delay before resubscribing after an error
+const SUBSCRIBE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// RedlimitChange is the message published by redlist_add and redrules_add on
+// the channel of the namespace.
+#[derive(Deserialize, Default)]
+struct RedlimitChange {
+    #[serde(default)]
+    redlist: HashMap<String, u64>,
+    #[serde(default)]
+    redrules: Vec<RedRuleEntry>,
+    // the ids erased by erase_id
+    #[serde(default)]
+    erased: Vec<String>,
+}
+
+type OnErase = Arc<dyn Fn(&NS, &str) + Send + Sync>;
+
+// init_redlimit_subscriber applies the published changes of the namespaces at
+// once with a dedicated client, the sync job catches up on the missed ones. The
+// erased ids are removed from the redlist cache, and on_erase scrubs the other
+// caches of the instance.
+pub fn init_redlimit_subscriber(
+    config: Config,
+    namespaces: Arc<Namespaces>,
+    on_erase: impl Fn(&NS, &str) + Send + Sync + 'static,
+) -> (JoinHandle<()>, CancellationToken) {
+    let cancel_subscriber = CancellationToken::new();
+    (
+        tokio::spawn(spawn_redlimit_subscriber(
+            config,
+            namespaces,
+            Arc::new(on_erase),
+            cancel_subscriber.clone(),
+        )),
+        cancel_subscriber,
+    )
+}
+
+async fn spawn_redlimit_subscriber(
+    config: Config,
+    namespaces: Arc<Namespaces>,
+    on_erase: OnErase,
+    stop_signal: CancellationToken,
+) {
+    loop {
+        let rt = tokio::select! {
+            _ = stop_signal.cancelled() => return,
+            rt = redlimit_subscribe(&config, &namespaces, &on_erase) => rt,
+        };
+        if let Err(err) = rt {
+            log::warn!(target: "sync", "redlimit subscriber error: {}", err);
+        }
+        tokio::select! {
+            _ = stop_signal.cancelled() => return,
+            _ = sleep(SUBSCRIBE_RETRY_DELAY) => {}
+        };
+    }
+}
+
+async fn redlimit_subscribe(
+    config: &Config,
+    namespaces: &Namespaces,
+    on_erase: &OnErase,
+) -> anyhow::Result<()> {
+    let client = Client::connect(config.clone()).await?;
+    let channels: Vec<String> = namespaces.iter().map(|r| r.ns.channel()).collect();
+    let mut stream = client.subscribe(channels).await?;
+    log::info!(target: "sync", "redlimit subscriber start");
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        let channel = String::from_utf8_lossy(&msg.channel);
+        let redrules = match namespaces.iter().find(|r| r.ns.channel() == channel) {
+            Some(redrules) => redrules,
+            None => continue,
+        };
+        match serde_json::from_slice::<RedlimitChange>(&msg.payload) {
+            Ok(change) => {
+                for id in &change.erased {
+                    redrules.erase(id);
+                    on_erase(&redrules.ns, id);
+                }
+                redrules.apply_change(unix_ms(), change)
+            }
+            Err(err) => log::warn!(target: "sync", "invalid redlimit change: {}", err),
+        }
+    }
+    Err(Error::msg("subscription closed"))
+}
+
+pub fn init_rules_reload(
+    namespaces: Arc<Namespaces>,
+    file_name: String,
+) -> (JoinHandle<()>, CancellationToken) {
+    let cancel_rules_reload = CancellationToken::new();
+    (
+        tokio::spawn(spawn_rules_reload(
+            namespaces,
+            file_name,
+            cancel_rules_reload.clone(),
+        )),
+        cancel_rules_reload,
+    )
+}
+
+// spawn_rules_reload reloads the static rules from the config file on SIGHUP.
+async fn spawn_rules_reload(
+    namespaces: Arc<Namespaces>,
+    file_name: String,
+    stop_signal: CancellationToken,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            log::error!("listen SIGHUP error: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            _ = stop_signal.cancelled() => {
+                log::info!("gracefully shutting down rules reload job");
+                break;
+            }
+            _ = hangup.recv() => {}
+        };
+
+        match conf::Conf::from(&file_name) {
+            Ok(cfg) => {
+                namespaces.reload(&cfg);
+                log::info!(target: "reload",
+                    rules = cfg.rules.len(),
+                    namespaces = cfg.namespaces.len();
+                    "ok",
+                );
+            }
+            Err(err) => {
+                log::error!("reload config error: {}", err);
+            }
+        }
+    }
+}
+
+// initial_sync loads the whole redlist and redrules of the namespaces before
+// serving, so a restarted instance doesn't let the redlisted ids through in its
+// first sync window. The failed namespaces are retried until job.initial_sync
+// seconds, then the sync job goes on as usual.
+pub async fn initial_sync(
+    pool: &RedisPool,
+    replica: &RedisPool,
+    namespaces: &Namespaces,
+    job: &conf::Job,
+    central: bool,
+) -> anyhow::Result<()> {
+    let scan = RedlistScan::from(job);
+    let lease = SweepLease::new(job.sweep_lease);
+    let mut pending: Vec<&RedRules> = namespaces.iter().collect();
+    let sync = async {
+        loop {
+            let mut failed = Vec::new();
+            for redrules in pending.drain(..) {
+                let start = Instant::now();
+                let now = unix_ms();
+                let rt =
+                    redlimit_sync_job(pool, replica, redrules, &scan, &lease, true, central).await;
+                redrules.sync_done(now, start.elapsed(), &rt);
+                if let Err(err) = rt {
+                    log::warn!(target: "sync",
+                        "initial sync of {} error: {}", redrules.ns.as_str(), err);
+                    failed.push(redrules);
+                }
+            }
+            if failed.is_empty() {
+                stats::STATS.record_sync(unix_ms());
+                return;
+            }
+            pending = failed;
+            sleep(INITIAL_SYNC_RETRY_DELAY).await;
+        }
+    };
+
+    timeout(Duration::from_secs(job.initial_sync), sync)
+        .await
+        .map_err(|_| {
+            Error::msg(format!(
+                "initial sync not finished in {} seconds",
+                job.initial_sync
+            ))
+        })
+}
+
+// the delay before retrying the failed namespaces of the initial sync
+const INITIAL_SYNC_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// the owner of the sweep lease, unique per process
+static SWEEP_LEASE_OWNER: OnceCell<String> = OnceCell::new();
+
+// SweepLease elects one instance per namespace to sweep the stale redlist and
+// redrules, the others only load them. The lease is taken with SET NX and
+// renewed by its owner on every sync run, ttl 0 makes every instance sweep.
+struct SweepLease {
+    owner: String,
+    ttl: u64, // ms
+}
+
+impl SweepLease {
+    fn new(ttl_secs: u64) -> Self {
+        let owner = SWEEP_LEASE_OWNER.get_or_init(|| {
+            let random = RandomState::new().build_hasher().finish();
+            format!("{}:{:016x}", std::process::id(), random)
+        });
+        SweepLease {
+            owner: owner.clone(),
+            ttl: ttl_secs * 1000,
+        }
+    }
+
+    // acquire takes or renews the lease, true if this instance holds it.
+    async fn acquire(&self, redis: &Client, ns: &NS) -> anyhow::Result<bool> {
+        if self.ttl == 0 {
+            return Ok(true);
+        }
+        let key = ns.sweep_lease_key();
+        let cmd = resp::cmd("SET")
+            .arg(&key)
+            .arg(&self.owner)
+            .arg("NX")
+            .arg("PX")
+            .arg(self.ttl);
+        if redis::timed(redis, cmd)
+            .await?
+            .to::<Option<String>>()?
+            .is_some()
+        {
+            return Ok(true);
+        }
+        let owner = redis::timed(redis, resp::cmd("GET").arg(&key))
+            .await?
+            .to::<Option<String>>()?;
+        if owner.as_deref() != Some(self.owner.as_str()) {
+            return Ok(false);
+        }
+        // the lease may expire and be taken by another instance between GET and
+        // PEXPIRE, both sweep in this run then, which is harmless
+        let cmd = resp::cmd("PEXPIRE").arg(&key).arg(self.ttl);
+        redis::timed(redis, cmd).await?;
+        Ok(true)
+    }
+}
+
+async fn redlimit_sync_job(
+    pool: &RedisPool,
+    replica: &RedisPool,
+    redrules: &RedRules,
+    scan: &RedlistScan,
+    lease: &SweepLease,
+    full: bool,
+    central: bool,
+) -> anyhow::Result<SyncLoaded> {
+    let redis = FnClient::get(pool).await?;
+    let replica = FnClient::get(replica).await?;
+    let inow = Instant::now();
+    let now = unix_ms();
+    let cursor = redrules.dyn_rules.load().redlist_cursor;
+    let (cursor, scan) = if full {
+        (
+            0,
+            RedlistScan {
+                max_entries: 0,
+                time_budget: Duration::ZERO,
+                ..*scan
+            },
+        )
+    } else {
+        (cursor, *scan)
+    };
+
+    let sweeper = lease.acquire(&redis.cli, &redrules.ns).await?;
+    let sweep = sweeper.then(|| redis.clone());
+
+    let dyn_rules =
+        redrules_load(replica.clone(), sweep.clone(), redrules.ns.as_str(), now).await?;
+
+    let dyn_list = redlist_load(
+        replica.clone(),
+        sweep,
+        redrules.ns.as_str(),
+        now,
+        cursor,
+        &scan,
+    )
+    .await?;
+
+    let cursor = dyn_list.0;
+    let rules_len = dyn_rules.len();
+    let list_len = dyn_list.1.len();
+    // always updated to observe the expired redlist entries
+    if full {
+        redrules.dyn_resync(now, cursor, dyn_list.1, dyn_rules);
+    } else {
+        redrules.dyn_update(now, cursor, dyn_list.1, dyn_rules);
+    }
+
+    log::info!(target: "sync",
+        ns = redrules.ns.as_str(),
+        full = full,
+        sweeper = sweeper,
+        cursor = cursor,
+        redrules = rules_len,
+        redlist = list_len,
+        elapsed = inow.elapsed().as_millis() as u64;
+        "ok",
+    );
+
+    if central {
+        let cmd = resp::cmd("HGETALL").arg(redrules.ns.central_key());
+        let rules = replica
+            .cli
+            .send(cmd, None)
+            .await?
+            .to::<HashMap<String, String>>()?;
+        let rules_len = rules.len();
+        if redrules.central_update(rules)? {
+            log::info!(target: "central",
+                ns = redrules.ns.as_str(),
+                rules = rules_len;
+                "ok",
+            );
+        }
+    }
+
+    Ok(SyncLoaded {
+        cursor,
+        redrules: rules_len,
+        redlist: list_len,
+        full,
+        sweeper,
+    })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RedRuleEntry(pub String, pub String, pub u64, pub u64);
+
+// redrules_load reads redrules from the replica, and sweeps stale ones on the
+// sweeper (redis).
+async fn redrules_load(
+    replica: FnClient,
+    sweeper: Option<FnClient>,
+    ns: &str,
+    now: u64,
+) -> anyhow::Result<HashMap<String, (u64, u64)>> {
+    let redrules_cmd = replica.fcall_ro("redrules_all", &[ns]);
+
+    let data = redis::timed(&replica.cli, redrules_cmd)
+        .await?
+        .to::<Vec<String>>()?;
+    let mut rt: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut has_stale = false;
+    for s in data {
+        if let Ok(v) = serde_json::from_str::<RedRuleEntry>(&s) {
+            if v.3 > now {
+                rt.insert(NS::redrules_key(&v.0, &v.1), (v.2, v.3));
+            } else {
+                has_stale = true
+            }
+        }
+    }
+
+    if let (true, Some(redis)) = (has_stale, sweeper) {
+        let sweep_cmd = redis.fcall("redrules_add", &[ns]);
+        redis::timed(&redis.cli, sweep_cmd).await?;
+    }
+
+    Ok(rt)
+}
+
+const REDLIST_SCAN_COUNT: usize = 10000;
+
+// RedlistScan limits the redlist loading of a sync run, the next runs go on
+// from the cursor. 0 is unlimited.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RedlistScan {
+    pub count: usize, // entries per redlist_scan call
+    pub max_entries: usize,
+    pub time_budget: Duration,
+}
+
+impl Default for RedlistScan {
+    fn default() -> Self {
+        RedlistScan {
+            count: REDLIST_SCAN_COUNT,
+            max_entries: 0,
+            time_budget: Duration::ZERO,
+        }
+    }
+}
+
+impl From<&conf::Job> for RedlistScan {
+    fn from(job: &conf::Job) -> Self {
+        RedlistScan {
+            count: job.scan_count.max(1),
+            max_entries: job.max_entries,
+            time_budget: Duration::from_millis(job.time_budget),
+        }
+    }
+}
+
+// redlist_load scans redlist from the replica, and sweeps stale ones on the
+// sweeper (redis).
+pub(crate) async fn redlist_load(
+    replica: FnClient,
+    sweeper: Option<FnClient>,
+    ns: &str,
+    now: u64,
+    cursor: u64,
+    scan: &RedlistScan,
+) -> anyhow::Result<(u64, HashMap<String, u64>)> {
+    let start = Instant::now();
+    let mut cursor = cursor;
+    let mut has_stale = false;
+    let mut rt: HashMap<String, u64> = HashMap::new();
+
+    'next_cursor: loop {
+        let blacklist_cmd = replica
+            .fcall_ro("redlist_scan", &[ns])
+            .arg(cursor)
+            .arg(scan.count);
+
+        let data = redis::timed(&replica.cli, blacklist_cmd)
+            .await?
+            .to::<Vec<String>>()?;
+        // [cursor, member, ttl, member, ttl ...]
+        let has_next = data.len() > scan.count * 2;
+
+        let mut iter = data.into_iter();
+        match iter.next() {
+            Some(c) => {
+                let new_cursor = c.parse::<u64>()?;
+                if cursor == new_cursor {
+                    cursor += 1;
+                } else {
+                    cursor = new_cursor;
+                }
+            }
+            None => {
+                break;
+            }
+        }
+
+        loop {
+            if let Some(id) = iter.next() {
+                match iter.next() {
+                    Some(ttl) => {
+                        let ttl = ttl.parse::<u64>()?;
+                        if ttl > now {
+                            rt.insert(id, ttl);
+                        } else {
+                            has_stale = true;
+                        }
+                        continue;
+                    }
+                    None => {
+                        break 'next_cursor;
+                    }
+                }
+            }
+            break;
+        }
+
+        if !has_next {
+            break;
+        }
+        if (scan.max_entries > 0 && rt.len() >= scan.max_entries)
+            || (!scan.time_budget.is_zero() && start.elapsed() >= scan.time_budget)
+        {
+            log::info!(target: "sync",
+                ns = ns,
+                cursor = cursor,
+                redlist = rt.len(),
+                elapsed = start.elapsed().as_millis() as u64;
+                "redlist scan limited, continue in the next run",
+            );
+            break;
+        }
+    }
+
+    if let (true, Some(redis)) = (has_stale, sweeper) {
+        let sweep_cmd = redis.fcall("redlist_add", &[ns]);
+        redis::timed(&redis.cli, sweep_cmd).await?;
+    }
+
+    Ok((cursor, rt))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{
+        super::{conf, redis, testing::TestRedis},
+        *,
+    };
+
+    #[tokio::test]
+    async fn limit_args_works() -> anyhow::Result<()> {
+        assert_eq!(LimitArgs(1, 0, 0, 0, 0), LimitArgs::new(1, &[]));
+        assert_eq!(LimitArgs(2, 0, 0, 0, 0), LimitArgs::new(2, &[]));
+        assert_eq!(LimitArgs(2, 0, 0, 0, 0), LimitArgs::new(2, &[100]));
+
+        assert_eq!(
+            LimitArgs(3, 100, 10000, 0, 0),
+            LimitArgs::new(3, &[100, 10000])
+        );
+
+        assert_eq!(
+            LimitArgs(3, 100, 10000, 10, 0),
+            LimitArgs::new(3, &[100, 10000, 10])
+        );
+
+        assert_eq!(
+            LimitArgs(1, 100, 10000, 50, 2000),
+            LimitArgs::new(1, &[100, 10000, 50, 2000])
+        );
+
+        assert_eq!(
+            LimitArgs(1, 0, 0, 0, 0),
+            LimitArgs::new(1, &[100, 10000, 50, 2000, 1])
+        );
+
+        assert_eq!(Ok(()), LimitArgs(1, 100, 10000, 50, 2000).validate());
+        assert_eq!(Ok(()), LimitArgs(100, 100, 60000, 0, 0).validate());
+        assert_eq!(
+            Err(LimitError::Quantity {
+                quantity: 1,
+                max_count: 0
+            }),
+            LimitArgs::new(1, &[]).validate()
+        );
+        assert_eq!(
+            Err(LimitError::Quantity {
+                quantity: 0,
+                max_count: 100
+            }),
+            LimitArgs(0, 100, 10000, 0, 0).validate()
+        );
+        assert_eq!(
+            Err(LimitError::Quantity {
+                quantity: 101,
+                max_count: 100
+            }),
+            LimitArgs(101, 100, 10000, 0, 0).validate()
+        );
+        assert_eq!(
+            Err(LimitError::Period(60001)),
+            LimitArgs(1, 100, 60001, 0, 0).validate()
+        );
+        assert_eq!(
+            Err(LimitError::Burst {
+                quantity: 5,
+                max_burst: 4
+            }),
+            LimitArgs(5, 100, 10000, 4, 0).validate()
+        );
+        assert_eq!(
+            Err(LimitError::BurstPeriod {
+                burst_period: 20000,
+                period: 10000
+            }),
+            LimitArgs(1, 100, 10000, 0, 20000).validate()
+        );
+        assert_eq!(
+            "quantity 101 should be in [1, max count 100]",
+            LimitArgs(101, 100, 10000, 0, 0)
+                .validate()
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(!LimitArgs(1, 100, 10000, 0, 20000).is_valid());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn composite_result_works() -> anyhow::Result<()> {
+        let all = vec![LimitArgs(1, 10, 1000, 0, 0), LimitArgs(1, 300, 60000, 0, 0)];
+
+        assert_eq!(
+            (10, LimitResult(8, 0)),
+            composite_result(
+                &all,
+                vec![LimitResult(8, 0), LimitResult(20, 0)],
+                Composite::And
+            ),
+            "the least remaining"
+        );
+        assert_eq!(
+            (300, LimitResult(299, 0)),
+            composite_result(
+                &all,
+                vec![LimitResult(8, 0), LimitResult(299, 0)],
+                Composite::And
+            ),
+            "the least remaining"
+        );
+        assert_eq!(
+            (300, LimitResult(300, 5000)),
+            composite_result(
+                &all,
+                vec![LimitResult(10, 500), LimitResult(300, 5000)],
+                Composite::And
+            ),
+            "the longest wait"
+        );
+        assert_eq!(
+            (10, LimitResult(10, 500)),
+            composite_result(
+                &all,
+                vec![LimitResult(10, 500), LimitResult(20, 0)],
+                Composite::And
+            ),
+            "limited by one"
+        );
+
+        assert_eq!(
+            (300, LimitResult(20, 0)),
+            composite_result(
+                &all,
+                vec![LimitResult(10, 500), LimitResult(20, 0)],
+                Composite::Or
+            ),
+            "allowed by one"
+        );
+        assert_eq!(
+            (10, LimitResult(10, 500)),
+            composite_result(
+                &all,
+                vec![LimitResult(10, 500), LimitResult(300, 5000)],
+                Composite::Or
+            ),
+            "the shortest wait"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redlist_events_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let mut rx = redrules.subscribe();
+        let event = |event: &'static str, id: &str, ttl: u64| RedlistEvent {
+            event,
+            id: id.to_string(),
+            ttl,
+        };
+
+        let list = HashMap::from([("user1".to_string(), 2000), ("user2".to_string(), 3000)]);
+        redrules.dyn_update(1000, 1, list, HashMap::new());
+        let mut added = vec![rx.recv().await?, rx.recv().await?];
+        added.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            vec![event("add", "user1", 2000), event("add", "user2", 3000)],
+            added
+        );
+
+        // unchanged entries are not sent again
+        let list = HashMap::from([("user1".to_string(), 2000)]);
+        redrules.dyn_update(1500, 2, list, HashMap::new());
+        assert!(rx.try_recv().is_err());
+
+        redrules.dyn_update(2500, 3, HashMap::new(), HashMap::new());
+        assert_eq!(event("expire", "user1", 2000), rx.recv().await?);
+
+        redrules.erase("user2");
+        assert_eq!(event("remove", "user2", 3000), rx.recv().await?);
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_change_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        redrules.dyn_update(1000, 5, HashMap::new(), HashMap::new());
+
+        // as published by redlist_add and redrules_add
+        let change: RedlimitChange = serde_json::from_str(r#"{"redlist":{"user1":3000}}"#)?;
+        redrules.apply_change(1000, change);
+        let change: RedlimitChange =
+            serde_json::from_str(r#"{"redrules":[["core","GET /v1/file/list",5,4000]]}"#)?;
+        redrules.apply_change(1000, change);
+
+        assert_eq!(
+            HashMap::from([("user1".to_string(), 3000)]),
+            redrules.redlist(1000)
+        );
+        assert_eq!(
+            HashMap::from([("core:GET /v1/file/list".to_string(), (5, 4000))]),
+            redrules.redrules(1000)
+        );
+        assert_eq!(5, redrules.dyn_rules.load().redlist_cursor);
+
+        // as published by erase_id
+        let change: RedlimitChange = serde_json::from_str(r#"{"erased":["user1"]}"#)?;
+        assert_eq!(vec!["user1".to_string()], change.erased);
+
+        // the expired entries are left to the sync job
+        let change: RedlimitChange = serde_json::from_str(r#"{"redlist":{"user2":9000}}"#)?;
+        redrules.apply_change(5000, change);
+        assert_eq!(2, redrules.dyn_rules.load().redlist.len());
+        assert_eq!(1, redrules.redlist(5000).len());
+        redrules.dyn_update(5000, 5, HashMap::new(), HashMap::new());
+        assert_eq!(1, redrules.dyn_rules.load().redlist.len());
+        assert!(redrules.redrules(0).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dyn_snapshot_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let snapshot = redrules.dyn_rules.load_full();
+
+        let list = HashMap::from([("user1".to_string(), 3000)]);
+        redrules.dyn_update(1000, 5, list, HashMap::new());
+        assert!(
+            snapshot.redlist.is_empty(),
+            "the loaded snapshot is immutable"
+        );
+        assert_eq!(0, snapshot.redlist_cursor);
+        assert_eq!(1, redrules.dyn_rules.load().redlist.len());
+        assert_eq!(
+            redrules.floor_args(),
+            redrules.limit_args(1000, "core", "", "user1")
+        );
+
+        let rules = HashMap::from([("core:GET /v1/app/info".to_string(), (7, 3000))]);
+        redrules.dyn_update(1000, 0, HashMap::new(), rules);
+        let args = redrules.limit_args(1000, "core", "GET /v1/app/info", "user2");
+        assert_eq!(7, args.0, "weighted by the redrule");
+        let args = redrules.limit_args(1000, "core", "GET /v1/app/infos", "user2");
+        assert_eq!(1, args.0);
+
+        redrules.erase("user1");
+        assert!(redrules.dyn_rules.load().redlist.is_empty());
+        assert_eq!(5, redrules.dyn_rules.load().redlist_cursor);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_delay_works() -> anyhow::Result<()> {
+        let mut job = conf::Conf::new()?.job;
+        job.interval = 3;
+        job.jitter = 0;
+        job.max_backoff = 20;
+        assert_eq!(Duration::from_secs(3), sync_delay(&job, 0, 12345));
+        assert_eq!(Duration::from_secs(6), sync_delay(&job, 1, 12345));
+        assert_eq!(Duration::from_secs(12), sync_delay(&job, 2, 12345));
+        assert_eq!(Duration::from_secs(20), sync_delay(&job, 3, 12345));
+        assert_eq!(Duration::from_secs(20), sync_delay(&job, u32::MAX, 12345));
+
+        job.jitter = 1000;
+        assert_eq!(Duration::from_millis(3333), sync_delay(&job, 0, 12345));
+        assert_eq!(Duration::from_millis(20000), sync_delay(&job, 3, 1001));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dyn_resync_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let list = HashMap::from([
+            ("user1".to_string(), 3000),
+            ("user2".to_string(), 3000),
+            ("10.0.0.0/8".to_string(), 3000),
+        ]);
+        let rules = HashMap::from([
+            ("core:GET /v1/file/list".to_string(), (5, 4000)),
+            ("core:GET /v1/app/info".to_string(), (5, 4000)),
+        ]);
+        redrules.dyn_update(1000, 9000, list, rules);
+        let mut rx = redrules.subscribe();
+
+        // user2, the CIDR and a redrule were removed in Redis
+        let list = HashMap::from([("user1".to_string(), 3000)]);
+        let rules = HashMap::from([("core:GET /v1/file/list".to_string(), (5, 4000))]);
+        redrules.dyn_resync(1000, 2000, list.clone(), rules.clone());
+        assert_eq!(list, redrules.redlist(1000));
+        assert_eq!(rules, redrules.redrules(1000));
+        assert!(redrules
+            .dyn_rules
+            .load()
+            .redlist_patterns
+            .get("10.1.2.3")
+            .is_none());
+        assert_eq!(2000, redrules.dyn_rules.load().redlist_cursor);
+
+        let mut removed = vec![rx.recv().await?.id, rx.recv().await?.id];
+        removed.sort();
+        assert_eq!(vec!["10.0.0.0/8".to_string(), "user2".to_string()], removed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn red_rules_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+
+        {
+            let sr = redrules.static_rules();
+            assert_eq!(vec![3, 10000, 1, 1000], sr.floor);
+
+            assert_eq!(vec![10, 10000, 3, 1000], sr.defaut.limit);
+            assert!(sr.defaut.path.is_empty());
+
+            assert_eq!(0, redrules.dyn_rules.load().redlist_cursor);
+
+            let core_rules = sr
+                .rules
+                .get("core")
+                .ok_or(anyhow::Error::msg("'core' not exists"))?;
+            assert_eq!(vec![100, 10000, 50, 2000], core_rules.limit);
+            assert_eq!(
+                5,
+                core_rules.path.get("GET /v1/file/list").unwrap().to_owned()
+            );
+
+            assert!(!sr.rules.contains_key("core2"));
+        }
+
+        {
+            assert!(redrules.redlist(0).is_empty());
+            assert!(redrules.redrules(0).is_empty());
+
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "core", "GET /v1/file/list", "user1")
+            );
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "core", "GET /v1/file/list", "user2"),
+                "any user"
+            );
+
+            assert_eq!(
+                LimitArgs(1, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "core", "GET /v2/file/list", "user1"),
+                "path not exists"
+            );
+
+            assert_eq!(
+                LimitArgs(1, 10, 10000, 3, 1000),
+                redrules.limit_args(0, "core2", "GET /v1/file/list", "user1"),
+                "scope not exists"
+            );
+
+            assert_eq!(
+                LimitArgs(1, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "biz", "GET /v1/app/info", "user1")
+            );
+            assert_eq!(
+                LimitArgs(3, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "biz", "GET /v2/app/info", "user1")
+            );
+            assert_eq!(
+                LimitArgs(10, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "biz", "GET /v3/app/info", "user1"),
+                "any user"
+            );
+        }
+
+        let ts = unix_ms();
+        {
+            let mut dyn_blacklist = HashMap::new();
+            dyn_blacklist.insert("user1".to_owned(), ts + 1000);
+            redrules.dyn_update(ts, 1, dyn_blacklist, HashMap::new());
+
+            {
+                let dr = redrules.dyn_rules.load();
+                assert_eq!(1, dr.redlist_cursor);
+            }
+
+            assert_eq!(1, redrules.redlist(0).len());
+            assert_eq!(1, redrules.redlist(ts + 1000).len());
+            assert!(redrules.redlist(ts + 1001).is_empty());
+            assert!(redrules.redrules(0).is_empty());
+
+            assert_eq!(
+                LimitArgs(1, 3, 10000, 1, 1000),
+                redrules.limit_args(0, "core", "GET /v1/file/list", "user1"),
+                "limited by dyn_blacklist"
+            );
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "core", "GET /v1/file/list", "user2"),
+                "not limited by dyn_blacklist"
+            );
+            assert_eq!(
+                LimitArgs(1, 3, 10000, 1, 1000),
+                redrules.limit_args(ts, "core", "GET /v1/file/list", "user1"),
+                "limited by dyn_blacklist"
+            );
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(ts + 1001, "core", "GET /v1/file/list", "user1"),
+                "not limited by dyn_blacklist after ttl"
+            );
+        }
+
+        {
+            let mut dyn_rules = HashMap::new();
+            dyn_rules.insert("core:GET /v1/file/list".to_owned(), (3, ts + 1000));
+            dyn_rules.insert("core:GET /v2/file/list".to_owned(), (5, ts + 1000));
+            redrules.dyn_update(ts, 2, HashMap::new(), dyn_rules);
+
+            {
+                let dr = redrules.dyn_rules.load();
+                assert_eq!(2, dr.redlist_cursor);
+            }
+
+            assert_eq!(1, redrules.redlist(0).len());
+            assert_eq!(2, redrules.redrules(0).len());
+            assert_eq!(2, redrules.redrules(ts + 1000).len());
+            assert!(redrules.redrules(ts + 1001).is_empty());
+
+            assert_eq!(
+                LimitArgs(1, 3, 10000, 1, 1000),
+                redrules.limit_args(0, "core", "GET /v1/file/list", "user1"),
+                "limited by dyn_blacklist"
+            );
+            assert_eq!(
+                LimitArgs(3, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "core", "GET /v1/file/list", "user2"),
+                "limited by dyn_rules"
+            );
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(0, "core", "GET /v2/file/list", "user2"),
+                "limited by dyn_rules"
+            );
+
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(ts + 1001, "core", "GET /v1/file/list", "user1"),
+                "not limited by dyn_blacklist after ttl"
+            );
+            assert_eq!(
+                LimitArgs(5, 100, 10000, 50, 2000),
+                redrules.limit_args(ts + 1001, "core", "GET /v1/file/list", "user2"),
+                "not limited by dyn_blacklist after ttl"
+            );
+            assert_eq!(
+                LimitArgs(1, 100, 10000, 50, 2000),
+                redrules.limit_args(ts + 1001, "core", "GET /v2/file/list", "user2"),
+                "not limited by dyn_blacklist after ttl"
+            );
+        }
+
+        {
+            redrules.dyn_update(ts + 1001, ts, HashMap::new(), HashMap::new());
+
+            {
+                let dr = redrules.dyn_rules.load();
+                assert_eq!(ts, dr.redlist_cursor);
+            }
+
+            assert!(redrules.redlist(0).is_empty(), "auto sweep stale rules");
+            assert!(redrules.redrules(0).is_empty(), "auto sweep stale rules");
+
+            let mut dyn_rules = HashMap::new();
+            dyn_rules.insert("core:GET /v1/file/list".to_owned(), (3, ts + 1000)); // stale rules
+            dyn_rules.insert("core:GET /v1/file/list".to_owned(), (5, ts + 1002));
+
+            redrules.dyn_update(ts + 1001, ts + 1, HashMap::new(), dyn_rules);
+
+            {
+                let dr = redrules.dyn_rules.load();
+                assert_eq!(ts + 1, dr.redlist_cursor);
+            }
+
+            assert!(redrules.redlist(0).is_empty());
+            assert_eq!(
+                1,
+                redrules.redrules(0).len(),
+                "stale rules should not be added"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn path_pattern_works() -> anyhow::Result<()> {
+        assert!(PathPattern::parse("GET /v1/files").is_none());
+        assert!(PathPattern::parse(":").is_none());
+
+        let p = PathPattern::parse("GET /v1/files/*").unwrap();
+        assert!(p.matches("GET /v1/files/123"));
+        assert!(!p.matches("GET /v1/files/"));
+        assert!(!p.matches("GET /v1/files"));
+        assert!(!p.matches("GET /v1/files/123/content"));
+        assert!(!p.matches("POST /v1/files/123"));
+
+        let p = PathPattern::parse("GET /v1/files/:id/content").unwrap();
+        assert!(p.matches("GET /v1/files/123/content"));
+        assert!(!p.matches("GET /v1/files/123/meta"));
+
+        let p = PathPattern::parse("GET /v1/**").unwrap();
+        assert!(p.matches("GET /v1/files/123/content"));
+        assert!(p.matches("GET /v1/"));
+        assert!(!p.matches("GET /v2/files"));
+
+        let mut rules = HashMap::new();
+        let mut path = HashMap::new();
+        path.insert("GET /v1/files/*".to_string(), 2);
+        path.insert("GET /v1/files/:id/content".to_string(), 3);
+        path.insert("GET /v1/files/shared/content".to_string(), 4);
+        path.insert("GET /v1/files/*/*".to_string(), 5);
+        path.insert("GET /v1/**".to_string(), 6);
+        rules.insert(
+            "core".to_string(),
+            Rule {
+                limit: vec![100, 10000],
+                path,
+                ..Rule::default()
+            },
+        );
+        let redrules = RedRules::new("TT", &rules);
+
+        for (quantity, path) in [
+            (2, "GET /v1/files/123"),
+            (3, "GET /v1/files/123/content"),
+            (4, "GET /v1/files/shared/content"),
+            (5, "GET /v1/files/123/meta"),
+            (6, "GET /v1/apps/123/meta"),
+            (1, "GET /v2/files/123"),
+        ] {
+            assert_eq!(
+                quantity,
+                redrules.limit_args(0, "core", path, "user1").0,
+                "{}",
+                path
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redlist_patterns_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let ts = unix_ms();
+
+        let mut dyn_redlist = HashMap::new();
+        dyn_redlist.insert("tenant123:*".to_owned(), ts + 1000);
+        dyn_redlist.insert("10.0.0.0/8".to_owned(), ts + 1000);
+        redrules.dyn_update(ts, 1, dyn_redlist, HashMap::new());
+
+        for id in ["tenant123:user1", "10.1.2.3"] {
+            assert!(redrules.is_redlisted(ts, id));
+            assert_eq!(
+                LimitArgs(1, 3, 10000, 1, 1000),
+                redrules.limit_args(ts, "core", "", id),
+                "{} limited by redlist pattern",
+                id
+            );
+        }
+        for id in ["tenant1234:user1", "11.1.2.3"] {
+            assert!(!redrules.is_redlisted(ts, id));
+            assert_eq!(
+                LimitArgs(1, 100, 10000, 50, 2000),
+                redrules.limit_args(ts, "core", "", id),
+                "{} not limited by redlist pattern",
+                id
+            );
+        }
+
+        redrules.dyn_update(ts + 1001, 2, HashMap::new(), HashMap::new());
+        assert_eq!(
+            LimitArgs(1, 100, 10000, 50, 2000),
+            redrules.limit_args(ts + 1001, "core", "", "tenant123:user1"),
+            "not limited by redlist pattern after ttl"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scheduled_limits_works() -> anyhow::Result<()> {
+        let mut rules = HashMap::new();
+        rules.insert(
+            "core".to_string(),
+            Rule {
+                limit: vec![100, 10000],
+                schedules: vec![conf::Schedule {
+                    active_hours: vec![9, 18],
+                    weekdays: vec![],
+                    utc_offset: 0,
+                    limit: vec![50, 10000],
+                }],
+                ..Rule::default()
+            },
+        );
+        let redrules = RedRules::new("TT", &rules);
+
+        let hour = 3600 * 1000;
+        assert_eq!(
+            LimitArgs(1, 100, 10000, 0, 0),
+            redrules.limit_args(8 * hour, "core", "", "user1")
+        );
+        assert_eq!(
+            LimitArgs(1, 50, 10000, 0, 0),
+            redrules.limit_args(9 * hour, "core", "", "user1"),
+            "peak hours"
+        );
+        assert_eq!(
+            LimitArgs(1, 100, 10000, 0, 0),
+            redrules.limit_args(18 * hour, "core", "", "user1")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redlimit_script_works() -> anyhow::Result<()> {
+        assert_eq!(
+            Some(6),
+            redis_major_version("# Server\r\nredis_version:6.2.14\r\nredis_mode:standalone\r\n")
+        );
+        assert_eq!(Some(7), redis_major_version("redis_version:7.0.0"));
+        assert_eq!(None, redis_major_version("# Server\r\n"));
+
+        let script = redlimit_script();
+        assert!(!script.contains("#!lua"));
+        assert!(!script.contains("redis.register_function"));
+        assert!(script.contains("register_function('limiting', limiting)"));
+        assert!(script.ends_with("return fn(KEYS, ARGV)\n"));
+
+        assert!(is_fn_missing("ERR Function not found"));
+        assert!(is_fn_missing(
+            "NOSCRIPT No matching script. Please use EVAL."
+        ));
+        assert!(!is_fn_missing("ERR unknown command"));
+
+        assert!(lua_version(redlimit_lua::REDLIMIT) > 0);
+        assert_eq!(0, lua_version("#!lua name=redlimit\n\nlocal function"));
+        assert_eq!(12, lua_version("#!lua name=redlimit\n-- version: 12\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rules_reload_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        redrules.dyn_update(
+            0,
+            1,
+            HashMap::from([("user2".to_string(), 10000)]),
+            HashMap::new(),
+        );
+        assert_eq!(
+            LimitArgs(5, 100, 10000, 50, 2000),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user1")
+        );
+
+        let mut rules = cfg.rules.clone();
+        rules.insert(
+            "core".to_string(),
+            Rule {
+                limit: vec![200, 10000],
+                quantity: 2,
+                ..Rule::default()
+            },
+        );
+        rules.remove("-");
+        redrules.reload(&rules);
+
+        assert_eq!(
+            LimitArgs(2, 200, 10000, 0, 0),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user1"),
+            "reloaded rule"
+        );
+        assert_eq!(
+            LimitArgs(1, 2, 10000, 1, 1000),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user2"),
+            "redlist kept, default floor"
+        );
+        assert_eq!(1, redrules.dyn_rules.load().redlist_cursor);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn namespaces_works() -> anyhow::Result<()> {
+        let mut cfg = conf::Conf::new()?;
+        cfg.namespaces.insert(
+            "tb".to_string(),
+            conf::Namespace {
+                rules: HashMap::from([(
+                    "core".to_string(),
+                    Rule {
+                        limit: vec![10, 1000],
+                        ..Rule::default()
+                    },
+                )]),
+            },
+        );
+        let namespaces = Namespaces::new(&cfg);
+        assert_eq!(2, namespaces.iter().count());
+        assert!(namespaces.get("xx").is_none());
+
+        let main = namespaces.get("").unwrap();
+        assert_eq!("RL", main.ns.as_str());
+        assert_eq!("RL", namespaces.get("RL").unwrap().ns.as_str());
+        assert_eq!(
+            LimitArgs(5, 100, 10000, 50, 2000),
+            main.limit_args(0, "core", "GET /v1/file/list", "user1")
+        );
+
+        let tb = namespaces.get("tb").unwrap();
+        assert_eq!("tb:core:user1", tb.ns.limiting_key("core", "user1"));
+        assert_eq!("tb:*:user\\*1", tb.ns.id_keys_pattern("user*1"));
+        assert_eq!("tb:core#2:user1", NS::extra_key("tb:core:user1", 2));
+        assert_eq!(
+            "tb:core#1:tenant123:user1",
+            NS::extra_key("tb:core:tenant123:user1", 1)
+        );
+        assert!(tb.ns.is_id_key("user1", "tb:core:user1"));
+        assert!(tb.ns.is_id_key("user1", "tb:core#2:user1"));
+        assert!(!tb.ns.is_id_key("user1", "tb:core:user12"));
+        assert!(!tb.ns.is_id_key("user1", "tb:core:user1:2"));
+        assert!(
+            !tb.ns.is_id_key("a", "tb:core:a:1"),
+            "erasing \"a\" keeps the keys of \"a:1\""
+        );
+        assert!(
+            !tb.ns.is_id_key("user1", "tb:a:b:user1"),
+            "missed under scope \"a:b\", rejected by validate_scope"
+        );
+        assert_eq!(Err(LimitError::Scope), validate_scope("a:b"));
+        assert_eq!(Err(LimitError::Scope), validate_scope("a#1"));
+        assert_eq!(Ok(()), validate_scope("core"));
+        assert!(!tb.ns.is_id_key("user1", "tb2:core:user1"));
+        assert!(!tb.ns.is_id_key("user1", "tb:core:tenant123:user1"));
+        assert!(!tb.ns.is_id_key("user1", "tb:core#1:tenant123:user1"));
+        assert!(tb
+            .ns
+            .is_id_key("tenant123:user1", "tb:core#1:tenant123:user1"));
+        assert!(tb
+            .ns
+            .is_id_key("tenant123:user1", "tb:core:tenant123:user1"));
+        assert!(!tb.ns.is_id_key("user1", "tb::user1"));
+        assert_eq!(
+            LimitArgs(1, 10, 1000, 0, 0),
+            tb.limit_args(0, "core", "GET /v1/file/list", "user1")
+        );
+
+        cfg.namespaces.get_mut("tb").unwrap().rules.insert(
+            "core".to_string(),
+            Rule {
+                limit: vec![20, 1000],
+                ..Rule::default()
+            },
+        );
+        namespaces.reload(&cfg);
+        assert_eq!(
+            LimitArgs(1, 20, 1000, 0, 0),
+            namespaces
+                .get("tb")
+                .unwrap()
+                .limit_args(0, "core", "GET /v1/file/list", "user1"),
+            "reloaded namespace"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_status_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new("RLSS", &cfg.rules);
+        assert_eq!(0, redrules.sync_status().last_run);
+
+        let rt = Ok(SyncLoaded {
+            cursor: 1000,
+            redrules: 2,
+            redlist: 3,
+            full: true,
+            sweeper: true,
+        });
+        redrules.sync_done(5000, Duration::from_millis(12), &rt);
+        let status = redrules.sync_status();
+        assert_eq!(5000, status.last_success);
+        assert_eq!(5000, status.last_full_sync);
+        assert!(status.sweeper);
+        assert_eq!(12, status.elapsed);
+        assert_eq!(
+            (1000, 2, 3),
+            (status.cursor, status.redrules, status.redlist)
+        );
+        assert!(status.error.is_empty());
+
+        redrules.sync_done(6000, Duration::from_millis(5), &Err(Error::msg("boom")));
+        let status = redrules.sync_status();
+        assert_eq!(6000, status.last_run);
+        assert_eq!(5000, status.last_success);
+        assert_eq!(1000, status.cursor);
+        assert_eq!("boom", status.error);
+        assert_eq!(1, status.failures);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn central_update_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        assert_eq!("RL:SR", redrules.ns.central_key());
+        assert_eq!("RL:CH", redrules.ns.channel());
+
+        let central = HashMap::from([(
+            "core".to_string(),
+            r#"{"limit": [200, 10000], "path": {"GET /v1/file/list": 4}}"#.to_string(),
+        )]);
+        assert!(redrules.central_update(central.clone())?);
+        assert!(!redrules.central_update(central.clone())?, "not changed");
+        assert_eq!(
+            LimitArgs(4, 200, 10000, 0, 0),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user1")
+        );
+        assert_eq!(
+            LimitArgs(10, 100, 10000, 50, 2000),
+            redrules.limit_args(0, "biz", "GET /v1/app", "user1"),
+            "file rules kept"
+        );
+
+        let err = redrules
+            .central_update(HashMap::from([
+                ("core".to_string(), r#"{"limit": [0, 10000]}"#.to_string()),
+                ("biz".to_string(), "{".to_string()),
+            ]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("central.\"biz\": "), "{}", err);
+        assert!(
+            err.contains("central.\"core\".limit: max count should > 0"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            LimitArgs(4, 200, 10000, 0, 0),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user1"),
+            "invalid central rules rejected"
+        );
+
+        redrules.reload(&cfg.rules);
+        assert_eq!(
+            LimitArgs(4, 200, 10000, 0, 0),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user1"),
+            "central rules kept on reload"
+        );
+
+        let checked = RedRules::new(&cfg.namespace, &cfg.rules).with_redlist_check(true);
+        let err = checked
+            .central_update(HashMap::from([(
+                "core".to_string(),
+                r#"{"limit": [200, 10000], "limits": [[300, 60000]]}"#.to_string(),
+            )]))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("central.\"core\": job.redlist_check doesn't support"),
+            "{}",
+            err
+        );
+
+        assert!(redrules.central_update(HashMap::new())?);
+        assert_eq!(
+            LimitArgs(5, 100, 10000, 50, 2000),
+            redrules.limit_args(0, "core", "GET /v1/file/list", "user1")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validate_inputs_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redrules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let inputs = conf::Inputs {
+            max_entries: 3,
+            ..conf::Inputs::default()
+        };
+
+        let list: HashMap<String, u64> =
+            HashMap::from([("user1".to_string(), 50000), ("user2".to_string(), 1000)]);
+        assert!(validate_redlist(&inputs, list.iter().map(|(k, v)| (k, *v))).is_ok());
+        let list: HashMap<String, u64> = HashMap::from([
+            ("user1".to_string(), 50000),
+            ("user2".to_string(), 50),
+            ("".to_string(), 50000),
+        ]);
+        let err = validate_redlist(&inputs, list.iter().map(|(k, v)| (k, *v))).unwrap_err();
+        assert_eq!("invalid redlist", err.message);
+        assert_eq!(2, err.entries.len());
+        assert_eq!("empty id", err.entries[""]);
+        assert_eq!(
+            "ttl 50 should be in [1000, 2592000000] milliseconds",
+            err.entries["user2"]
+        );
+        assert_eq!(
+            "invalid redlist: \"\": empty id, \"user2\": ttl 50 should be in [1000, 2592000000] milliseconds",
+            err.to_string()
+        );
+        let list: HashMap<String, u64> = (0..4).map(|i| (i.to_string(), 50000)).collect();
+        let err = validate_redlist(&inputs, list.iter().map(|(k, v)| (k, *v))).unwrap_err();
+        assert_eq!("too many entries: 4 > 3", err.to_string());
+        let list: HashMap<String, u64> = HashMap::from([
+            ("*".to_string(), 50000),
+            ("10.0.0.0/33".to_string(), 50000),
+            ("10.0.0.0/8".to_string(), 50000),
+        ]);
+        let err = validate_redlist(&inputs, list.iter().map(|(k, v)| (k, *v))).unwrap_err();
+        assert_eq!(2, err.entries.len());
+        assert_eq!("\"*\" matches every id", err.entries["*"]);
+        assert_eq!("malformed CIDR", err.entries["10.0.0.0/33"]);
+        assert_eq!(None, check_redlist_entry(&inputs, "10.0.0.0/8", 50000));
+        let ids = [
+            "10.0.0.0/8".to_string(),
+            "tenant123:*".to_string(),
+            "user1".to_string(),
+            "10.0.0.1".to_string(),
+        ];
+        let err = validate_hashed_redlist(ids.iter()).unwrap_err();
+        assert_eq!(2, err.entries.len());
+        assert_eq!(
+            "patterns don't match the hashed ids",
+            err.entries["10.0.0.0/8"]
+        );
+        assert_eq!(
+            "patterns don't match the hashed ids",
+            err.entries["tenant123:*"]
+        );
+        assert!(validate_hashed_redlist(ids[2..].iter()).is_ok());
+
+        let rules: HashMap<String, (u64, u64)> = HashMap::from([
+            ("GET /v1/file/list".to_string(), (5, 50000)),
+            ("GET /v2/file/list".to_string(), (50, 50000)),
+        ]);
+        assert!(redrules.validate_redrules(&inputs, "core", &rules).is_ok());
+        let err = redrules.validate_redrules(&inputs, "", &rules).unwrap_err();
+        assert_eq!("empty scope", err.to_string());
+        let err = redrules
+            .validate_redrules(&inputs, "a:b", &rules)
+            .unwrap_err();
+        assert_eq!("scope should not contain ':' or '#'", err.to_string());
+
+        let rules: HashMap<String, (u64, u64)> = HashMap::from([
+            ("GET /v1/file/list".to_string(), (5, 50000)),
+            ("GET /v2/file/list".to_string(), (0, 50000)),
+            ("GET /v3/file/list".to_string(), (60, 50000)),
+            ("".to_string(), (1, 50000)),
+        ]);
+        let err = redrules
+            .validate_redrules(&inputs, "core", &rules)
+            .unwrap_err();
+        assert_eq!("too many entries: 4 > 3", err.to_string());
+
+        let rules: HashMap<String, (u64, u64)> = HashMap::from([
+            ("GET /v2/file/list".to_string(), (0, 50000)),
+            ("GET /v3/file/list".to_string(), (60, 50000)),
+            ("GET /v4/file/list".to_string(), (5, 999)),
+        ]);
+        let err = redrules
+            .validate_redrules(&inputs, "core", &rules)
+            .unwrap_err();
+        assert_eq!("invalid redrules", err.message);
+        assert_eq!(
+            "quantity 0 should be in [1, max count 100]",
+            err.entries["GET /v2/file/list"]
+        );
+        assert_eq!(
+            "quantity 60 should <= max burst 50",
+            err.entries["GET /v3/file/list"]
+        );
+        assert_eq!(
+            "ttl 999 should be in [1000, 2592000000] milliseconds",
+            err.entries["GET /v4/file/list"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn script_sha_per_pool_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let redis7 = redis::new_lazy(&cfg.redis)?;
+        let redis6 = redis::new_lazy(&cfg.redis)?;
+        assert!(redis6.set_script_sha("sha1"));
+        assert!(redis6.set_script_sha("sha1"));
+        assert!(!redis6.set_script_sha("sha2"));
+        assert_eq!(None, redis7.script_sha(), "not shared");
+
+        let cmd = fcall(&redis7, "limiting", &["RL:core:u1"]);
+        assert_eq!("FCALL", cmd.name);
+        assert_eq!(b"limiting".to_vec(), cmd.args[0].to_vec());
+        let cmd = fcall(&redis6, "limiting", &["RL:core:u1"]);
+        assert_eq!("EVALSHA", cmd.name);
+        assert_eq!(b"sha1".to_vec(), cmd.args[0].to_vec());
+        assert_eq!(b"limiting".to_vec(), cmd.args[3].to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn is_throttled_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let mut rules = cfg.rules.clone();
+        rules.insert(
+            "beta".to_string(),
+            Rule {
+                limit: vec![100, 10000],
+                allow_percent: 60,
+                ..Rule::default()
+            },
+        );
+        let redrules = RedRules::new(&cfg.namespace, &rules);
+
+        assert_eq!(
+            fnv1a(&["beta", "GET /v1", "user1"]),
+            fnv1a(&["beta", "GET /v1", "user1"])
+        );
+        assert_ne!(
+            fnv1a(&["beta", "GET /v1", "user1"]),
+            fnv1a(&["beta", "GET /v1", "user2"])
+        );
+
+        assert!(!redrules.is_throttled("core", "GET /v1", "user1"));
+        assert!(!redrules.is_throttled("beta", "GET /v1", ""));
+
+        let throttled = (0..1000)
+            .filter(|i| redrules.is_throttled("beta", "GET /v1", &format!("user{}", i)))
+            .count();
+        assert!(throttled > 300 && throttled < 500, "about 40% throttled");
+
+        // the decision of an id is stable on a path, and differs by path.
+        for i in 0..100 {
+            let id = format!("user{}", i);
+            let throttled = redrules.is_throttled("beta", "GET /v1", &id);
+            assert!((0..10).all(|_| redrules.is_throttled("beta", "GET /v1", &id) == throttled));
+        }
+        let differs = (0..1000)
+            .filter(|i| {
+                let id = format!("user{}", i);
+                redrules.is_throttled("beta", "GET /v1", &id)
+                    != redrules.is_throttled("beta", "GET /v2", &id)
+            })
+            .count();
+        assert!(differs > 0, "hashed with the path");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn init_redlimit_fn_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+
+        assert!(init_redlimit_fn(&pool).await.is_ok());
+        assert!(init_redlimit_fn(&pool).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limiting_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let retry = cfg.redis.retry.clone();
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(1, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(LimitResult(1, 0), res);
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(3, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(LimitResult(4, 0), res);
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(3, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(4, res.0);
+        assert!(res.1 > 0);
+
+        sleep(Duration::from_millis(res.1 + 1)).await;
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(3, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(LimitResult(7, 0), res);
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(2, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(7, res.0);
+        assert!(res.1 > 0);
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(1, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(LimitResult(8, 0), res);
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(1, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(8, res.0);
+        assert!(res.1 > 0);
+
+        sleep(Duration::from_millis(res.1 + 1)).await;
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(1, 8, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(LimitResult(1, 0), res);
+
+        let res = limiting(
+            &pool,
+            &retry,
+            "TT:core:user1",
+            LimitArgs(1, 1, 1000, 5, 300),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(1, res.0);
+        assert!(res.1 > 0, "with new max count");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limiting_lease_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let retry = cfg.redis.retry.clone();
+        let key = "TT:lease:user1";
+        let cli = redis::get(&pool).await?;
+        cli.send(resp::cmd("DEL").arg(key), None).await?;
+
+        let (leased, ttl, count) = limiting_lease(&pool, &retry, key, 4, 10, 1000).await?;
+        assert_eq!((4, 4), (leased, count));
+        assert!(ttl > 0 && ttl <= 1000);
+        let (leased, _, count) = limiting_lease(&pool, &retry, key, 4, 10, 1000).await?;
+        assert_eq!((4, 8), (leased, count));
+        let (leased, _, count) = limiting_lease(&pool, &retry, key, 4, 10, 1000).await?;
+        assert_eq!((2, 10), (leased, count), "the remaining count");
+        let (leased, _, count) = limiting_lease(&pool, &retry, key, 4, 10, 1000).await?;
+        assert_eq!((0, 10), (leased, count), "exhausted");
+
+        let res = limiting(
+            &pool,
+            &retry,
+            key,
+            LimitArgs(1, 10, 1000, 0, 0),
+            Algorithm::FixedWindow,
+        )
+        .await?;
+        assert_eq!(10, res.0);
+        assert!(res.1 > 0, "shares the key with limiting");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limiting_redlist_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let retry = cfg.redis.retry.clone();
+        let ns = NS::new("TT".to_string());
+        let cli = redis::get(&pool).await?;
+        cli.send(
+            resp::cmd("DEL")
+                .arg("TT:LT")
+                .arg("TT:LC")
+                .arg("TT:redlist:user1")
+                .arg("TT:redlist:user2")
+                .arg("TT:redlist:user3"),
+            None,
+        )
+        .await?;
+
+        let limits = Limits::new(1, &[10, 1000], &[], Composite::And);
+        let floor = LimitArgs(1, 2, 1000, 0, 0);
+        let res = limiting_redlist(
+            &pool,
+            &retry,
+            &ns,
+            "TT:redlist:user1",
+            "user1",
+            &limits,
+            floor,
+        )
+        .await?;
+        assert_eq!((10, LimitResult(1, 0)), res);
+
+        redlist_add(
+            &pool,
+            &retry,
+            "TT",
+            &HashMap::from([("user2".to_string(), 10000)]),
+        )
+        .await?;
+        let key = "TT:redlist:user2";
+        let res = limiting_redlist(&pool, &retry, &ns, key, "user2", &limits, floor).await?;
+        assert_eq!((2, LimitResult(1, 0)), res, "limited by the floor at once");
+        let res = limiting_redlist(&pool, &retry, &ns, key, "user2", &limits, floor).await?;
+        assert_eq!((2, LimitResult(2, 0)), res);
+        let res = limiting_redlist(&pool, &retry, &ns, key, "user2", &limits, floor).await?;
+        assert_eq!(2, res.0);
+        assert!(res.1 .1 > 0);
+
+        let limits = limits.with_algorithm(Algorithm::Gcra);
+        let res = limiting_redlist(
+            &pool,
+            &retry,
+            &ns,
+            "TT:redlist:user3",
+            "user3",
+            &limits,
+            floor,
+        )
+        .await?;
+        assert_eq!((10, LimitResult(1, 0)), res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limiting_composite_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let retry = cfg.redis.retry.clone();
+        let limits =
+            |quantity: u64| Limits::new(quantity, &[3, 1000], &[vec![5, 10000]], Composite::And);
+
+        let res = limiting_composite(&pool, &retry, "TT:composite:user1", &limits(1)).await?;
+        assert_eq!((3, LimitResult(1, 0)), res);
+
+        let res = limiting_composite(&pool, &retry, "TT:composite:user1", &limits(2)).await?;
+        assert_eq!((3, LimitResult(3, 0)), res);
+
+        let res = limiting_composite(&pool, &retry, "TT:composite:user1", &limits(1)).await?;
+        assert_eq!(3, res.0);
+        assert_eq!(3, res.1 .0);
+        assert!(res.1 .1 > 0);
+
+        sleep(Duration::from_millis(res.1 .1 + 1)).await;
+        let res = limiting_composite(&pool, &retry, "TT:composite:user1", &limits(2)).await?;
+        assert_eq!((5, LimitResult(5, 0)), res, "the least remaining");
+
+        let res = limiting_composite(&pool, &retry, "TT:composite:user1", &limits(1)).await?;
+        assert_eq!(5, res.0);
+        assert!(res.1 .1 > 0, "limited by the second limit");
+
+        let single = Limits::new(1, &[3, 1000], &[], Composite::And);
+        let res = limiting_composite(&pool, &retry, "TT:composite:user1:1", &single).await?;
+        assert_eq!(
+            (3, LimitResult(1, 0)),
+            res,
+            "the id \"user1:1\" doesn't share the extra key of \"user1\""
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_lease_works() -> anyhow::Result<()> {
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let cli = redis::get(&pool).await?;
+        let ns = NS::new("sweep_lease_works".to_string());
+        cli.send(resp::cmd("DEL").arg(ns.sweep_lease_key()), None)
+            .await?;
+
+        let lease = |owner: &str| SweepLease {
+            owner: owner.to_string(),
+            ttl: 10000,
+        };
+        let (a, b) = (lease("a"), lease("b"));
+        assert!(a.acquire(&cli, &ns).await?);
+        assert!(!b.acquire(&cli, &ns).await?);
+        assert!(a.acquire(&cli, &ns).await?, "renewed by the owner");
+        assert!(SweepLease::new(0).acquire(&cli, &ns).await?, "no lease");
+
+        cli.send(resp::cmd("DEL").arg(ns.sweep_lease_key()), None)
+            .await?;
+        assert!(b.acquire(&cli, &ns).await?, "taken after expired");
+        assert!(!a.acquire(&cli, &ns).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redrules_add_load_works() -> anyhow::Result<()> {
+        let ns = "redrules_add_load_works";
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let retry = cfg.redis.retry.clone();
+        let ts = unix_ms();
+
+        let cli = FnClient::get(&pool).await?;
+
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts).await?;
+        assert!(dyn_redrules.is_empty());
+
+        let mut rules = HashMap::new();
+        redrules_add(&pool, &retry, ns, "core", &rules).await?;
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts).await?;
+        assert!(dyn_redrules.is_empty());
+
+        rules.insert("path1".to_owned(), (2, 100));
+        redrules_add(&pool, &retry, ns, "core", &rules).await?;
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts).await?;
+        assert_eq!(1, dyn_redrules.len());
+
+        redrules_add(&pool, &retry, ns, "core2", &rules).await?;
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts).await?;
+        assert_eq!(2, dyn_redrules.len());
+
+        let rt = dyn_redrules
+            .get("core:path1")
+            .ok_or(anyhow::Error::msg("'core:path1' not exists"))?
+            .to_owned();
+        assert_eq!(2, rt.0);
+        assert!(rt.1 > ts);
+
+        let rt = dyn_redrules
+            .get("core2:path1")
+            .ok_or(anyhow::Error::msg("'core2:path1' not exists"))?
+            .to_owned();
+        assert_eq!(2, rt.0);
+        assert!(rt.1 > ts);
+
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts + 210).await?;
+        assert_eq!(0, dyn_redrules.len());
+
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts).await?;
+        assert_eq!(2, dyn_redrules.len());
+
+        sleep(Duration::from_millis(210)).await;
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts + 210).await?;
+        assert_eq!(0, dyn_redrules.len(), "will sweep stale rules");
+        let dyn_redrules = redrules_load(cli.clone(), Some(cli.clone()), ns, ts).await?;
+        assert_eq!(0, dyn_redrules.len(), "should sweeped stale rules");
+
+        Ok(())
+    }
+
+    #[test]
+    fn redlist_meta_works() -> anyhow::Result<()> {
+        let meta = RedlistMeta {
+            reason: "credential stuffing".to_string(),
+            actor: "alice".to_string(),
+            source: String::new(),
+        };
+        let s = serde_json::to_string(&meta)?;
+        assert_eq!(r#"{"reason":"credential stuffing","actor":"alice"}"#, s);
+        assert_eq!(meta, serde_json::from_str(&s)?);
+        assert_eq!("RL:LM", NS::new("RL".to_string()).redlist_meta_key());
+        assert_eq!(
+            "RL/AL:key1",
+            NS::new("RL".to_string()).admin_limit_key("key1")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redlist_add_load_works() -> anyhow::Result<()> {
+        let ns = "redlist_add_load_works";
+        let test_redis = TestRedis::start().await?;
+        let cfg = &test_redis.cfg;
+        let pool = redis::new(cfg.redis.clone()).await?;
+        let retry = cfg.redis.retry.clone();
+        let ts = unix_ms();
+        let cli = FnClient::get(&pool).await?;
+        let scan = RedlistScan::default();
+
+        let dyn_redlist = redlist_load(cli.clone(), Some(cli.clone()), ns, ts, 0, &scan).await?;
+        assert!(dyn_redlist.1.is_empty());
+
+        let mut rules: HashMap<String, u64> = HashMap::new();
+        redlist_add(&pool, &retry, ns, &rules).await?;
+        let dyn_redlist = redlist_load(cli.clone(), Some(cli.clone()), ns, ts, 0, &scan).await?;
+        assert!(dyn_redlist.1.is_empty());
+
+        rules.insert("user1".to_owned(), 100);
+        redlist_add(&pool, &retry, ns, &rules).await?;
+        let dyn_redlist = redlist_load(cli.clone(), Some(cli.clone()), ns, ts, 0, &scan).await?;
+        assert!(dyn_redlist.0 > ts - 1000);
+        assert_eq!(1, dyn_redlist.1.len());
+
+        redlist_add(&pool, &retry, ns, &rules).await?;
+        let dyn_redlist =
+            redlist_load(cli.clone(), Some(cli.clone()), ns, ts, dyn_redlist.0, &scan).await?;
+        assert!(dyn_redlist.0 > ts);
+        assert_eq!(1, dyn_redlist.1.len());
+
+        let rt = dyn_redlist
+            .1
+            .get("user1")
+            .ok_or(anyhow::Error::msg("'user1' not exists"))?
+            .to_owned();
+        assert!(rt > ts);
+
+        let dyn_redlist =
+            redlist_load(cli.clone(), Some(cli.clone()), ns, ts + 210, 0, &scan).await?;
+        assert_eq!(0, dyn_redlist.1.len());
+        let dyn_redlist = redlist_load(cli.clone(), Some(cli.clone()), ns, ts, 0, &scan).await?;
+        assert_eq!(1, dyn_redlist.1.len());
+
+        sleep(Duration::from_millis(210)).await;
+        let dyn_redlist =
+            redlist_load(cli.clone(), Some(cli.clone()), ns, ts + 210, 0, &scan).await?;
+        assert_eq!(0, dyn_redlist.1.len(), "will sweep stale rules");
+        let dyn_redlist = redlist_load(cli.clone(), Some(cli.clone()), ns, ts, 0, &scan).await?;
+        assert_eq!(0, dyn_redlist.1.len(), "should sweeped stale rules");
+
+        let ts = unix_ms();
+        rules.clear();
+        rules.insert("user2".to_owned(), 10000);
+        redlist_add(&pool, &retry, ns, &rules).await?;
+        // the cursor is the adding time in ms
+        sleep(Duration::from_millis(2)).await;
+        rules.clear();
+        rules.insert("user3".to_owned(), 10000);
+        redlist_add(&pool, &retry, ns, &rules).await?;
+        let limited = RedlistScan {
+            count: 1,
+            max_entries: 1,
+            time_budget: Duration::ZERO,
+        };
+        let dyn_redlist = redlist_load(cli.clone(), Some(cli.clone()), ns, ts, 0, &limited).await?;
+        assert_eq!(1, dyn_redlist.1.len(), "should stop at max_entries");
+        let dyn_redlist = redlist_load(
+            cli.clone(),
+            Some(cli.clone()),
+            ns,
+            ts,
+            dyn_redlist.0,
+            &limited,
+        )
+        .await?;
+        assert!(
+            dyn_redlist.1.contains_key("user3"),
+            "should go on from the cursor"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,2 @@
+// REDLIMIT is the redlimit function library loaded into Redis, see redlimit.lua.
+pub static REDLIMIT: &str = include_str!("redlimit.lua");
@@ -0,0 +1,256 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+};
+
+// PatternList indexes redlist entries that match more than one id: id prefixes
+// ending with '*' (e.g. "tenant123:*") and IP networks in CIDR notation (e.g.
+// "10.0.0.0/8"). Lookups only probe the prefix lengths that exist. The entries
+// are kept in persistent maps, so a clone shares them.
+#[derive(Clone, Default)]
+pub struct PatternList {
+    prefixes: im::HashMap<String, (String, u64)>, // prefix -> (entry, ttl)
+    prefix_lens: BTreeSet<usize>,
+    nets: HashMap<(bool, u8), im::HashMap<u128, (String, u64)>>, // (is ipv6, prefix len) -> network -> (entry, ttl)
+}
+
+#[derive(PartialEq, Debug)]
+enum Pattern {
+    Prefix(String),
+    Net(bool, u8, u128),
+}
+
+impl Pattern {
+    fn parse(entry: &str) -> Option<Self> {
+        if let Some(prefix) = entry.strip_suffix('*') {
+            return Some(Pattern::Prefix(prefix.to_string()));
+        }
+
+        let (addr, len) = entry.split_once('/')?;
+        let addr = addr.parse::<IpAddr>().ok()?;
+        let len = len.parse::<u8>().ok()?;
+        let (v6, bits, max) = ip_bits(&addr);
+        if len > max {
+            return None;
+        }
+        Some(Pattern::Net(v6, len, mask(bits, len, max)))
+    }
+}
+
+// check_pattern returns why the redlist entry is a malformed pattern: "*" (the
+// empty prefix) matches every id, '*' is only allowed at the end, and an IP
+// address followed by '/' should be a valid CIDR.
+pub fn check_pattern(entry: &str) -> Option<String> {
+    match entry.find('*') {
+        Some(0) if entry.len() == 1 => return Some("\"*\" matches every id".to_string()),
+        Some(i) if i != entry.len() - 1 => {
+            return Some("'*' is only allowed at the end".to_string())
+        }
+        Some(_) => return None,
+        None => {}
+    }
+    let addr = entry.split_once('/')?.0;
+    let is_addr = addr.contains(['.', ':'])
+        && addr
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() || c == '.' || c == ':');
+    if is_addr && Pattern::parse(entry).is_none() {
+        return Some("malformed CIDR".to_string());
+    }
+    None
+}
+
+// is_pattern reports whether the redlist entry is an id prefix or a CIDR that
+// matches more than one id.
+pub fn is_pattern(entry: &str) -> bool {
+    Pattern::parse(entry).is_some()
+}
+
+fn ip_bits(addr: &IpAddr) -> (bool, u128, u8) {
+    match addr {
+        IpAddr::V4(v4) => (false, u32::from(*v4) as u128, 32),
+        IpAddr::V6(v6) => (true, u128::from(*v6), 128),
+    }
+}
+
+fn mask(bits: u128, len: u8, max: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        bits >> (max - len) << (max - len)
+    }
+}
+
+impl PatternList {
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty() && self.nets.is_empty()
+    }
+
+    // insert returns false if the entry is not a pattern.
+    pub fn insert(&mut self, entry: &str, ttl: u64) -> bool {
+        match Pattern::parse(entry) {
+            Some(Pattern::Prefix(prefix)) => {
+                self.prefix_lens.insert(prefix.len());
+                self.prefixes.insert(prefix, (entry.to_string(), ttl));
+                true
+            }
+            Some(Pattern::Net(v6, len, net)) => {
+                self.nets
+                    .entry((v6, len))
+                    .or_default()
+                    .insert(net, (entry.to_string(), ttl));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // remove removes the entry if it is a pattern.
+    pub fn remove(&mut self, entry: &str) {
+        match Pattern::parse(entry) {
+            Some(Pattern::Prefix(prefix)) => {
+                self.prefixes.remove(&prefix);
+                let prefixes = &self.prefixes;
+                self.prefix_lens
+                    .retain(|len| prefixes.keys().any(|p| p.len() == *len));
+            }
+            Some(Pattern::Net(v6, len, net)) => {
+                if let Some(nets) = self.nets.get_mut(&(v6, len)) {
+                    nets.remove(&net);
+                    if nets.is_empty() {
+                        self.nets.remove(&(v6, len));
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    pub fn retain(&mut self, now: u64) {
+        self.prefixes.retain(|_, v| v.1 > now);
+        let prefixes = &self.prefixes;
+        self.prefix_lens
+            .retain(|len| prefixes.keys().any(|p| p.len() == *len));
+        self.nets.retain(|_, nets| {
+            nets.retain(|_, v| v.1 > now);
+            !nets.is_empty()
+        });
+    }
+
+    // get returns the matched entry with the latest ttl.
+    pub fn get(&self, id: &str) -> Option<(&str, u64)> {
+        let prefixes = self
+            .prefix_lens
+            .iter()
+            .filter_map(|len| self.prefixes.get(id.get(..*len)?));
+
+        let addr = if self.nets.is_empty() {
+            None
+        } else {
+            id.parse::<IpAddr>().ok().map(|addr| ip_bits(&addr))
+        };
+        let nets = self.nets.iter().filter_map(|((is_v6, len), nets)| {
+            let (v6, bits, max) = addr?;
+            if *is_v6 != v6 {
+                return None;
+            }
+            nets.get(&mask(bits, *len, max))
+        });
+
+        prefixes
+            .chain(nets)
+            .max_by_key(|v| v.1)
+            .map(|v| (v.0.as_str(), v.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_works() {
+        assert_eq!(None, Pattern::parse("user1"));
+        assert_eq!(None, Pattern::parse("10.0.0.1"));
+        assert_eq!(None, Pattern::parse("10.0.0.0/33"));
+        assert_eq!(None, Pattern::parse("a/8"));
+        assert_eq!(
+            Some(Pattern::Prefix("tenant123:".to_string())),
+            Pattern::parse("tenant123:*")
+        );
+        assert_eq!(
+            Some(Pattern::Net(false, 8, 10 << 24)),
+            Pattern::parse("10.1.2.3/8")
+        );
+        assert_eq!(
+            Some(Pattern::Net(true, 16, 0xfe80 << 112)),
+            Pattern::parse("fe80::1/16")
+        );
+        assert_eq!(Some(Pattern::Net(false, 0, 0)), Pattern::parse("1.2.3.4/0"));
+    }
+
+    #[test]
+    fn check_pattern_works() {
+        assert_eq!(None, check_pattern("user1"));
+        assert_eq!(None, check_pattern("tenant123:*"));
+        assert_eq!(None, check_pattern("10.0.0.0/8"));
+        assert_eq!(None, check_pattern("fe80::/16"));
+        assert_eq!(None, check_pattern("GET /v1/file"));
+        assert_eq!(
+            Some("\"*\" matches every id".to_string()),
+            check_pattern("*")
+        );
+        assert_eq!(
+            Some("'*' is only allowed at the end".to_string()),
+            check_pattern("tenant*:user1")
+        );
+        assert!(check_pattern("**").is_some());
+        assert_eq!(
+            Some("malformed CIDR".to_string()),
+            check_pattern("10.0.0.0/33")
+        );
+        assert!(check_pattern("10.0.0/8").is_some());
+        assert!(check_pattern("10.0.0.0/x").is_some());
+        assert!(check_pattern("fe80::/129").is_some());
+    }
+
+    #[test]
+    fn pattern_list_works() {
+        let mut list = PatternList::default();
+        assert!(list.is_empty());
+        assert!(!list.insert("user1", 100));
+        assert!(list.is_empty());
+
+        assert!(list.insert("tenant123:*", 100));
+        assert!(list.insert("tenant1*", 200));
+        assert!(list.insert("10.0.0.0/8", 100));
+        assert!(list.insert("10.1.0.0/16", 300));
+        assert!(list.insert("fe80::/16", 100));
+
+        assert_eq!(None, list.get("user1"));
+        assert_eq!(Some(("tenant1*", 200)), list.get("tenant123:user1"));
+        assert_eq!(Some(("tenant1*", 200)), list.get("tenant1"));
+        assert_eq!(None, list.get("tenant"));
+        assert_eq!(Some(("10.0.0.0/8", 100)), list.get("10.2.3.4"));
+        assert_eq!(Some(("10.1.0.0/16", 300)), list.get("10.1.3.4"));
+        assert_eq!(None, list.get("11.1.3.4"));
+        assert_eq!(Some(("fe80::/16", 100)), list.get("fe80::1"));
+        assert_eq!(None, list.get("fe81::1"));
+
+        list.retain(100);
+        assert_eq!(Some(("tenant1*", 200)), list.get("tenant123:user1"));
+        assert_eq!(None, list.get("10.2.3.4"));
+        assert_eq!(Some(("10.1.0.0/16", 300)), list.get("10.1.3.4"));
+
+        list.remove("user1");
+        list.remove("10.1.0.0/16");
+        assert_eq!(None, list.get("10.1.3.4"));
+        assert_eq!(Some(("tenant1*", 200)), list.get("tenant123:user1"));
+
+        list.retain(200);
+        assert_eq!(None, list.get("tenant123:user1"));
+
+        list.retain(300);
+        assert!(list.is_empty());
+    }
+}
@@ -0,0 +1,6 @@
+use sentry_core::Level;
+
+// error reports an error event, it is a no-op if Sentry is not initialized.
+pub fn error(msg: &str) {
+    sentry_core::capture_message(msg, Level::Error);
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    sync::Arc,
+};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    conf,
+    context::unix_ms,
+    redis::{self, RedisPool},
+    redlimit::{self, redlist_load, FnClient, Namespaces, RedRuleEntry, RedlistScan},
+};
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+// Snapshot is the dynamic state (redlist and redrules) of the namespaces, for
+// backup and migration between Redis instances. The expiration times are
+// absolute, the expired entries are skipped on import.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub version: u32,
+    pub ts: u64, // unix ms
+    pub namespaces: BTreeMap<String, NsSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct NsSnapshot {
+    // id -> expiration time in ms
+    pub redlist: BTreeMap<String, u64>,
+    // (scope, path, quantity, expiration time in ms), as stored in Redis
+    pub redrules: Vec<(String, String, u64, u64)>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct ImportResult {
+    pub redlist: usize,
+    pub redrules: usize,
+    // the namespaces of the snapshot that are not configured
+    pub skipped: Vec<String>,
+}
+
+// export reads the redlist and redrules of the namespaces from Redis.
+pub async fn export(pool: &RedisPool, namespaces: &[&str], now: u64) -> Result<Snapshot> {
+    let cli = FnClient::get(pool).await?;
+    let mut snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        ts: now,
+        namespaces: BTreeMap::new(),
+    };
+    for ns in namespaces {
+        let (_, redlist) = redlist_load(
+            cli.clone(),
+            Some(cli.clone()),
+            ns,
+            now,
+            0,
+            &RedlistScan::default(),
+        )
+        .await?;
+        let data = redis::timed(&cli.cli, cli.fcall_ro("redrules_all", &[ns]))
+            .await?
+            .to::<Vec<String>>()?;
+        let mut redrules: Vec<(String, String, u64, u64)> = data
+            .iter()
+            .filter_map(|s| serde_json::from_str::<RedRuleEntry>(s).ok())
+            .filter(|v| v.3 > now)
+            .map(|v| (v.0, v.1, v.2, v.3))
+            .collect();
+        redrules.sort();
+        snapshot.namespaces.insert(
+            ns.to_string(),
+            NsSnapshot {
+                redlist: redlist.into_iter().collect(),
+                redrules,
+            },
+        );
+    }
+    Ok(snapshot)
+}
+
+// import adds the unexpired entries of the configured namespaces to Redis with
+// the remaining TTLs. The existing entries are kept or overwritten.
+pub async fn import(
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    namespaces: &[&str],
+    snapshot: &Snapshot,
+    now: u64,
+) -> Result<ImportResult> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(Error::msg(format!(
+            "unsupported snapshot version: {}",
+            snapshot.version
+        )));
+    }
+    let mut rt = ImportResult::default();
+    for (ns, data) in &snapshot.namespaces {
+        if !namespaces.contains(&ns.as_str()) {
+            rt.skipped.push(ns.clone());
+            continue;
+        }
+        let redlist: HashMap<String, u64> = data
+            .redlist
+            .iter()
+            .filter(|(_, expire)| **expire > now)
+            .map(|(id, expire)| (id.clone(), expire - now))
+            .collect();
+        redlimit::redlist_add(pool, retry, ns, &redlist).await?;
+        rt.redlist += redlist.len();
+
+        let mut scopes: HashMap<&str, HashMap<String, (u64, u64)>> = HashMap::new();
+        for (scope, path, quantity, expire) in &data.redrules {
+            if *expire > now {
+                scopes
+                    .entry(scope)
+                    .or_default()
+                    .insert(path.clone(), (*quantity, expire - now));
+            }
+        }
+        for (scope, rules) in scopes {
+            redlimit::redrules_add(pool, retry, ns, scope, &rules).await?;
+            rt.redrules += rules.len();
+        }
+    }
+    Ok(rt)
+}
+
+// cached reads the dynamic state cached by this instance, no Redis calls.
+pub fn cached(namespaces: &Namespaces, now: u64) -> Snapshot {
+    let mut snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        ts: now,
+        namespaces: BTreeMap::new(),
+    };
+    for rules in namespaces.iter() {
+        let mut redrules: Vec<(String, String, u64, u64)> = rules
+            .redrules(now)
+            .into_iter()
+            .filter_map(|(k, (quantity, expire))| {
+                let (scope, path) = k.split_once(':')?;
+                Some((scope.to_string(), path.to_string(), quantity, expire))
+            })
+            .collect();
+        redrules.sort();
+        snapshot.namespaces.insert(
+            rules.ns.as_str().to_string(),
+            NsSnapshot {
+                redlist: rules.redlist(now).into_iter().collect(),
+                redrules,
+            },
+        );
+    }
+    snapshot
+}
+
+// validate checks the redlist ids of the snapshot before it is imported, the
+// invalid ones are keyed by "<ns>:<id>", see redlimit::validate_redlist.
+pub fn validate(snapshot: &Snapshot) -> Result<(), redlimit::InputError> {
+    let mut entries = BTreeMap::new();
+    for (ns, data) in &snapshot.namespaces {
+        for id in data.redlist.keys() {
+            if let Some(err) = redlimit::check_redlist_id(id) {
+                entries.insert(format!("{}:{}", ns, id), err);
+            }
+        }
+    }
+    redlimit::InputError::entries("invalid snapshot redlist", entries)
+}
+
+// restore caches the unexpired entries of the configured namespaces, the sync
+// job still loads the redlist from cursor 0. It returns the restored entries.
+pub fn restore(namespaces: &Namespaces, snapshot: &Snapshot, now: u64) -> Result<usize> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(Error::msg(format!(
+            "unsupported snapshot version: {}",
+            snapshot.version
+        )));
+    }
+    let mut n = 0;
+    for (ns, data) in &snapshot.namespaces {
+        let rules = match namespaces.get(ns) {
+            Some(rules) => rules,
+            None => continue,
+        };
+        let redlist: HashMap<String, u64> = data
+            .redlist
+            .iter()
+            .filter(|(_, expire)| **expire > now)
+            .map(|(id, expire)| (id.clone(), *expire))
+            .collect();
+        let redrules: HashMap<String, (u64, u64)> = data
+            .redrules
+            .iter()
+            .filter(|v| v.3 > now)
+            .map(|v| (redlimit::NS::redrules_key(&v.0, &v.1), (v.2, v.3)))
+            .collect();
+        n += redlist.len() + redrules.len();
+        rules.dyn_update(now, 0, redlist, redrules);
+    }
+    Ok(n)
+}
+
+// load_file restores the snapshot file written by the snapshot job, a missing
+// file is not an error.
+pub async fn load_file(namespaces: &Namespaces, path: &str, now: u64) -> Result<usize> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&data)?;
+    restore(namespaces, &snapshot, now)
+}
+
+// write_file writes the cached state to a temporary file and renames it, so
+// that a crash never leaves a partial snapshot.
+pub async fn write_file(namespaces: &Namespaces, path: &str, now: u64) -> Result<()> {
+    let data = serde_json::to_vec(&cached(namespaces, now))?;
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+// init_snapshot_job writes the cached state to the file every interval seconds
+// and on shutdown, for a fast cold start with load_file.
+pub fn init_snapshot_job(
+    namespaces: Arc<Namespaces>,
+    path: String,
+    interval_secs: u64,
+) -> (JoinHandle<()>, CancellationToken) {
+    let cancel_snapshot = CancellationToken::new();
+    let stop_signal = cancel_snapshot.clone();
+    (
+        tokio::spawn(async move {
+            loop {
+                let stopped = tokio::select! {
+                    _ = stop_signal.cancelled() => true,
+                    _ = sleep(Duration::from_secs(interval_secs.max(1))) => false,
+                };
+                if let Err(err) = write_file(&namespaces, &path, unix_ms()).await {
+                    log::error!(target: "snapshot", "write {} error: {}", path, err);
+                }
+                if stopped {
+                    return;
+                }
+            }
+        }),
+        cancel_snapshot,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_works() -> anyhow::Result<()> {
+        let mut snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            ts: 1700000000000,
+            namespaces: BTreeMap::new(),
+        };
+        let mut data = NsSnapshot::default();
+        data.redlist.insert("user1".to_string(), 1700000060000);
+        data.redrules.push((
+            "core".to_string(),
+            "GET /v1/file/list".to_string(),
+            5,
+            1700000060000,
+        ));
+        snapshot.namespaces.insert("RL".to_string(), data);
+
+        let s = serde_json::to_string(&snapshot)?;
+        assert_eq!(
+            r#"{"version":1,"ts":1700000000000,"namespaces":{"RL":{"redlist":{"user1":1700000060000},"redrules":[["core","GET /v1/file/list",5,1700000060000]]}}}"#,
+            s
+        );
+        assert_eq!(snapshot, serde_json::from_str(&s)?);
+
+        assert!(validate(&snapshot).is_ok());
+        let data = snapshot.namespaces.get_mut("RL").unwrap();
+        data.redlist.insert("*".to_string(), 1700000060000);
+        let err = validate(&snapshot).unwrap_err();
+        assert_eq!("invalid snapshot redlist", err.message);
+        assert_eq!("\"*\" matches every id", err.entries["RL:*"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_file_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let path = std::env::temp_dir()
+            .join(format!("redlimit-snapshot-{}.json", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let namespaces = Namespaces::new(&cfg);
+        assert_eq!(0, load_file(&namespaces, &path, 1000).await?);
+
+        let rules = namespaces.get("").unwrap();
+        rules.dyn_update(
+            1000,
+            5,
+            HashMap::from([("user1".to_string(), 5000), ("user2".to_string(), 2000)]),
+            HashMap::from([(
+                redlimit::NS::redrules_key("core", "GET /v1/file/list"),
+                (5, 5000),
+            )]),
+        );
+        write_file(&namespaces, &path, 1000).await?;
+
+        let restarted = Namespaces::new(&cfg);
+        // user2 expired before the restart
+        assert_eq!(2, load_file(&restarted, &path, 3000).await?);
+        let rules = restarted.get("").unwrap();
+        assert_eq!(
+            HashMap::from([("user1".to_string(), 5000)]),
+            rules.redlist(3000)
+        );
+        assert_eq!(
+            HashMap::from([("core:GET /v1/file/list".to_string(), (5, 5000))]),
+            rules.redrules(3000)
+        );
+        assert_eq!(cached(&namespaces, 3000), cached(&restarted, 3000));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
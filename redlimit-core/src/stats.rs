@@ -0,0 +1,373 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+const WINDOW_SECS: u64 = 300;
+const OFFENDER_MINUTES: u64 = 5;
+// The offenders are sharded by id, so that the limited decisions of distinct ids
+// rarely wait for the same lock.
+const OFFENDER_SHARDS: usize = 16;
+// The max number of ids counted per scope per minute, new ids are ignored when
+// full so that the memory is bounded under a flood of distinct ids. Each shard
+// counts up to its share.
+const OFFENDER_MAX_IDS: usize = 10000;
+
+pub static STATS: Lazy<Stats> = Lazy::new(Stats::new);
+
+// Stats keeps per-second counters of limiting requests in a ring of the last
+// 5 minutes, to serve rolling aggregates without a metrics stack.
+pub struct Stats {
+    buckets: Vec<Bucket>,
+    last_sync: AtomicU64,
+    offenders: Vec<Mutex<Vec<OffenderBucket>>>,
+    hasher: RandomState,
+}
+
+// OffenderBucket counts the limited decisions by scope and id in a minute.
+#[derive(Default)]
+struct OffenderBucket {
+    min: u64,
+    scopes: HashMap<String, HashMap<String, u64>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Offender {
+    pub id: String,
+    pub limited: u64,
+}
+
+// Bucket counts the requests of a second with atomics, so that recording takes
+// no lock.
+#[derive(Default)]
+struct Bucket {
+    sec: AtomicU64,
+    requests: AtomicU64,
+    limited: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct Aggregate {
+    pub requests: u64,
+    pub limited: u64,
+    pub errors: u64,
+    pub limited_rate: f64,
+    pub error_rate: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Snapshot {
+    #[serde(rename = "1m")]
+    pub m1: Aggregate,
+    #[serde(rename = "5m")]
+    pub m5: Aggregate,
+    pub sync_lag: Option<u64>, // milliseconds since the last successful sync
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            buckets: (0..WINDOW_SECS).map(|_| Bucket::default()).collect(),
+            last_sync: AtomicU64::new(0),
+            offenders: (0..OFFENDER_SHARDS)
+                .map(|_| {
+                    Mutex::new(
+                        (0..OFFENDER_MINUTES)
+                            .map(|_| OffenderBucket::default())
+                            .collect(),
+                    )
+                })
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn offender_shard(&self, id: &str) -> &Mutex<Vec<OffenderBucket>> {
+        let mut hasher = self.hasher.build_hasher();
+        id.hash(&mut hasher);
+        &self.offenders[hasher.finish() as usize % OFFENDER_SHARDS]
+    }
+
+    // record_limited counts a limited decision of the id in the scope.
+    pub fn record_limited(&self, now: u64, scope: &str, id: &str) {
+        let min = now / 60000;
+        let mut buckets = self.offender_shard(id).lock().unwrap();
+        let b = &mut buckets[(min % OFFENDER_MINUTES) as usize];
+        if b.min != min {
+            b.min = min;
+            b.scopes.clear();
+        }
+        let ids = b.scopes.entry(scope.to_string()).or_default();
+        if let Some(n) = ids.get_mut(id) {
+            *n += 1;
+        } else if ids.len() < OFFENDER_MAX_IDS / OFFENDER_SHARDS {
+            ids.insert(id.to_string(), 1);
+        }
+    }
+
+    // erase removes the id from the offenders of all scopes.
+    pub fn erase(&self, id: &str) {
+        let mut buckets = self.offender_shard(id).lock().unwrap();
+        for b in buckets.iter_mut() {
+            for ids in b.scopes.values_mut() {
+                ids.remove(id);
+            }
+        }
+    }
+
+    // offenders returns the top ids with the most limited decisions in the last
+    // 5 minutes by scope, or of the given scope if not empty.
+    pub fn offenders(&self, now: u64, scope: &str, top: usize) -> HashMap<String, Vec<Offender>> {
+        let min = now / 60000;
+        let mut counts: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for shard in &self.offenders {
+            let buckets = shard.lock().unwrap();
+            for b in buckets.iter() {
+                if b.min > min || b.min + OFFENDER_MINUTES <= min {
+                    continue;
+                }
+                for (s, ids) in &b.scopes {
+                    if !scope.is_empty() && s != scope {
+                        continue;
+                    }
+                    let c = counts.entry(s.clone()).or_default();
+                    for (id, n) in ids {
+                        *c.entry(id.clone()).or_default() += n;
+                    }
+                }
+            }
+        }
+
+        counts
+            .into_iter()
+            .map(|(s, ids)| {
+                let mut ids: Vec<(String, u64)> = ids.into_iter().collect();
+                ids.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                ids.truncate(top);
+                let offenders = ids
+                    .into_iter()
+                    .map(|(id, limited)| Offender { id, limited })
+                    .collect();
+                (s, offenders)
+            })
+            .collect()
+    }
+
+    // record a limiting request at `now` milliseconds.
+    pub fn record(&self, now: u64, limited: bool, error: bool) {
+        let sec = now / 1000;
+        let b = &self.buckets[(sec % WINDOW_SECS) as usize];
+        b.claim(sec);
+        b.requests.fetch_add(1, Ordering::Relaxed);
+        if limited {
+            b.limited.fetch_add(1, Ordering::Relaxed);
+        }
+        if error {
+            b.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_sync(&self, now: u64) {
+        self.last_sync.store(now, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, now: u64) -> Snapshot {
+        let sec = now / 1000;
+        let mut m1 = Aggregate::default();
+        let mut m5 = Aggregate::default();
+        for b in self.buckets.iter() {
+            let bsec = b.sec.load(Ordering::Acquire);
+            if bsec > sec || bsec + WINDOW_SECS <= sec {
+                continue;
+            }
+            let counts = (
+                b.requests.load(Ordering::Relaxed),
+                b.limited.load(Ordering::Relaxed),
+                b.errors.load(Ordering::Relaxed),
+            );
+            if counts.0 == 0 {
+                continue;
+            }
+            m5.add(counts);
+            if bsec + 60 > sec {
+                m1.add(counts);
+            }
+        }
+        m1.rates();
+        m5.rates();
+
+        let last_sync = self.last_sync.load(Ordering::Relaxed);
+        Snapshot {
+            m1,
+            m5,
+            sync_lag: if last_sync > 0 {
+                Some(now.saturating_sub(last_sync))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl Bucket {
+    // claim resets the bucket for the second if it counts another one, only the
+    // caller that swaps the second resets it. A few requests recorded by others
+    // in the meantime may be lost, which is fine for the rolling aggregates.
+    fn claim(&self, sec: u64) {
+        let old = self.sec.load(Ordering::Acquire);
+        if old != sec
+            && self
+                .sec
+                .compare_exchange(old, sec, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.requests.store(0, Ordering::Relaxed);
+            self.limited.store(0, Ordering::Relaxed);
+            self.errors.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Aggregate {
+    fn add(&mut self, (requests, limited, errors): (u64, u64, u64)) {
+        self.requests += requests;
+        self.limited += limited;
+        self.errors += errors;
+    }
+
+    fn rates(&mut self) {
+        if self.requests > 0 {
+            self.limited_rate = self.limited as f64 / self.requests as f64;
+            self.error_rate = self.errors as f64 / self.requests as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_works() {
+        let stats = Stats::new();
+        let s = stats.snapshot(1_000_000);
+        assert_eq!(Aggregate::default(), s.m1);
+        assert_eq!(None, s.sync_lag);
+
+        stats.record(1_000_000, false, false);
+        stats.record(1_000_500, true, false);
+        stats.record(1_100_000, true, true);
+        stats.record(1_200_000, false, false);
+        stats.record_sync(1_190_000);
+
+        let s = stats.snapshot(1_200_000);
+        assert_eq!(1, s.m1.requests);
+        assert_eq!(4, s.m5.requests);
+        assert_eq!(2, s.m5.limited);
+        assert_eq!(1, s.m5.errors);
+        assert_eq!(0.5, s.m5.limited_rate);
+        assert_eq!(0.25, s.m5.error_rate);
+        assert_eq!(Some(10_000), s.sync_lag);
+
+        let s = stats.snapshot(1_350_000);
+        assert_eq!(0, s.m1.requests);
+        assert_eq!(2, s.m5.requests, "expired the first second");
+
+        // the ring slot is reused after the window
+        stats.record(1_300_000, false, false);
+        let s = stats.snapshot(1_300_000);
+        assert_eq!(3, s.m5.requests);
+    }
+
+    #[test]
+    fn offenders_works() {
+        let stats = Stats::new();
+        assert!(stats.offenders(60_000, "", 10).is_empty());
+
+        stats.record_limited(60_000, "core", "u1");
+        stats.record_limited(120_000, "core", "u2");
+        stats.record_limited(130_000, "core", "u2");
+        stats.record_limited(140_000, "core", "u3");
+        stats.record_limited(140_000, "file", "u1");
+
+        let rt = stats.offenders(150_000, "", 2);
+        assert_eq!(2, rt.len());
+        assert_eq!(
+            vec![
+                Offender {
+                    id: "u2".to_string(),
+                    limited: 2
+                },
+                Offender {
+                    id: "u1".to_string(),
+                    limited: 1
+                },
+            ],
+            rt["core"]
+        );
+        assert_eq!(1, rt["file"].len());
+
+        let rt = stats.offenders(150_000, "file", 10);
+        assert_eq!(1, rt.len());
+        assert_eq!(1, rt["file"][0].limited);
+
+        // the first minute expired
+        let rt = stats.offenders(360_000, "core", 10);
+        assert_eq!(2, rt["core"].len());
+        assert_eq!("u2", rt["core"][0].id);
+
+        stats.erase("u2");
+        let rt = stats.offenders(150_000, "", 10);
+        assert!(rt["core"].iter().all(|o| o.id != "u2"));
+        assert_eq!(2, rt["core"].len());
+
+        // the ids are bounded per scope per minute
+        for i in 0..OFFENDER_MAX_IDS * 2 {
+            stats.record_limited(600_000, "flood", &format!("id{}", i));
+        }
+        let rt = stats.offenders(600_000, "flood", OFFENDER_MAX_IDS * 2);
+        assert!(rt["flood"].len() <= OFFENDER_MAX_IDS);
+        assert!(rt["flood"].len() > OFFENDER_MAX_IDS / 2);
+    }
+
+    #[test]
+    fn concurrent_record_works() {
+        let stats = std::sync::Arc::new(Stats::new());
+        stats.record(2_000_000, false, false);
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let stats = stats.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        stats.record(2_000_000 + i % 1000, i % 2 == 0, false);
+                        stats.record_limited(2_000_000, "core", &format!("u{}", t));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let s = stats.snapshot(2_000_999);
+        assert_eq!(8001, s.m1.requests);
+        assert_eq!(4000, s.m1.limited);
+        let rt = stats.offenders(2_000_999, "core", 10);
+        assert_eq!(8, rt["core"].len());
+        assert!(rt["core"].iter().all(|o| o.limited == 1000));
+    }
+}
@@ -0,0 +1,7 @@
+use opentelemetry::{global, global::BoxedTracer};
+
+// tracer returns the global tracer, the spans are no-op if no tracer provider
+// is installed.
+pub fn tracer() -> BoxedTracer {
+    global::tracer("redlimit")
+}
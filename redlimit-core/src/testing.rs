@@ -0,0 +1,153 @@
+// testing provides the Redis server of the tests that call Redis. With the
+// "redis-harness" feature, every TestRedis starts a disposable server, so that
+// the tests don't depend on a local Redis or mutate its keys: a redis-server
+// process (REDLIMIT_TEST_REDIS_SERVER, default "redis-server" in PATH) on a
+// free port, or a docker container of REDLIMIT_TEST_REDIS_IMAGE (default
+// "redis:7-alpine") if redis-server is not found. The server is killed when the
+// TestRedis is dropped. Without the feature, the tests use the Redis of the
+// default config.
+
+use anyhow::Result;
+
+use super::conf;
+
+pub struct TestRedis {
+    // the default config, its redis section points to the disposable server
+    pub cfg: conf::Conf,
+    _server: Option<Server>,
+}
+
+impl TestRedis {
+    pub async fn start() -> Result<Self> {
+        let mut cfg = conf::Conf::new()?;
+        let server = Server::start(&mut cfg.redis).await?;
+        Ok(TestRedis {
+            cfg,
+            _server: server,
+        })
+    }
+}
+
+#[cfg(not(feature = "redis-harness"))]
+struct Server;
+
+#[cfg(not(feature = "redis-harness"))]
+impl Server {
+    async fn start(_cfg: &mut conf::Redis) -> Result<Option<Self>> {
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "redis-harness")]
+enum Server {
+    Process(std::process::Child),
+    Container(String),
+}
+
+#[cfg(feature = "redis-harness")]
+impl Server {
+    async fn start(cfg: &mut conf::Redis) -> Result<Option<Self>> {
+        use std::{io::ErrorKind, net::TcpListener, process::Command, process::Stdio};
+
+        let bin =
+            std::env::var("REDLIMIT_TEST_REDIS_SERVER").unwrap_or_else(|_| "redis-server".into());
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let spawned = Command::new(&bin)
+            .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .args(["--save", "", "--appendonly", "no"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let (server, port) = match spawned {
+            Ok(child) => (Server::Process(child), port),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let image = std::env::var("REDLIMIT_TEST_REDIS_IMAGE")
+                    .unwrap_or_else(|_| "redis:7-alpine".into());
+                let id = docker(&["run", "-d", "--rm", "-p", "127.0.0.1::6379", &image])?;
+                let server = Server::Container(id.clone());
+                // e.g. "127.0.0.1:49153"
+                let addr = docker(&["port", &id, "6379/tcp"])?;
+                let port = addr
+                    .lines()
+                    .next()
+                    .and_then(|addr| addr.rsplit(':').next())
+                    .and_then(|port| port.parse().ok())
+                    .ok_or_else(|| anyhow::Error::msg(format!("invalid docker port {}", addr)))?;
+                (server, port)
+            }
+            Err(err) => return Err(anyhow::Error::msg(format!("{} error: {}", bin, err))),
+        };
+
+        wait_ready(port).await?;
+        cfg.url = String::new();
+        cfg.host = "127.0.0.1".to_string();
+        cfg.port = port;
+        cfg.username = String::new();
+        cfg.password = String::new();
+        cfg.password_file = String::new();
+        cfg.db = 0;
+        cfg.shards = Vec::new();
+        cfg.replica = String::new();
+        Ok(Some(server))
+    }
+}
+
+#[cfg(feature = "redis-harness")]
+impl Drop for Server {
+    fn drop(&mut self) {
+        match self {
+            Server::Process(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            Server::Container(id) => {
+                let _ = docker(&["rm", "-f", id]);
+            }
+        }
+    }
+}
+
+// docker runs the docker command, and returns its trimmed stdout.
+#[cfg(feature = "redis-harness")]
+fn docker(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("docker").args(args).output()?;
+    if !output.status.success() {
+        return Err(anyhow::Error::msg(format!(
+            "docker {} error: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// wait_ready waits until the server responds to PING, a published docker port
+// accepts connections before Redis is up.
+#[cfg(feature = "redis-harness")]
+async fn wait_ready(port: u16) -> Result<()> {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::{sleep, Duration, Instant},
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)).await {
+            let mut buf = [0u8; 7];
+            if stream.write_all(b"PING\r\n").await.is_ok()
+                && stream.read_exact(&mut buf).await.is_ok()
+                && &buf == b"+PONG\r\n"
+            {
+                return Ok(());
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow::Error::msg(format!(
+                "test redis on port {} is not ready",
+                port
+            )));
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+}
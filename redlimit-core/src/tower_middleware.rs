@@ -0,0 +1,216 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{header::HeaderValue, Request, Response, StatusCode};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use super::guard::Guard;
+
+type Extract<B> = Arc<dyn Fn(&Request<B>) -> Option<String> + Send + Sync>;
+
+// RateLimitLayer is the tower layer (for axum, tonic or hyper services) that
+// enforces the limits with the Guard, the limited requests are responded with
+// 429. The scope, path and id of a request are extracted by the closures, the
+// request is not limited if any of them is None. The path defaults to
+// "<method> <path>". The response body must be From<String>, as axum's Body.
+//
+//     Router::new().layer(RateLimitLayer::new(
+//         guard.clone(),
+//         |_| Some("core".to_string()),
+//         |req| req.headers().get("x-user-id")?.to_str().ok().map(String::from),
+//     ))
+pub struct RateLimitLayer<B> {
+    guard: Arc<Guard>,
+    namespace: Arc<String>,
+    scope: Extract<B>,
+    path: Extract<B>,
+    id: Extract<B>,
+}
+
+impl<B> RateLimitLayer<B> {
+    pub fn new(
+        guard: Arc<Guard>,
+        scope: impl Fn(&Request<B>) -> Option<String> + Send + Sync + 'static,
+        id: impl Fn(&Request<B>) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        RateLimitLayer {
+            guard,
+            namespace: Arc::new(String::new()),
+            scope: Arc::new(scope),
+            path: Arc::new(|req: &Request<B>| {
+                Some(format!("{} {}", req.method(), req.uri().path()))
+            }),
+            id: Arc::new(id),
+        }
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Arc::new(namespace.to_string());
+        self
+    }
+
+    pub fn path(
+        mut self,
+        path: impl Fn(&Request<B>) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.path = Arc::new(path);
+        self
+    }
+}
+
+impl<B> Clone for RateLimitLayer<B> {
+    fn clone(&self) -> Self {
+        RateLimitLayer {
+            guard: self.guard.clone(),
+            namespace: self.namespace.clone(),
+            scope: self.scope.clone(),
+            path: self.path.clone(),
+            id: self.id.clone(),
+        }
+    }
+}
+
+impl<S, B> Layer<S> for RateLimitLayer<B> {
+    type Service = RateLimitService<S, B>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub struct RateLimitService<S, B> {
+    inner: S,
+    layer: RateLimitLayer<B>,
+}
+
+impl<S: Clone, B> Clone for RateLimitService<S, B> {
+    fn clone(&self) -> Self {
+        RateLimitService {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<S, B, ResBody> Service<Request<B>> for RateLimitService<S, B>
+where
+    S: Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+    ResBody: From<String> + Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let keys = (self.layer.scope)(&req)
+            .zip((self.layer.path)(&req))
+            .zip((self.layer.id)(&req));
+        // the ready inner service is taken, a clone is left for the next call
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let guard = self.layer.guard.clone();
+        let namespace = self.layer.namespace.clone();
+        Box::pin(async move {
+            let d = match keys {
+                Some(((scope, path), id)) => {
+                    match guard.check(&namespace, &scope, &path, &id).await {
+                        Ok(d) => Some(d),
+                        Err(err) => {
+                            log::warn!("rate limit error: {}", err);
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let mut res = match d {
+                Some(ref d) if d.is_limited() => {
+                    let body = serde_json::json!({
+                        "error": {"code": 429, "message": "too many requests"}
+                    });
+                    let mut res = Response::new(ResBody::from(body.to_string()));
+                    *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                    res.headers_mut().insert(
+                        http::header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json"),
+                    );
+                    res
+                }
+                _ => inner.call(req).await?,
+            };
+            if let Some(d) = d {
+                for (name, value) in d.headers() {
+                    res.headers_mut().insert(name, HeaderValue::from(value));
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{conf, context::unix_ms};
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn rate_limit_layer_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let guard = Arc::new(Guard::new(&cfg).await?);
+        let layer = RateLimitLayer::new(
+            guard,
+            |_| Some("core".to_string()),
+            |req: &Request<String>| {
+                req.headers()
+                    .get("x-user-id")?
+                    .to_str()
+                    .ok()
+                    .map(String::from)
+            },
+        );
+        let svc = layer.layer(service_fn(|_req: Request<String>| async {
+            Ok::<_, Infallible>(Response::new("ok".to_string()))
+        }));
+
+        let id = format!("tower_user{}", unix_ms());
+        let request = || {
+            Request::get("/v1/file/list")
+                .header("x-user-id", id.as_str())
+                .body(String::new())
+                .unwrap()
+        };
+        // "GET /v1/file/list" consumes 5 of the burst 50
+        for _ in 0..10 {
+            let res = svc.clone().oneshot(request()).await?;
+            assert_eq!(200, res.status().as_u16());
+            assert_eq!("100", res.headers().get("x-ratelimit-limit").unwrap());
+        }
+
+        let res = svc.clone().oneshot(request()).await?;
+        assert_eq!(429, res.status().as_u16());
+        assert!(res.headers().get("retry-after").is_some());
+
+        // no id, not limited
+        let req = Request::get("/v1/file/list").body(String::new())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(200, res.status().as_u16());
+        Ok(())
+    }
+}
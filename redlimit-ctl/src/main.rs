@@ -0,0 +1,339 @@
+//! redlimitctl manages a running redlimit service through its admin endpoints:
+//! the redlist, the redrules, the sync job, the snapshot export and the health.
+//!
+//! ```sh
+//! export REDLIMIT_API_KEY=...
+//! redlimitctl --url http://127.0.0.1:8080 redlist add user1 user2 --ttl 30m --reason abuse
+//! redlimitctl redrules add --scope core "GET /v1/file/list" --quantity 5 --ttl 1h
+//! redlimitctl sync --wait
+//! redlimitctl export -o redlimit-snapshot.json
+//! redlimitctl health
+//! ```
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use clap::{Parser, Subcommand};
+use redlimit_client::{Client, Options, RedlistEntry};
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// The base URL of the redlimit service, or its admin listener.
+    #[arg(long, env = "REDLIMIT_URL", default_value = "http://127.0.0.1:8080")]
+    url: String,
+
+    /// The api key of the admin endpoints, see security.api_keys.
+    #[arg(
+        long,
+        env = "REDLIMIT_API_KEY",
+        default_value = "",
+        hide_env_values = true
+    )]
+    api_key: String,
+
+    /// The namespace, the main namespace of the service if empty.
+    #[arg(short = 'N', long, default_value = "")]
+    namespace: String,
+
+    /// The timeout of a request in milliseconds.
+    #[arg(long, default_value = "10000")]
+    timeout: u64,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List, add or remove the redlist entries.
+    #[command(subcommand)]
+    Redlist(RedlistCommand),
+    /// List or add the dynamic rules.
+    #[command(subcommand)]
+    Redrules(RedrulesCommand),
+    /// Trigger the sync job to load the redlist and the redrules now.
+    Sync {
+        /// Wait until the triggered sync of every namespace is done.
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Print the last sync job status of every namespace.
+    Status,
+    /// Export the snapshot of all namespaces, it can be posted to /admin/import.
+    Export {
+        /// The file to write, stdout if not set.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Check the service is up and the sync jobs are not failing.
+    Health,
+}
+
+#[derive(Subcommand, Debug)]
+enum RedlistCommand {
+    /// Print the unexpired ids with their expire time in unix ms.
+    List,
+    /// Add the ids to the redlist.
+    Add {
+        #[arg(required = true)]
+        ids: Vec<String>,
+        /// The expire duration, in milliseconds or with a unit: "30s", "10m", "1h", "1d".
+        #[arg(long, value_parser = parse_ttl)]
+        ttl: u64,
+        #[arg(long, default_value = "")]
+        reason: String,
+        #[arg(long, default_value = "redlimitctl")]
+        source: String,
+    },
+    /// Erase the ids: the redlist entries and the limiting keys of all scopes.
+    Remove {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RedrulesCommand {
+    /// Print the unexpired rules as (quantity, expire time in unix ms).
+    List,
+    /// Add the rule of the paths in the scope.
+    Add {
+        #[arg(long)]
+        scope: String,
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// The quantity consumed by a request of the paths.
+        #[arg(long)]
+        quantity: u64,
+        /// The expire duration, in milliseconds or with a unit: "30s", "10m", "1h", "1d".
+        #[arg(long, value_parser = parse_ttl)]
+        ttl: u64,
+    },
+}
+
+// parse_ttl parses a duration in milliseconds, or with a unit of ms, s, m, h or d.
+fn parse_ttl(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    let unit = match unit {
+        "" | "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("invalid duration unit {:?}", unit)),
+    };
+    if num == 0 {
+        return Err("duration should be greater than 0".to_string());
+    }
+    Ok(num.saturating_mul(unit))
+}
+
+// SYNC_WAIT_POLL is the interval to poll the sync status with `sync --wait`.
+const SYNC_WAIT_POLL: Duration = Duration::from_millis(200);
+
+async fn run(args: Args) -> Result<()> {
+    let cli = Client::new(
+        &args.url,
+        Options {
+            api_key: args.api_key,
+            timeout: Duration::from_millis(args.timeout),
+            cache_capacity: 0,
+            ..Default::default()
+        },
+    )?;
+    let ns = args.namespace.as_str();
+
+    match args.command {
+        Command::Redlist(RedlistCommand::List) => print_json(&cli.redlist(ns).await?),
+        Command::Redlist(RedlistCommand::Add {
+            ids,
+            ttl,
+            reason,
+            source,
+        }) => {
+            let entry = RedlistEntry {
+                ttl,
+                reason,
+                source,
+            };
+            let entries: HashMap<String, RedlistEntry> =
+                ids.into_iter().map(|id| (id, entry.clone())).collect();
+            cli.redlist_add_entries(ns, &entries).await?;
+            println!("added {} ids", entries.len());
+            Ok(())
+        }
+        Command::Redlist(RedlistCommand::Remove { ids }) => {
+            for id in &ids {
+                cli.redlist_remove(ns, id).await?;
+            }
+            println!("removed {} ids", ids.len());
+            Ok(())
+        }
+        Command::Redrules(RedrulesCommand::List) => print_json(&cli.redrules(ns).await?),
+        Command::Redrules(RedrulesCommand::Add {
+            scope,
+            paths,
+            quantity,
+            ttl,
+        }) => {
+            let rules: HashMap<String, (u64, u64)> = paths
+                .into_iter()
+                .map(|path| (path, (quantity, ttl)))
+                .collect();
+            cli.redrules_add(ns, &scope, &rules).await?;
+            println!("added {} rules to scope {}", rules.len(), scope);
+            Ok(())
+        }
+        Command::Sync { wait } => {
+            let before = cli.sync_status().await?;
+            cli.sync().await?;
+            if !wait {
+                println!("sync triggered");
+                return Ok(());
+            }
+
+            let deadline = Instant::now() + Duration::from_millis(args.timeout);
+            loop {
+                tokio::time::sleep(SYNC_WAIT_POLL).await;
+                let status = cli.sync_status().await?;
+                let done = status
+                    .iter()
+                    .all(|(ns, s)| before.get(ns).map_or(true, |b| s.last_run > b.last_run));
+                if done {
+                    return print_json(&status);
+                }
+                if Instant::now() >= deadline {
+                    return Err(Error::msg("timed out waiting for the sync"));
+                }
+            }
+        }
+        Command::Status => print_json(&cli.sync_status().await?),
+        Command::Export { output } => {
+            let data = cli.export().await?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, &data)?;
+                    println!("exported {} bytes to {}", data.len(), path);
+                }
+                None => println!("{}", String::from_utf8_lossy(&data)),
+            }
+            Ok(())
+        }
+        Command::Health => {
+            let info = cli.version().await?;
+            println!("{} {}", info.name, info.version);
+            let mut failing = 0;
+            for (ns, s) in cli.sync_status().await? {
+                if s.failures > 0 {
+                    failing += 1;
+                    println!("{}: sync failed {} times: {}", ns, s.failures, s.error);
+                }
+            }
+            if failing > 0 {
+                return Err(Error::msg(format!(
+                    "sync failing in {} namespaces",
+                    failing
+                )));
+            }
+            println!("ok");
+            Ok(())
+        }
+    }
+}
+
+fn print_json<T: serde::Serialize>(v: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(v)?);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run(Args::parse()).await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ttl_works() {
+        assert_eq!(Ok(1500), parse_ttl("1500"));
+        assert_eq!(Ok(1500), parse_ttl("1500ms"));
+        assert_eq!(Ok(30 * 1000), parse_ttl("30s"));
+        assert_eq!(Ok(10 * 60 * 1000), parse_ttl("10m"));
+        assert_eq!(Ok(2 * 60 * 60 * 1000), parse_ttl("2h"));
+        assert_eq!(Ok(24 * 60 * 60 * 1000), parse_ttl("1d"));
+        assert!(parse_ttl("").is_err());
+        assert!(parse_ttl("0").is_err());
+        assert!(parse_ttl("10w").is_err());
+        assert!(parse_ttl("m").is_err());
+    }
+
+    #[test]
+    fn args_works() {
+        let args = Args::parse_from([
+            "redlimitctl",
+            "-N",
+            "RL2",
+            "redlist",
+            "add",
+            "user1",
+            "user2",
+            "--ttl",
+            "10m",
+        ]);
+        assert_eq!("RL2", args.namespace);
+        match args.command {
+            Command::Redlist(RedlistCommand::Add {
+                ids, ttl, source, ..
+            }) => {
+                assert_eq!(vec!["user1", "user2"], ids);
+                assert_eq!(600000, ttl);
+                assert_eq!("redlimitctl", source);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        let args = Args::parse_from([
+            "redlimitctl",
+            "redrules",
+            "add",
+            "--scope",
+            "core",
+            "GET /v1/file/list",
+            "--quantity",
+            "5",
+            "--ttl",
+            "1h",
+        ]);
+        match args.command {
+            Command::Redrules(RedrulesCommand::Add {
+                scope,
+                paths,
+                quantity,
+                ttl,
+            }) => {
+                assert_eq!("core", scope);
+                assert_eq!(vec!["GET /v1/file/list"], paths);
+                assert_eq!(5, quantity);
+                assert_eq!(3600000, ttl);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        assert!(Args::try_parse_from(["redlimitctl", "redlist", "add", "user1"]).is_err());
+        assert!(Args::try_parse_from(["redlimitctl", "redlist", "remove"]).is_err());
+    }
+}
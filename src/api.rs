@@ -1,11 +1,53 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write};
 
-use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
+use actix_web::{
+    error::{InternalError, JsonPayloadError, PayloadError},
+    http::{
+        header::{HeaderMap, CONTENT_LENGTH},
+        StatusCode,
+    },
+    web,
+    web::{Bytes, BytesMut},
+    Error, HttpRequest, HttpResponse,
+};
+use opentelemetry::{
+    trace::{FutureExt, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, to_value, Value};
-use tokio::time::{timeout, Duration};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::mpsc,
+    time::{interval, Duration, Instant},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, IntervalStream, ReceiverStream},
+    StreamExt,
+};
 
-use crate::{context::ContextExt, redis::RedisPool, redlimit, redlimit::RedRules};
+use crate::{
+    approx::ApproxLimiter,
+    audit::{self, Auditor},
+    auth,
+    codec::Format,
+    conf,
+    context::{unix_ms, ContextExt},
+    drain::Drain,
+    events, guard,
+    hook::Hook,
+    lease::LeaseLimiter,
+    local::LocalLimiter,
+    metrics,
+    privacy::IdHasher,
+    redis::{RedisPool, Shards},
+    redlimit,
+    redlimit::{Namespaces, RedRules, RedlistSnapshot, NS},
+    shedder::LoadShedder,
+    snapshot::{self, Snapshot},
+    stats, telemetry,
+    waiter::Waiter,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct AppInfo {
@@ -21,97 +63,766 @@ pub async fn version(
     let state = pool.state();
     let mut ctx = req.context_mut().unwrap();
     ctx.log
-        .insert("connections".to_string(), Value::from(state.connections));
-    ctx.log.insert(
-        "idle_connections".to_string(),
-        Value::from(state.idle_connections),
-    );
+        .insert("connections", Value::from(state.connections));
+    ctx.log
+        .insert("idle_connections", Value::from(state.idle_connections));
     respond_result(info)
 }
 
 #[derive(Deserialize)]
 pub struct LimitRequest {
+    #[serde(default)]
+    namespace: String,
     scope: String,
     path: String,
     id: String,
+    // limit by it if the id is empty, see conf::EmptyId
+    #[serde(default)]
+    fallback_id: String,
+    // wait server-side up to wait_ms for the limited decision, see Waiter
+    #[serde(default)]
+    wait_ms: u64,
 }
 
 #[derive(Serialize)]
 pub struct LimitResponse {
-    limit: u64,     // x-ratelimit-limit
-    remaining: u64, // x-ratelimit-remaining
-    reset: u64,     // x-ratelimit-reset
-    retry: u64,     // retry-after delay-milliseconds
+    pub limit: u64,      // x-ratelimit-limit
+    pub remaining: u64,  // x-ratelimit-remaining
+    pub reset: u64,      // x-ratelimit-reset
+    pub retry: u64,      // retry-after delay-milliseconds
+    pub throttled: bool, // out of allow_percent, retrying doesn't help
 }
 
 pub async fn post_limiting(
     req: HttpRequest,
-    pool: web::Data<RedisPool>,
-    rules: web::Data<RedRules>,
-    input: web::Json<LimitRequest>,
+    shards: web::Data<Shards>,
+    namespaces: web::Data<Namespaces>,
+    shedder: web::Data<LoadShedder>,
+    local: web::Data<LocalLimiter>,
+    retry: web::Data<conf::Retry>,
+    payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
-    let input = input.into_inner();
+    let body = match read_body(req.headers(), payload, MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    let format = match Format::request(&req) {
+        Some(format) => format,
+        None => return respond_error(415, "unsupported content type".to_string()),
+    };
+    let input: LimitRequest = match format.decode(&body) {
+        Ok(input) => input,
+        Err(err) => return respond_error(400, format!("invalid request body: {}", err)),
+    };
+    let rules = match namespace(&namespaces, &input.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
+    let empty_id = req
+        .app_data::<web::Data<conf::EmptyId>>()
+        .map_or(conf::EmptyId::Allow, |policy| *policy.get_ref());
+    let id = match empty_id.resolve(input.id, input.fallback_id) {
+        Some(id) => hash_id(&req, id),
+        None => return respond_error(400, "empty id".to_string()),
+    };
     let ts = req.context()?.unix_ms;
-    let args = rules
-        .limit_args(ts, &input.scope, &input.path, &input.id)
-        .await;
-    let limit = args.1;
-
-    let rt = if pool.state().connections > 0 {
-        match timeout(
-            Duration::from_millis(100),
-            redlimit::limiting(pool, &rules.ns.limiting_key(&input.scope, &input.id), args),
-        )
+    let hook = req.app_data::<web::Data<Hook>>();
+    let attrs = hook_attrs(&req, hook.is_some());
+    let limiter = Limiter {
+        shards: &shards,
+        shedder: &shedder,
+        local: &local,
+        approx: req
+            .app_data::<web::Data<ApproxLimiter>>()
+            .map(|a| a.get_ref()),
+        leaser: req
+            .app_data::<web::Data<LeaseLimiter>>()
+            .map(|l| l.get_ref()),
+        retry: &retry,
+        hook: hook.map(|h| h.get_ref()),
+        attrs: &attrs,
+    };
+    let d = match limiter
+        .decide(rules, ts, &input.scope, &input.path, &id)
         .await
-        {
-            Ok(rt) => rt,
-            Err(_) => Err(anyhow::Error::msg("limiting timeout".to_string())),
-        }
-    } else {
-        Err(anyhow::Error::msg("no redis connection".to_string()))
+    {
+        Ok(d) => d,
+        Err(err) => return respond_limit_error(&req, rules, &input.scope, &input.path, err),
     };
-
-    let rt = match rt {
-        Ok(rt) => rt,
-        Err(err) => {
-            log::warn!("post_limiting error: {}", err);
-            redlimit::LimitResult(0, 0)
+    // the retries of the waiter are recorded once, by the final decision
+    let (d, ts) = match req.app_data::<web::Data<Waiter>>() {
+        Some(waiter) if input.wait_ms > 0 => {
+            let rt = waiter
+                .wait(input.wait_ms, ts, d, |ts| {
+                    limiter.decide(rules, ts, &input.scope, &input.path, &id)
+                })
+                .await;
+            let (d, ts, waited) = match rt {
+                Ok(rt) => rt,
+                Err(err) => {
+                    return respond_limit_error(&req, rules, &input.scope, &input.path, err)
+                }
+            };
+            if waited > 0 {
+                req.context_mut()?.log.insert("waited", Value::from(waited));
+            }
+            (d, ts)
         }
+        _ => (d, ts),
+    };
+    limiter.record(rules, ts, &input.scope, &input.path, &id, &d);
+    log_decision(&req, input.namespace, input.scope, input.path, id, &d)?;
+    let result = ResultBody {
+        result: d.response(ts),
     };
+    match Format::response(&req, format).respond(&result) {
+        Ok(res) => Ok(res),
+        Err(err) => respond_error(500, err.to_string()),
+    }
+}
 
+// ResultBody is the {"result": ...} body without a serde_json::Value.
+#[derive(Serialize)]
+struct ResultBody<T> {
+    result: T,
+}
+
+// log_decision adds the limiting decision to the access log.
+fn log_decision(
+    req: &HttpRequest,
+    namespace: String,
+    scope: String,
+    path: String,
+    id: String,
+    d: &Decision,
+) -> Result<(), Error> {
+    let rt = &d.rt;
     let mut ctx = req.context_mut()?;
+    ctx.sampling = rt.1 == 0 && !d.fallback;
+    if !namespace.is_empty() {
+        ctx.log.insert("namespace", Value::from(namespace));
+    }
+    ctx.log.insert("scope", Value::from(scope));
+    ctx.log.insert("path", Value::from(path));
+    ctx.log.insert("id", Value::from(id));
+    ctx.log.insert("count", Value::from(rt.0));
     ctx.log
-        .insert("scope".to_string(), Value::from(input.scope));
-    ctx.log.insert("path".to_string(), Value::from(input.path));
-    ctx.log.insert("id".to_string(), Value::from(input.id));
-    ctx.log.insert("count".to_string(), Value::from(rt.0));
-    ctx.log
-        .insert("bursted".to_string(), Value::from(rt.0 < limit && rt.1 > 0));
-    ctx.log.insert("limited".to_string(), Value::from(rt.1 > 0));
-
-    respond_result(LimitResponse {
-        limit,
-        remaining: if limit > rt.0 { limit - rt.0 } else { 0 },
-        reset: if rt.1 > 0 { (ts + rt.1) / 1000 } else { 0 },
-        retry: rt.1,
-    })
+        .insert("bursted", Value::from(rt.0 < d.limit && rt.1 > 0));
+    ctx.log.insert("limited", Value::from(rt.1 > 0));
+    if d.shedding {
+        ctx.log.insert("shedding", Value::from(true));
+    }
+    if d.throttled {
+        ctx.log.insert("throttled", Value::from(true));
+    }
+    if d.fallback {
+        ctx.log.insert("fallback", Value::from("local"));
+    }
+    Ok(())
+}
+
+// respond_limit_error responds 422 for the invalid limiting args resolved from
+// the rules, instead of not limiting the request.
+fn respond_limit_error(
+    req: &HttpRequest,
+    rules: &RedRules,
+    scope: &str,
+    path: &str,
+    err: redlimit::LimitError,
+) -> Result<HttpResponse, Error> {
+    req.context_mut()?
+        .log
+        .insert("error", Value::from(err.to_string()));
+    // an invalid scope is a bug of the caller rather than of the rules
+    if err == redlimit::LimitError::Scope {
+        return respond_error(400, format!("invalid scope {:?}: {}", scope, err));
+    }
+    log::error!(target: "limiting",
+        ns = rules.ns.as_str(),
+        scope = scope,
+        path = path;
+        "invalid limit args: {}", err,
+    );
+    respond_error(
+        422,
+        format!("invalid limit args of scope {:?}: {}", scope, err),
+    )
+}
+
+// forward_auth serves Traefik's ForwardAuth middleware, see conf::ForwardAuth.
+// The request is allowed with 200, or responded 429 if limited.
+pub async fn forward_auth(
+    req: HttpRequest,
+    shards: web::Data<Shards>,
+    namespaces: web::Data<Namespaces>,
+    shedder: web::Data<LoadShedder>,
+    local: web::Data<LocalLimiter>,
+    retry: web::Data<conf::Retry>,
+    cfg: web::Data<conf::ForwardAuth>,
+) -> Result<HttpResponse, Error> {
+    let rules = match namespace(&namespaces, &cfg.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
+    let empty_id = req
+        .app_data::<web::Data<conf::EmptyId>>()
+        .map_or(conf::EmptyId::Allow, |policy| *policy.get_ref());
+    let (scope, path, id) = match forward_keys(&req, &cfg, empty_id) {
+        Some(keys) if keys.2.is_empty() => return Ok(HttpResponse::Ok().finish()),
+        Some(keys) => keys,
+        None => return respond_error(400, "empty id".to_string()),
+    };
+    let id = hash_id(&req, id);
+    let ts = req.context()?.unix_ms;
+    let hook = req.app_data::<web::Data<Hook>>();
+    let attrs = hook_attrs(&req, hook.is_some());
+    let limiter = Limiter {
+        shards: &shards,
+        shedder: &shedder,
+        local: &local,
+        approx: req
+            .app_data::<web::Data<ApproxLimiter>>()
+            .map(|a| a.get_ref()),
+        leaser: req
+            .app_data::<web::Data<LeaseLimiter>>()
+            .map(|l| l.get_ref()),
+        retry: &retry,
+        hook: hook.map(|h| h.get_ref()),
+        attrs: &attrs,
+    };
+    let d = match limiter.check(rules, ts, &scope, &path, &id).await {
+        Ok(d) => d,
+        Err(err) => return respond_limit_error(&req, rules, &scope, &path, err),
+    };
+    log_decision(&req, cfg.namespace.clone(), scope, path, id, &d)?;
+
+    let d = d.guard_decision(ts);
+    let mut res = if d.is_limited() {
+        HttpResponse::TooManyRequests()
+    } else {
+        HttpResponse::Ok()
+    };
+    for header in d.headers() {
+        res.insert_header(header);
+    }
+    if d.is_limited() {
+        Ok(res.json(json!({ "error": {"code": 429, "message": "too many requests" }})))
+    } else {
+        Ok(res.finish())
+    }
+}
+
+// forward_keys returns the (scope, path, id) of a ForwardAuth request, the id is
+// resolved by the empty_id policy with fallback_id_header, None if the request
+// should be rejected.
+fn forward_keys(
+    req: &HttpRequest,
+    cfg: &conf::ForwardAuth,
+    empty_id: conf::EmptyId,
+) -> Option<(String, String, String)> {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+    };
+    let id = cfg
+        .id_headers
+        .iter()
+        .find_map(|name| {
+            let value = header(name)?;
+            if name.eq_ignore_ascii_case("x-forwarded-for") {
+                value.split(',').next().map(|ip| ip.trim().to_string())
+            } else {
+                Some(value.to_string())
+            }
+        })
+        .unwrap_or_default();
+    let fallback_id = Some(cfg.fallback_id_header.as_str())
+        .filter(|name| !name.is_empty())
+        .and_then(header)
+        .unwrap_or_default()
+        .to_string();
+    let id = empty_id.resolve(id, fallback_id)?;
+    let scope = Some(cfg.scope_header.as_str())
+        .filter(|name| !name.is_empty())
+        .and_then(header)
+        .unwrap_or(&cfg.scope)
+        .to_string();
+    let method = header("x-forwarded-method").unwrap_or("GET");
+    let uri = header("x-forwarded-uri").unwrap_or("/");
+    let path = uri.split(['?', '#']).next().unwrap_or("/");
+    Some((scope, format!("{} {}", method, path), id))
+}
+
+// hook_attrs returns the request headers for the hook, none without hook.
+fn hook_attrs(req: &HttpRequest, enabled: bool) -> Vec<(&str, &str)> {
+    if !enabled {
+        return Vec::new();
+    }
+    req.headers()
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_str(), v.to_str().ok()?)))
+        .collect()
+}
+
+// Limiter makes the limiting decisions with the shared state, for both the
+// HTTP and gRPC servers.
+pub struct Limiter<'a> {
+    pub shards: &'a Shards,
+    pub shedder: &'a LoadShedder,
+    pub local: &'a LocalLimiter,
+    pub approx: Option<&'a ApproxLimiter>,
+    pub leaser: Option<&'a LeaseLimiter>,
+    pub retry: &'a conf::Retry,
+    pub hook: Option<&'a Hook>,
+    // the request attributes for the hook
+    pub attrs: &'a [(&'a str, &'a str)],
+}
+
+pub struct Decision {
+    pub limit: u64,
+    pub rt: redlimit::LimitResult,
+    pub shedding: bool,
+    pub throttled: bool,
+    pub fallback: bool,
+    // the limiters failed, see Limiter::record
+    pub errored: bool,
+}
+
+impl Decision {
+    pub fn response(&self, ts: u64) -> LimitResponse {
+        LimitResponse {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(self.rt.0),
+            reset: if self.rt.1 > 0 {
+                (ts + self.rt.1) / 1000
+            } else {
+                0
+            },
+            retry: self.rt.1,
+            throttled: self.throttled,
+        }
+    }
+
+    // guard_decision returns the decision for the x-ratelimit-* headers.
+    pub fn guard_decision(&self, ts: u64) -> guard::Decision {
+        guard::Decision {
+            throttled: self.throttled,
+            ..guard::Decision::new(ts, self.limit, &self.rt)
+        }
+    }
+}
+
+impl Limiter<'_> {
+    // check decides and records the decision, see decide and record.
+    pub async fn check(
+        &self,
+        rules: &RedRules,
+        ts: u64,
+        scope: &str,
+        path: &str,
+        id: &str,
+    ) -> Result<Decision, redlimit::LimitError> {
+        let d = self.decide(rules, ts, scope, path, id).await?;
+        self.record(rules, ts, scope, path, id, &d);
+        Ok(d)
+    }
+
+    // decide limits the (hashed) id, it falls back to the local limiter if
+    // enabled on Redis errors. It returns an error if the scope is not a valid
+    // name or the limits resolved from the rules are invalid. An empty id is allowed without calling the
+    // limiters, see conf::EmptyId.
+    pub async fn decide(
+        &self,
+        rules: &RedRules,
+        ts: u64,
+        scope: &str,
+        path: &str,
+        id: &str,
+    ) -> Result<Decision, redlimit::LimitError> {
+        redlimit::validate_scope(scope)?;
+        if id.is_empty() {
+            return Ok(Decision {
+                limit: 0,
+                rt: redlimit::LimitResult(0, 0),
+                shedding: false,
+                throttled: false,
+                fallback: false,
+                errored: false,
+            });
+        }
+        let mut limits = rules.limits(ts, scope, path, id);
+        limits.validate()?;
+        let span = telemetry::tracer().start("post_limiting");
+        let cx = Context::current_with_span(span);
+        if let Some(hook) = self.hook {
+            let hd = hook.decide(rules.ns.as_str(), scope, path, id, self.attrs);
+            // the redlist can't be bypassed by the hook
+            if hd.allow && !rules.is_redlisted(ts, id) {
+                return Ok(Decision {
+                    limit: limits.args.1,
+                    rt: redlimit::LimitResult(0, 0),
+                    shedding: false,
+                    throttled: false,
+                    fallback: false,
+                    errored: false,
+                });
+            }
+            if let Some(quantity) = hd.quantity {
+                limits.args.0 = quantity;
+                for args in limits.extra.iter_mut() {
+                    args.0 = quantity;
+                }
+                limits.validate()?;
+            }
+        }
+        let shedding = self.shedder.is_shedding(ts);
+        if shedding {
+            limits.args = self.shedder.tighten(limits.args);
+            limits.extra = limits
+                .extra
+                .into_iter()
+                .map(|args| self.shedder.tighten(args))
+                .collect();
+        }
+        let limit = limits.args.1;
+
+        let limiting_key = rules.ns.limiting_key(scope, id);
+        let throttled = rules.is_throttled(scope, path, id);
+        let rt = if throttled {
+            Ok((limit, redlimit::LimitResult(limit, limits.args.2.max(1))))
+        } else if let Some(rt) = self
+            .approx
+            .and_then(|approx| approx.limiting(ts, &limiting_key, &limits))
+        {
+            Ok(rt)
+        } else {
+            let start = Instant::now();
+            let leased = match self.leaser {
+                Some(leaser) => {
+                    leaser
+                        .limiting(self.shards, self.retry, ts, &limiting_key, &limits)
+                        .with_context(cx.clone())
+                        .await
+                }
+                None => None,
+            };
+            let rt = match leased {
+                Some(rt) => rt,
+                None => {
+                    guard::limiting_with_redlist(
+                        self.shards,
+                        self.retry,
+                        rules,
+                        &limiting_key,
+                        id,
+                        &limits,
+                    )
+                    .with_context(cx.clone())
+                    .await
+                }
+            };
+
+            self.shedder
+                .record(ts, start.elapsed().as_millis() as u64, rt.is_ok());
+            rt
+        };
+
+        let mut fallback = false;
+        let errored = rt.is_err();
+        let (limit, rt) = match rt {
+            Ok(rt) => rt,
+            Err(err) => {
+                log::warn!("post_limiting error: {}", err);
+                let (limit, rt, local) =
+                    guard::on_failure(self.local, rules, ts, scope, &limiting_key, limits);
+                fallback = local;
+                (limit, rt)
+            }
+        };
+
+        let span = cx.span();
+        // the attributes are allocated only if the span is exported
+        if span.is_recording() {
+            span.set_attributes([
+                KeyValue::new("ns", rules.ns.as_str().to_string()),
+                KeyValue::new("scope", scope.to_string()),
+                KeyValue::new("path", path.to_string()),
+                KeyValue::new("limited", rt.1 > 0),
+                KeyValue::new("fallback", fallback),
+            ]);
+        }
+        span.end();
+
+        Ok(Decision {
+            limit,
+            rt,
+            shedding,
+            throttled,
+            fallback,
+            errored,
+        })
+    }
+
+    // record records the stats of a decision, and publishes a violation if
+    // limited. It is called once per request, for the final decision.
+    pub fn record(
+        &self,
+        rules: &RedRules,
+        ts: u64,
+        scope: &str,
+        path: &str,
+        id: &str,
+        d: &Decision,
+    ) {
+        stats::STATS.record(ts, d.rt.1 > 0, d.errored);
+        if d.rt.1 > 0 {
+            stats::STATS.record_limited(ts, scope, id);
+            events::publish(|| {
+                events::Event::Violation(events::Violation {
+                    ns: rules.ns.as_str().to_string(),
+                    scope: scope.to_string(),
+                    path: path.to_string(),
+                    id: id.to_string(),
+                    count: d.rt.0,
+                    ts,
+                })
+            });
+        }
+    }
+}
+
+// NamespaceQuery selects the namespace of redlist and redrules, default to the
+// main namespace.
+#[derive(Deserialize)]
+pub struct NamespaceQuery {
+    #[serde(default)]
+    namespace: String,
+}
+
+#[derive(Deserialize)]
+pub struct RedlistQuery {
+    #[serde(default)]
+    namespace: String,
+    // respond the metadata with the expiration time, read from Redis
+    #[serde(default)]
+    metadata: bool,
+}
+
+// RedlistEntry is an entry of "GET /redlist?metadata=true".
+#[derive(Serialize)]
+pub struct RedlistEntry {
+    ttl: u64,
+    #[serde(flatten)]
+    meta: redlimit::RedlistMeta,
 }
 
 pub async fn get_redlist(
     req: HttpRequest,
-    rules: web::Data<RedRules>,
+    pool: web::Data<RedisPool>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    query: web::Query<RedlistQuery>,
 ) -> Result<HttpResponse, Error> {
+    let rules = match namespace(&namespaces, &query.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
     let ts = req.context()?.unix_ms;
-    let rt = rules.redlist(ts).await;
+    let redlist = rules.redlist_snapshot();
+    if !query.metadata {
+        let ndjson = req
+            .headers()
+            .get("accept")
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.contains(NDJSON));
+        return Ok(stream_redlist(redlist, ts, ndjson));
+    }
+
+    let mut meta = match redlimit::redlist_meta(&pool, &retry, &rules.ns).await {
+        Ok(meta) => meta,
+        Err(err) => {
+            log::error!("redlist_meta error: {}", err);
+            return respond_error(500, err.to_string());
+        }
+    };
+    let rt: HashMap<&str, RedlistEntry> = redlist
+        .iter(ts)
+        .map(|(id, ttl)| {
+            let meta = meta.remove(id).unwrap_or_default();
+            (id, RedlistEntry { ttl, meta })
+        })
+        .collect();
     respond_result(rt)
 }
 
+const NDJSON: &str = "application/x-ndjson";
+// the entries serialized per chunk of a streamed redlist
+const REDLIST_CHUNK: usize = 1000;
+
+// stream_redlist responds the redlist serialized chunk by chunk as the client
+// reads, as {"result": {<id>: <ttl>}}, or NDJSON lines {"id", "ttl"}. The
+// bounded channel holds a few chunks, a million-entry redlist is not copied.
+fn stream_redlist(redlist: RedlistSnapshot, now: u64, ndjson: bool) -> HttpResponse {
+    let (tx, rx) = mpsc::channel::<Bytes>(4);
+    actix_web::rt::spawn(async move {
+        let mut buf = Vec::with_capacity(64 * 1024);
+        if !ndjson {
+            buf.extend_from_slice(b"{\"result\":{");
+        }
+        for (i, (id, ttl)) in redlist.iter(now).enumerate() {
+            // writing to a Vec does not fail
+            if ndjson {
+                buf.extend_from_slice(b"{\"id\":");
+                let _ = serde_json::to_writer(&mut buf, id);
+                let _ = writeln!(buf, ",\"ttl\":{}}}", ttl);
+            } else {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                let _ = serde_json::to_writer(&mut buf, id);
+                let _ = write!(buf, ":{}", ttl);
+            }
+            if (i + 1) % REDLIST_CHUNK == 0
+                && tx
+                    .send(Bytes::from(std::mem::take(&mut buf)))
+                    .await
+                    .is_err()
+            {
+                return; // the client is gone
+            }
+        }
+        if !ndjson {
+            buf.extend_from_slice(b"}}");
+        }
+        let _ = tx.send(Bytes::from(buf)).await;
+    });
+
+    HttpResponse::Ok()
+        .content_type(if ndjson { NDJSON } else { "application/json" })
+        .streaming(ReceiverStream::new(rx).map(Ok::<_, Error>))
+}
+
+// get_redlist_stream pushes the redlist events observed by the sync job of this
+// instance as server-sent events: "add", "remove" and "expire" with data
+// {"event", "id", "ttl"}, "lagged" if events were dropped for a slow reader
+// (reload by GET /redlist), and a ping comment every 15 seconds.
+pub async fn get_redlist_stream(
+    namespaces: web::Data<Namespaces>,
+    query: web::Query<NamespaceQuery>,
+) -> Result<HttpResponse, Error> {
+    let rules = match namespace(&namespaces, &query.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
+    let events = BroadcastStream::new(rules.subscribe()).map(|rt| {
+        let msg = match rt {
+            Ok(ev) => format!(
+                "event: {}\ndata: {}\n\n",
+                ev.event,
+                serde_json::to_string(&ev).unwrap_or_default()
+            ),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                format!("event: lagged\ndata: {}\n\n", n)
+            }
+        };
+        Ok::<_, Error>(Bytes::from(msg))
+    });
+    let pings = IntervalStream::new(interval(Duration::from_secs(15)))
+        .map(|_| Ok::<_, Error>(Bytes::from_static(b": ping\n\n")));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        .streaming(events.merge(pings)))
+}
+
+// RedlistInput is the expire duration in ms of an id, or with the reason and
+// the source tag. The actor is the caller of the request.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RedlistInput {
+    Ttl(u64),
+    Entry {
+        ttl: u64,
+        #[serde(default)]
+        reason: String,
+        #[serde(default)]
+        source: String,
+    },
+}
+
+impl RedlistInput {
+    fn ttl(&self) -> u64 {
+        match self {
+            RedlistInput::Ttl(ttl) => *ttl,
+            RedlistInput::Entry { ttl, .. } => *ttl,
+        }
+    }
+}
+
 pub async fn post_redlist(
+    req: HttpRequest,
     pool: web::Data<RedisPool>,
-    rules: web::Data<RedRules>,
-    input: web::Json<HashMap<String, u64>>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    auditor: web::Data<Auditor>,
+    query: web::Query<NamespaceQuery>,
+    input: web::Json<HashMap<String, RedlistInput>>,
 ) -> Result<HttpResponse, Error> {
-    if let Err(err) = redlimit::redlist_add(pool, rules.ns.as_str(), &input.into_inner()).await {
+    let rules = match namespace(&namespaces, &query.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
+    let input = input.into_inner();
+    if let Err(err) =
+        redlimit::validate_redlist(&inputs(&req), input.iter().map(|(id, v)| (id, v.ttl())))
+            .and_then(|_| validate_hashed_redlist(&req, input.keys()))
+    {
+        return respond_input_error(err);
+    }
+    let input: HashMap<String, RedlistInput> = input
+        .into_iter()
+        .map(|(id, v)| (hash_id(&req, id), v))
+        .collect();
+    let mut entry = auditor.entry(
+        &req,
+        "redlist.add",
+        rules.ns.as_str(),
+        audit::summary(input.keys()),
+    );
+    let mut list: HashMap<String, u64> = HashMap::with_capacity(input.len());
+    let mut meta: HashMap<String, redlimit::RedlistMeta> = HashMap::with_capacity(input.len());
+    for (id, v) in input {
+        let (ttl, reason, source) = match v {
+            RedlistInput::Ttl(ttl) => (ttl, String::new(), String::new()),
+            RedlistInput::Entry {
+                ttl,
+                reason,
+                source,
+            } => (ttl, reason, source),
+        };
+        meta.insert(
+            id.clone(),
+            redlimit::RedlistMeta {
+                reason,
+                actor: entry.actor().to_string(),
+                source,
+            },
+        );
+        list.insert(id, ttl);
+    }
+    entry.summary = audit::with_meta(entry.summary.take(), meta.values());
+    let mut entry = match limit_admin(&req, &pool, &retry, &auditor, &rules.ns, entry, 1).await {
+        Ok(entry) => entry,
+        Err(resp) => return resp,
+    };
+    let rt = redlimit::redlist_add_with_meta(&pool, &retry, &rules.ns, &list, &meta).await;
+    entry.result = audit::result(&rt);
+    auditor.record(&pool, &retry, entry).await;
+    if let Err(err) = rt {
         log::error!("redlist_add error: {}", err);
         return respond_error(500, err.to_string());
     }
@@ -121,10 +832,15 @@ pub async fn post_redlist(
 
 pub async fn get_redrules(
     req: HttpRequest,
-    rules: web::Data<RedRules>,
+    namespaces: web::Data<Namespaces>,
+    query: web::Query<NamespaceQuery>,
 ) -> Result<HttpResponse, Error> {
+    let rules = match namespace(&namespaces, &query.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
     let ts = req.context()?.unix_ms;
-    let rt = rules.redrules(ts).await;
+    let rt = rules.redrules(ts);
     respond_result(rt)
 }
 
@@ -135,14 +851,36 @@ pub struct RedRulesRequest {
 }
 
 pub async fn post_redrules(
+    req: HttpRequest,
     pool: web::Data<RedisPool>,
-    rules: web::Data<RedRules>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    auditor: web::Data<Auditor>,
+    query: web::Query<NamespaceQuery>,
     input: web::Json<RedRulesRequest>,
 ) -> Result<HttpResponse, Error> {
+    let rules = match namespace(&namespaces, &query.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
     let input = input.into_inner();
-    if let Err(err) =
-        redlimit::redrules_add(pool, rules.ns.as_str(), &input.scope, &input.rules).await
+    if let Err(err) = rules.validate_redrules(&inputs(&req), &input.scope, &input.rules) {
+        return respond_input_error(err);
+    }
+    let mut summary = audit::summary(input.rules.keys());
+    summary["scope"] = Value::from(input.scope.as_str());
+    let entry = auditor.entry(&req, "redrules.add", rules.ns.as_str(), summary);
+    let fcalls = input.rules.len() as u64;
+    let mut entry = match limit_admin(&req, &pool, &retry, &auditor, &rules.ns, entry, fcalls).await
     {
+        Ok(entry) => entry,
+        Err(resp) => return resp,
+    };
+    let rt =
+        redlimit::redrules_add(&pool, &retry, rules.ns.as_str(), &input.scope, &input.rules).await;
+    entry.result = audit::result(&rt);
+    auditor.record(&pool, &retry, entry).await;
+    if let Err(err) = rt {
         log::error!("redlist_add error: {}", err);
         return respond_error(500, err.to_string());
     }
@@ -150,6 +888,384 @@ pub async fn post_redrules(
     respond_result("ok")
 }
 
+// ERASE_ID_WEIGHT is the admin limit weight of erasing an id on each shard, the
+// SCAN of the whole keyspace costs more than a mutation.
+const ERASE_ID_WEIGHT: u64 = 10;
+
+// delete_id erases an id (right to erasure): the limiting keys of all scopes
+// and the redlist entry on Redis, and the caches of this instance. The other
+// instances scrub their caches on the published erasure if job.subscribe is
+// enabled, or keep the cached entries until they expire.
+pub async fn delete_id(
+    req: HttpRequest,
+    shards: web::Data<Shards>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    auditor: web::Data<Auditor>,
+    query: web::Query<NamespaceQuery>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let rules = match namespace(&namespaces, &query.namespace) {
+        Ok(rules) => rules,
+        Err(resp) => return resp,
+    };
+    let id = hash_id(&req, id.into_inner());
+    if id.is_empty() {
+        return respond_error(400, "id is required".to_string());
+    }
+    let pool = &shards.pools()[0];
+    // the id is not kept in the audit trail
+    let entry = auditor.entry(&req, "ids.delete", rules.ns.as_str(), json!({}));
+    let fcalls = ERASE_ID_WEIGHT * shards.pools().len() as u64;
+    let mut entry = match limit_admin(&req, pool, &retry, &auditor, &rules.ns, entry, fcalls).await
+    {
+        Ok(entry) => entry,
+        Err(resp) => return resp,
+    };
+
+    let rt = redlimit::erase_id(&shards, &retry, &rules.ns, &id).await;
+    rules.erase(&id);
+    erase_caches(
+        req.app_data::<web::Data<LocalLimiter>>()
+            .map(|l| l.get_ref()),
+        req.app_data::<web::Data<ApproxLimiter>>()
+            .map(|a| a.get_ref()),
+        req.app_data::<web::Data<LeaseLimiter>>()
+            .map(|l| l.get_ref()),
+        &rules.ns,
+        &id,
+    );
+    if let Ok(deleted) = &rt {
+        entry.summary = json!({ "deleted": deleted });
+    }
+    entry.result = audit::result(&rt);
+    auditor.record(pool, &retry, entry).await;
+    match rt {
+        Ok(deleted) => respond_result(json!({ "deleted": deleted })),
+        Err(err) => {
+            log::error!("erase_id error: {}", err);
+            respond_error(500, err.to_string())
+        }
+    }
+}
+
+// erase_caches removes the id from the local, approximate and leased counters
+// and the offenders of this instance.
+pub fn erase_caches(
+    local: Option<&LocalLimiter>,
+    approx: Option<&ApproxLimiter>,
+    leaser: Option<&LeaseLimiter>,
+    ns: &NS,
+    id: &str,
+) {
+    if let Some(local) = local {
+        local.erase(|k| ns.is_id_key(id, k));
+    }
+    if let Some(approx) = approx {
+        approx.erase(|k| ns.is_id_key(id, k));
+    }
+    if let Some(leaser) = leaser {
+        leaser.erase(|k| ns.is_id_key(id, k));
+    }
+    stats::STATS.erase(id);
+}
+
+// get_export responds the snapshot of the redlist and redrules of all
+// namespaces, as the body of POST /admin/import.
+pub async fn get_export(
+    req: HttpRequest,
+    pool: web::Data<RedisPool>,
+    namespaces: web::Data<Namespaces>,
+) -> Result<HttpResponse, Error> {
+    let ts = req.context()?.unix_ms;
+    let names: Vec<&str> = namespaces.iter().map(|r| r.ns.as_str()).collect();
+    match snapshot::export(&pool, &names, ts).await {
+        Ok(rt) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((
+                "content-disposition",
+                "attachment; filename=\"redlimit-snapshot.json\"",
+            ))
+            .json(rt)),
+        Err(err) => {
+            log::error!("export error: {}", err);
+            respond_error(500, err.to_string())
+        }
+    }
+}
+
+// MAX_SNAPSHOT_SIZE is the body limit of POST /admin/import.
+pub const MAX_SNAPSHOT_SIZE: usize = 64 * 1024 * 1024;
+
+// post_import adds the unexpired entries of a snapshot to Redis, the namespaces
+// that are not configured are skipped.
+pub async fn post_import(
+    req: HttpRequest,
+    pool: web::Data<RedisPool>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    auditor: web::Data<Auditor>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let body = match read_body(req.headers(), payload, MAX_SNAPSHOT_SIZE).await {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    let input: Snapshot = match serde_json::from_slice(&body) {
+        Ok(input) => input,
+        Err(err) => return respond_error(400, format!("invalid snapshot: {}", err)),
+    };
+    if let Err(err) = snapshot::validate(&input) {
+        return respond_input_error(err);
+    }
+    let ts = req.context()?.unix_ms;
+    let names: Vec<&str> = namespaces.iter().map(|r| r.ns.as_str()).collect();
+    let entry = auditor.entry(&req, "admin.import", "", json!({ "ts": input.ts }));
+    // one FCALL for the redlist and one for each of the redrules of a namespace
+    let fcalls = input
+        .namespaces
+        .values()
+        .map(|data| 1 + data.redrules.len() as u64)
+        .sum();
+    let main = &namespaces.get("").expect("main namespace").ns;
+    let mut entry = match limit_admin(&req, &pool, &retry, &auditor, main, entry, fcalls).await {
+        Ok(entry) => entry,
+        Err(resp) => return resp,
+    };
+    let rt = snapshot::import(&pool, &retry, &names, &input, ts).await;
+    if let Ok(rt) = &rt {
+        entry.summary = json!({ "ts": input.ts, "redlist": rt.redlist, "redrules": rt.redrules });
+    }
+    entry.result = audit::result(&rt);
+    auditor.record(&pool, &retry, entry).await;
+    match rt {
+        Ok(rt) => respond_result(rt),
+        Err(err) => {
+            log::error!("import error: {}", err);
+            respond_error(500, err.to_string())
+        }
+    }
+}
+
+// hash_id returns the id hashed by the IdHasher of the app if configured.
+fn hash_id(req: &HttpRequest, id: String) -> String {
+    match req.app_data::<web::Data<IdHasher>>() {
+        Some(hasher) => hasher.hash(&id),
+        None => id,
+    }
+}
+
+// validate_hashed_redlist rejects the redlist patterns if the ids are hashed by
+// the IdHasher of the app.
+fn validate_hashed_redlist<'a>(
+    req: &HttpRequest,
+    ids: impl Iterator<Item = &'a String>,
+) -> Result<(), redlimit::InputError> {
+    match req.app_data::<web::Data<IdHasher>>() {
+        Some(hasher) => hasher.validate_redlist(ids),
+        None => Ok(()),
+    }
+}
+
+// AdminLimit limits the admin mutations of a caller, weighted by the FCALLs
+// they send.
+pub struct AdminLimit(pub Vec<u64>);
+
+// inputs returns the bounds of the redlist and redrules entries, the default
+// ones if not configured.
+fn inputs(req: &HttpRequest) -> conf::Inputs {
+    req.app_data::<web::Data<conf::Inputs>>()
+        .map_or_else(conf::Inputs::default, |inputs| inputs.as_ref().clone())
+}
+
+fn admin_limit(req: &HttpRequest) -> &[u64] {
+    req.app_data::<web::Data<AdminLimit>>()
+        .map_or(&[], |limit| &limit.0)
+}
+
+// limit_admin limits the admin request of the authenticated principal, or the
+// remote address without credentials. The audit entry is recorded and 429 is
+// responded if limited.
+async fn limit_admin(
+    req: &HttpRequest,
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    auditor: &Auditor,
+    ns: &NS,
+    mut entry: audit::Entry,
+    fcalls: u64,
+) -> Result<audit::Entry, Result<HttpResponse, Error>> {
+    let caller = auth::principal(req).unwrap_or_else(|| entry.remote.clone());
+    entry.limited = admin_limited(admin_limit(req), pool, retry, ns, &caller, fcalls).await;
+    if entry.limited {
+        entry.result = "limited".to_string();
+        auditor.record(pool, retry, entry).await;
+        return Err(respond_error(429, "too many admin requests".to_string()));
+    }
+    Ok(entry)
+}
+
+// admin_limited returns true if the caller is limited, it fails open on Redis
+// errors.
+pub async fn admin_limited(
+    limit: &[u64],
+    pool: &RedisPool,
+    retry: &conf::Retry,
+    ns: &NS,
+    caller: &str,
+    fcalls: u64,
+) -> bool {
+    if limit.is_empty() {
+        return false;
+    }
+    let args = redlimit::LimitArgs::new(fcalls.clamp(1, limit[0]), limit);
+    let key = ns.admin_limit_key(caller);
+    match redlimit::limiting(pool, retry, &key, args, conf::Algorithm::FixedWindow).await {
+        Ok(rt) => rt.1 > 0,
+        Err(err) => {
+            log::warn!("admin limiting error: {}", err);
+            false
+        }
+    }
+}
+
+// get_sync_status returns the last sync job status of every namespace.
+pub async fn get_sync_status(namespaces: web::Data<Namespaces>) -> Result<HttpResponse, Error> {
+    let rt: HashMap<&str, redlimit::SyncStatus> = namespaces
+        .iter()
+        .map(|rules| (rules.ns.as_str(), rules.sync_status()))
+        .collect();
+    respond_result(rt)
+}
+
+// get_ready is the readiness probe of the load balancers, it fails once the
+// instance is draining.
+pub async fn get_ready(drain: web::Data<Drain>) -> Result<HttpResponse, Error> {
+    if drain.is_draining() {
+        return respond_error(503, "draining".to_string());
+    }
+    respond_result("ok")
+}
+
+// post_drain starts draining the instance for a deployment, see Drain. It is
+// idempotent.
+pub async fn post_drain(
+    req: HttpRequest,
+    pool: web::Data<RedisPool>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    auditor: web::Data<Auditor>,
+    drain: web::Data<Drain>,
+) -> Result<HttpResponse, Error> {
+    let delay = drain.delay().as_secs();
+    let entry = auditor.entry(&req, "admin.drain", "", json!({ "delay": delay }));
+    let main = &namespaces.get("").expect("main namespace").ns;
+    let mut entry = match limit_admin(&req, &pool, &retry, &auditor, main, entry, 1).await {
+        Ok(entry) => entry,
+        Err(resp) => return resp,
+    };
+    if drain.start(namespaces.into_inner()) {
+        entry.result = "ok".to_string();
+        auditor.record(&pool, &retry, entry).await;
+    }
+    respond_result(json!({ "draining": true, "delay": delay }))
+}
+
+// post_sync triggers the sync job to load the redlist and redrules now, it
+// returns before the sync is done, see get_sync_status.
+pub async fn post_sync(
+    req: HttpRequest,
+    pool: web::Data<RedisPool>,
+    retry: web::Data<conf::Retry>,
+    namespaces: web::Data<Namespaces>,
+    auditor: web::Data<Auditor>,
+) -> Result<HttpResponse, Error> {
+    let entry = auditor.entry(&req, "admin.sync", "", json!({}));
+    let main = &namespaces.get("").expect("main namespace").ns;
+    let mut entry = match limit_admin(&req, &pool, &retry, &auditor, main, entry, 1).await {
+        Ok(entry) => entry,
+        Err(resp) => return resp,
+    };
+    namespaces.trigger_sync();
+    entry.result = "ok".to_string();
+    auditor.record(&pool, &retry, entry).await;
+    respond_result("ok")
+}
+
+#[derive(Deserialize)]
+pub struct OffendersQuery {
+    #[serde(default)]
+    scope: String,
+    #[serde(default = "default_top")]
+    top: usize,
+    #[serde(default)]
+    raw: bool,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+// get_offenders returns the ids with the most limited decisions in the last 5
+// minutes by scope. The ids are masked unless raw=true is requested with an
+// admin credential.
+pub async fn get_offenders(
+    req: HttpRequest,
+    query: web::Query<OffendersQuery>,
+) -> Result<HttpResponse, Error> {
+    if query.raw && auth::role(&req) == Some(auth::Role::Read) {
+        return respond_error(403, "raw ids require an admin credential".to_string());
+    }
+    let mut rt = stats::STATS.offenders(unix_ms(), &query.scope, query.top);
+    if !query.raw {
+        for offender in rt.values_mut().flatten() {
+            offender.id = mask_id(&offender.id);
+        }
+    }
+    respond_result(rt)
+}
+
+// mask_id identifies an id without revealing it, by the first 16 hex digits of
+// its SHA256.
+fn mask_id(id: &str) -> String {
+    format!(
+        "sha256:{}",
+        hex::encode(&Sha256::digest(id.as_bytes())[..8])
+    )
+}
+
+pub async fn get_stats() -> Result<HttpResponse, Error> {
+    respond_result(stats::STATS.snapshot(unix_ms()))
+}
+
+pub async fn get_metrics(shards: web::Data<Shards>) -> Result<HttpResponse, Error> {
+    for (name, pool) in shards.names().iter().zip(shards.pools()) {
+        metrics::observe_pool(name, pool);
+    }
+
+    match metrics::render() {
+        Ok(text) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(text)),
+        Err(err) => respond_error(500, err.to_string()),
+    }
+}
+
+// get_config returns the configuration resolved at startup, with secrets masked.
+pub async fn get_config(cfg: web::Data<conf::Conf>) -> Result<HttpResponse, Error> {
+    respond_result(cfg.as_ref())
+}
+
+// namespace returns the rules of the namespace, or a 400 response if unknown.
+fn namespace<'a>(
+    namespaces: &'a Namespaces,
+    ns: &str,
+) -> Result<&'a RedRules, Result<HttpResponse, Error>> {
+    namespaces
+        .get(ns)
+        .ok_or_else(|| respond_error(400, format!("unknown namespace: {}", ns)))
+}
+
 fn respond_result(result: impl serde::ser::Serialize) -> Result<HttpResponse, Error> {
     match to_value(result) {
         Ok(result) => Ok(HttpResponse::Ok()
@@ -160,12 +1276,94 @@ fn respond_result(result: impl serde::ser::Serialize) -> Result<HttpResponse, Er
 }
 
 fn respond_error(code: u16, err_msg: String) -> Result<HttpResponse, Error> {
+    Ok(error_response(code, err_msg))
+}
+
+fn error_response(code: u16, err_msg: String) -> HttpResponse {
     let err_json = json!({ "error": {"code": code, "message": err_msg }});
-    Ok(HttpResponse::build(StatusCode::from_u16(code).unwrap())
+    HttpResponse::build(StatusCode::from_u16(code).unwrap())
+        .content_type("application/json")
+        .json(err_json)
+}
+
+// respond_input_error responds 422 with the errors of the invalid entries of a
+// redlist or redrules mutation, by their ids or paths.
+fn respond_input_error(err: redlimit::InputError) -> Result<HttpResponse, Error> {
+    let err_json = json!({ "error": {
+        "code": 422,
+        "message": err.message,
+        "entries": err.entries,
+    }});
+    Ok(HttpResponse::UnprocessableEntity()
         .content_type("application/json")
         .json(err_json))
 }
 
+// json_config responds the errors of the JSON bodies with the same envelope as
+// respond_error, instead of the plain text of actix-web.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _| {
+        let code = match &err {
+            JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => 413,
+            JsonPayloadError::ContentType => 415,
+            _ => 400,
+        };
+        let res = error_response(code, format!("invalid request body: {}", err));
+        InternalError::from_response(err, res).into()
+    })
+}
+
+// MAX_BODY_SIZE is the body limit of POST /limiting.
+pub const MAX_BODY_SIZE: usize = 256 * 1024;
+
+// read_body reads a request body of at most limit bytes, the errors are
+// responded as json_config does: 413 if the body is too large, 400 if it can't
+// be read.
+pub async fn read_body<S>(
+    headers: &HeaderMap,
+    mut payload: S,
+    limit: usize,
+) -> Result<Bytes, HttpResponse>
+where
+    S: futures_core::Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let overflow = || {
+        error_response(
+            413,
+            format!(
+                "invalid request body: payload reached size limit ({} bytes)",
+                limit
+            ),
+        )
+    };
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<usize>().ok());
+    if length.map_or(false, |n| n > limit) {
+        return Err(overflow());
+    }
+
+    let mut body = BytesMut::with_capacity(length.unwrap_or(0));
+    while let Some(chunk) = payload.next().await {
+        let chunk =
+            chunk.map_err(|err| error_response(400, format!("invalid request body: {}", err)))?;
+        if body.len() + chunk.len() > limit {
+            return Err(overflow());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+// query_config responds the errors of the query strings as json_config does.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _| {
+        let res = error_response(400, format!("invalid query: {}", err));
+        InternalError::from_response(err, res).into()
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -174,10 +1372,168 @@ mod tests {
     const APP_NAME: &str = env!("CARGO_PKG_NAME");
     const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+    #[actix_web::test]
+    async fn redlist_input_works() -> anyhow::Result<()> {
+        let input: HashMap<String, RedlistInput> = serde_json::from_str(
+            r#"{"user1": 50000, "user2": {"ttl": 120000, "reason": "spam", "source": "waf"}}"#,
+        )?;
+        assert!(matches!(input["user1"], RedlistInput::Ttl(50000)));
+        match &input["user2"] {
+            RedlistInput::Entry {
+                ttl,
+                reason,
+                source,
+            } => assert_eq!(
+                (120000, "spam", "waf"),
+                (*ttl, reason.as_str(), source.as_str())
+            ),
+            _ => panic!("should be an entry"),
+        }
+        assert_eq!(50000, input["user1"].ttl());
+        assert_eq!(120000, input["user2"].ttl());
+
+        let err = redlimit::validate_redlist(
+            &conf::Inputs::default(),
+            input.iter().map(|(id, v)| (id, v.ttl() / 1000)),
+        )
+        .unwrap_err();
+        let res = respond_input_error(err).unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let body: Value =
+            serde_json::from_slice(&actix_web::body::to_bytes(res.into_body()).await.unwrap())?;
+        assert_eq!("invalid redlist", body["error"]["message"]);
+        assert_eq!(
+            "ttl 50 should be in [1000, 2592000000] milliseconds",
+            body["error"]["entries"]["user1"]
+        );
+        assert_eq!(2, body["error"]["entries"].as_object().unwrap().len());
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn json_config_works() -> anyhow::Result<()> {
+        async fn echo(
+            input: web::Json<Value>,
+            _query: web::Query<NamespaceQuery>,
+        ) -> Result<HttpResponse, Error> {
+            respond_result(input.into_inner())
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(json_config().limit(64))
+                .app_data(query_config())
+                .route("/", web::post().to(echo)),
+        )
+        .await;
+
+        for (req, code, message) in [
+            (
+                test::TestRequest::post()
+                    .uri("/")
+                    .insert_header(ContentType::json())
+                    .set_payload("{\"a\":"),
+                400,
+                "invalid request body: Json deserialize error",
+            ),
+            (
+                test::TestRequest::post()
+                    .uri("/")
+                    .insert_header(ContentType::plaintext())
+                    .set_payload("{}"),
+                415,
+                "invalid request body: Content type error",
+            ),
+            (
+                test::TestRequest::post()
+                    .uri("/")
+                    .insert_header(ContentType::json())
+                    .set_payload(format!("{{\"a\":\"{}\"}}", "x".repeat(100))),
+                413,
+                "invalid request body: JSON payload",
+            ),
+            (
+                test::TestRequest::post()
+                    .uri("/?namespace=a&namespace=b")
+                    .insert_header(ContentType::json())
+                    .set_payload("{}"),
+                400,
+                "invalid query: ",
+            ),
+        ] {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(code, resp.status().as_u16());
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(code, body["error"]["code"]);
+            let msg = body["error"]["message"].as_str().unwrap();
+            assert!(msg.starts_with(message), "{}", msg);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/?namespace=a")
+            .insert_header(ContentType::json())
+            .set_payload("{\"a\":1}");
+        let body: Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(json!({"result": {"a": 1}}), body);
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn limiting_body_limit_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let pool = std::sync::Arc::new(redlimit_core::redis::new_lazy(&cfg.redis)?);
+        let shards = web::Data::new(Shards::new(&cfg.redis, pool).await?);
+        let namespaces = web::Data::new(Namespaces::new(&cfg));
+        let shedder = web::Data::new(LoadShedder::new(cfg.shedding.clone()));
+        let local = web::Data::new(LocalLimiter::new(cfg.fallback.clone()));
+        let retry = web::Data::new(cfg.redis.retry.clone());
+        let hmac = conf::Hmac {
+            secrets: vec!["secret1".to_string()],
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(shards)
+                .app_data(namespaces)
+                .app_data(shedder)
+                .app_data(local)
+                .app_data(retry)
+                .route("/limiting", web::post().to(post_limiting))
+                .service(
+                    web::resource("/signed/limiting")
+                        .wrap(auth::HmacAuth::new(&hmac))
+                        .route(web::post().to(post_limiting)),
+                ),
+        )
+        .await;
+
+        for uri in ["/limiting", "/signed/limiting"] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(ContentType::json())
+                .set_payload(format!("{{\"id\":\"{}\"}}", "x".repeat(MAX_BODY_SIZE)));
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(413, resp.status().as_u16(), "{}", uri);
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(413, body["error"]["code"]);
+            let msg = body["error"]["message"].as_str().unwrap();
+            assert!(msg.starts_with("invalid request body: payload"), "{}", msg);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/limiting")
+            .insert_header(ContentType::json())
+            .set_payload("{\"id\":");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(400, resp.status().as_u16());
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(400, body["error"]["code"]);
+        Ok(())
+    }
+
     #[actix_web::test]
     async fn get_version_works() -> anyhow::Result<()> {
-        let cfg = super::super::conf::Conf::new()?;
-        let pool = web::Data::new(super::super::redis::new(cfg.redis.clone()).await?);
+        let test_redis = redlimit_core::testing::TestRedis::start().await?;
+        let pool = web::Data::new(super::super::redis::new(test_redis.cfg.redis.clone()).await?);
         let info = web::Data::new(AppInfo {
             name: APP_NAME.to_string(),
             version: APP_VERSION.to_string(),
@@ -187,7 +1543,7 @@ mod tests {
             App::new()
                 .app_data(pool.clone())
                 .app_data(info.clone())
-                .wrap(super::super::context::ContextTransform {})
+                .wrap(super::super::context::ContextTransform::new(1, &[], 0))
                 .route("/", web::get().to(version)),
         )
         .await;
@@ -199,4 +1555,326 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_web::test]
+    async fn admin_limited_works() -> anyhow::Result<()> {
+        let test_redis = redlimit_core::testing::TestRedis::start().await?;
+        let pool = super::super::redis::new(test_redis.cfg.redis.clone()).await?;
+        let retry = conf::Retry::default();
+        let ns = NS::new("admin_limited_works".to_string());
+        let limit = [2, 60000];
+
+        assert!(!admin_limited(&limit, &pool, &retry, &ns, "key:1", 1).await);
+        assert!(!admin_limited(&limit, &pool, &retry, &ns, "key:1", 1).await);
+        assert!(admin_limited(&limit, &pool, &retry, &ns, "key:1", 1).await);
+        assert!(!admin_limited(&limit, &pool, &retry, &ns, "key:2", 1).await);
+        assert!(!admin_limited(&[], &pool, &retry, &ns, "key:1", 1).await);
+
+        // no scope of a limiting request reaches the admin counters
+        let key = ns.admin_limit_key("key:1");
+        assert!(!key.starts_with(&format!("{}:", ns.as_str())));
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn wait_records_once_works() -> anyhow::Result<()> {
+        let mut cfg = conf::Conf::new()?;
+        cfg.rules.insert(
+            "wait_test".to_string(),
+            conf::Rule {
+                limit: vec![1, 20],
+                allow_percent: 0,
+                ..conf::Rule::default()
+            },
+        );
+        let pool = std::sync::Arc::new(redlimit_core::redis::new_lazy(&cfg.redis)?);
+        let app = test::init_service(
+            App::new()
+                .wrap(super::super::context::ContextTransform::new(1, &[], 0))
+                .app_data(web::Data::new(Shards::new(&cfg.redis, pool).await?))
+                .app_data(web::Data::new(Namespaces::new(&cfg)))
+                .app_data(web::Data::new(LoadShedder::new(cfg.shedding.clone())))
+                .app_data(web::Data::new(LocalLimiter::new(cfg.fallback.clone())))
+                .app_data(web::Data::new(cfg.redis.retry.clone()))
+                .app_data(web::Data::new(Waiter::new(&cfg.wait)))
+                .route("/limiting", web::post().to(post_limiting)),
+        )
+        .await;
+
+        // throttled, not retried by the waiter
+        let req = test::TestRequest::post().uri("/limiting").set_json(
+            json!({"scope": "wait_test", "path": "GET /", "id": "user1", "wait_ms": 100}),
+        );
+        let start = std::time::Instant::now();
+        let rt: Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+        assert_eq!(20, rt["result"]["retry"]);
+        assert_eq!(true, rt["result"]["throttled"]);
+
+        let offenders = stats::STATS.offenders(unix_ms(), "wait_test", 10);
+        assert_eq!(1, offenders["wait_test"].len());
+        assert_eq!(1, offenders["wait_test"][0].limited, "recorded once");
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn empty_id_works() -> anyhow::Result<()> {
+        let mut cfg = conf::Conf::new()?;
+        cfg.rules.get_mut("core").unwrap().failure_policy = conf::FailurePolicy::Closed;
+        let pool = std::sync::Arc::new(redlimit_core::redis::new_lazy(&cfg.redis)?);
+        let shards = Shards::new(&cfg.redis, pool).await?;
+        let rules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let shedder = LoadShedder::new(cfg.shedding.clone());
+        let local = LocalLimiter::new(cfg.fallback.clone());
+        let limiter = Limiter {
+            shards: &shards,
+            shedder: &shedder,
+            local: &local,
+            approx: None,
+            leaser: None,
+            retry: &cfg.redis.retry,
+            hook: None,
+            attrs: &[],
+        };
+
+        // a second far from now, not recorded by other tests
+        let ts = 7_000_000;
+        let d = limiter.check(&rules, ts, "core", "GET /", "").await?;
+        assert_eq!(redlimit::LimitResult(0, 0), d.rt);
+        assert!(!d.fallback);
+        let s = stats::STATS.snapshot(ts);
+        assert_eq!(1, s.m1.requests);
+        assert_eq!(0, s.m1.errors);
+        assert_eq!(0, s.m1.limited);
+        assert!(!shedder.is_shedding(ts));
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn hook_check_works() -> anyhow::Result<()> {
+        let script = std::env::temp_dir().join(format!("redlimit-hook-{}.rhai", unix_ms()));
+        std::fs::write(
+            &script,
+            r#"
+            fn decide(req) {
+                if req.path == "GET /zero" {
+                    return #{ quantity: 0 };
+                }
+                #{ allow: true }
+            }
+            "#,
+        )?;
+        let hook = Hook::new(&conf::Hook {
+            script: script.to_string_lossy().to_string(),
+            ..conf::Hook::default()
+        })?
+        .unwrap();
+        std::fs::remove_file(&script)?;
+
+        let mut cfg = conf::Conf::new()?;
+        cfg.rules.get_mut("core").unwrap().failure_policy = conf::FailurePolicy::Closed;
+        cfg.redis.port = 1; // refused
+        let pool = std::sync::Arc::new(redlimit_core::redis::new_lazy(&cfg.redis)?);
+        let shards = Shards::new(&cfg.redis, pool).await?;
+        let rules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let shedder = LoadShedder::new(cfg.shedding.clone());
+        let local = LocalLimiter::new(cfg.fallback.clone());
+        let limiter = Limiter {
+            shards: &shards,
+            shedder: &shedder,
+            local: &local,
+            approx: None,
+            leaser: None,
+            retry: &conf::Retry::default(),
+            hook: Some(&hook),
+            attrs: &[],
+        };
+
+        let ts = unix_ms();
+        let d = limiter.check(&rules, ts, "core", "GET /", "user1").await?;
+        assert_eq!(redlimit::LimitResult(0, 0), d.rt, "allowed by the hook");
+        assert!(matches!(
+            limiter
+                .check(&rules, ts, "core", "GET /zero", "user1")
+                .await,
+            Err(redlimit::LimitError::Quantity { quantity: 0, .. })
+        ));
+        assert_eq!(
+            Err(redlimit::LimitError::Scope),
+            limiter
+                .check(&rules, ts, "core:x", "GET /", "user1")
+                .await
+                .map(|d| d.rt),
+            "the keys of scope \"core:x\" would be missed by erasing"
+        );
+
+        rules.dyn_update(
+            ts,
+            1,
+            HashMap::from([("user1".to_string(), ts + 10000)]),
+            HashMap::new(),
+        );
+        let d = limiter.check(&rules, ts, "core", "GET /", "user1").await?;
+        assert!(
+            d.rt.1 > 0,
+            "redlisted, limited by the closed failure policy"
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn stream_redlist_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let rules = RedRules::new(&cfg.namespace, &cfg.rules);
+        let mut list: HashMap<String, u64> =
+            (0..2500).map(|i| (format!("user{}", i), 3000)).collect();
+        list.insert("user\"x".to_string(), 3000);
+        rules.dyn_update(1000, 0, list.clone(), HashMap::new());
+
+        let resp = stream_redlist(rules.redlist_snapshot(), 1000, false);
+        assert_eq!(
+            "application/json",
+            resp.headers().get("content-type").unwrap()
+        );
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let rt: HashMap<String, HashMap<String, u64>> = serde_json::from_slice(&body)?;
+        assert_eq!(list, rt["result"]);
+
+        let resp = stream_redlist(rules.redlist_snapshot(), 1000, true);
+        assert_eq!(NDJSON, resp.headers().get("content-type").unwrap());
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let mut rt = HashMap::new();
+        for line in body.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let entry: Value = serde_json::from_slice(line)?;
+            rt.insert(
+                entry["id"].as_str().unwrap().to_string(),
+                entry["ttl"].as_u64().unwrap(),
+            );
+        }
+        assert_eq!(list, rt);
+
+        let resp = stream_redlist(rules.redlist_snapshot(), 3001, false);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(r#"{"result":{}}"#, String::from_utf8_lossy(&body));
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn get_offenders_works() -> anyhow::Result<()> {
+        stats::STATS.record_limited(unix_ms(), "offenders_test", "user1");
+        let app = test::init_service(
+            App::new().service(
+                web::resource("/stats/offenders")
+                    .wrap(auth::Auth::new(
+                        &["admin1".to_string()],
+                        &["read1".to_string()],
+                        None,
+                    ))
+                    .route(web::get().to(get_offenders)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/stats/offenders?scope=offenders_test")
+            .insert_header(("x-api-key", "read1"))
+            .to_request();
+        let rt: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            json!([{"id": mask_id("user1"), "limited": 1}]),
+            rt["result"]["offenders_test"]
+        );
+        assert!(!rt.to_string().contains("user1"));
+
+        let req = test::TestRequest::with_uri("/stats/offenders?scope=offenders_test&raw=true")
+            .insert_header(("x-api-key", "read1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(403, resp.status().as_u16());
+
+        let req = test::TestRequest::with_uri("/stats/offenders?scope=offenders_test&raw=true")
+            .insert_header(("x-api-key", "admin1"))
+            .to_request();
+        let rt: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            json!([{"id": "user1", "limited": 1}]),
+            rt["result"]["offenders_test"]
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn forward_keys_works() {
+        let mut cfg = conf::ForwardAuth::default();
+        let allow = conf::EmptyId::Allow;
+        let req = test::TestRequest::default().to_http_request();
+        assert_eq!(
+            Some(("core".to_string(), "GET /".to_string(), "".to_string())),
+            forward_keys(&req, &cfg, allow),
+            "no id"
+        );
+        assert_eq!(None, forward_keys(&req, &cfg, conf::EmptyId::Reject));
+        assert_eq!(None, forward_keys(&req, &cfg, conf::EmptyId::Fallback));
+        let req = test::TestRequest::default()
+            .insert_header(("x-real-ip", "10.0.0.3"))
+            .to_http_request();
+        assert_eq!(
+            Some((
+                "core".to_string(),
+                "GET /".to_string(),
+                "10.0.0.3".to_string()
+            )),
+            forward_keys(&req, &cfg, conf::EmptyId::Fallback)
+        );
+
+        let req = test::TestRequest::default()
+            .insert_header(("x-forwarded-method", "POST"))
+            .insert_header(("x-forwarded-uri", "/v1/file/upload?x=1"))
+            .insert_header(("x-forwarded-for", "10.0.0.1, 10.0.0.2"))
+            .insert_header(("x-scope", "biz"))
+            .insert_header(("x-user-id", "user1"))
+            .to_http_request();
+        assert_eq!(
+            Some((
+                "core".to_string(),
+                "POST /v1/file/upload".to_string(),
+                "10.0.0.1".to_string()
+            )),
+            forward_keys(&req, &cfg, allow)
+        );
+
+        cfg.scope_header = "x-scope".to_string();
+        cfg.id_headers = vec!["x-user-id".to_string(), "x-forwarded-for".to_string()];
+        assert_eq!(
+            Some((
+                "biz".to_string(),
+                "POST /v1/file/upload".to_string(),
+                "user1".to_string()
+            )),
+            forward_keys(&req, &cfg, allow)
+        );
+    }
+
+    #[actix_web::test]
+    async fn request_id_works() -> anyhow::Result<()> {
+        let app = test::init_service(
+            App::new()
+                .wrap(super::super::context::ContextTransform::new(1, &[], 0))
+                .route("/stats", web::get().to(get_stats)),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/stats")
+            .insert_header(("x-request-id", "xid123"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!("xid123", resp.headers().get("x-request-id").unwrap());
+
+        let req = test::TestRequest::with_uri("/stats").to_request();
+        let resp = test::call_service(&app, req).await;
+        let xid = resp.headers().get("x-request-id").unwrap().to_str()?;
+        assert_eq!(32, xid.len());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,414 @@
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac};
+use rustis::resp;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::task::JoinHandle;
+use tonic::metadata::MetadataMap;
+use uuid::Uuid;
+
+use super::{
+    auth, conf,
+    context::{unix_ms, ContextExt},
+    redis,
+    redis::RedisPool,
+    redlimit::RedlistMeta,
+    redlist::PatternList,
+};
+
+// The max number of ids or paths kept in the payload summary.
+const SUMMARY_KEYS: usize = 20;
+
+// Auditor records the mutations of redlist and redrules to the "audit" log
+// target, and to a Redis stream and the webhooks if configured.
+pub struct Auditor {
+    cfg: conf::Audit,
+    http: reqwest::Client,
+    proxies: PatternList,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub action: String,
+    pub namespace: String,
+    pub caller: String,
+    pub remote: String,
+    pub xid: String,
+    pub summary: Value,
+    pub limited: bool, // by the admin limit
+    pub result: String,
+}
+
+impl Entry {
+    // actor is the caller, or the remote address without a caller identity.
+    pub fn actor(&self) -> &str {
+        if self.caller.is_empty() {
+            &self.remote
+        } else {
+            &self.caller
+        }
+    }
+}
+
+impl Auditor {
+    pub fn new(cfg: conf::Audit) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(cfg.webhook_timeout))
+            .build()
+            .unwrap_or_default();
+        let mut proxies = PatternList::default();
+        for proxy in &cfg.trusted_proxies {
+            let net = match proxy.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => format!("{}/32", proxy),
+                Ok(IpAddr::V6(_)) => format!("{}/128", proxy),
+                Err(_) => proxy.clone(),
+            };
+            if net.ends_with('*') || !proxies.insert(&net, u64::MAX) {
+                log::warn!(target: "audit", "invalid trusted proxy {:?}", proxy);
+            }
+        }
+        Auditor { cfg, http, proxies }
+    }
+
+    // remote returns the address of the client, from the forwarded headers only
+    // if the peer is a trusted proxy.
+    fn remote(&self, req: &HttpRequest) -> String {
+        match req.peer_addr().map(|addr| addr.ip().to_string()) {
+            Some(peer) if self.proxies.get(&peer).is_some() => req
+                .connection_info()
+                .realip_remote_addr()
+                .unwrap_or("")
+                .to_string(),
+            Some(peer) => peer,
+            None => String::new(),
+        }
+    }
+
+    // entry creates an audit entry with the caller identity of the request.
+    pub fn entry(&self, req: &HttpRequest, action: &str, namespace: &str, summary: Value) -> Entry {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        };
+        Entry {
+            action: action.to_string(),
+            namespace: namespace.to_string(),
+            caller: match auth::principal(req) {
+                Some(principal) => principal,
+                None if self.cfg.identity_header.is_empty() => String::new(),
+                None => header(&self.cfg.identity_header),
+            },
+            remote: self.remote(req),
+            xid: req
+                .context()
+                .map_or_else(|_| header("x-request-id"), |ctx| ctx.xid.clone()),
+            summary,
+            limited: false,
+            result: String::new(),
+        }
+    }
+
+    // grpc_entry creates an audit entry with the authenticated credential, or
+    // the caller identity of the gRPC request metadata.
+    pub fn grpc_entry(
+        &self,
+        metadata: &MetadataMap,
+        principal: Option<String>,
+        remote: Option<SocketAddr>,
+        action: &str,
+        namespace: &str,
+        summary: Value,
+    ) -> Entry {
+        let header = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        };
+        let xid = header("x-request-id");
+        Entry {
+            action: action.to_string(),
+            namespace: namespace.to_string(),
+            caller: match principal {
+                Some(principal) => principal,
+                None if self.cfg.identity_header.is_empty() => String::new(),
+                None => header(&self.cfg.identity_header),
+            },
+            remote: remote.map_or_else(String::new, |addr| addr.ip().to_string()),
+            xid: if xid.is_empty() {
+                Uuid::new_v4().simple().to_string()
+            } else {
+                xid
+            },
+            summary,
+            limited: false,
+            result: String::new(),
+        }
+    }
+
+    pub async fn record(&self, pool: &RedisPool, retry: &conf::Retry, entry: Entry) {
+        log::info!(target: "audit",
+            action = entry.action,
+            namespace = entry.namespace,
+            caller = entry.caller,
+            remote = entry.remote,
+            xid = entry.xid,
+            result = entry.result,
+            limited = entry.limited,
+            summary = log::as_serde!(&entry.summary);
+            "",
+        );
+        if !entry.limited && entry.result == "ok" {
+            self.notify(&entry);
+        }
+
+        if self.cfg.stream.is_empty() {
+            return;
+        }
+        let cmd = resp::cmd("XADD")
+            .arg(self.cfg.stream.as_str())
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.cfg.stream_maxlen)
+            .arg("*")
+            .arg("action")
+            .arg(entry.action)
+            .arg("namespace")
+            .arg(entry.namespace)
+            .arg("caller")
+            .arg(entry.caller)
+            .arg("remote")
+            .arg(entry.remote)
+            .arg("xid")
+            .arg(entry.xid)
+            .arg("summary")
+            .arg(entry.summary.to_string())
+            .arg("limited")
+            .arg(entry.limited)
+            .arg("result")
+            .arg(entry.result);
+        if let Err(err) = redis::send(pool, cmd, retry).await {
+            log::error!(target: "audit", "audit stream error: {}", err);
+        }
+    }
+
+    // notify posts the entry to the webhooks in background, signed with the
+    // webhook secret if set.
+    pub fn notify(&self, entry: &Entry) -> Option<JoinHandle<()>> {
+        if self.cfg.webhooks.is_empty() {
+            return None;
+        }
+        let body = json!({
+            "action": entry.action,
+            "namespace": entry.namespace,
+            "caller": entry.caller,
+            "remote": entry.remote,
+            "xid": entry.xid,
+            "summary": entry.summary,
+            "ts": unix_ms(),
+        })
+        .to_string();
+        let ts = (unix_ms() / 1000).to_string();
+        let signature = sign(&self.cfg.webhook_secret, &ts, &body);
+        let (http, urls) = (self.http.clone(), self.cfg.webhooks.clone());
+        Some(tokio::spawn(async move {
+            for url in urls {
+                let mut req = http
+                    .post(&url)
+                    .header("content-type", "application/json")
+                    .body(body.clone());
+                if let Some(signature) = &signature {
+                    req = req
+                        .header("x-signature-timestamp", ts.as_str())
+                        .header("x-signature", signature.as_str());
+                }
+                let rt = match req.send().await {
+                    Ok(res) => res.error_for_status().map(|_| ()),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = rt {
+                    // the url is not logged, it may contain a token
+                    log::error!(target: "audit", "webhook error: {}", err.without_url());
+                }
+            }
+        }))
+    }
+}
+
+// sign returns the hex HMAC-SHA256 of "<timestamp>\n<body>", None if no secret.
+fn sign(secret: &str, ts: &str, body: &str) -> Option<String> {
+    if secret.is_empty() {
+        return None;
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(format!("{}\n{}", ts, body).as_bytes());
+    Some(hex::encode(mac.finalize().into_bytes()))
+}
+
+// summary keeps the count and the first sorted keys of a payload.
+pub fn summary<'a>(keys: impl Iterator<Item = &'a String>) -> Value {
+    let mut keys: Vec<&String> = keys.collect();
+    keys.sort();
+    json!({
+        "count": keys.len(),
+        "keys": keys.into_iter().take(SUMMARY_KEYS).collect::<Vec<&String>>(),
+    })
+}
+
+// with_meta adds the distinct reasons and sources of the redlist metadata to
+// the summary, the actor is the caller of the entry.
+pub fn with_meta<'a>(mut summary: Value, meta: impl Iterator<Item = &'a RedlistMeta>) -> Value {
+    let mut reasons: BTreeSet<&str> = BTreeSet::new();
+    let mut sources: BTreeSet<&str> = BTreeSet::new();
+    for m in meta {
+        if !m.reason.is_empty() {
+            reasons.insert(&m.reason);
+        }
+        if !m.source.is_empty() {
+            sources.insert(&m.source);
+        }
+    }
+    if !reasons.is_empty() {
+        summary["reasons"] = json!(reasons.into_iter().take(SUMMARY_KEYS).collect::<Vec<_>>());
+    }
+    if !sources.is_empty() {
+        summary["sources"] = json!(sources.into_iter().take(SUMMARY_KEYS).collect::<Vec<_>>());
+    }
+    summary
+}
+
+pub fn result<T>(rt: &anyhow::Result<T>) -> String {
+    match rt {
+        Ok(_) => "ok".to_string(),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test::TestRequest, HttpMessage};
+
+    use super::*;
+
+    #[test]
+    fn with_meta_works() {
+        let meta = |reason: &str, source: &str| RedlistMeta {
+            reason: reason.to_string(),
+            actor: "alice".to_string(),
+            source: source.to_string(),
+        };
+        let keys = ["user1".to_string()];
+        let rt = with_meta(summary(keys.iter()), [].iter());
+        assert_eq!(json!({"count": 1, "keys": ["user1"]}), rt);
+        let metas = [meta("spam", "waf"), meta("spam", ""), meta("abuse", "")];
+        let rt = with_meta(summary(keys.iter()), metas.iter());
+        assert_eq!(
+            json!({"count": 1, "keys": ["user1"], "reasons": ["abuse", "spam"], "sources": ["waf"]}),
+            rt
+        );
+    }
+
+    #[test]
+    fn summary_works() {
+        let keys: Vec<String> = (0..30).map(|i| format!("user{:02}", i)).collect();
+        let rt = summary(keys.iter().rev());
+        assert_eq!(30, rt["count"]);
+        assert_eq!(SUMMARY_KEYS, rt["keys"].as_array().unwrap().len());
+        assert_eq!("user00", rt["keys"][0]);
+
+        assert_eq!(json!({"count": 0, "keys": []}), summary([].iter()));
+    }
+
+    #[test]
+    fn entry_works() {
+        let auditor = Auditor::new(conf::Audit::default());
+        let req = TestRequest::default()
+            .insert_header(("x-user-id", "admin1"))
+            .insert_header(("x-request-id", "xid1"))
+            .insert_header(("x-forwarded-for", "10.0.0.1"))
+            .to_http_request();
+        let entry = auditor.entry(&req, "redlist.add", "RL", json!({"count": 1}));
+        assert_eq!("admin1", entry.caller);
+        assert_eq!("", entry.remote, "no peer address");
+        assert_eq!("xid1", entry.xid);
+        assert_eq!("RL", entry.namespace);
+
+        let req = TestRequest::default()
+            .insert_header(("x-user-id", "admin1"))
+            .insert_header(("x-forwarded-for", "10.0.0.1"))
+            .peer_addr("192.168.0.2:5000".parse().unwrap())
+            .to_http_request();
+        req.extensions_mut().insert(auth::Principal::key("key1"));
+        let entry = auditor.entry(&req, "redlist.add", "RL", json!({"count": 1}));
+        assert_eq!(auth::Principal::key("key1").0, entry.caller);
+        assert_eq!("192.168.0.2", entry.remote, "untrusted forwarded header");
+
+        let auditor = Auditor::new(conf::Audit {
+            trusted_proxies: vec!["192.168.0.0/16".to_string(), "10.0.0.9".to_string()],
+            ..conf::Audit::default()
+        });
+        let entry = auditor.entry(&req, "redlist.add", "RL", json!({"count": 1}));
+        assert_eq!("10.0.0.1", entry.remote);
+
+        let rt: anyhow::Result<()> = Err(anyhow::Error::msg("boom"));
+        assert_eq!("boom", result(&rt));
+        assert_eq!("ok", result(&Ok(())));
+    }
+
+    #[actix_web::test]
+    async fn notify_works() -> anyhow::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let cfg = conf::Audit {
+            webhooks: vec![format!("http://{}/hook", listener.local_addr()?)],
+            webhook_secret: "secret1".to_string(),
+            ..Default::default()
+        };
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let auditor = Auditor::new(cfg);
+        let req = TestRequest::default().to_http_request();
+        let entry = auditor.entry(&req, "redlist.add", "RL", json!({"count": 1}));
+        auditor.notify(&entry).unwrap().await?;
+
+        let received = server.await?;
+        assert!(received.starts_with("POST /hook HTTP/1.1"));
+        let (head, body) = received.split_once("\r\n\r\n").unwrap();
+        let header = |name: &str| {
+            head.lines()
+                .find_map(|l| l.strip_prefix(&format!("{}: ", name)))
+                .unwrap()
+        };
+        let ts = header("x-signature-timestamp");
+        assert_eq!(sign("secret1", ts, body).unwrap(), header("x-signature"));
+        let body: Value = serde_json::from_str(body)?;
+        assert_eq!("redlist.add", body["action"]);
+        assert_eq!(1, body["summary"]["count"]);
+
+        assert!(Auditor::new(conf::Audit::default())
+            .notify(&entry)
+            .is_none());
+        Ok(())
+    }
+}
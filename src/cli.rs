@@ -0,0 +1,167 @@
+use clap::Parser;
+use config::ConfigError;
+
+use super::conf::{Conf, DEFAULT_FILE};
+
+// Cli flags override the config file and the environment variables, so that an
+// ad-hoc instance can be started without crafting a config file.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// The config file path.
+    #[arg(
+        short,
+        long,
+        env = "CONFIG_FILE_PATH",
+        default_value = DEFAULT_FILE
+    )]
+    pub config: String,
+
+    /// The port to listen on, overrides server.port.
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// The Redis URL, e.g. "redis://127.0.0.1:6379/0", overrides redis.url.
+    #[arg(long)]
+    pub redis_url: Option<String>,
+
+    /// The log level: trace, debug, info, warn or error, overrides log.level.
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// The file to write the pid to, overrides server.pid_file.
+    #[arg(long, value_name = "FILE")]
+    pub pid_file: Option<String>,
+
+    /// Print the resolved configuration with secrets masked, then exit.
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Validate a config file, then exit with non-zero status on errors.
+    #[arg(long, value_name = "FILE")]
+    pub check: Option<String>,
+
+    /// Print the JSON Schema of the config file for editor tooling, then exit.
+    #[arg(long)]
+    pub schema: bool,
+
+    /// Export the redlist and redrules of all namespaces to a file or an S3
+    /// URL ("s3://<bucket>/<key>"), then exit.
+    #[arg(long, value_name = "URL")]
+    pub export: Option<String>,
+
+    /// Import a snapshot from a file or an S3 URL into Redis, then exit.
+    #[arg(long, value_name = "URL", conflicts_with = "export")]
+    pub import: Option<String>,
+}
+
+impl Cli {
+    pub fn apply(&self, cfg: &mut Conf) {
+        if let Some(port) = self.port {
+            cfg.server.port = port;
+        }
+        if let Some(url) = &self.redis_url {
+            cfg.redis.url = url.clone();
+        }
+        if let Some(level) = &self.log_level {
+            cfg.log.level = level.clone();
+        }
+        if let Some(pid_file) = &self.pid_file {
+            cfg.server.pid_file = pid_file.clone();
+        }
+    }
+}
+
+// check loads and validates a config file, including the bind addresses.
+pub fn check(file_name: &str) -> Result<Conf, ConfigError> {
+    let cfg = Conf::from(file_name)?;
+    cfg.server.addrs()?;
+    cfg.server.admin_addrs()?;
+    cfg.server.grpc_addr()?;
+    cfg.spoe.addr()?;
+    crate::hook::Hook::new(&cfg.hook).map_err(|err| ConfigError::Message(err.to_string()))?;
+    Ok(cfg)
+}
+
+pub fn schema() -> anyhow::Result<String> {
+    Ok(serde_json::to_string_pretty(&schemars::schema_for!(Conf))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_works() -> anyhow::Result<()> {
+        let cli = Cli::try_parse_from(["redlimit", "--config", "./config/test.toml"])?;
+        let mut cfg = Conf::from(&cli.config)?;
+        cli.apply(&mut cfg);
+        assert_eq!("TEST", cfg.namespace);
+        assert_eq!(8080, cfg.server.port);
+        assert_eq!("", cfg.redis.url);
+        assert_eq!("info", cfg.log.level);
+
+        let cli = Cli::try_parse_from([
+            "redlimit",
+            "-c",
+            "./config/test.toml",
+            "--port",
+            "8081",
+            "--redis-url",
+            "redis://10.0.0.1:6379/1",
+            "--log-level",
+            "debug",
+            "--pid-file",
+            "/run/redlimit.pid",
+        ])?;
+        cli.apply(&mut cfg);
+        assert_eq!(8081, cfg.server.port);
+        assert_eq!("redis://10.0.0.1:6379/1", cfg.redis.url);
+        assert_eq!("debug", cfg.log.level);
+        assert_eq!("/run/redlimit.pid", cfg.server.pid_file);
+
+        assert!(Cli::try_parse_from(["redlimit", "--port", "x"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_works() -> anyhow::Result<()> {
+        let cli = Cli::try_parse_from(["redlimit", "--check", "./config/test.toml"])?;
+        assert_eq!(Some("./config/test.toml".to_string()), cli.check);
+        assert!(check("./config/test.toml").is_ok());
+        assert!(check("./config/test.yaml").is_ok());
+
+        assert!(
+            crate::conf::is_builtin(DEFAULT_FILE),
+            "the default config is built in redlimit-core"
+        );
+        assert!(check(DEFAULT_FILE).is_ok());
+        let err = check("./config/not_exists.toml").unwrap_err().to_string();
+        assert!(err.contains("not_exists.toml"), "{}", err);
+
+        let file_name = std::env::temp_dir().join("redlimit_check_test.toml");
+        let bad = std::fs::read_to_string("./config/test.toml")?
+            .replace("limit = [100, 10000, 20, 1000]", "limit = [100, 100000]");
+        std::fs::write(&file_name, bad)?;
+        let err = check(file_name.to_str().unwrap()).unwrap_err().to_string();
+        std::fs::remove_file(&file_name)?;
+        assert!(
+            err.contains("rules.\"core\".limit: period should be in [1, 60000]"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn schema_works() -> anyhow::Result<()> {
+        let schema: serde_json::Value = serde_json::from_str(&schema()?)?;
+        assert_eq!("Conf", schema["title"]);
+        assert!(schema["definitions"]["Rule"]["properties"]["limit"].is_object());
+        assert_eq!(
+            serde_json::json!(["fixed-window", "sliding", "gcra", "token-bucket"]),
+            schema["definitions"]["Algorithm"]["enum"]
+        );
+        Ok(())
+    }
+}
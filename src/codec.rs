@@ -0,0 +1,128 @@
+use actix_web::{
+    http::header::{ACCEPT, CONTENT_TYPE},
+    HttpRequest, HttpResponse,
+};
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Format is the body format of "POST /limiting", negotiated by the Content-Type
+// and Accept headers. MessagePack and CBOR save the JSON overhead for the
+// high-frequency internal callers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    // from_mime returns the format of a media type, None if not supported.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MsgPack)
+            }
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    // request returns the format of the request body, JSON if no Content-Type.
+    pub fn request(req: &HttpRequest) -> Option<Self> {
+        match req.headers().get(CONTENT_TYPE) {
+            Some(v) => Format::from_mime(v.to_str().ok()?),
+            None => Some(Format::Json),
+        }
+    }
+
+    // response returns the first supported format in the Accept header, or the
+    // format of the request body.
+    pub fn response(req: &HttpRequest, request: Format) -> Self {
+        req.headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').find_map(Format::from_mime))
+            .unwrap_or(request)
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T> {
+        Ok(match self {
+            Format::Json => serde_json::from_slice(body)?,
+            Format::MsgPack => rmp_serde::from_slice(body)?,
+            Format::Cbor => ciborium::de::from_reader(body)?,
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Format::Json => serde_json::to_vec(value)?,
+            // struct as map, as JSON objects
+            Format::MsgPack => rmp_serde::to_vec_named(value)?,
+            Format::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::ser::into_writer(value, &mut buf)?;
+                buf
+            }
+        })
+    }
+
+    pub fn respond<T: Serialize>(&self, value: &T) -> Result<HttpResponse> {
+        Ok(HttpResponse::Ok()
+            .content_type(self.mime())
+            .body(self.encode(value)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn format_works() -> anyhow::Result<()> {
+        assert_eq!(
+            Some(Format::Json),
+            Format::from_mime("application/json; charset=utf-8")
+        );
+        assert_eq!(
+            Some(Format::MsgPack),
+            Format::from_mime("application/x-msgpack")
+        );
+        assert_eq!(None, Format::from_mime("text/plain"));
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(Some(Format::Json), Format::request(&req));
+        assert_eq!(Format::Json, Format::response(&req, Format::Json));
+
+        let req = TestRequest::default()
+            .insert_header(("content-type", "application/cbor"))
+            .insert_header(("accept", "text/html, application/msgpack, */*"))
+            .to_http_request();
+        assert_eq!(Some(Format::Cbor), Format::request(&req));
+        assert_eq!(Format::MsgPack, Format::response(&req, Format::Cbor));
+
+        let req = TestRequest::default()
+            .insert_header(("content-type", "application/cbor"))
+            .insert_header(("accept", "*/*"))
+            .to_http_request();
+        assert_eq!(Format::Cbor, Format::response(&req, Format::Cbor));
+
+        let value = json!({"result": {"limit": 100, "remaining": 95, "retry": 0}});
+        for f in [Format::Json, Format::MsgPack, Format::Cbor] {
+            let data = f.encode(&value)?;
+            assert_eq!(value, f.decode::<Value>(&data)?);
+        }
+        Ok(())
+    }
+}
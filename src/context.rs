@@ -1,26 +1,83 @@
 use std::{
-    cell::{Ref, RefMut},
-    collections::HashMap,
-    time::Instant,
+    cell::{Cell, Ref, RefMut},
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
 use actix_utils::future::{ready, Ready};
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
     error::ErrorInternalServerError,
+    http::header::{HeaderName, HeaderValue},
     Error, HttpMessage, HttpRequest,
 };
 use futures_core::future::LocalBoxFuture;
 use serde_json::Value;
+use uuid::Uuid;
 
-pub use structured_logger::unix_ms;
+pub use redlimit_core::context::unix_ms;
+use redlimit_core::context::{Timings, TIMINGS};
 
-pub struct ContextTransform;
+const X_REQUEST_ID: &str = "x-request-id";
+
+// ContextTransform logs 1 in `sample` successful requests marked as sampling,
+// others are always logged except the `skip_paths`. Requests slower than
+// `slow_threshold` milliseconds are always logged at WARN.
+pub struct ContextTransform {
+    sample: u64,
+    skip_paths: Rc<HashSet<String>>,
+    slow_threshold: u64,
+}
+
+impl ContextTransform {
+    pub fn new(sample: u64, skip_paths: &[String], slow_threshold: u64) -> Self {
+        ContextTransform {
+            sample,
+            skip_paths: Rc::new(skip_paths.iter().cloned().collect()),
+            slow_threshold,
+        }
+    }
+}
 
 pub struct Context {
     pub unix_ms: u64,
     pub start: Instant,
-    pub log: HashMap<String, Value>,
+    pub log: HashMap<&'static str, Value>,
+    // xid is the x-request-id of the request, generated if missing.
+    pub xid: String,
+    // sampling marks a high-volume request whose access log can be sampled.
+    pub sampling: bool,
+    // slow is the detail of a request slower than the threshold, set when
+    // the request is done.
+    pub slow: Option<SlowRequest>,
+}
+
+// SlowRequest is the timing of a slow request in milliseconds: the total
+// elapsed time, the pool wait and the Redis command time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowRequest {
+    pub elapsed: u64,
+    pub pool_wait: u64,
+    pub redis: u64,
+}
+
+impl SlowRequest {
+    // check returns the timing if the elapsed or the Redis time (pool wait
+    // and commands) reaches the threshold, None if the threshold is 0.
+    fn check(threshold: u64, elapsed: Duration, timings: &Timings) -> Option<Self> {
+        let rt = SlowRequest {
+            elapsed: elapsed.as_millis() as u64,
+            pool_wait: timings.pool_wait.get().as_millis() as u64,
+            redis: timings.command.get().as_millis() as u64,
+        };
+        let redis = (timings.pool_wait.get() + timings.command.get()).as_millis() as u64;
+        if threshold > 0 && (rt.elapsed >= threshold || redis >= threshold) {
+            Some(rt)
+        } else {
+            None
+        }
+    }
 }
 
 impl Context {
@@ -28,7 +85,11 @@ impl Context {
         Context {
             unix_ms: unix_ms(),
             start: Instant::now(),
-            log: HashMap::new(),
+            // the fields of a limiting decision, without growing
+            log: HashMap::with_capacity(16),
+            xid: String::new(),
+            sampling: false,
+            slow: None,
         }
     }
 }
@@ -75,12 +136,22 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(ContextMiddleware { service }))
+        ready(Ok(ContextMiddleware {
+            service,
+            sample: self.sample,
+            skip_paths: self.skip_paths.clone(),
+            slow_threshold: self.slow_threshold,
+            counter: Rc::new(Cell::new(0)),
+        }))
     }
 }
 
 pub struct ContextMiddleware<S> {
     service: S,
+    sample: u64,
+    skip_paths: Rc<HashSet<String>>,
+    slow_threshold: u64,
+    counter: Rc<Cell<u64>>, // per worker
 }
 
 impl<S, B> Service<ServiceRequest> for ContextMiddleware<S>
@@ -96,33 +167,143 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let log_method = req.method().to_string();
+        let log_method = req.method().clone();
         let log_path = req.path().to_string();
-        let log_xid = req
+        let log_xid = match req
             .headers()
-            .get("x-request-id")
-            .map_or("", |h| h.to_str().unwrap())
-            .to_string();
+            .get(X_REQUEST_ID)
+            .and_then(|h| h.to_str().ok())
+        {
+            Some(xid) if !xid.is_empty() => xid.to_string(),
+            _ => Uuid::new_v4().simple().to_string(),
+        };
 
-        let ctx = Context::new();
+        let mut ctx = Context::new();
+        ctx.xid = log_xid.clone();
         req.request().extensions_mut().insert(ctx);
-        let fut = self.service.call(req);
+        let skip_path = self.skip_paths.contains(&log_path);
+        let timings = Rc::new(Timings::default());
+        let fut = TIMINGS.scope(timings.clone(), self.service.call(req));
+        let sample = self.sample;
+        let slow_threshold = self.slow_threshold;
+        let counter = self.counter.clone();
         Box::pin(async move {
-            let res = fut.await?;
+            let mut res = fut.await?;
+            if let Ok(xid) = HeaderValue::from_str(&log_xid) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(X_REQUEST_ID), xid);
+            }
             {
-                let ctx = res.request().context_mut().unwrap();
-                log::info!(target: "api",
-                    method = log_method,
-                    path = log_path,
-                    xid = log_xid,
-                    status = res.response().status().as_u16(),
-                    start = ctx.unix_ms,
-                    elapsed = ctx.start.elapsed().as_millis() as u64,
-                    kv = log::as_serde!(&ctx.log);
-                    "",
-                );
+                let mut ctx = res.request().context_mut().unwrap();
+                let elapsed = ctx.start.elapsed();
+                ctx.slow = SlowRequest::check(slow_threshold, elapsed, &timings);
+                if let Some(slow) = ctx.slow {
+                    log::warn!(target: "api",
+                        method = log_method.as_str(),
+                        path = log_path,
+                        xid = log_xid,
+                        status = res.response().status().as_u16(),
+                        start = ctx.unix_ms,
+                        elapsed = slow.elapsed,
+                        pool_wait = slow.pool_wait,
+                        redis = slow.redis,
+                        kv = log::as_serde!(&ctx.log);
+                        "slow request",
+                    );
+                }
+
+                let mut skip = ctx.slow.is_some() || skip_path;
+                if !skip && sample > 1 && ctx.sampling && res.response().status().is_success() {
+                    let n = counter.get();
+                    counter.set(n.wrapping_add(1));
+                    skip = n % sample != 0;
+                    ctx.log.insert("sample", Value::from(sample));
+                }
+                if !skip {
+                    log::info!(target: "api",
+                        method = log_method.as_str(),
+                        path = log_path,
+                        xid = log_xid,
+                        status = res.response().status().as_u16(),
+                        start = ctx.unix_ms,
+                        elapsed = elapsed.as_millis() as u64,
+                        kv = log::as_serde!(&ctx.log);
+                        "",
+                    );
+                }
             }
             Ok(res)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+    use redlimit_core::context::{add_command, add_pool_wait};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn slow_request_works() -> anyhow::Result<()> {
+        let app = test::init_service(
+            App::new()
+                .wrap(ContextTransform::new(1, &[], 50))
+                .route("/fast", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/redis",
+                    web::get().to(|| async {
+                        add_pool_wait(Duration::from_millis(30));
+                        add_command(Duration::from_millis(30));
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route(
+                    "/sleep",
+                    web::get().to(|| async {
+                        tokio::time::sleep(Duration::from_millis(60)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let slow = |path: &'static str| {
+            let app = &app;
+            async move {
+                let req = test::TestRequest::with_uri(path).to_request();
+                let res = test::call_service(app, req).await;
+                assert_eq!(200, res.status().as_u16());
+                // the context borrows the response, copy it out first
+                let rt = res.request().context().unwrap().slow;
+                rt
+            }
+        };
+        assert_eq!(None, slow("/fast").await);
+
+        let rt = slow("/redis").await.expect("slow by the Redis time");
+        assert_eq!(30, rt.pool_wait);
+        assert_eq!(30, rt.redis);
+
+        let rt = slow("/sleep").await.expect("slow by the elapsed time");
+        assert!(rt.elapsed >= 60, "{:?}", rt);
+        assert_eq!(0, rt.redis);
+
+        let timings = Timings::default();
+        timings.command.set(Duration::from_millis(100));
+        assert_eq!(
+            None,
+            SlowRequest::check(0, Duration::from_millis(100), &timings),
+            "disabled"
+        );
+        assert_eq!(
+            Some(SlowRequest {
+                elapsed: 10,
+                pool_wait: 0,
+                redis: 100
+            }),
+            SlowRequest::check(50, Duration::from_millis(10), &timings)
+        );
+        Ok(())
+    }
+}
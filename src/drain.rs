@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use actix_web::dev::ServerHandle;
+use sd_notify::NotifyState;
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{redlimit::Namespaces, systemd};
+
+// the max time to wait for the final sync of a drain
+const FINAL_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Drain takes the instance out of rotation for a deployment: the readiness
+// fails at once, then after the delay for the load balancers to notice it, a
+// final sync runs and the servers stop accepting new connections, completing
+// the in-flight requests.
+pub struct Drain {
+    draining: AtomicBool,
+    stopping: AtomicBool,
+    delay: Duration,
+    stop: CancellationToken,
+}
+
+impl Drain {
+    pub fn new(delay_secs: u64) -> Self {
+        Drain {
+            draining: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            delay: Duration::from_secs(delay_secs),
+            stop: CancellationToken::new(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    // start starts draining in background, it returns false if already started.
+    pub fn start(self: &Arc<Self>, namespaces: Arc<Namespaces>) -> bool {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.draining.store(true, Ordering::SeqCst);
+        log::warn!(target: "drain", "draining, stop serving in {:?}", self.delay);
+        systemd::notify(NotifyState::Status("draining"));
+
+        let drain = self.clone();
+        tokio::spawn(async move {
+            sleep(drain.delay).await;
+            final_sync(&namespaces).await;
+            drain.stop.cancel();
+        });
+        true
+    }
+
+    // handover stops the servers at once without failing the readiness, the
+    // listening sockets are served by the new process of an upgrade. It returns
+    // false if already started.
+    pub fn handover(&self) -> bool {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        log::warn!(target: "drain", "handed over, stop serving");
+        self.stop.cancel();
+        true
+    }
+}
+
+// init_drain_stop stops the servers gracefully when the drain is done.
+pub fn init_drain_stop(drain: Arc<Drain>, servers: Vec<ServerHandle>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        drain.stop.cancelled().await;
+        log::warn!(target: "drain", "stop accepting new connections");
+        for server in servers {
+            server.stop(true).await;
+        }
+    })
+}
+
+// final_sync triggers the sync job and waits until every namespace has run it,
+// so that the snapshot written on exit is fresh.
+async fn final_sync(namespaces: &Namespaces) {
+    let before: HashMap<&str, u64> = namespaces
+        .iter()
+        .map(|r| (r.ns.as_str(), r.sync_status().last_run))
+        .collect();
+    namespaces.trigger_sync();
+
+    let deadline = Instant::now() + FINAL_SYNC_TIMEOUT;
+    while Instant::now() < deadline {
+        sleep(Duration::from_millis(100)).await;
+        if namespaces
+            .iter()
+            .all(|r| r.sync_status().last_run > before[r.ns.as_str()])
+        {
+            log::info!(target: "drain", "final sync done");
+            return;
+        }
+    }
+    log::warn!(target: "drain", "final sync timed out after {:?}", FINAL_SYNC_TIMEOUT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::conf, *};
+
+    #[tokio::test]
+    async fn drain_works() -> anyhow::Result<()> {
+        let cfg = conf::Conf::new()?;
+        let namespaces = Arc::new(Namespaces::new(&cfg));
+        let drain = Arc::new(Drain::new(0));
+        assert!(!drain.is_draining());
+
+        assert!(drain.start(namespaces.clone()));
+        assert!(drain.is_draining());
+        assert!(!drain.start(namespaces), "started once");
+        assert!(!drain.stop.is_cancelled(), "waiting for the final sync");
+        assert!(!drain.handover());
+
+        let drain = Drain::new(0);
+        assert!(drain.handover());
+        assert!(!drain.is_draining(), "still ready");
+        assert!(drain.stop.is_cancelled());
+        assert!(!drain.handover());
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+// tonic::Status is the error of the RPCs, returned as is.
+#![allow(clippy::result_large_err)]
+
+use tonic::{Request, Response, Status};
+
+use super::{api::Limiter, conf, context::unix_ms, grpc::Service, guard::Decision};
+
+pub mod pb {
+    tonic::include_proto!("envoy.service.ratelimit.v3");
+}
+
+use pb::{
+    rate_limit_descriptor::Entry,
+    rate_limit_response::{Code, DescriptorStatus},
+    rate_limit_service_server::RateLimitService,
+    Duration, HeaderValue, RateLimitRequest, RateLimitResponse,
+};
+
+// Envoy's global rate limit service on the gRPC server, each descriptor is
+// limited as a "POST /limiting" request, see keys.
+#[tonic::async_trait]
+impl RateLimitService for Service {
+    async fn should_rate_limit(
+        &self,
+        request: Request<RateLimitRequest>,
+    ) -> Result<Response<RateLimitResponse>, Status> {
+        let input = request.into_inner();
+        let rules = self
+            .namespaces
+            .get(&input.domain)
+            .or_else(|| self.namespaces.get(""))
+            .ok_or_else(|| Status::internal("no main namespace"))?;
+        let ts = unix_ms();
+
+        let mut res = RateLimitResponse {
+            overall_code: Code::Ok as i32,
+            ..Default::default()
+        };
+        // the x-ratelimit-* headers of the descriptor with the least remaining
+        let mut least: Option<Decision> = None;
+        for descriptor in &input.descriptors {
+            let (scope, path, id) = keys(
+                &self.envoy,
+                self.empty_id,
+                &input.domain,
+                &descriptor.entries,
+            )
+            .ok_or_else(|| Status::invalid_argument("empty id"))?;
+            if id.is_empty() {
+                res.statuses.push(DescriptorStatus {
+                    code: Code::Ok as i32,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let id = self.hasher.hash(&id);
+            let attrs: Vec<(&str, &str)> = if self.hook.is_some() {
+                descriptor
+                    .entries
+                    .iter()
+                    .map(|e| (e.key.as_str(), e.value.as_str()))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let limiter = Limiter {
+                shards: &self.shards,
+                shedder: &self.shedder,
+                local: &self.local,
+                approx: Some(&self.approx),
+                leaser: Some(&self.leaser),
+                retry: &self.retry,
+                hook: self.hook.as_ref().map(|h| h.get_ref()),
+                attrs: &attrs,
+            };
+            let d = limiter
+                .check(rules, ts, &scope, &path, &id)
+                .await
+                .map_err(|err| Status::invalid_argument(format!("invalid limit args: {}", err)))?;
+            let d = d.guard_decision(ts);
+            let code = if d.is_limited() {
+                res.overall_code = Code::OverLimit as i32;
+                Code::OverLimit
+            } else {
+                Code::Ok
+            };
+            res.statuses.push(DescriptorStatus {
+                code: code as i32,
+                limit_remaining: d.remaining.min(u32::MAX as u64) as u32,
+                duration_until_reset: Some(d.retry)
+                    .filter(|retry| *retry > 0 && !d.throttled)
+                    .map(|retry| Duration {
+                        seconds: (retry / 1000) as i64,
+                        nanos: ((retry % 1000) * 1_000_000) as i32,
+                    }),
+            });
+            if least.as_ref().map_or(true, |l| d.remaining < l.remaining) {
+                least = Some(d);
+            }
+        }
+
+        if let Some(d) = least {
+            res.response_headers_to_add = d
+                .headers()
+                .into_iter()
+                .map(|(key, value)| HeaderValue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect();
+        }
+        Ok(Response::new(res))
+    }
+}
+
+// keys returns the (scope, path, id) of a descriptor by the configured entry
+// keys, the scope defaults to the domain and the path to empty (the default
+// quantity). The id is resolved by the empty_id policy, None if the descriptor
+// should be rejected.
+fn keys(
+    cfg: &conf::Envoy,
+    empty_id: conf::EmptyId,
+    domain: &str,
+    entries: &[Entry],
+) -> Option<(String, String, String)> {
+    let value = |key: &str| {
+        entries
+            .iter()
+            .find(|e| e.key == key)
+            .map(|e| e.value.clone())
+    };
+    let id = empty_id.resolve(
+        value(&cfg.id_key).unwrap_or_default(),
+        value(&cfg.fallback_id_key).unwrap_or_default(),
+    )?;
+    let scope = value(&cfg.scope_key).unwrap_or_else(|| domain.to_string());
+    let path = value(&cfg.path_key).unwrap_or_default();
+    Some((scope, path, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_works() {
+        let cfg = conf::Envoy::default();
+        let entry = |key: &str, value: &str| Entry {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+
+        let allow = conf::EmptyId::Allow;
+        assert_eq!(
+            Some(("core".to_string(), "".to_string(), "".to_string())),
+            keys(&cfg, allow, "core", &[])
+        );
+        assert_eq!(
+            Some((
+                "core".to_string(),
+                "GET /v1/file/list".to_string(),
+                "".to_string()
+            )),
+            keys(&cfg, allow, "core", &[entry("path", "GET /v1/file/list")])
+        );
+        assert_eq!(
+            Some(("core".to_string(), "".to_string(), "10.0.0.1".to_string())),
+            keys(&cfg, allow, "core", &[entry("remote_address", "10.0.0.1")])
+        );
+        assert_eq!(None, keys(&cfg, conf::EmptyId::Reject, "core", &[]));
+        assert_eq!(None, keys(&cfg, conf::EmptyId::Fallback, "core", &[]));
+        assert_eq!(
+            Some(("core".to_string(), "".to_string(), "user1".to_string())),
+            keys(
+                &cfg,
+                conf::EmptyId::Fallback,
+                "core",
+                &[entry("fallback_id", "user1")]
+            )
+        );
+        assert_eq!(
+            Some((
+                "biz".to_string(),
+                "GET /v1/app/info".to_string(),
+                "10.0.0.1".to_string()
+            )),
+            keys(
+                &cfg,
+                allow,
+                "core",
+                &[
+                    entry("scope", "biz"),
+                    entry("path", "GET /v1/app/info"),
+                    entry("remote_address", "10.0.0.1"),
+                ]
+            )
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Error, Result};
+use tokio::{
+    task::{JoinHandle, JoinSet},
+    time::{sleep, Duration},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    conf, metrics,
+    privacy::IdHasher,
+    redis::RedisPool,
+    redlimit::{self, Namespaces, RedlistMeta},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+// the ids per redlist_add call, Lua's unpack is limited to about 8000 values
+const FEED_BATCH: usize = 1000;
+
+// Feeds fetches the blocklist feeds and merges them into the redlist.
+struct Feeds {
+    pool: Arc<RedisPool>,
+    retry: conf::Retry,
+    namespaces: Arc<Namespaces>,
+    hasher: Arc<IdHasher>,
+    inputs: conf::Inputs,
+    http: reqwest::Client,
+}
+
+// init_feeds runs every enabled feed in background at once and then every
+// interval, None if no feeds enabled. The feed entries are checked as the
+// redlist mutations, except max_entries.
+pub fn init_feeds(
+    feeds: &HashMap<String, conf::Feed>,
+    pool: Arc<RedisPool>,
+    retry: conf::Retry,
+    namespaces: Arc<Namespaces>,
+    hasher: Arc<IdHasher>,
+    inputs: conf::Inputs,
+) -> Option<(JoinHandle<()>, CancellationToken)> {
+    let enabled: Vec<(String, conf::Feed)> = feeds
+        .iter()
+        .filter(|(_, feed)| feed.enabled)
+        .map(|(name, feed)| (name.clone(), feed.clone()))
+        .collect();
+    if enabled.is_empty() {
+        return None;
+    }
+
+    let job = Arc::new(Feeds {
+        pool,
+        retry,
+        namespaces,
+        hasher,
+        inputs,
+        http: reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default(),
+    });
+    let cancel_feeds = CancellationToken::new();
+    let mut tasks = JoinSet::new();
+    for (name, feed) in enabled {
+        let (job, stop_signal) = (job.clone(), cancel_feeds.clone());
+        tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = stop_signal.cancelled() => return,
+                    rt = job.run(&name, &feed) => match rt {
+                        Ok(n) => {
+                            log::info!(target: "feeds", feed = name, entries = n; "ok");
+                            metrics::FEED_RUNS.with_label_values(&[&name, "ok"]).inc();
+                            metrics::FEED_ENTRIES
+                                .with_label_values(&[&name])
+                                .set(n as i64);
+                        }
+                        Err(err) => {
+                            log::error!(target: "feeds", "feed {} error: {}", name, err);
+                            metrics::FEED_RUNS.with_label_values(&[&name, "error"]).inc();
+                        }
+                    },
+                };
+                tokio::select! {
+                    _ = stop_signal.cancelled() => return,
+                    _ = sleep(Duration::from_secs(feed.interval.max(1))) => {}
+                };
+            }
+        });
+    }
+
+    Some((
+        tokio::spawn(async move { while tasks.join_next().await.is_some() {} }),
+        cancel_feeds,
+    ))
+}
+
+impl Feeds {
+    // run fetches the feed and adds its valid entries to the redlist, it returns
+    // the number of them. The invalid entries are skipped with a warning.
+    async fn run(&self, name: &str, feed: &conf::Feed) -> Result<usize> {
+        let rules = self
+            .namespaces
+            .get(&feed.namespace)
+            .ok_or_else(|| Error::msg(format!("unknown namespace: {}", feed.namespace)))?;
+        let body = self
+            .http
+            .get(&feed.url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| err.without_url())?
+            .text()
+            .await?;
+        let mut entries = parse_feed(&body, feed.ttl)?;
+        let mut invalid = Vec::new();
+        entries.retain(|id, ttl| {
+            match redlimit::check_redlist_entry(&self.inputs, id, *ttl)
+                .or_else(|| self.hasher.check_redlist_id(id))
+            {
+                Some(err) => {
+                    invalid.push(format!("{:?}: {}", id, err));
+                    false
+                }
+                None => true,
+            }
+        });
+        if !invalid.is_empty() {
+            invalid.sort();
+            log::warn!(target: "feeds",
+                feed = name;
+                "skip {} invalid entries, e.g. {}", invalid.len(), invalid[0],
+            );
+        }
+        let meta = RedlistMeta {
+            reason: String::new(),
+            actor: "feed".to_string(),
+            source: name.to_string(),
+        };
+
+        let entries: Vec<(String, u64)> = entries
+            .into_iter()
+            .map(|(id, ttl)| (self.hasher.hash(&id), ttl))
+            .collect();
+        for batch in entries.chunks(FEED_BATCH) {
+            let list: HashMap<String, u64> = batch.iter().cloned().collect();
+            let metas: HashMap<String, RedlistMeta> = batch
+                .iter()
+                .map(|(id, _)| (id.clone(), meta.clone()))
+                .collect();
+            redlimit::redlist_add_with_meta(&self.pool, &self.retry, &rules.ns, &list, &metas)
+                .await?;
+        }
+        Ok(entries.len())
+    }
+}
+
+// parse_feed reads a JSON object {"<id>": <ttl ms>}, or lines of "<id> [<ttl
+// ms>]" with "#" or ";" comments, the ttl defaults to default_ttl.
+fn parse_feed(body: &str, default_ttl: u64) -> Result<HashMap<String, u64>> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        return Ok(serde_json::from_str(body)?);
+    }
+
+    let mut rt = HashMap::new();
+    for line in body.lines() {
+        let line = line.split(['#', ';']).next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let id = match fields.next() {
+            Some(id) => id,
+            None => continue,
+        };
+        let ttl = match fields.next() {
+            Some(ttl) => ttl
+                .parse::<u64>()
+                .map_err(|_| Error::msg(format!("invalid ttl of {}: {}", id, ttl)))?,
+            None => default_ttl,
+        };
+        rt.insert(id.to_string(), ttl);
+    }
+    Ok(rt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_feed_works() -> anyhow::Result<()> {
+        let body = "; Spamhaus DROP List\n\
+            1.10.16.0/20 ; SBL256894\n\
+            \n\
+            # ids with ttl\n\
+            user1 60000\n\
+            user2\t120000 # abuse\n";
+        assert_eq!(
+            HashMap::from([
+                ("1.10.16.0/20".to_string(), 1000),
+                ("user1".to_string(), 60000),
+                ("user2".to_string(), 120000),
+            ]),
+            parse_feed(body, 1000)?
+        );
+
+        assert_eq!(
+            HashMap::from([("user1".to_string(), 60000)]),
+            parse_feed(r#" {"user1": 60000}"#, 1000)?
+        );
+        assert!(parse_feed("user1 1m", 1000).is_err());
+        assert!(parse_feed("", 1000)?.is_empty());
+        Ok(())
+    }
+}
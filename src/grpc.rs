@@ -0,0 +1,443 @@
+// tonic::Status is the error of the RPCs, returned as is.
+#![allow(clippy::result_large_err)]
+
+use std::{collections::HashMap, fs, io};
+
+use actix_web::web;
+use prost::Message;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use tonic::{
+    metadata::{KeyAndValueRef, MetadataMap},
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+
+use super::{
+    api::{self, Limiter},
+    approx::ApproxLimiter,
+    audit::{self, Auditor},
+    auth, conf,
+    context::unix_ms,
+    envoy::pb::rate_limit_service_server::RateLimitServiceServer,
+    hook::Hook,
+    lease::LeaseLimiter,
+    local::LocalLimiter,
+    privacy::IdHasher,
+    redis::Shards,
+    redlimit::{self, Namespaces, RedRules},
+    shedder::LoadShedder,
+};
+
+pub mod pb {
+    tonic::include_proto!("redlimit.v1");
+}
+
+use pb::{
+    red_limit_server::{RedLimit, RedLimitServer},
+    AddResponse, CheckRequest, CheckResponse, RedlistAddRequest, RedrulesAddRequest,
+};
+
+// Service serves the gRPC API with the same state as the HTTP server. The Check
+// requests are signed if security.hmac is configured, see verify_hmac. The
+// mutations are served only if mutations is true, and require one of the api
+// keys as "authorization: Bearer <key>" or "x-api-key: <key>" metadata if any
+// credential is configured for the HTTP admin endpoints, the read keys and JWTs
+// are not accepted. It serves Envoy's rate limit service too, see envoy.rs.
+#[derive(Clone)]
+pub struct Service {
+    pub shards: web::Data<Shards>,
+    pub namespaces: web::Data<Namespaces>,
+    pub shedder: web::Data<LoadShedder>,
+    pub local: web::Data<LocalLimiter>,
+    pub approx: web::Data<ApproxLimiter>,
+    pub leaser: web::Data<LeaseLimiter>,
+    pub retry: web::Data<conf::Retry>,
+    pub auditor: web::Data<Auditor>,
+    pub hasher: web::Data<IdHasher>,
+    pub hook: Option<web::Data<Hook>>,
+    pub security: conf::Security,
+    pub envoy: conf::Envoy,
+    pub empty_id: conf::EmptyId,
+    pub mutations: bool,
+}
+
+impl Service {
+    // admin authorizes a mutation, it returns the principal of the api key.
+    fn admin(&self, metadata: &MetadataMap) -> Result<Option<String>, Status> {
+        if !self.mutations {
+            return Err(Status::permission_denied(
+                "mutations are served on server.grpc_admin_bind",
+            ));
+        }
+        authorize(&self.security, metadata)
+    }
+
+    fn namespace(&self, ns: &str) -> Result<&RedRules, Status> {
+        self.namespaces
+            .get(ns)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown namespace: {}", ns)))
+    }
+
+    // record_mutation limits the principal, or the remote address without
+    // credentials, and records the audit entry of a mutation.
+    async fn record_mutation<T>(
+        &self,
+        rules: &RedRules,
+        principal: Option<String>,
+        mut entry: audit::Entry,
+        fcalls: u64,
+        mutation: impl std::future::Future<Output = anyhow::Result<T>>,
+    ) -> Result<(), Status> {
+        let pool = &self.shards.pools()[0];
+        let caller = principal.unwrap_or_else(|| entry.remote.clone());
+        entry.limited = api::admin_limited(
+            &self.security.admin_limit,
+            pool,
+            &self.retry,
+            &rules.ns,
+            &caller,
+            fcalls,
+        )
+        .await;
+        if entry.limited {
+            entry.result = "limited".to_string();
+            self.auditor.record(pool, &self.retry, entry).await;
+            return Err(Status::resource_exhausted("too many admin requests"));
+        }
+
+        let rt = mutation.await;
+        entry.result = audit::result(&rt);
+        let action = entry.action.clone();
+        self.auditor.record(pool, &self.retry, entry).await;
+        rt.map(|_| ()).map_err(|err| {
+            log::error!(target: "grpc", "{} error: {}", action, err);
+            Status::internal(err.to_string())
+        })
+    }
+}
+
+// hook_attrs returns the ASCII metadata for the hook, none without hook.
+fn hook_attrs(metadata: &MetadataMap, enabled: bool) -> Vec<(&str, &str)> {
+    if !enabled {
+        return Vec::new();
+    }
+    metadata
+        .iter()
+        .filter_map(|kv| match kv {
+            KeyAndValueRef::Ascii(k, v) => Some((k.as_str(), v.to_str().ok()?)),
+            KeyAndValueRef::Binary(..) => None,
+        })
+        .collect()
+}
+
+#[tonic::async_trait]
+impl RedLimit for Service {
+    async fn check(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<CheckResponse>, Status> {
+        let (metadata, _, input) = request.into_parts();
+        verify_hmac(&self.security.hmac, &metadata, &input)?;
+        let rules = self.namespace(&input.namespace)?;
+        let id = self
+            .empty_id
+            .resolve(input.id, input.fallback_id)
+            .ok_or_else(|| Status::invalid_argument("empty id"))?;
+        let id = self.hasher.hash(&id);
+        let ts = unix_ms();
+        let attrs = hook_attrs(&metadata, self.hook.is_some());
+        let limiter = Limiter {
+            shards: &self.shards,
+            shedder: &self.shedder,
+            local: &self.local,
+            approx: Some(&self.approx),
+            leaser: Some(&self.leaser),
+            retry: &self.retry,
+            hook: self.hook.as_ref().map(|h| h.get_ref()),
+            attrs: &attrs,
+        };
+        let res = limiter
+            .check(rules, ts, &input.scope, &input.path, &id)
+            .await
+            .map_err(|err| Status::invalid_argument(format!("invalid limit args: {}", err)))?
+            .response(ts);
+        Ok(Response::new(CheckResponse {
+            limit: res.limit,
+            remaining: res.remaining,
+            reset: res.reset,
+            retry: res.retry,
+            throttled: res.throttled,
+        }))
+    }
+
+    async fn redlist_add(
+        &self,
+        request: Request<RedlistAddRequest>,
+    ) -> Result<Response<AddResponse>, Status> {
+        let principal = self.admin(request.metadata())?;
+        let (metadata, remote) = (request.metadata().clone(), request.remote_addr());
+        let input = request.into_inner();
+        let rules = self.namespace(&input.namespace)?;
+        redlimit::validate_redlist(
+            &self.security.inputs,
+            input.ids.iter().map(|(id, ttl)| (id, *ttl)),
+        )
+        .and_then(|_| self.hasher.validate_redlist(input.ids.keys()))
+        .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let ids: HashMap<String, u64> = input
+            .ids
+            .into_iter()
+            .map(|(id, ttl)| (self.hasher.hash(&id), ttl))
+            .collect();
+        let mut entry = self.auditor.grpc_entry(
+            &metadata,
+            principal.clone(),
+            remote,
+            "redlist.add",
+            rules.ns.as_str(),
+            audit::summary(ids.keys()),
+        );
+        let meta = redlimit::RedlistMeta {
+            reason: input.reason,
+            actor: entry.actor().to_string(),
+            source: input.source,
+        };
+        entry.summary = audit::with_meta(entry.summary.take(), std::iter::once(&meta));
+        let meta: HashMap<String, redlimit::RedlistMeta> =
+            ids.keys().map(|id| (id.clone(), meta.clone())).collect();
+        let pool = &self.shards.pools()[0];
+        let mutation = redlimit::redlist_add_with_meta(pool, &self.retry, &rules.ns, &ids, &meta);
+        self.record_mutation(rules, principal, entry, 1, mutation)
+            .await?;
+        Ok(Response::new(AddResponse {}))
+    }
+
+    async fn redrules_add(
+        &self,
+        request: Request<RedrulesAddRequest>,
+    ) -> Result<Response<AddResponse>, Status> {
+        let principal = self.admin(request.metadata())?;
+        let (metadata, remote) = (request.metadata().clone(), request.remote_addr());
+        let input = request.into_inner();
+        let rules = self.namespace(&input.namespace)?;
+        let redrules: HashMap<String, (u64, u64)> = input
+            .rules
+            .into_iter()
+            .map(|(path, rule)| (path, (rule.quantity, rule.ttl)))
+            .collect();
+        rules
+            .validate_redrules(&self.security.inputs, &input.scope, &redrules)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let mut summary = audit::summary(redrules.keys());
+        summary["scope"] = input.scope.as_str().into();
+        let entry = self.auditor.grpc_entry(
+            &metadata,
+            principal.clone(),
+            remote,
+            "redrules.add",
+            rules.ns.as_str(),
+            summary,
+        );
+        let pool = &self.shards.pools()[0];
+        let mutation = redlimit::redrules_add(
+            pool,
+            &self.retry,
+            rules.ns.as_str(),
+            &input.scope,
+            &redrules,
+        );
+        self.record_mutation(rules, principal, entry, redrules.len() as u64, mutation)
+            .await?;
+        Ok(Response::new(AddResponse {}))
+    }
+}
+
+const CHECK_PATH: &str = "/redlimit.v1.RedLimit/Check";
+
+// verify_hmac verifies the signature of a Check request as auth::HmacAuth does,
+// with "POST", the method path and the protobuf encoded request as the body.
+fn verify_hmac(
+    cfg: &conf::Hmac,
+    metadata: &MetadataMap,
+    input: &CheckRequest,
+) -> Result<(), Status> {
+    let secrets: Vec<String> = cfg
+        .secrets
+        .iter()
+        .filter(|k| !k.is_empty())
+        .cloned()
+        .collect();
+    if secrets.is_empty() {
+        return Ok(());
+    }
+    let header = |name: &str| {
+        metadata
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("")
+    };
+    auth::verify_signature(
+        &secrets,
+        cfg.max_skew,
+        unix_ms() / 1000,
+        header("x-signature-timestamp"),
+        header("x-signature"),
+        &format!("POST\n{}", CHECK_PATH),
+        &input.encode_to_vec(),
+    )
+    .map_err(|err| Status::unauthenticated(err.to_string()))
+}
+
+// tls_config returns the TLS config of the https server, with client
+// certificates required if client_ca_file is set, None without cert_file or
+// key_file.
+pub fn tls_config(cfg: &conf::Server) -> io::Result<Option<ServerTlsConfig>> {
+    if cfg.cert_file.is_empty() || cfg.key_file.is_empty() {
+        return Ok(None);
+    }
+    let identity = Identity::from_pem(fs::read(&cfg.cert_file)?, fs::read(&cfg.key_file)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if !cfg.client_ca_file.is_empty() {
+        tls = tls.client_ca_root(Certificate::from_pem(fs::read(&cfg.client_ca_file)?));
+    }
+    Ok(Some(tls))
+}
+
+// authorize checks the admin api key and returns its principal, None when no
+// credential is configured.
+fn authorize(sec: &conf::Security, metadata: &MetadataMap) -> Result<Option<String>, Status> {
+    if sec.api_keys.is_empty() && sec.read_keys.is_empty() && sec.jwt.jwks_url.is_empty() {
+        return Ok(None);
+    }
+
+    let header = |name: &str| metadata.get(name).and_then(|h| h.to_str().ok());
+    let key = header("authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .or_else(|| header("x-api-key"))
+        .unwrap_or("");
+    if !key.is_empty()
+        && sec
+            .api_keys
+            .iter()
+            .any(|k| !k.is_empty() && auth::constant_time_eq(k, key))
+    {
+        Ok(Some(auth::Principal::key(key).0))
+    } else {
+        Err(Status::unauthenticated("invalid api key"))
+    }
+}
+
+// init_grpc_server serves the gRPC API on the listener until cancelled, with
+// TLS if configured.
+pub fn init_grpc_server(
+    listener: std::net::TcpListener,
+    svc: Service,
+    tls: Option<ServerTlsConfig>,
+) -> io::Result<(JoinHandle<()>, CancellationToken)> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder
+            .tls_config(tls)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    }
+    let cancel_grpc = CancellationToken::new();
+    let stop_signal = cancel_grpc.clone();
+    Ok((
+        tokio::spawn(async move {
+            let rt = builder
+                .add_service(RateLimitServiceServer::new(svc.clone()))
+                .add_service(RedLimitServer::new(svc))
+                .serve_with_incoming_shutdown(
+                    TcpListenerStream::new(listener),
+                    stop_signal.cancelled(),
+                )
+                .await;
+            if let Err(err) = rt {
+                log::error!(target: "grpc", "grpc server error: {}", err);
+            }
+        }),
+        cancel_grpc,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize_works() {
+        let mut sec = conf::Security::default();
+        let mut metadata = MetadataMap::new();
+        assert_eq!(
+            None,
+            authorize(&sec, &metadata).unwrap(),
+            "no credential configured"
+        );
+
+        sec.read_keys = vec!["reader1".to_string()];
+        assert!(authorize(&sec, &metadata).is_err(), "read keys only");
+        sec.api_keys = vec!["admin1".to_string()];
+        metadata.insert("x-api-key", "reader1".parse().unwrap());
+        assert!(authorize(&sec, &metadata).is_err());
+        metadata.insert("x-api-key", "admin1".parse().unwrap());
+        assert_eq!(
+            Some(auth::Principal::key("admin1").0),
+            authorize(&sec, &metadata).unwrap()
+        );
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("authorization", "Bearer admin1".parse().unwrap());
+        assert!(authorize(&sec, &metadata).is_ok());
+        metadata.insert("authorization", "Bearer admin2".parse().unwrap());
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            authorize(&sec, &metadata).unwrap_err().code()
+        );
+    }
+
+    #[test]
+    fn verify_hmac_works() {
+        use hmac::{Hmac, Mac};
+        use sha2::{Digest, Sha256};
+
+        let mut cfg = conf::Hmac::default();
+        let input = CheckRequest {
+            scope: "core".to_string(),
+            id: "user1".to_string(),
+            ..CheckRequest::default()
+        };
+        let mut metadata = MetadataMap::new();
+        assert!(verify_hmac(&cfg, &metadata, &input).is_ok(), "no secret");
+
+        cfg.secrets = vec!["secret1".to_string()];
+        assert_eq!(
+            tonic::Code::Unauthenticated,
+            verify_hmac(&cfg, &metadata, &input).unwrap_err().code()
+        );
+        let ts = (unix_ms() / 1000).to_string();
+        let message = format!(
+            "{}\nPOST\n{}\n{}",
+            ts,
+            CHECK_PATH,
+            hex::encode(Sha256::digest(input.encode_to_vec()))
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret1").unwrap();
+        mac.update(message.as_bytes());
+        metadata.insert("x-signature-timestamp", ts.parse().unwrap());
+        metadata.insert(
+            "x-signature",
+            hex::encode(mac.finalize().into_bytes()).parse().unwrap(),
+        );
+        assert!(verify_hmac(&cfg, &metadata, &input).is_ok());
+
+        let input = CheckRequest {
+            id: "user2".to_string(),
+            ..input
+        };
+        assert!(verify_hmac(&cfg, &metadata, &input).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    env, io,
+    net::{SocketAddr, TcpListener},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    process::{Child, Command},
+    sync::Arc,
+};
+
+use sd_notify::NotifyState;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+    time::{sleep, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{drain::Drain, systemd};
+
+// LISTEN_FDS_ENV passes the listening sockets to the new process of an upgrade,
+// as the comma separated fds. The systemd LISTEN_FDS can't be used, its
+// LISTEN_PID is unknown before the new process is spawned.
+pub const LISTEN_FDS_ENV: &str = "REDLIMIT_LISTEN_FDS";
+
+// Listeners binds the TCP listeners of the servers, it reuses the sockets passed
+// by systemd socket activation (ListenStream=) or by the old process of an
+// upgrade, so that no connection is refused while restarting. The bound
+// listeners are kept to be passed on to the new process of the next upgrade.
+pub struct Listeners {
+    inherited: Vec<TcpListener>,
+    bound: Vec<TcpListener>,
+}
+
+impl Listeners {
+    pub fn inherit() -> io::Result<Self> {
+        let fds = match env::var(LISTEN_FDS_ENV) {
+            Ok(fds) => {
+                env::remove_var(LISTEN_FDS_ENV);
+                parse_fds(&fds)?
+            }
+            Err(_) => sd_notify::listen_fds()?.collect(),
+        };
+
+        let mut inherited = Vec::with_capacity(fds.len());
+        for fd in fds {
+            // the fds are passed to this process, nothing else owns them
+            let lst = unsafe { TcpListener::from_raw_fd(fd) };
+            SockRef::from(&lst).set_cloexec(true)?;
+            let addr = lst.local_addr()?;
+            log::info!("inherited listener {} (fd {})", addr, fd);
+            inherited.push(lst);
+        }
+        Ok(Listeners {
+            inherited,
+            bound: Vec::new(),
+        })
+    }
+
+    // bind takes an inherited listener of the address, or binds a new one.
+    pub fn bind(&mut self, addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+        let inherited = self
+            .inherited
+            .iter()
+            .position(|lst| lst.local_addr().map_or(false, |a| a == addr));
+        let lst = match inherited {
+            Some(i) => self.inherited.swap_remove(i),
+            None => new_listener(addr, reuse_port)?,
+        };
+        self.bound.push(lst.try_clone()?);
+        Ok(lst)
+    }
+}
+
+// new_listener binds a listener with the backlog of actix-web, more listeners
+// can be bound to the same address with SO_REUSEPORT. An IPv6 listener accepts
+// IPv6 only, so that "0.0.0.0" and "[::]" can be bound together for dual-stack.
+fn new_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+// init_upgrade re-executes the binary with the same arguments and the bound
+// listeners on SIGUSR2. If the new process is still running after the drain
+// delay, the servers of this process stop accepting and exit after the
+// in-flight requests, the new process accepts the connections on the sockets.
+pub fn init_upgrade(
+    listeners: Listeners,
+    drain: Arc<Drain>,
+) -> io::Result<(JoinHandle<()>, CancellationToken)> {
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    let cancel_upgrade = CancellationToken::new();
+    let stop_signal = cancel_upgrade.clone();
+    Ok((
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = stop_signal.cancelled() => {
+                        log::info!("gracefully shutting down upgrade job");
+                        return;
+                    }
+                    _ = sigusr2.recv() => {}
+                };
+
+                let mut child = match spawn_new(&listeners.bound) {
+                    Ok(child) => child,
+                    Err(err) => {
+                        log::error!("upgrade error: {}", err);
+                        continue;
+                    }
+                };
+                log::warn!("upgrade started, new process {}", child.id());
+                let deadline = Instant::now() + drain.delay();
+                let exited = loop {
+                    match child.try_wait() {
+                        Ok(None) if Instant::now() < deadline => {
+                            sleep(Duration::from_millis(100)).await
+                        }
+                        Ok(None) => break None,
+                        Ok(Some(status)) => break Some(status.to_string()),
+                        Err(err) => break Some(err.to_string()),
+                    }
+                };
+                if let Some(status) = exited {
+                    log::error!(
+                        "upgrade aborted, new process {} exited: {}",
+                        child.id(),
+                        status
+                    );
+                    continue;
+                }
+
+                systemd::notify(NotifyState::MainPid(child.id()));
+                drain.handover();
+                return;
+            }
+        }),
+        cancel_upgrade,
+    ))
+}
+
+// spawn_new spawns the binary with the listeners, their fds are kept open
+// across the exec.
+fn spawn_new(listeners: &[TcpListener]) -> io::Result<Child> {
+    let fds: Vec<String> = listeners
+        .iter()
+        .map(|lst| lst.as_raw_fd().to_string())
+        .collect();
+    let mut args = env::args_os();
+    let bin = args
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no program name"))?;
+    let mut cmd = Command::new(bin);
+    cmd.args(args)
+        .env(LISTEN_FDS_ENV, fds.join(","))
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES");
+
+    for lst in listeners {
+        SockRef::from(lst).set_cloexec(false)?;
+    }
+    let rt = cmd.spawn();
+    for lst in listeners {
+        SockRef::from(lst).set_cloexec(true)?;
+    }
+    rt
+}
+
+fn parse_fds(s: &str) -> io::Result<Vec<RawFd>> {
+    s.split(',')
+        .filter(|fd| !fd.trim().is_empty())
+        .map(|fd| {
+            fd.trim().parse::<RawFd>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid {} {:?}", LISTEN_FDS_ENV, s),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fds_works() {
+        assert_eq!(Vec::<RawFd>::new(), parse_fds("").unwrap());
+        assert_eq!(vec![3], parse_fds("3").unwrap());
+        assert_eq!(vec![3, 5, 6], parse_fds("3, 5,6").unwrap());
+        assert!(parse_fds("3,x").is_err());
+    }
+
+    #[test]
+    fn listeners_works() -> io::Result<()> {
+        let lst = new_listener("127.0.0.1:0".parse().unwrap(), false)?;
+        let addr = lst.local_addr()?;
+        let mut listeners = Listeners {
+            inherited: vec![lst],
+            bound: Vec::new(),
+        };
+
+        let lst = listeners.bind(addr, false)?;
+        assert_eq!(addr, lst.local_addr()?);
+        assert!(listeners.inherited.is_empty(), "inherited listener taken");
+        assert_eq!(1, listeners.bound.len());
+
+        let lst2 = listeners.bind("127.0.0.1:0".parse().unwrap(), false)?;
+        assert_ne!(addr, lst2.local_addr()?);
+        assert_eq!(2, listeners.bound.len());
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_port_works() -> io::Result<()> {
+        let lst = new_listener("127.0.0.1:0".parse().unwrap(), true)?;
+        let addr = lst.local_addr()?;
+        // another process binding with SO_REUSEPORT shares the address
+        let lst2 = new_listener(addr, true)?;
+        assert_eq!(addr, lst2.local_addr()?);
+        assert!(new_listener(addr, false).is_err());
+
+        let lst = new_listener("127.0.0.1:0".parse().unwrap(), false)?;
+        assert!(new_listener(lst.local_addr()?, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_works() -> io::Result<()> {
+        let lst4 = new_listener("0.0.0.0:0".parse().unwrap(), false)?;
+        let port = lst4.local_addr()?.port();
+        let lst6 = match new_listener(SocketAddr::from(([0u16; 8], port)), false) {
+            Ok(lst) => lst,
+            // no IPv6 on this host
+            Err(err) if err.kind() == io::ErrorKind::AddrNotAvailable => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        assert_eq!(port, lst6.local_addr()?.port());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Error, Result};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use super::conf;
+
+// the function of the hook script
+const HOOK_FN: &str = "decide";
+
+// Hook runs the operator's Rhai script on every limiting decision. The script
+// defines "fn decide(req)" with req #{namespace, scope, path, id, attrs}, attrs
+// are the request headers (HTTP), metadata (gRPC) or descriptor entries
+// (Envoy). It returns () to limit as usual, or #{allow: true} to exempt the
+// request unless the id is in the redlist, or #{quantity: n} to count it as n,
+// an invalid quantity for the limits of the scope is an error of the rules.
+// The id is hashed if security.id_hash_key is set.
+pub struct Hook {
+    engine: Engine,
+    ast: AST,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HookDecision {
+    pub allow: bool,
+    pub quantity: Option<u64>,
+}
+
+impl Hook {
+    // new compiles the script file, None if not configured.
+    pub fn new(cfg: &conf::Hook) -> Result<Option<Self>> {
+        if cfg.script.is_empty() {
+            return Ok(None);
+        }
+        let script = std::fs::read_to_string(&cfg.script)
+            .map_err(|err| Error::msg(format!("read hook script {}: {}", cfg.script, err)))?;
+        Self::compile(&script, cfg.max_operations).map(Some)
+    }
+
+    fn compile(script: &str, max_operations: u64) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(max_operations);
+        let ast = engine
+            .compile(script)
+            .map_err(|err| Error::msg(format!("compile hook script: {}", err)))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == HOOK_FN && f.params.len() == 1)
+        {
+            return Err(Error::msg(format!(
+                "no \"fn {}(req)\" in hook script",
+                HOOK_FN
+            )));
+        }
+        Ok(Hook { engine, ast })
+    }
+
+    // decide runs the script, the errors are logged and the request is limited
+    // as usual.
+    pub fn decide(
+        &self,
+        namespace: &str,
+        scope: &str,
+        path: &str,
+        id: &str,
+        attrs: &[(&str, &str)],
+    ) -> HookDecision {
+        let mut req = Map::new();
+        req.insert("namespace".into(), namespace.into());
+        req.insert("scope".into(), scope.into());
+        req.insert("path".into(), path.into());
+        req.insert("id".into(), id.into());
+        let attrs: Map = attrs
+            .iter()
+            .map(|(k, v)| ((*k).into(), (*v).into()))
+            .collect();
+        req.insert("attrs".into(), attrs.into());
+
+        let rt = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, HOOK_FN, (req,));
+        match rt {
+            Ok(v) => match v.try_cast::<Map>() {
+                Some(m) => HookDecision {
+                    allow: m
+                        .get("allow")
+                        .and_then(|v| v.as_bool().ok())
+                        .unwrap_or(false),
+                    quantity: m
+                        .get("quantity")
+                        .and_then(|v| v.as_int().ok())
+                        .map(|q| q.max(0) as u64),
+                },
+                None => HookDecision::default(),
+            },
+            Err(err) => {
+                log::warn!(target: "hook", "hook script error: {}", err);
+                HookDecision::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_works() -> anyhow::Result<()> {
+        assert!(Hook::compile("fn other(req) { () }", 1000).is_err());
+        assert!(Hook::compile("fn decide(req) {", 1000).is_err());
+
+        let hook = Hook::compile(
+            r#"
+            fn decide(req) {
+                if req.attrs["x-internal"] == "1" {
+                    return #{ allow: true };
+                }
+                if req.scope == "core" && req.path.starts_with("POST ") {
+                    return #{ quantity: 3 };
+                }
+                if req.id == "loop" {
+                    loop {}
+                }
+            }
+            "#,
+            1000,
+        )?;
+        assert_eq!(
+            HookDecision {
+                allow: true,
+                quantity: None
+            },
+            hook.decide("RL", "core", "GET /", "user1", &[("x-internal", "1")])
+        );
+        assert_eq!(
+            HookDecision {
+                allow: false,
+                quantity: Some(3)
+            },
+            hook.decide("RL", "core", "POST /v1/file", "user1", &[])
+        );
+        assert_eq!(
+            HookDecision::default(),
+            hook.decide("RL", "biz", "GET /", "user1", &[])
+        );
+        // stopped by max_operations
+        assert_eq!(
+            HookDecision::default(),
+            hook.decide("RL", "biz", "GET /", "loop", &[])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::conf;
+
+// LogFile is a shared handle of a log file that rotates by size and time, the
+// rotated files are renamed to "<file>.<unix ms>" and the oldest ones beyond
+// `max_files` are removed.
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<RotatingFile>>);
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64, // bytes, 0 to disable
+    period: u64,   // seconds, 0 to disable
+    max_files: usize,
+    file: File,
+    size: u64,
+    opened: u64, // the period index when opened
+}
+
+impl LogFile {
+    pub fn open(cfg: &conf::Log) -> io::Result<Self> {
+        let path = PathBuf::from(&cfg.file);
+        let file = open(&path)?;
+        let size = file.metadata()?.len();
+        let period = match cfg.file_rotation {
+            conf::LogRotation::Never => 0,
+            conf::LogRotation::Hourly => 3600,
+            conf::LogRotation::Daily => 86400,
+        };
+        Ok(LogFile(Arc::new(Mutex::new(RotatingFile {
+            path,
+            max_size: cfg.file_max_size * 1024 * 1024,
+            period,
+            max_files: cfg.file_max_files,
+            file,
+            size,
+            opened: period_index(now_ms(), period),
+        }))))
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut f = self.0.lock().unwrap();
+        f.rotate_if_needed(buf.len() as u64, now_ms())?;
+        let n = f.file.write(buf)?;
+        f.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().file.flush()
+    }
+}
+
+impl RotatingFile {
+    fn rotate_if_needed(&mut self, len: u64, now: u64) -> io::Result<()> {
+        let period = period_index(now, self.period);
+        let oversize = self.max_size > 0 && self.size > 0 && self.size + len > self.max_size;
+        if !oversize && period == self.opened {
+            return Ok(());
+        }
+
+        self.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", now));
+        fs::rename(&self.path, &rotated)?;
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.opened = period;
+        self.remove_old()
+    }
+
+    fn remove_old(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return Ok(());
+        }
+
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let mut rotated: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let ts = name.strip_prefix(&prefix)?.parse::<u64>().ok()?;
+                Some((ts, entry.path()))
+            })
+            .collect();
+        if rotated.len() > self.max_files {
+            rotated.sort();
+            for (_, path) in &rotated[..rotated.len() - self.max_files] {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn period_index(now: u64, period: u64) -> u64 {
+    (now / 1000).checked_div(period).unwrap_or(0)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_file_works() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("redlimit-logfile-{}", now_ms()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("redlimit.log");
+
+        let mut f = RotatingFile {
+            path: path.clone(),
+            max_size: 10,
+            period: 3600,
+            max_files: 2,
+            file: open(&path)?,
+            size: 0,
+            opened: period_index(1000, 3600),
+        };
+
+        // rotates by size
+        for now in [1000, 2000, 3000, 4000] {
+            f.rotate_if_needed(8, now)?;
+            f.file.write_all(b"12345678")?;
+            f.size += 8;
+        }
+        let rotated = |dir: &Path| -> anyhow::Result<Vec<String>> {
+            let mut names: Vec<String> = fs::read_dir(dir)?
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            Ok(names)
+        };
+        assert_eq!(
+            vec!["redlimit.log", "redlimit.log.3000", "redlimit.log.4000"],
+            rotated(&dir)?,
+            "keeps the newest 2 rotated files"
+        );
+
+        // rotates by time
+        f.rotate_if_needed(1, 3600 * 1000 + 5000)?;
+        assert_eq!(0, f.size);
+        assert_eq!(
+            vec!["redlimit.log", "redlimit.log.3605000", "redlimit.log.4000"],
+            rotated(&dir)?
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    pin::Pin,
+    sync::Arc,
+};
+
+use log::kv::{Key, Value};
+use serde_json::Value as JsonValue;
+use structured_logger::Writer;
+use tokio::{io::AsyncWrite, sync::Mutex};
+
+// LogfmtWriter writes logs asynchronously in logfmt, e.g.
+// `level=INFO method=POST path=/limiting kv.scope=core kv.limited=false`,
+// nested objects are flattened with dotted keys.
+pub struct LogfmtWriter<W: AsyncWrite + Sync + Send + 'static>(Arc<Mutex<Pin<Box<W>>>>);
+
+pub fn new_writer<W: AsyncWrite + Sync + Send + 'static>(w: W) -> Box<dyn Writer> {
+    Box::new(LogfmtWriter(Arc::new(Mutex::new(Box::pin(w)))))
+}
+
+// SyncLogfmtWriter writes logs synchronously in logfmt, e.g. to a file.
+pub struct SyncLogfmtWriter<W: Write + Sync + Send + 'static>(std::sync::Mutex<W>);
+
+pub fn new_sync_writer<W: Write + Sync + Send + 'static>(w: W) -> Box<dyn Writer> {
+    Box::new(SyncLogfmtWriter(std::sync::Mutex::new(w)))
+}
+
+impl<W: Write + Sync + Send + 'static> Writer for SyncLogfmtWriter<W> {
+    fn write_log(&self, value: &BTreeMap<Key, Value>) -> Result<(), io::Error> {
+        let line = format_line(value)?;
+        let mut w = self.0.lock().unwrap();
+        w.write_all(line.as_bytes())
+    }
+}
+
+impl<W: AsyncWrite + Sync + Send + 'static> Writer for LogfmtWriter<W> {
+    fn write_log(&self, value: &BTreeMap<Key, Value>) -> Result<(), io::Error> {
+        let line = format_line(value)?;
+        let w = self.0.clone();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            let mut w = w.lock().await;
+            if let Err(err) = w.as_mut().write_all(line.as_bytes()).await {
+                eprintln!("LogfmtWriter failed to write log: {}", err);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn format_line(value: &BTreeMap<Key, Value>) -> Result<String, io::Error> {
+    let mut line = String::with_capacity(256);
+    for (k, v) in value {
+        let v = serde_json::to_value(v).map_err(io::Error::from)?;
+        write_pair(&mut line, k.as_str(), &v);
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+fn write_pair(line: &mut String, key: &str, value: &JsonValue) {
+    match value {
+        JsonValue::Object(obj) => {
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
+            for k in keys {
+                write_pair(line, &format!("{}.{}", key, k), &obj[k]);
+            }
+        }
+        _ => {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(key);
+            line.push('=');
+            match value {
+                JsonValue::String(s) => push_str(line, s),
+                JsonValue::Null => {}
+                v => push_str(line, &v.to_string()),
+            }
+        }
+    }
+}
+
+// push_str quotes the value if it is empty or has spaces, '=' or '"'.
+fn push_str(line: &mut String, s: &str) {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '=' || c == '"') {
+        line.push_str(&JsonValue::from(s).to_string());
+    } else {
+        line.push_str(s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_pair_works() {
+        let mut line = String::new();
+        write_pair(&mut line, "level", &JsonValue::from("INFO"));
+        write_pair(&mut line, "status", &JsonValue::from(200));
+        write_pair(&mut line, "xid", &JsonValue::from(""));
+        write_pair(
+            &mut line,
+            "kv",
+            &serde_json::json!({
+                "scope": "core",
+                "path": "POST /v1/file/list",
+                "limited": false,
+                "q": "a=\"b\"",
+            }),
+        );
+        assert_eq!(
+            r#"level=INFO status=200 xid="" kv.limited=false kv.path="POST /v1/file/list" kv.q="a=\"b\"" kv.scope=core"#,
+            line
+        );
+    }
+}
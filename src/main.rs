@@ -1,84 +1,606 @@
-use std::{fs::File, io::BufReader};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    os::unix::fs::FileTypeExt,
+};
 
-use actix_web::{web, App, HttpServer};
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use actix_web::{http::KeepAlive, web, App, HttpServer};
+use clap::Parser;
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
 use rustls_pemfile::{certs, read_one, Item};
-use structured_logger::{async_json::new_writer, Builder};
+use structured_logger::{async_json::new_writer, json, Builder};
 use tokio::{io, time::Duration};
 
 mod api;
-mod conf;
+mod audit;
+mod auth;
+mod cli;
+mod codec;
 mod context;
-mod redis;
-mod redlimit;
-mod redlimit_lua;
+mod drain;
+mod envoy;
+mod events;
+mod feeds;
+mod grpc;
+mod handover;
+mod hook;
+mod logfile;
+mod logfmt;
+mod privacy;
+mod report;
+mod shedder;
+mod spoe;
+mod storage;
+mod systemd;
+mod telemetry;
+mod waiter;
+
+use redlimit_core::{
+    approx, chaos, conf, guard, lease, local, metrics, redis, redlimit, redlist, snapshot, stats,
+};
+use sd_notify::NotifyState;
+use systemd::{EXIT_CONFIG, EXIT_UNAVAILABLE};
 
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
+    let cli = cli::Cli::parse();
+    if cli.schema {
+        println!("{}", cli::schema()?);
+        return Ok(());
+    }
+    if let Some(file_name) = &cli.check {
+        match cli::check(file_name) {
+            Ok(_) => {
+                println!("{}: ok", file_name);
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("{}: {}", file_name, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut cfg = conf::Conf::from(&cli.config)
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("config error: {}", err)));
+    cli.apply(&mut cfg);
+    if cli.print_config {
+        println!("{}", serde_json::to_string_pretty(&cfg.redacted())?);
+        return Ok(());
+    }
 
-    Builder::with_level(cfg.log.level.as_str())
-        .with_target_writer("api", new_writer(io::stdout()))
-        .init();
+    let builder = if cfg.log.file.is_empty() {
+        let api_writer = match cfg.log.format {
+            conf::LogFormat::Json => new_writer(io::stdout()),
+            conf::LogFormat::Logfmt => logfmt::new_writer(io::stdout()),
+        };
+        Builder::with_level(cfg.log.level.as_str()).with_target_writer("api", api_writer)
+    } else {
+        let file = logfile::LogFile::open(&cfg.log).unwrap_or_else(|err| {
+            systemd::exit(
+                EXIT_CONFIG,
+                format!("log file {} error: {}", cfg.log.file, err),
+            )
+        });
+        let api_writer = match cfg.log.format {
+            conf::LogFormat::Json => json::new_writer(file.clone()),
+            conf::LogFormat::Logfmt => logfmt::new_sync_writer(file.clone()),
+        };
+        Builder::with_level(cfg.log.level.as_str())
+            .with_default_writer(json::new_writer(file))
+            .with_target_writer("api", api_writer)
+    };
+    builder.init();
+    telemetry::init(&cfg.telemetry)
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("telemetry error: {}", err)));
+    let _sentry = report::init(&cfg.sentry, &cfg.env, APP_VERSION)
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("sentry error: {}", err)));
 
-    log::debug!("{:?}", cfg);
+    if conf::is_builtin(&cli.config) {
+        log::warn!(
+            "config file {} not found, use the built-in config",
+            cli.config
+        );
+    }
+    log::debug!("{:?}", cfg.redacted());
 
-    let pool = web::Data::new(
-        redis::new(cfg.redis)
+    let pool = web::Data::new(redis::new(cfg.redis.clone()).await.unwrap_or_else(|err| {
+        systemd::exit(
+            EXIT_UNAVAILABLE,
+            format!("redis connection pool error: {}", err),
+        )
+    }));
+    let replica = if cfg.redis.replica.is_empty() {
+        pool.clone()
+    } else {
+        web::Data::new(
+            redis::new_with_addr(&cfg.redis, &cfg.redis.replica)
+                .await
+                .unwrap_or_else(|err| {
+                    systemd::exit(
+                        EXIT_UNAVAILABLE,
+                        format!("redis replica connection pool error: {}", err),
+                    )
+                }),
+        )
+    };
+    let shards = web::Data::new(
+        redis::Shards::new(&cfg.redis, pool.clone().into_inner())
             .await
-            .unwrap_or_else(|err| panic!("redis connection pool error: {}", err)),
+            .unwrap_or_else(|err| {
+                systemd::exit(
+                    EXIT_UNAVAILABLE,
+                    format!("redis shards connection pool error: {}", err),
+                )
+            }),
     );
 
-    if let Err(err) = redlimit::init_redlimit_fn(pool.clone()).await {
-        panic!("redis FUNCTION error: {}", err)
+    for pool in shards.pools() {
+        if let Err(err) = redlimit::init_redlimit_fn(pool).await {
+            systemd::exit(EXIT_UNAVAILABLE, format!("redis FUNCTION error: {}", err))
+        }
+    }
+    if !cfg.redis.replica.is_empty() {
+        if let Err(err) = redlimit::init_replica_fn(&replica).await {
+            systemd::exit(EXIT_UNAVAILABLE, format!("redis replica error: {}", err))
+        }
     }
 
-    let redrules = web::Data::new(redlimit::RedRules::new(&cfg.namespace, &cfg.rules));
+    let namespaces = web::Data::new(redlimit::Namespaces::new(&cfg));
+    if let Some(url) = &cli.export {
+        let names: Vec<&str> = namespaces.iter().map(|r| r.ns.as_str()).collect();
+        let data = snapshot::export(&pool, &names, context::unix_ms()).await?;
+        storage::write(url, serde_json::to_vec(&data)?).await?;
+        println!("{}: ok", url);
+        return Ok(());
+    }
+    if let Some(url) = &cli.import {
+        let names: Vec<&str> = namespaces.iter().map(|r| r.ns.as_str()).collect();
+        let data: snapshot::Snapshot = serde_json::from_slice(&storage::read(url).await?)?;
+        let rt =
+            snapshot::import(&pool, &cfg.redis.retry, &names, &data, context::unix_ms()).await?;
+        println!("{}: {}", url, serde_json::to_string(&rt)?);
+        return Ok(());
+    }
+    let snapshot_file = &cfg.job.snapshot_file;
+    if !snapshot_file.is_empty() {
+        match snapshot::load_file(&namespaces, snapshot_file, context::unix_ms()).await {
+            Ok(n) => log::info!(target: "snapshot", "{} entries loaded from {}", n, snapshot_file),
+            Err(err) => log::warn!(target: "snapshot", "load {} error: {}", snapshot_file, err),
+        }
+    }
+    if cfg.job.initial_sync > 0 {
+        let inow = std::time::Instant::now();
+        match redlimit::initial_sync(
+            pool.get_ref(),
+            replica.get_ref(),
+            &namespaces,
+            &cfg.job,
+            cfg.central.enabled,
+        )
+        .await
+        {
+            Ok(_) => log::info!(target: "sync",
+                elapsed = inow.elapsed().as_millis() as u64;
+                "initial sync ok",
+            ),
+            Err(err) => log::warn!(target: "sync", "{}, serve anyway", err),
+        }
+    }
+    // after the startup calls, so that an injected fault does not abort the start
+    chaos::init(&cfg.chaos);
+    let shedder = web::Data::new(shedder::LoadShedder::new(cfg.shedding.clone()));
+    let auditor = web::Data::new(audit::Auditor::new(cfg.audit.clone()));
+    let local = web::Data::new(local::LocalLimiter::new(cfg.fallback.clone()));
+    let approx = web::Data::new(approx::ApproxLimiter::new(cfg.approximate.clone()));
+    let leaser = web::Data::new(lease::LeaseLimiter::new(cfg.leasing.clone()));
+    let retry = web::Data::new(cfg.redis.retry.clone());
+    let redacted = web::Data::new(cfg.redacted());
+    let admin_limit = web::Data::new(api::AdminLimit(cfg.security.admin_limit.clone()));
+    let inputs = web::Data::new(cfg.security.inputs.clone());
+    let empty_id = web::Data::new(cfg.empty_id);
+    let hasher = web::Data::new(privacy::IdHasher::new(&cfg.security.id_hash_key));
+    let forward_auth = web::Data::new(cfg.forward_auth.clone());
+    let waiter = web::Data::new(waiter::Waiter::new(&cfg.wait));
+    let drain = web::Data::new(drain::Drain::new(cfg.server.drain_delay));
+    let drain_servers = drain.clone().into_inner();
+    let hook = hook::Hook::new(&cfg.hook)
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("hook error: {}", err)))
+        .map(web::Data::new);
 
     // background jobs relating to local, disposable tasks
-    let (redlimit_sync_handle, cancel_redlimit_sync) =
-        redlimit::init_redlimit_sync(pool.clone(), redrules.clone(), cfg.job.interval);
-
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(api::AppInfo {
-                name: APP_NAME.to_string(),
-                version: APP_VERSION.to_string(),
-            }))
-            .app_data(pool.clone())
-            .app_data(redrules.clone())
-            .wrap(context::ContextTransform {})
-            .service(web::resource("/limiting").route(web::post().to(api::post_limiting)))
-            .service(
-                web::resource("/redlist")
-                    .route(web::get().to(api::get_redlist))
-                    .route(web::post().to(api::post_redlist)),
+    let (redlimit_sync_handle, cancel_redlimit_sync) = redlimit::init_redlimit_sync(
+        pool.clone().into_inner(),
+        replica.into_inner(),
+        shards.clone().into_inner(),
+        namespaces.clone().into_inner(),
+        &cfg.job,
+        cfg.central.enabled,
+    );
+    let redlimit_subscriber = if cfg.job.subscribe {
+        let config = redis::main_config(&cfg.redis).unwrap_or_else(|err| {
+            systemd::exit(
+                EXIT_UNAVAILABLE,
+                format!("redis subscriber config error: {}", err),
             )
-            .service(
-                web::resource("/redrules")
-                    .route(web::get().to(api::get_redrules))
-                    .route(web::post().to(api::post_redrules)),
+        });
+        let (local, approx, leaser) = (local.clone(), approx.clone(), leaser.clone());
+        Some(redlimit::init_redlimit_subscriber(
+            config,
+            namespaces.clone().into_inner(),
+            move |ns, id| api::erase_caches(Some(&local), Some(&approx), Some(&leaser), ns, id),
+        ))
+    } else {
+        None
+    };
+    let (approx_flush_handle, cancel_approx_flush) = approx::init_approx_flush(
+        approx.clone().into_inner(),
+        shards.clone().into_inner(),
+        cfg.redis.retry.clone(),
+    );
+    let (rules_reload_handle, cancel_rules_reload) =
+        redlimit::init_rules_reload(namespaces.clone().into_inner(), cli.config);
+    let events_publisher = events::init_events(cfg.events.clone());
+    let feeds_job = feeds::init_feeds(
+        &cfg.feeds,
+        pool.clone().into_inner(),
+        cfg.redis.retry.clone(),
+        namespaces.clone().into_inner(),
+        hasher.clone().into_inner(),
+        cfg.security.inputs.clone(),
+    );
+    let snapshot_job = if cfg.job.snapshot_file.is_empty() {
+        None
+    } else {
+        Some(snapshot::init_snapshot_job(
+            namespaces.clone().into_inner(),
+            cfg.job.snapshot_file.clone(),
+            cfg.job.snapshot_interval,
+        ))
+    };
+    let expired_events = if cfg.job.expired_events {
+        let config = redis::main_config(&cfg.redis).unwrap_or_else(|err| {
+            systemd::exit(
+                EXIT_UNAVAILABLE,
+                format!("redis keyspace config error: {}", err),
             )
-            .route("/version", web::get().to(api::version))
+        });
+        Some(events::init_expired_events(
+            config,
+            namespaces.clone().into_inner(),
+        ))
+    } else {
+        None
+    };
+
+    let namespaces_watched = namespaces.clone().into_inner();
+    let log_cfg = cfg.log.clone();
+    let api_keys = cfg.security.api_keys.clone();
+    let read_keys = cfg.security.read_keys.clone();
+    let hmac = cfg.security.hmac.clone();
+    let jwt = auth::JwtVerifier::new(&cfg.security.jwt);
+    if let Some(jwt) = &jwt {
+        if let Err(err) = jwt.refresh().await {
+            log::warn!("jwks refresh error: {}", err);
+        }
+    }
+    let grpc_svc = grpc::Service {
+        shards: shards.clone(),
+        namespaces: namespaces.clone(),
+        shedder: shedder.clone(),
+        local: local.clone(),
+        approx: approx.clone(),
+        leaser: leaser.clone(),
+        retry: retry.clone(),
+        auditor: auditor.clone(),
+        hasher: hasher.clone(),
+        hook: hook.clone(),
+        security: cfg.security.clone(),
+        envoy: cfg.envoy.clone(),
+        empty_id: cfg.empty_id,
+        mutations: true,
+    };
+    let app_data = move |c: &mut web::ServiceConfig| {
+        c.app_data(web::Data::new(api::AppInfo {
+            name: APP_NAME.to_string(),
+            version: APP_VERSION.to_string(),
+        }))
+        .app_data(pool.clone())
+        .app_data(shards.clone())
+        .app_data(namespaces.clone())
+        .app_data(shedder.clone())
+        .app_data(auditor.clone())
+        .app_data(local.clone())
+        .app_data(approx.clone())
+        .app_data(leaser.clone())
+        .app_data(retry.clone())
+        .app_data(redacted.clone())
+        .app_data(admin_limit.clone())
+        .app_data(inputs.clone())
+        .app_data(empty_id.clone())
+        .app_data(hasher.clone())
+        .app_data(waiter.clone())
+        .app_data(drain.clone())
+        .app_data(api::json_config())
+        .app_data(api::query_config());
+        if let Some(hook) = &hook {
+            c.app_data(hook.clone());
+        }
+    };
+    let admin_routes = move |c: &mut web::ServiceConfig| {
+        c.service(
+            web::resource("/redlist")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route(web::get().to(api::get_redlist))
+                .route(web::post().to(api::post_redlist)),
+        )
+        .service(
+            web::resource("/redlist/stream")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route(web::get().to(api::get_redlist_stream)),
+        )
+        .service(
+            web::resource("/redrules")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route(web::get().to(api::get_redrules))
+                .route(web::post().to(api::post_redrules)),
+        )
+        .route("/version", web::get().to(api::version))
+        .route("/ready", web::get().to(api::get_ready))
+        .service(
+            web::resource("/metrics")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route(web::get().to(api::get_metrics)),
+        )
+        .service(
+            web::resource("/stats")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route(web::get().to(api::get_stats)),
+        )
+        .service(
+            web::resource("/stats/offenders")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route(web::get().to(api::get_offenders)),
+        )
+        .service(
+            web::scope("/admin")
+                .wrap(auth::Auth::new(&api_keys, &read_keys, jwt.clone()))
+                .route("/config", web::get().to(api::get_config))
+                .route("/sync", web::post().to(api::post_sync))
+                .route("/drain", web::post().to(api::post_drain))
+                .route("/sync/status", web::get().to(api::get_sync_status))
+                .route("/ids/{id}", web::delete().to(api::delete_id))
+                .route("/export", web::get().to(api::get_export))
+                .route("/import", web::post().to(api::post_import)),
+        );
+    };
+
+    let admin_addrs = cfg
+        .server
+        .admin_addrs()
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("config error: {}", err)));
+    let separate_admin = !admin_addrs.is_empty();
+    let (data, admin, log) = (app_data.clone(), admin_routes.clone(), log_cfg.clone());
+    let mut server = HttpServer::new(move || {
+        let app = App::new()
+            .configure(data.clone())
+            .wrap(context::ContextTransform::new(
+                log.sample,
+                &log.skip_paths,
+                log.slow_threshold,
+            ))
+            .service(
+                web::resource("/limiting")
+                    .wrap(auth::HmacAuth::new(&hmac))
+                    .route(web::post().to(api::post_limiting)),
+            );
+        let app = if forward_auth.enabled {
+            app.app_data(forward_auth.clone())
+                .route("/forward_auth", web::route().to(api::forward_auth))
+        } else {
+            app
+        };
+        if separate_admin {
+            app.route("/version", web::get().to(api::version))
+                .route("/ready", web::get().to(api::get_ready))
+        } else {
+            app.configure(admin.clone())
+        }
     })
     .workers(cfg.server.workers as usize)
-    .keep_alive(Duration::from_secs(25))
-    .shutdown_timeout(10);
+    .keep_alive(if cfg.server.keep_alive > 0 {
+        KeepAlive::Timeout(Duration::from_secs(cfg.server.keep_alive))
+    } else {
+        KeepAlive::Disabled
+    })
+    .shutdown_timeout(cfg.server.shutdown_timeout)
+    .client_request_timeout(Duration::from_millis(cfg.server.client_request_timeout))
+    .max_connections(cfg.server.max_connections);
+
+    // the admin server is low volume, served by a single worker
+    let mut admin_server = HttpServer::new(move || {
+        App::new()
+            .configure(app_data.clone())
+            .wrap(context::ContextTransform::new(
+                log_cfg.sample,
+                &log_cfg.skip_paths,
+                log_cfg.slow_threshold,
+            ))
+            .configure(admin_routes.clone())
+    })
+    .workers(1)
+    .shutdown_timeout(cfg.server.shutdown_timeout);
+
+    let addrs = cfg
+        .server
+        .addrs()
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("config error: {}", err)));
+    log::info!("redlimit service start at {:?}, env: {}", addrs, cfg.env);
+    let tls = if cfg.server.key_file.is_empty() || cfg.server.cert_file.is_empty() {
+        None
+    } else {
+        Some(load_rustls_config(cfg.server.clone()))
+    };
+    let mut listeners = handover::Listeners::inherit().unwrap_or_else(|err| {
+        systemd::exit(EXIT_CONFIG, format!("inherited listeners error: {}", err))
+    });
+    for addr in addrs {
+        let lst = listeners.bind(addr, cfg.server.reuse_port)?;
+        server = match &tls {
+            Some(config) => server.listen_rustls(lst, config.clone())?,
+            None => server.listen(lst)?,
+        };
+    }
+
+    let grpc_addr = cfg
+        .server
+        .grpc_addr()
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("config error: {}", err)));
+    let grpc_admin_addr = cfg
+        .server
+        .grpc_admin_addr()
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("config error: {}", err)));
+    let grpc_tls = grpc::tls_config(&cfg.server)
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("grpc tls error: {}", err)));
+    let grpc_server = match grpc_addr {
+        Some(addr) => {
+            log::info!("redlimit grpc service start at {:?}", addr);
+            // the mutations go to the admin listeners if separated
+            let svc = grpc::Service {
+                mutations: !separate_admin,
+                ..grpc_svc.clone()
+            };
+            Some(grpc::init_grpc_server(
+                listeners.bind(addr, false)?,
+                svc,
+                grpc_tls.clone(),
+            )?)
+        }
+        None => None,
+    };
+    let grpc_admin_server = match grpc_admin_addr {
+        Some(addr) => {
+            log::info!("redlimit grpc admin service start at {:?}", addr);
+            Some(grpc::init_grpc_server(
+                listeners.bind(addr, false)?,
+                grpc_svc.clone(),
+                grpc_tls,
+            )?)
+        }
+        None => None,
+    };
+    let spoe_addr = cfg
+        .spoe
+        .addr()
+        .unwrap_or_else(|err| systemd::exit(EXIT_CONFIG, format!("config error: {}", err)));
+    let spoe_server = match spoe_addr {
+        Some(addr) => {
+            log::info!("redlimit spoe agent start at {:?}", addr);
+            Some(spoe::init_spoe_server(
+                listeners.bind(addr, false)?,
+                grpc_svc,
+                cfg.spoe.clone(),
+                tls.clone()
+                    .map(|config| tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config))),
+            )?)
+        }
+        None => None,
+    };
+
+    if separate_admin {
+        log::info!("redlimit admin service start at {:?}", admin_addrs);
+        for addr in admin_addrs {
+            admin_server = match (addr, &tls) {
+                (conf::Listen::Tcp(addr), Some(config)) => {
+                    admin_server.listen_rustls(listeners.bind(addr, false)?, config.clone())?
+                }
+                (conf::Listen::Tcp(addr), None) => {
+                    admin_server.listen(listeners.bind(addr, false)?)?
+                }
+                (conf::Listen::Unix(path), _) => {
+                    // remove the stale socket of the last run
+                    if fs::metadata(&path).map_or(false, |m| m.file_type().is_socket()) {
+                        fs::remove_file(&path)?;
+                    }
+                    admin_server.bind_uds(path)?
+                }
+            };
+        }
+    }
 
-    log::info!("redlimit service start at 0.0.0.0:{}", cfg.server.port);
-    let addr = ("0.0.0.0", cfg.server.port);
-    if cfg.server.key_file.is_empty() || cfg.server.cert_file.is_empty() {
-        server.bind(addr)?.run().await?;
+    let _pid_file = systemd::PidFile::create(&cfg.server.pid_file).unwrap_or_else(|err| {
+        systemd::exit(
+            EXIT_CONFIG,
+            format!("pid file {} error: {}", cfg.server.pid_file, err),
+        )
+    });
+    let watchdog = systemd::init_watchdog(namespaces_watched, cfg.server.watchdog_stale);
+    let (upgrade_handle, cancel_upgrade) =
+        handover::init_upgrade(listeners, drain_servers.clone())?;
+    systemd::notify(NotifyState::Ready);
+    let server = server.run();
+    if separate_admin {
+        let admin_server = admin_server.run();
+        let drain_stop =
+            drain::init_drain_stop(drain_servers, vec![server.handle(), admin_server.handle()]);
+        tokio::try_join!(server, admin_server)?;
+        drain_stop.abort();
     } else {
-        let config = load_rustls_config(cfg.server);
-        server.bind_rustls(addr, config)?.run().await?;
+        let drain_stop = drain::init_drain_stop(drain_servers, vec![server.handle()]);
+        server.await?;
+        drain_stop.abort();
     }
+    systemd::notify(NotifyState::Stopping);
 
+    cancel_upgrade.cancel();
+    upgrade_handle.await.unwrap();
+    if let Some((watchdog_handle, cancel_watchdog)) = watchdog {
+        cancel_watchdog.cancel();
+        watchdog_handle.await.unwrap();
+    }
+    if let Some((grpc_handle, cancel_grpc)) = grpc_server {
+        cancel_grpc.cancel();
+        grpc_handle.await.unwrap();
+    }
+    if let Some((grpc_handle, cancel_grpc)) = grpc_admin_server {
+        cancel_grpc.cancel();
+        grpc_handle.await.unwrap();
+    }
+    if let Some((spoe_handle, cancel_spoe)) = spoe_server {
+        cancel_spoe.cancel();
+        spoe_handle.await.unwrap();
+    }
+    cancel_approx_flush.cancel();
+    approx_flush_handle.await.unwrap();
     cancel_redlimit_sync.cancel();
+    cancel_rules_reload.cancel();
     redlimit_sync_handle.await.unwrap();
+    if let Some((subscriber_handle, cancel_subscriber)) = redlimit_subscriber {
+        cancel_subscriber.cancel();
+        subscriber_handle.await.unwrap();
+    }
+    rules_reload_handle.await.unwrap();
+    if let Some((feeds_handle, cancel_feeds)) = feeds_job {
+        cancel_feeds.cancel();
+        feeds_handle.await.unwrap();
+    }
+    if let Some((snapshot_handle, cancel_snapshot)) = snapshot_job {
+        cancel_snapshot.cancel();
+        snapshot_handle.await.unwrap();
+    }
+    if let Some((expired_handle, cancel_expired)) = expired_events {
+        cancel_expired.cancel();
+        expired_handle.await.unwrap();
+    }
+    if let Some((events_handle, cancel_events)) = events_publisher {
+        cancel_events.cancel();
+        events_handle.await.unwrap();
+    }
+    tokio::task::spawn_blocking(telemetry::shutdown).await?;
     log::info!("redlimit service shutdown gracefully");
 
     Ok(())
@@ -86,9 +608,20 @@ async fn main() -> anyhow::Result<()> {
 
 fn load_rustls_config(cfg: conf::Server) -> rustls::ServerConfig {
     // init server config builder with safe defaults
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth();
+    let config = ServerConfig::builder().with_safe_defaults();
+    let config = if cfg.client_ca_file.is_empty() {
+        config.with_no_client_auth()
+    } else {
+        let ca_file = &mut BufReader::new(
+            File::open(cfg.client_ca_file.as_str()).expect("cannot open client CA file"),
+        );
+        let mut roots = RootCertStore::empty();
+        let (valid, _) = roots.add_parsable_certificates(&certs(ca_file).unwrap());
+        if valid == 0 {
+            panic!("cannot locate client CA certificate");
+        }
+        config.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+    };
 
     // load TLS key/cert files
     let cert_file = &mut BufReader::new(
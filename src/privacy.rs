@@ -0,0 +1,95 @@
+use hmac::{Hmac, Mac};
+use redlimit_core::redlimit::{self, InputError};
+use sha2::Sha256;
+
+// IdHasher replaces the ids with their keyed hashes before they are used in
+// the limiting keys, redlist, stats and logs, so that the user ids and IPs
+// never appear in clear text. The ids are kept as is if no key is configured.
+pub struct IdHasher {
+    key: Option<Hmac<Sha256>>,
+}
+
+impl IdHasher {
+    pub fn new(key: &str) -> Self {
+        IdHasher {
+            key: if key.is_empty() {
+                None
+            } else {
+                Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()
+            },
+        }
+    }
+
+    // hash returns the hex of the first 16 bytes of HMAC-SHA256(key, id), an
+    // empty id (not limited) is kept empty.
+    pub fn hash(&self, id: &str) -> String {
+        match &self.key {
+            Some(key) if !id.is_empty() => {
+                let mut mac = key.clone();
+                mac.update(id.as_bytes());
+                hex::encode(&mac.finalize().into_bytes()[..16])
+            }
+            _ => id.to_string(),
+        }
+    }
+
+    // check_redlist_id returns why a redlist entry can't be hashed, the id
+    // prefix and CIDR patterns are rejected if a key is configured.
+    pub fn check_redlist_id(&self, id: &str) -> Option<String> {
+        self.key
+            .as_ref()
+            .and_then(|_| redlimit::check_hashed_redlist_id(id))
+    }
+
+    // validate_redlist checks the redlist entries before they are hashed, see
+    // check_redlist_id.
+    pub fn validate_redlist<'a>(
+        &self,
+        ids: impl Iterator<Item = &'a String>,
+    ) -> Result<(), InputError> {
+        match self.key {
+            Some(_) => redlimit::validate_hashed_redlist(ids),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_hasher_works() {
+        let hasher = IdHasher::new("");
+        assert_eq!("user1", hasher.hash("user1"));
+
+        let hasher = IdHasher::new("key1");
+        let h = hasher.hash("user1");
+        assert_eq!(32, h.len());
+        assert_ne!("user1", h);
+        assert_eq!(h, hasher.hash("user1"), "stable");
+        assert_ne!(h, hasher.hash("user2"));
+        assert_ne!(h, IdHasher::new("key2").hash("user1"));
+        assert_eq!("", hasher.hash(""));
+    }
+
+    #[test]
+    fn validate_redlist_works() {
+        let ids = [
+            "10.0.0.0/8".to_string(),
+            "tenant123:*".to_string(),
+            "user1".to_string(),
+        ];
+        assert!(IdHasher::new("").validate_redlist(ids.iter()).is_ok());
+        assert_eq!(None, IdHasher::new("").check_redlist_id("10.0.0.0/8"));
+
+        let hasher = IdHasher::new("key1");
+        let err = hasher.validate_redlist(ids.iter()).unwrap_err();
+        assert_eq!(
+            vec!["10.0.0.0/8", "tenant123:*"],
+            err.entries.keys().collect::<Vec<_>>()
+        );
+        assert!(hasher.check_redlist_id("tenant123:*").is_some());
+        assert_eq!(None, hasher.check_redlist_id("user1"));
+    }
+}
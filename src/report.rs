@@ -0,0 +1,42 @@
+use sentry::{types::Dsn, ClientInitGuard, ClientOptions};
+
+use super::conf;
+
+// init initializes the Sentry client if the dsn is configured, panics are
+// reported by the panic integration. Events are flushed when the guard drops.
+pub fn init(
+    cfg: &conf::Sentry,
+    env: &str,
+    release: &str,
+) -> anyhow::Result<Option<ClientInitGuard>> {
+    if cfg.dsn.is_empty() {
+        return Ok(None);
+    }
+
+    let dsn = cfg.dsn.parse::<Dsn>()?;
+    Ok(Some(sentry::init(ClientOptions {
+        dsn: Some(dsn),
+        environment: Some(env.to_string().into()),
+        release: Some(release.to_string().into()),
+        sample_rate: cfg.sample_rate,
+        ..ClientOptions::default()
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use redlimit_core::report::error;
+
+    use super::*;
+
+    #[test]
+    fn init_works() -> anyhow::Result<()> {
+        let mut cfg = conf::Sentry::default();
+        assert!(init(&cfg, "test", "0.0.0")?.is_none());
+        error("not initialized");
+
+        cfg.dsn = "invalid dsn".to_string();
+        assert!(init(&cfg, "test", "0.0.0").is_err());
+        Ok(())
+    }
+}
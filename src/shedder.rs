@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{conf, redlimit::LimitArgs};
+
+// LoadShedder tracks the latency and error rate of limiting calls in fixed
+// windows, and tightens the effective limits for a cooldown period when Redis
+// looks overloaded.
+pub struct LoadShedder {
+    cfg: conf::Shedding,
+    window_start: AtomicU64,
+    calls: AtomicU64,
+    errors: AtomicU64,
+    elapsed: AtomicU64,
+    shedding_until: AtomicU64,
+}
+
+impl LoadShedder {
+    pub fn new(cfg: conf::Shedding) -> Self {
+        LoadShedder {
+            cfg,
+            window_start: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            elapsed: AtomicU64::new(0),
+            shedding_until: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_shedding(&self, now: u64) -> bool {
+        self.cfg.enabled && self.shedding_until.load(Ordering::Relaxed) > now
+    }
+
+    // record a limiting call with its elapsed milliseconds and outcome.
+    pub fn record(&self, now: u64, elapsed: u64, ok: bool) {
+        if !self.cfg.enabled {
+            return;
+        }
+
+        let start = self.window_start.load(Ordering::Relaxed);
+        if start + self.cfg.window <= now
+            && self
+                .window_start
+                .compare_exchange(start, now, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            let calls = self.calls.swap(0, Ordering::AcqRel);
+            let errors = self.errors.swap(0, Ordering::AcqRel);
+            let elapsed = self.elapsed.swap(0, Ordering::AcqRel);
+            if calls > 0 && calls >= self.cfg.min_calls {
+                let avg_latency = elapsed / calls;
+                let error_rate = errors * 100 / calls;
+                if (self.cfg.latency_threshold > 0 && avg_latency >= self.cfg.latency_threshold)
+                    || (self.cfg.error_threshold > 0 && error_rate >= self.cfg.error_threshold)
+                {
+                    self.shedding_until
+                        .store(now + self.cfg.cooldown, Ordering::Relaxed);
+                    log::warn!(target: "shedder",
+                        calls = calls,
+                        avg_latency = avg_latency,
+                        error_rate = error_rate;
+                        "redis overloaded, tighten limits for {} ms", self.cfg.cooldown,
+                    );
+                }
+            }
+        }
+
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.elapsed.fetch_add(elapsed, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // tighten scales down max count and max burst by `limit_percent`, but never
+    // below the quantity so that the args are still valid.
+    pub fn tighten(&self, args: LimitArgs) -> LimitArgs {
+        let scale = |v: u64| (v * self.cfg.limit_percent / 100).max(args.0);
+        LimitArgs(
+            args.0,
+            scale(args.1),
+            args.2,
+            if args.3 > 0 { scale(args.3) } else { 0 },
+            args.4,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_shedder_works() {
+        let shedder = LoadShedder::new(conf::Shedding {
+            enabled: true,
+            window: 1000,
+            min_calls: 2,
+            latency_threshold: 50,
+            error_threshold: 50,
+            cooldown: 5000,
+            limit_percent: 50,
+        });
+
+        assert!(!shedder.is_shedding(0));
+        shedder.record(1000, 10, true);
+        shedder.record(1100, 10, true);
+        shedder.record(2000, 10, true);
+        assert!(!shedder.is_shedding(2000), "healthy window");
+
+        shedder.record(2100, 80, true);
+        shedder.record(2200, 90, true);
+        shedder.record(3000, 10, true);
+        assert!(shedder.is_shedding(3000), "slow window");
+        assert!(shedder.is_shedding(7999));
+        assert!(!shedder.is_shedding(8000), "after cooldown");
+
+        shedder.record(8100, 10, false);
+        shedder.record(8200, 10, false);
+        assert!(!shedder.is_shedding(8200));
+        shedder.record(9100, 10, true);
+        assert!(shedder.is_shedding(9100), "error window");
+
+        assert_eq!(
+            LimitArgs(5, 50, 10000, 25, 2000),
+            shedder.tighten(LimitArgs(5, 100, 10000, 50, 2000))
+        );
+        assert_eq!(
+            LimitArgs(5, 5, 10000, 0, 0),
+            shedder.tighten(LimitArgs(5, 8, 10000, 0, 0))
+        );
+    }
+
+    #[test]
+    fn load_shedder_disabled() {
+        let shedder = LoadShedder::new(conf::Shedding::default());
+        shedder.record(1000, 1000, false);
+        shedder.record(100000, 1000, false);
+        assert!(!shedder.is_shedding(100000));
+    }
+}
@@ -0,0 +1,659 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Error, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+use super::{api::Limiter, conf, context::unix_ms, grpc::Service, guard::Decision};
+
+// frame types
+const HAPROXY_HELLO: u8 = 1;
+const HAPROXY_DISCONNECT: u8 = 2;
+const NOTIFY: u8 = 3;
+const AGENT_HELLO: u8 = 101;
+const AGENT_DISCONNECT: u8 = 102;
+const ACK: u8 = 103;
+
+const FLAG_FIN: u32 = 1;
+
+// data types
+const TYPE_NULL: u8 = 0;
+const TYPE_BOOL: u8 = 1;
+const TYPE_INT32: u8 = 2;
+const TYPE_UINT32: u8 = 3;
+const TYPE_INT64: u8 = 4;
+const TYPE_UINT64: u8 = 5;
+const TYPE_IPV4: u8 = 6;
+const TYPE_IPV6: u8 = 7;
+const TYPE_STRING: u8 = 8;
+const TYPE_BINARY: u8 = 9;
+
+const ACTION_SET_VAR: u8 = 1;
+const SCOPE_TXN: u8 = 2;
+
+// disconnect status codes
+const STATUS_NORMAL: u32 = 0;
+const STATUS_TOO_BIG: u32 = 3;
+const STATUS_INVALID: u32 = 4;
+const STATUS_VERSION: u32 = 5;
+const STATUS_UNSUPPORTED: u32 = 8;
+
+// init_spoe_server serves HAProxy's SPOP on the listener until cancelled, each
+// connection is served by a task with the same state as the gRPC server. The
+// connections are accepted with TLS if tls is set.
+pub fn init_spoe_server(
+    listener: std::net::TcpListener,
+    svc: Service,
+    cfg: conf::Spoe,
+    tls: Option<TlsAcceptor>,
+) -> std::io::Result<(JoinHandle<()>, CancellationToken)> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let cancel_spoe = CancellationToken::new();
+    let stop_signal = cancel_spoe.clone();
+    Ok((
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = stop_signal.cancelled() => return,
+                    rt = listener.accept() => match rt {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            log::warn!(target: "spoe", "accept error: {}", err);
+                            continue;
+                        }
+                    },
+                };
+                let (svc, cfg, stop_signal) = (svc.clone(), cfg.clone(), stop_signal.clone());
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    let serving = async {
+                        match tls {
+                            Some(tls) => serve(tls.accept(stream).await?, &svc, &cfg).await,
+                            None => serve(stream, &svc, &cfg).await,
+                        }
+                    };
+                    tokio::select! {
+                        _ = stop_signal.cancelled() => {}
+                        rt = serving => if let Err(err) = rt {
+                            log::warn!(target: "spoe", "connection error: {}", err);
+                        },
+                    }
+                });
+            }
+        }),
+        cancel_spoe,
+    ))
+}
+
+// serve handles the frames of a connection, without pipelining, async and
+// fragmentation capabilities: HAProxy waits for the ACK of a NOTIFY frame.
+async fn serve<S>(mut stream: S, svc: &Service, cfg: &conf::Spoe) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut max_frame_size = cfg.max_frame_size;
+    let mut hello = false;
+    loop {
+        let size = match stream.read_u32().await {
+            Ok(size) => size,
+            // closed by HAProxy
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if size > max_frame_size {
+            return disconnect(&mut stream, STATUS_TOO_BIG, "frame is too big").await;
+        }
+        let mut buf = vec![0u8; size as usize];
+        stream.read_exact(&mut buf).await?;
+        let frame = match Frame::decode(&buf) {
+            Ok(frame) => frame,
+            Err(err) => return disconnect(&mut stream, STATUS_INVALID, &err.to_string()).await,
+        };
+
+        match frame.kind {
+            HAPROXY_HELLO if !hello => {
+                let (payload, size, healthcheck) = match agent_hello(frame.payload, max_frame_size)
+                {
+                    Ok(rt) => rt,
+                    Err(err) => {
+                        return disconnect(&mut stream, STATUS_VERSION, &err.to_string()).await
+                    }
+                };
+                stream
+                    .write_all(&encode_frame(AGENT_HELLO, 0, 0, &payload))
+                    .await?;
+                if healthcheck {
+                    return Ok(());
+                }
+                max_frame_size = size;
+                hello = true;
+            }
+            NOTIFY if hello => {
+                if frame.flags & FLAG_FIN == 0 {
+                    return disconnect(&mut stream, STATUS_UNSUPPORTED, "fragmentation").await;
+                }
+                let messages = match decode_messages(frame.payload) {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        return disconnect(&mut stream, STATUS_INVALID, &err.to_string()).await
+                    }
+                };
+                let mut payload = Vec::new();
+                for (name, args) in messages {
+                    if name != cfg.message {
+                        continue;
+                    }
+                    match keys(cfg, svc.empty_id, &args) {
+                        // no id, allowed without limiting
+                        Some(keys) if keys.3.is_empty() => {}
+                        Some(keys) => {
+                            let d = check(svc, keys).await;
+                            encode_actions(&mut payload, &d);
+                        }
+                        None => encode_invalid(&mut payload),
+                    }
+                }
+                stream
+                    .write_all(&encode_frame(
+                        ACK,
+                        frame.stream_id,
+                        frame.frame_id,
+                        &payload,
+                    ))
+                    .await?;
+            }
+            HAPROXY_DISCONNECT => {
+                return disconnect(&mut stream, STATUS_NORMAL, "").await;
+            }
+            _ => {
+                return disconnect(&mut stream, STATUS_INVALID, "unexpected frame").await;
+            }
+        }
+    }
+}
+
+async fn check(svc: &Service, (ns, scope, path, id): (String, String, String, String)) -> Decision {
+    let ts = unix_ms();
+    let rules = match svc.namespaces.get(&ns).or_else(|| svc.namespaces.get("")) {
+        Some(rules) => rules,
+        // no limits
+        None => return Decision::default(),
+    };
+    let limiter = Limiter {
+        shards: &svc.shards,
+        shedder: &svc.shedder,
+        local: &svc.local,
+        approx: Some(&svc.approx),
+        leaser: Some(&svc.leaser),
+        retry: &svc.retry,
+        hook: svc.hook.as_ref().map(|h| h.get_ref()),
+        attrs: &[],
+    };
+    let id = svc.hasher.hash(&id);
+    match limiter.check(rules, ts, &scope, &path, &id).await {
+        Ok(d) => d.guard_decision(ts),
+        // SPOP has no error reply, the ACK sets no limits
+        Err(err) => {
+            log::error!(target: "spoe", "invalid limit args of scope {:?}: {}", scope, err);
+            Decision::default()
+        }
+    }
+}
+
+async fn disconnect<S>(stream: &mut S, status: u32, message: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut payload = Vec::new();
+    put_string(&mut payload, "status-code");
+    put_data(&mut payload, &Data::Uint(status as u64));
+    put_string(&mut payload, "message");
+    put_data(&mut payload, &Data::Str(message.to_string()));
+    stream
+        .write_all(&encode_frame(AGENT_DISCONNECT, 0, 0, &payload))
+        .await?;
+    if status == STATUS_NORMAL {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "disconnected ({}): {}",
+            status, message
+        )))
+    }
+}
+
+// agent_hello returns the AGENT-HELLO payload of a HAPROXY-HELLO payload, the
+// negotiated max frame size and whether it is a healthcheck.
+fn agent_hello(payload: &[u8], max_frame_size: u32) -> Result<(Vec<u8>, u32, bool)> {
+    let mut r = Reader(payload);
+    let (mut version_ok, mut size, mut healthcheck) = (false, max_frame_size, false);
+    while !r.0.is_empty() {
+        let key = r.string()?;
+        let value = r.data()?;
+        match (key.as_str(), value) {
+            ("supported-versions", Data::Str(versions)) => {
+                version_ok = versions.split(',').any(|v| v.trim() == "2.0");
+            }
+            ("max-frame-size", Data::Uint(v)) => size = size.min(v as u32),
+            ("healthcheck", Data::Bool(v)) => healthcheck = v,
+            _ => {}
+        }
+    }
+    if !version_ok {
+        return Err(Error::msg("unsupported version"));
+    }
+
+    let mut buf = Vec::new();
+    put_string(&mut buf, "version");
+    put_data(&mut buf, &Data::Str("2.0".to_string()));
+    put_string(&mut buf, "max-frame-size");
+    put_data(&mut buf, &Data::Uint(size as u64));
+    put_string(&mut buf, "capabilities");
+    put_data(&mut buf, &Data::Str(String::new()));
+    Ok((buf, size, healthcheck))
+}
+
+type Message = (String, Vec<(String, Data)>);
+
+fn decode_messages(payload: &[u8]) -> Result<Vec<Message>> {
+    let mut r = Reader(payload);
+    let mut messages = Vec::new();
+    while !r.0.is_empty() {
+        let name = r.string()?;
+        let n = r.u8()?;
+        let mut args = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            args.push((r.string()?, r.data()?));
+        }
+        messages.push((name, args));
+    }
+    Ok(messages)
+}
+
+// keys returns the (namespace, scope, path, id) of a message by its arguments,
+// the id is resolved by the empty_id policy with the "fallback_id" argument,
+// None if the message should be rejected.
+fn keys(
+    cfg: &conf::Spoe,
+    empty_id: conf::EmptyId,
+    args: &[(String, Data)],
+) -> Option<(String, String, String, String)> {
+    let value = |key: &str| {
+        args.iter()
+            .find(|(name, _)| name == key)
+            .and_then(|(_, v)| v.to_text())
+    };
+    let id = empty_id.resolve(
+        value("id").unwrap_or_default(),
+        value("fallback_id").unwrap_or_default(),
+    )?;
+    Some((
+        value("namespace").unwrap_or_default(),
+        value("scope").unwrap_or_else(|| cfg.scope.clone()),
+        value("path").unwrap_or_default(),
+        id,
+    ))
+}
+
+fn encode_actions(buf: &mut Vec<u8>, d: &Decision) {
+    let vars = [
+        ("limited", Data::Bool(d.is_limited())),
+        ("limit", Data::Int(d.limit as i64)),
+        ("remaining", Data::Int(d.remaining as i64)),
+        ("reset", Data::Int(d.reset as i64)),
+        ("retry", Data::Int(d.retry as i64)),
+        ("throttled", Data::Bool(d.throttled)),
+    ];
+    for (name, value) in vars {
+        buf.extend_from_slice(&[ACTION_SET_VAR, 3, SCOPE_TXN]);
+        put_string(buf, name);
+        put_data(buf, &value);
+    }
+}
+
+// encode_invalid sets the variable invalid (bool) for a message rejected by the
+// empty_id policy, SPOP has no error reply.
+fn encode_invalid(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&[ACTION_SET_VAR, 3, SCOPE_TXN]);
+    put_string(buf, "invalid");
+    put_data(buf, &Data::Bool(true));
+}
+
+struct Frame<'a> {
+    kind: u8,
+    flags: u32,
+    stream_id: u64,
+    frame_id: u64,
+    payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    // decode decodes a frame without the length prefix.
+    fn decode(buf: &'a [u8]) -> Result<Self> {
+        let mut r = Reader(buf);
+        let kind = r.u8()?;
+        let flags = u32::from_be_bytes(r.take(4)?.try_into()?);
+        let stream_id = r.varint()?;
+        let frame_id = r.varint()?;
+        Ok(Frame {
+            kind,
+            flags,
+            stream_id,
+            frame_id,
+            payload: r.0,
+        })
+    }
+}
+
+fn encode_frame(kind: u8, stream_id: u64, frame_id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0, 0, 0, 0, kind];
+    buf.extend_from_slice(&FLAG_FIN.to_be_bytes());
+    put_varint(&mut buf, stream_id);
+    put_varint(&mut buf, frame_id);
+    buf.extend_from_slice(payload);
+    let size = (buf.len() - 4) as u32;
+    buf[..4].copy_from_slice(&size.to_be_bytes());
+    buf
+}
+
+// Data is a typed value of SPOP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Data {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Ip(IpAddr),
+    Str(String),
+    Bin(Vec<u8>),
+}
+
+impl Data {
+    fn to_text(&self) -> Option<String> {
+        match self {
+            Data::Null => None,
+            Data::Bool(v) => Some(v.to_string()),
+            Data::Int(v) => Some(v.to_string()),
+            Data::Uint(v) => Some(v.to_string()),
+            Data::Ip(v) => Some(v.to_string()),
+            Data::Str(v) => Some(v.clone()),
+            Data::Bin(v) => Some(String::from_utf8_lossy(v).to_string()),
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(Error::msg("unexpected end of frame"));
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut v = self.u8()? as u64;
+        if v < 240 {
+            return Ok(v);
+        }
+        let mut shift = 4;
+        loop {
+            if shift > 60 {
+                return Err(Error::msg("invalid varint"));
+            }
+            let b = self.u8()?;
+            v += (b as u64) << shift;
+            shift += 7;
+            if b < 128 {
+                return Ok(v);
+            }
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let n = self.varint()?;
+        self.take(n as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    fn data(&mut self) -> Result<Data> {
+        let t = self.u8()?;
+        Ok(match t & 0x0f {
+            TYPE_NULL => Data::Null,
+            TYPE_BOOL => Data::Bool(t & 0x10 != 0),
+            TYPE_INT32 | TYPE_INT64 => Data::Int(self.varint()? as i64),
+            TYPE_UINT32 | TYPE_UINT64 => Data::Uint(self.varint()?),
+            TYPE_IPV4 => {
+                let v: [u8; 4] = self.take(4)?.try_into()?;
+                Data::Ip(IpAddr::V4(Ipv4Addr::from(v)))
+            }
+            TYPE_IPV6 => {
+                let v: [u8; 16] = self.take(16)?.try_into()?;
+                Data::Ip(IpAddr::V6(Ipv6Addr::from(v)))
+            }
+            TYPE_STRING => Data::Str(self.string()?),
+            TYPE_BINARY => Data::Bin(self.bytes()?.to_vec()),
+            t => return Err(Error::msg(format!("unknown data type: {}", t))),
+        })
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    if v < 240 {
+        buf.push(v as u8);
+        return;
+    }
+    buf.push(v as u8 | 240);
+    v = (v - 240) >> 4;
+    while v >= 128 {
+        buf.push(v as u8 | 128);
+        v = (v - 128) >> 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_string(buf: &mut Vec<u8>, v: &str) {
+    put_varint(buf, v.len() as u64);
+    buf.extend_from_slice(v.as_bytes());
+}
+
+fn put_data(buf: &mut Vec<u8>, v: &Data) {
+    match v {
+        Data::Null => buf.push(TYPE_NULL),
+        Data::Bool(v) => buf.push(TYPE_BOOL | if *v { 0x10 } else { 0 }),
+        Data::Int(v) => {
+            buf.push(TYPE_INT64);
+            put_varint(buf, *v as u64);
+        }
+        Data::Uint(v) => {
+            buf.push(TYPE_UINT64);
+            put_varint(buf, *v);
+        }
+        Data::Ip(IpAddr::V4(v)) => {
+            buf.push(TYPE_IPV4);
+            buf.extend_from_slice(&v.octets());
+        }
+        Data::Ip(IpAddr::V6(v)) => {
+            buf.push(TYPE_IPV6);
+            buf.extend_from_slice(&v.octets());
+        }
+        Data::Str(v) => {
+            buf.push(TYPE_STRING);
+            put_string(buf, v);
+        }
+        Data::Bin(v) => {
+            buf.push(TYPE_BINARY);
+            put_varint(buf, v.len() as u64);
+            buf.extend_from_slice(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_works() -> anyhow::Result<()> {
+        let cases: [(u64, &[u8]); 5] = [
+            (0, &[0]),
+            (239, &[239]),
+            (240, &[240, 0]),
+            (2287, &[255, 127]),
+            (2288, &[240, 128, 0]),
+        ];
+        for (v, bytes) in cases {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            assert_eq!(bytes, buf.as_slice(), "{}", v);
+            assert_eq!(v, Reader(bytes).varint()?);
+        }
+        for v in [u32::MAX as u64, u64::MAX / 3] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            assert_eq!(v, Reader(&buf).varint()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn agent_hello_works() -> anyhow::Result<()> {
+        let mut payload = Vec::new();
+        put_string(&mut payload, "supported-versions");
+        put_data(&mut payload, &Data::Str("2.0".to_string()));
+        put_string(&mut payload, "max-frame-size");
+        put_data(&mut payload, &Data::Uint(1024));
+        put_string(&mut payload, "capabilities");
+        put_data(&mut payload, &Data::Str("pipelining,async".to_string()));
+
+        let (hello, size, healthcheck) = agent_hello(&payload, 16384)?;
+        assert_eq!(1024, size);
+        assert!(!healthcheck);
+        let mut r = Reader(&hello);
+        assert_eq!("version", r.string()?);
+        assert_eq!(Data::Str("2.0".to_string()), r.data()?);
+        assert_eq!("max-frame-size", r.string()?);
+        assert_eq!(Data::Uint(1024), r.data()?);
+        assert_eq!("capabilities", r.string()?);
+        assert_eq!(Data::Str(String::new()), r.data()?);
+
+        put_string(&mut payload, "healthcheck");
+        put_data(&mut payload, &Data::Bool(true));
+        assert!(agent_hello(&payload, 16384)?.2);
+
+        let mut payload = Vec::new();
+        put_string(&mut payload, "supported-versions");
+        put_data(&mut payload, &Data::Str("1.0".to_string()));
+        assert!(agent_hello(&payload, 16384).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn notify_works() -> anyhow::Result<()> {
+        let cfg = conf::Spoe::default();
+        let mut payload = Vec::new();
+        put_string(&mut payload, "redlimit");
+        payload.push(2);
+        put_string(&mut payload, "path");
+        put_data(&mut payload, &Data::Str("GET /v1/file/list".to_string()));
+        put_string(&mut payload, "id");
+        put_data(&mut payload, &Data::Ip("10.0.0.1".parse()?));
+        put_string(&mut payload, "other");
+        payload.push(0);
+
+        let mut buf = vec![NOTIFY];
+        buf.extend_from_slice(&FLAG_FIN.to_be_bytes());
+        put_varint(&mut buf, 300);
+        put_varint(&mut buf, 1);
+        buf.extend_from_slice(&payload);
+        let frame = Frame::decode(&buf)?;
+        assert_eq!(NOTIFY, frame.kind);
+        assert_eq!(300, frame.stream_id);
+        assert_eq!(1, frame.frame_id);
+
+        let messages = decode_messages(frame.payload)?;
+        assert_eq!(2, messages.len());
+        assert_eq!("other", messages[1].0);
+        assert_eq!(
+            Some((
+                "".to_string(),
+                "core".to_string(),
+                "GET /v1/file/list".to_string(),
+                "10.0.0.1".to_string()
+            )),
+            keys(&cfg, conf::EmptyId::Allow, &messages[0].1)
+        );
+        assert_eq!(
+            Some((
+                "".to_string(),
+                "core".to_string(),
+                "".to_string(),
+                "".to_string()
+            )),
+            keys(&cfg, conf::EmptyId::Allow, &messages[1].1),
+            "allowed without id"
+        );
+        assert_eq!(None, keys(&cfg, conf::EmptyId::Reject, &messages[1].1));
+        assert_eq!(None, keys(&cfg, conf::EmptyId::Fallback, &messages[1].1));
+        let args = [
+            ("id".to_string(), Data::Str(String::new())),
+            ("fallback_id".to_string(), Data::Str("10.0.0.2".to_string())),
+        ];
+        assert_eq!(
+            "10.0.0.2",
+            keys(&cfg, conf::EmptyId::Fallback, &args).unwrap().3
+        );
+        let mut actions = Vec::new();
+        encode_invalid(&mut actions);
+        let mut r = Reader(&actions);
+        assert_eq!([ACTION_SET_VAR, 3, SCOPE_TXN], r.take(3)?);
+        assert_eq!("invalid", r.string()?);
+        assert_eq!(Data::Bool(true), r.data()?);
+
+        let d = Decision {
+            limit: 100,
+            remaining: 0,
+            reset: 1700000001,
+            retry: 500,
+            throttled: false,
+        };
+        let mut actions = Vec::new();
+        encode_actions(&mut actions, &d);
+        let mut r = Reader(&actions);
+        assert_eq!([ACTION_SET_VAR, 3, SCOPE_TXN], r.take(3)?);
+        assert_eq!("limited", r.string()?);
+        assert_eq!(Data::Bool(true), r.data()?);
+        assert_eq!([ACTION_SET_VAR, 3, SCOPE_TXN], r.take(3)?);
+        assert_eq!("limit", r.string()?);
+        assert_eq!(Data::Int(100), r.data()?);
+
+        let ack = encode_frame(ACK, 300, 1, &actions);
+        assert_eq!(
+            (ack.len() - 4) as u32,
+            u32::from_be_bytes(ack[..4].try_into()?)
+        );
+        let frame = Frame::decode(&ack[4..])?;
+        assert_eq!(
+            (ACK, FLAG_FIN, 300, 1),
+            (frame.kind, frame.flags, frame.stream_id, frame.frame_id)
+        );
+        assert_eq!(actions.as_slice(), frame.payload);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use std::{fmt, fs, io, process, sync::Arc, time::Duration};
+
+use redlimit_core::report;
+use sd_notify::NotifyState;
+use tokio::{task::JoinHandle, time::interval};
+use tokio_util::sync::CancellationToken;
+
+use super::{context::unix_ms, redlimit::Namespaces};
+
+// Exit codes of sysexits.h, so that systemd can tell a bad config, which
+// restarting will not fix (RestartPreventExitStatus=78), from an unavailable
+// dependency.
+pub const EXIT_UNAVAILABLE: i32 = 69;
+pub const EXIT_CONFIG: i32 = 78;
+
+// exit logs the fatal error and exits the process with the code.
+pub fn exit(code: i32, msg: impl fmt::Display) -> ! {
+    log::error!("{}", msg);
+    eprintln!("{}", msg);
+    process::exit(code)
+}
+
+// notify sends the state to systemd, it does nothing if not started by systemd
+// with Type=notify.
+pub fn notify(state: NotifyState) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        log::warn!("sd_notify error: {}", err);
+    }
+}
+
+// PidFile writes the pid of the process to the file, and removes it on drop if
+// not overwritten by the new process of an upgrade.
+pub struct PidFile(String);
+
+impl PidFile {
+    pub fn create(path: &str) -> io::Result<Option<Self>> {
+        if path.is_empty() {
+            return Ok(None);
+        }
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(Some(PidFile(path.to_string())))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if fs::read_to_string(&self.0).map_or(true, |s| s.trim() != process::id().to_string()) {
+            return;
+        }
+        if let Err(err) = fs::remove_file(&self.0) {
+            log::warn!("remove pid file {} error: {}", self.0, err);
+        }
+    }
+}
+
+// init_watchdog pings the systemd watchdog (WatchdogSec=) at half its timeout
+// while the sync job keeps running, so that systemd restarts an instance with
+// a stuck sync job. It returns None if the watchdog is not enabled.
+pub fn init_watchdog(
+    namespaces: Arc<Namespaces>,
+    stale: u64,
+) -> Option<(JoinHandle<()>, CancellationToken)> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return None;
+    }
+
+    let cancel_watchdog = CancellationToken::new();
+    let stop_signal = cancel_watchdog.clone();
+    let started = unix_ms();
+    Some((
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_micros(usec / 2));
+            let mut stalled = false;
+            loop {
+                tokio::select! {
+                    _ = stop_signal.cancelled() => {
+                        log::info!("gracefully shutting down watchdog job");
+                        return;
+                    }
+                    _ = ticker.tick() => {}
+                };
+
+                let last_runs = namespaces.iter().map(|r| r.sync_status().last_run);
+                if sync_stalled(last_runs, started, unix_ms(), stale) {
+                    if !stalled {
+                        stalled = true;
+                        let msg = format!("sync job stalled for {} seconds", stale);
+                        log::error!("{}, stop pinging the watchdog", msg);
+                        report::error(&msg);
+                        notify(NotifyState::Status(&msg));
+                    }
+                    continue;
+                }
+                if stalled {
+                    stalled = false;
+                    notify(NotifyState::Status("serving"));
+                }
+                notify(NotifyState::Watchdog);
+            }
+        }),
+        cancel_watchdog,
+    ))
+}
+
+// sync_stalled returns true if a namespace has not run the sync job in the last
+// stale seconds, the process start time counts as the last run of the first.
+fn sync_stalled(last_runs: impl Iterator<Item = u64>, started: u64, now: u64, stale: u64) -> bool {
+    stale > 0
+        && last_runs
+            .map(|ts| ts.max(started))
+            .any(|ts| now.saturating_sub(ts) > stale * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_stalled_works() {
+        let now = 1_000_000;
+        assert!(!sync_stalled([now - 1000].into_iter(), 0, now, 300));
+        assert!(!sync_stalled([].into_iter(), 0, now, 300));
+        assert!(sync_stalled(
+            [now - 1000, now - 301_000].into_iter(),
+            0,
+            now,
+            300
+        ));
+        assert!(!sync_stalled([now - 301_000].into_iter(), 0, now, 0));
+        // not run yet
+        assert!(!sync_stalled([0].into_iter(), now - 10_000, now, 300));
+        assert!(sync_stalled([0].into_iter(), now - 301_000, now, 300));
+    }
+
+    #[test]
+    fn pid_file_works() -> io::Result<()> {
+        assert!(PidFile::create("")?.is_none());
+
+        let path = std::env::temp_dir().join(format!("redlimit-{}.pid", process::id()));
+        let path = path.to_str().unwrap();
+        let pid_file = PidFile::create(path)?;
+        assert_eq!(format!("{}\n", process::id()), fs::read_to_string(path)?);
+        drop(pid_file);
+        assert!(fs::metadata(path).is_err());
+
+        let pid_file = PidFile::create(path)?;
+        fs::write(path, "1\n")?;
+        drop(pid_file);
+        assert_eq!("1\n", fs::read_to_string(path)?, "kept for the new process");
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
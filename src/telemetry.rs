@@ -0,0 +1,56 @@
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+
+use super::conf;
+
+pub use redlimit_core::telemetry::tracer;
+
+// init installs the global OTLP tracer if enabled, otherwise the spans are no-op.
+pub fn init(cfg: &conf::Telemetry) -> anyhow::Result<()> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&cfg.endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(trace::Sampler::ParentBased(Box::new(
+                    trace::Sampler::TraceIdRatioBased(cfg.sample_ratio),
+                )))
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    cfg.service_name.clone(),
+                )])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(())
+}
+
+// shutdown flushes the pending spans, it blocks.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{Span, Tracer};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn telemetry_works() -> anyhow::Result<()> {
+        init(&conf::Telemetry::default())?;
+        let mut span = tracer().start("test");
+        span.set_attribute(KeyValue::new("scope", "core"));
+        assert!(!span.span_context().is_sampled(), "no-op when disabled");
+        span.end();
+        Ok(())
+    }
+}
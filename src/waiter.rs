@@ -0,0 +1,139 @@
+use std::future::Future;
+
+use tokio::{
+    sync::Semaphore,
+    time::{sleep, Duration},
+};
+
+use super::{api::Decision, conf, context::unix_ms};
+
+// Waiter retries the limited decisions server-side after their retry delays,
+// bounded by the wait of the request, max_wait and the number of concurrent
+// waiters.
+pub struct Waiter {
+    max_wait: u64,
+    permits: Semaphore,
+}
+
+impl Waiter {
+    pub fn new(cfg: &conf::Wait) -> Self {
+        Waiter {
+            max_wait: cfg.max_wait,
+            permits: Semaphore::new(cfg.max_concurrent),
+        }
+    }
+
+    // wait returns the decision after retries, its timestamp and the waited
+    // milliseconds, or the error of a retry. It returns at once if the decision
+    // is throttled, the first retry delay exceeds the wait, or no permit is
+    // available.
+    pub async fn wait<F, Fut, E>(
+        &self,
+        wait_ms: u64,
+        ts: u64,
+        mut d: Decision,
+        mut check: F,
+    ) -> Result<(Decision, u64, u64), E>
+    where
+        F: FnMut(u64) -> Fut,
+        Fut: Future<Output = Result<Decision, E>>,
+    {
+        let deadline = ts + wait_ms.min(self.max_wait);
+        if d.rt.1 == 0 || d.throttled || ts + d.rt.1 > deadline {
+            return Ok((d, ts, 0));
+        }
+        let _permit = match self.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => return Ok((d, ts, 0)),
+        };
+        let mut now = ts;
+        while d.rt.1 > 0 && now + d.rt.1 <= deadline {
+            sleep(Duration::from_millis(d.rt.1)).await;
+            now = unix_ms();
+            d = check(now).await?;
+        }
+        Ok((d, now, now.saturating_sub(ts)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redlimit::LimitResult;
+    use std::cell::Cell;
+
+    fn decision(retry: u64) -> Decision {
+        Decision {
+            limit: 10,
+            rt: LimitResult(10, retry),
+            shedding: false,
+            throttled: false,
+            fallback: false,
+            errored: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn waiter_works() {
+        let waiter = Waiter::new(&conf::Wait {
+            max_wait: 100,
+            max_concurrent: 1,
+        });
+        let calls = Cell::new(0);
+        let check = |_| {
+            calls.set(calls.get() + 1);
+            let retry = if calls.get() < 2 { 10 } else { 0 };
+            async move { Ok::<_, ()>(decision(retry)) }
+        };
+
+        let ts = unix_ms();
+        // allowed, no wait
+        let (d, _, waited) = waiter.wait(50, ts, decision(0), check).await.unwrap();
+        assert_eq!((0, 0), (d.rt.1, waited));
+        assert_eq!(0, calls.get());
+
+        // the retry delay exceeds the wait
+        let (d, _, waited) = waiter.wait(50, ts, decision(60), check).await.unwrap();
+        assert_eq!((60, 0), (d.rt.1, waited));
+        assert_eq!(0, calls.get());
+
+        // allowed after 2 retries
+        let ts = unix_ms();
+        let (d, now, waited) = waiter.wait(500, ts, decision(10), check).await.unwrap();
+        assert_eq!(0, d.rt.1);
+        assert_eq!(2, calls.get());
+        assert!(waited >= 20);
+        assert_eq!(ts + waited, now);
+
+        // no permit available
+        let _permit = waiter.permits.try_acquire().unwrap();
+        let (d, _, waited) = waiter
+            .wait(50, unix_ms(), decision(10), check)
+            .await
+            .unwrap();
+        assert_eq!((10, 0), (d.rt.1, waited));
+    }
+
+    #[tokio::test]
+    async fn waiter_skips_throttled() {
+        let waiter = Waiter::new(&conf::Wait {
+            max_wait: 100,
+            max_concurrent: 1,
+        });
+        let calls = Cell::new(0);
+        let check = |_| {
+            calls.set(calls.get() + 1);
+            async move { Ok::<_, ()>(decision(0)) }
+        };
+
+        // throttled stays limited on retries, returned at once
+        let d = Decision {
+            throttled: true,
+            ..decision(10)
+        };
+        let (d, _, waited) = waiter.wait(50, unix_ms(), d, check).await.unwrap();
+        assert!(d.throttled);
+        assert_eq!((10, 0), (d.rt.1, waited));
+        assert_eq!(0, calls.get());
+    }
+}